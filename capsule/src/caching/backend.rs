@@ -1,13 +1,13 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use std::fmt;
 use std::pin::Pin;
 use tokio::io::AsyncRead;
 
-use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
+use crate::iohashing::{InputHashBundle, InputOutputBundle, Metadata, OutputHashBundle};
 
 #[async_trait]
-pub trait CachingBackend {
+pub trait CachingBackend: Send + Sync {
     /// Return the name of this backend.
     fn name(&self) -> &'static str {
         "backend"
@@ -16,8 +16,24 @@ pub trait CachingBackend {
     /// Lookup the cache by the inputs hash, and return Some result if there's cache hit.
     async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>>;
 
-    /// Write a cache entry keyed by input, containing hashes of outputs.
-    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()>;
+    /// Lookup several cache entries at once, returning the results in the same order as the inputs.
+    /// Backends that can issue the lookups concurrently should override it.
+    async fn lookup_many(&self, inputs: &[InputHashBundle]) -> Result<Vec<Option<InputOutputBundle>>> {
+        let mut results = Vec::with_capacity(inputs.len());
+        for inputs in inputs {
+            results.push(self.lookup(inputs).await?);
+        }
+        Ok(results)
+    }
+
+    /// Write a cache entry keyed by input, containing hashes of outputs, and the attached metadata.
+    async fn write(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        metadata: Metadata,
+    ) -> Result<()>;
 
     /// Download a file addressed by item_hash from the backend storage, and return an AsyncRead handle
     /// that allows the caller to keep asynchrnously fetching the content.
@@ -32,6 +48,41 @@ pub trait CachingBackend {
         file: Pin<Box<dyn AsyncRead + Send>>,
         content_length: u64,
     ) -> Result<()>;
+
+    /// Return the key the object addressed by item_hash is stored under in the backend storage.
+    fn object_key(&self, item_hash: &str) -> String {
+        item_hash.to_owned()
+    }
+
+    /// Remove the cache entry keyed by the inputs hash. It's only used by the self-test, so not all
+    /// backends support it.
+    async fn remove(&self, _inputs: &InputHashBundle) -> Result<()> {
+        bail!("Removing cache entries is not supported by the {} backend", self.name())
+    }
+
+    /// Remove the object addressed by item_hash. It's only used by the self-test, so not all
+    /// backends support it.
+    async fn remove_object_file(&self, _item_hash: &str) -> Result<()> {
+        bail!("Removing objects is not supported by the {} backend", self.name())
+    }
+
+    /// List the inputs hashes of the cache entries of the capsule ID. It's only used by `capsule migrate`,
+    /// so not all backends support it.
+    async fn list_entries(&self) -> Result<Vec<String>> {
+        bail!("Listing cache entries is not supported by the {} backend", self.name())
+    }
+
+    /// Return the (uncompressed) size of the object addressed by item_hash, or None if it's not stored.
+    /// It's only used by `capsule migrate`, so not all backends support it.
+    async fn object_size(&self, _item_hash: &str) -> Result<Option<u64>> {
+        bail!("Checking objects is not supported by the {} backend", self.name())
+    }
+
+    /// Return a summary of what the backend did, to be logged at the end of the run. Only the
+    /// backends collecting statistics have one.
+    fn summary(&self) -> Option<String> {
+        None
+    }
 }
 
 impl fmt::Debug for dyn CachingBackend {
@@ -5,7 +5,7 @@ use log::info;
 use std::pin::Pin;
 use tokio::io::AsyncRead;
 
-use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
+use crate::iohashing::{InputHashBundle, InputOutputBundle, Metadata, OutputHashBundle};
 
 #[derive(Default)]
 pub struct DummyBackend {
@@ -38,7 +38,13 @@ impl CachingBackend for DummyBackend {
         Ok(())
     }
 
-    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+    async fn write(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        metadata: Metadata,
+    ) -> Result<()> {
         info!(
             "Capsule ID: '{}'. Capsule Source: '{}', Inputs key: '{}', Outputs key: {}",
             self.capsule_id, source, inputs.hash, outputs.hash,
@@ -46,6 +52,7 @@ impl CachingBackend for DummyBackend {
         if self.verbose_output {
             info!("  Capsule Inputs hashes: {:?}", inputs.hash_details);
             info!("  Capsule Outputs hashes: {:?}", outputs.hash_details);
+            info!("  Capsule metadata: {:?}", metadata);
         }
         Ok(())
     }
@@ -0,0 +1,235 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::info;
+use reqwest::multipart;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use tempfile::tempfile;
+use tokio::io::{AsyncRead, AsyncSeekExt};
+use tokio_util::codec;
+use tokio_util::io::StreamReader;
+
+use crate::caching::backend::CachingBackend;
+use crate::config::Config;
+use crate::iohashing::{InputHashBundle, InputOutputBundle, Metadata, OutputHashBundle};
+
+/// Objects-only adapter that stores objects in IPFS, and delegates the cache keys to another backend.
+///
+/// IPFS addresses objects by their CIDs, which cannot be derived from the sha256 hashes that capsule
+/// uses as object keys. Therefore, for every uploaded object we also write a small sha256 -> CID index
+/// entry into the keys backend.
+pub struct IpfsBackend {
+    /// URL of the IPFS node HTTP API, e.g. http://127.0.0.1:5001
+    pub api_url: String,
+
+    /// Backend for the cache entries, and the sha256 -> CID index.
+    pub keys_backend: Box<dyn CachingBackend + Send + Sync>,
+
+    /// HTTP client for the IPFS API.
+    client: reqwest::Client,
+}
+
+/// The part of the response of /api/v0/add that we are interested in.
+#[derive(Deserialize)]
+struct AddResponse {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+impl IpfsBackend {
+    pub fn new(api_url: &str, keys_backend: Box<dyn CachingBackend + Send + Sync>) -> Self {
+        Self {
+            api_url: api_url.trim_end_matches('/').to_owned(),
+            keys_backend,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn from_config(config: &Config, keys_backend: Box<dyn CachingBackend + Send + Sync>) -> Result<Self> {
+        let api_url = config
+            .ipfs_api_url
+            .as_ref()
+            .ok_or_else(|| anyhow!("IPFS API URL not specified"))?;
+        Ok(Self::new(api_url, keys_backend))
+    }
+
+    fn api(&self, method: &str) -> String {
+        format!("{}/api/v0/{}", self.api_url, method)
+    }
+
+    // The index entry is stored as a cache entry, whose key is derived from the object hash, so that
+    // it can never collide with a real inputs hash. The CID is kept in the 'source' field.
+    fn index_key(item_hash: &str) -> InputHashBundle {
+        let mut acc = Sha256::new();
+        acc.update("ipfs-cid");
+        acc.update(item_hash);
+        InputHashBundle {
+            hash: format!("{:x}", acc.finalize()),
+            hash_details: vec![],
+            ..Default::default()
+        }
+    }
+
+    async fn lookup_cid(&self, item_hash: &str) -> Result<Option<String>> {
+        let entry = self.keys_backend.lookup(&Self::index_key(item_hash)).await?;
+        Ok(entry.map(|entry| entry.source))
+    }
+
+    async fn write_cid(&self, item_hash: &str, cid: String) -> Result<()> {
+        self.keys_backend
+            .write(
+                &Self::index_key(item_hash),
+                &OutputHashBundle::default(),
+                cid,
+                Metadata::default(),
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl CachingBackend for IpfsBackend {
+    fn name(&self) -> &'static str {
+        "ipfs"
+    }
+
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        self.keys_backend.lookup(inputs).await
+    }
+
+    async fn lookup_many(&self, inputs: &[InputHashBundle]) -> Result<Vec<Option<InputOutputBundle>>> {
+        self.keys_backend.lookup_many(inputs).await
+    }
+
+    async fn write(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        metadata: Metadata,
+    ) -> Result<()> {
+        self.keys_backend.write(inputs, outputs, source, metadata).await
+    }
+
+    async fn remove(&self, inputs: &InputHashBundle) -> Result<()> {
+        self.keys_backend.remove(inputs).await
+    }
+
+    /// Find the CID of the object in the index, and stream it from the IPFS node.
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
+        let cid = self
+            .lookup_cid(item_hash)
+            .await?
+            .ok_or_else(|| anyhow!("No IPFS CID found for object '{}'", item_hash))?;
+        let response = self
+            .client
+            .post(self.api("cat"))
+            .query(&[("arg", &cid)])
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("Fetching object '{}' from IPFS", cid))?;
+        // Not io::Error::other, which needs Rust 1.74.
+        #[allow(clippy::io_other_error)]
+        let stream = response
+            .bytes_stream()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    async fn upload_object_file(
+        &self,
+        name: String,
+        item_hash: &str,
+        mut file: Pin<Box<dyn AsyncRead + Send>>,
+        _content_length: u64,
+    ) -> Result<()> {
+        // Objects are content addressed, so if we already know the CID, the upload can be skipped.
+        if self.lookup_cid(item_hash).await?.is_some() {
+            info!("Skipping upload for {} with hash '{}'", name, item_hash);
+            return Ok(());
+        } else {
+            info!("Uploading object {} to IPFS '{}'", name, item_hash);
+        }
+
+        // The request body stream has to be Sync, so copy the file into a temporary file first.
+        let tmp = tempfile()?;
+        let mut tmp = tokio::fs::File::from_std(tmp);
+        tokio::io::copy(&mut file, &mut tmp).await?;
+        let content_length = tmp.metadata().await?.len();
+        tmp.seek(std::io::SeekFrom::Start(0)).await?;
+
+        let byte_stream = codec::FramedRead::new(tmp, codec::BytesCodec::new()).map_ok(|r| r.freeze());
+        let part = multipart::Part::stream_with_length(reqwest::Body::wrap_stream(byte_stream), content_length)
+            .file_name(name.clone());
+        let response: AddResponse = self
+            .client
+            .post(self.api("add"))
+            .query(&[("pin", "true"), ("cid-version", "1")])
+            .multipart(multipart::Form::new().part("file", part))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("Uploading object {} to IPFS", name))?
+            .json()
+            .await
+            .context("Parsing IPFS add response")?;
+
+        self.write_cid(item_hash, response.hash).await
+    }
+
+    /// Unpin the object from the IPFS node, and drop it from the index.
+    async fn remove_object_file(&self, item_hash: &str) -> Result<()> {
+        if let Some(cid) = self.lookup_cid(item_hash).await? {
+            self.client
+                .post(self.api("pin/rm"))
+                .query(&[("arg", &cid)])
+                .send()
+                .await?
+                .error_for_status()
+                .with_context(|| format!("Unpinning object '{}' in IPFS", cid))?;
+        }
+        self.keys_backend.remove(&Self::index_key(item_hash)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::test::{TestBackend, TestBackendConfig};
+
+    #[tokio::test]
+    async fn test_cid_index() {
+        let backend = IpfsBackend::new(
+            "http://127.0.0.1:5001/",
+            Box::new(TestBackend::new("wtf", TestBackendConfig::default())),
+        );
+        assert_eq!(backend.api("add"), "http://127.0.0.1:5001/api/v0/add");
+        assert!(backend.lookup_cid("abcdef").await.unwrap().is_none());
+        backend.write_cid("abcdef", "bafkqaaa".to_owned()).await.unwrap();
+        assert_eq!(backend.lookup_cid("abcdef").await.unwrap().unwrap(), "bafkqaaa");
+        assert!(backend.lookup_cid("fedcba").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_many() {
+        let backend = IpfsBackend::new(
+            "http://127.0.0.1:5001/",
+            Box::new(TestBackend::new("wtf", TestBackendConfig::default())),
+        );
+        backend.write_cid("abcdef", "bafkqaaa".to_owned()).await.unwrap();
+        backend.write_cid("012345", "bafkqbbb".to_owned()).await.unwrap();
+        let keys = ["012345", "fedcba", "abcdef"].map(IpfsBackend::index_key);
+        let results = backend.lookup_many(&keys).await.unwrap();
+        let sources: Vec<_> = results
+            .into_iter()
+            .map(|entry| entry.map(|entry| entry.source))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![Some("bafkqbbb".to_owned()), None, Some("bafkqaaa".to_owned())]
+        );
+    }
+}
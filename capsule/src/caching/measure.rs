@@ -0,0 +1,147 @@
+use crate::caching::backend::CachingBackend;
+use anyhow::{anyhow, Result};
+use async_compression::tokio::bufread::GzipEncoder;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::Config;
+use crate::iohashing::{InputHashBundle, InputOutputBundle, Metadata, OutputHashBundle};
+
+#[derive(Default)]
+struct Measurements {
+    // Sizes of the objects that would be uploaded, by their hashes, so that each is counted once.
+    objects: HashMap<String, u64>,
+    bundles: u64,
+    bundle_bytes: u64,
+}
+
+// A backend that stores nothing, and always misses, but measures how much would have been stored,
+// to estimate the storage needs before choosing a real backend.
+#[derive(Default)]
+pub struct MeasureBackend {
+    compress_bundles: bool,
+    measurements: Mutex<Measurements>,
+}
+
+impl MeasureBackend {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            compress_bundles: config.compress_bundles,
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl CachingBackend for MeasureBackend {
+    fn name(&self) -> &'static str {
+        "measure"
+    }
+
+    async fn lookup(&self, _inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        Ok(None)
+    }
+
+    async fn download_object_file(&self, _item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
+        Err(anyhow!("downloading object file in the measure backend"))
+    }
+
+    async fn upload_object_file(
+        &self,
+        _name: String,
+        item_hash: &str,
+        _file: Pin<Box<dyn AsyncRead + Send>>,
+        content_length: u64,
+    ) -> Result<()> {
+        let mut measurements = self.measurements.lock().unwrap();
+        measurements.objects.insert(item_hash.to_owned(), content_length);
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        metadata: Metadata,
+    ) -> Result<()> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+            metadata,
+        };
+        // Serialized the same way as the S3 backend does.
+        let mut data = serde_json::to_vec(&io_bundle)?;
+        if self.compress_bundles {
+            let mut encoded = Vec::new();
+            GzipEncoder::new(&data[..]).read_to_end(&mut encoded).await?;
+            data = encoded;
+        }
+        let mut measurements = self.measurements.lock().unwrap();
+        measurements.bundles += 1;
+        measurements.bundle_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    fn summary(&self) -> Option<String> {
+        let measurements = self.measurements.lock().unwrap();
+        Some(format!(
+            "Would upload {} objects of {} bytes, and write {} cache entries of {} bytes",
+            measurements.objects.len(),
+            measurements.objects.values().sum::<u64>(),
+            measurements.bundles,
+            measurements.bundle_bytes
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::{InputSet, OutputSet};
+
+    #[tokio::test]
+    async fn test_measure() {
+        let backend = MeasureBackend::default();
+        assert_eq!(
+            backend.summary().unwrap(),
+            "Would upload 0 objects of 0 bytes, and write 0 cache entries of 0 bytes"
+        );
+        for _ in 0..2 {
+            // The same object is only counted once.
+            backend
+                .upload_object_file("a".into(), "hash_a", Box::pin(&b"12345"[..]), 5)
+                .await
+                .unwrap();
+        }
+        backend
+            .upload_object_file("b".into(), "hash_b", Box::pin(&b"123"[..]), 3)
+            .await
+            .unwrap();
+        let inputs = InputSet::default().hash_bundle(&None).unwrap();
+        let outputs = OutputSet::default().hash_bundle(&None).unwrap();
+        backend
+            .write(&inputs, &outputs, "source".into(), Metadata::default())
+            .await
+            .unwrap();
+        let bundle_bytes = serde_json::to_vec(&InputOutputBundle {
+            inputs,
+            outputs,
+            source: "source".into(),
+            metadata: Metadata::default(),
+        })
+        .unwrap()
+        .len();
+        assert_eq!(
+            backend.summary().unwrap(),
+            format!(
+                "Would upload 2 objects of 8 bytes, and write 1 cache entries of {} bytes",
+                bundle_bytes
+            )
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Copying the cache entries of a capsule ID, with the objects they reference, from one backend to
+//! another, for `capsule migrate`.
+use anyhow::{Context, Result};
+use log::warn;
+use std::collections::BTreeSet;
+use std::fmt;
+use tempfile::tempfile;
+use tokio::io::AsyncSeekExt;
+
+use crate::caching::backend::CachingBackend;
+use crate::iohashing::InputHashBundle;
+
+/// What 'capsule migrate' copied, or would copy with --dry_run.
+#[derive(Default, Debug)]
+pub struct MigrateSummary {
+    /// Cache entries copied.
+    pub entries: usize,
+    /// Cache entries already in the destination.
+    pub skipped_entries: usize,
+    /// Cache entries not copied, as some of their objects are missing in the source.
+    pub missing_entries: usize,
+    /// Objects copied.
+    pub objects: usize,
+    /// Objects already in the destination.
+    pub skipped_objects: usize,
+    /// Total (uncompressed) size of the objects copied.
+    pub bytes: u64,
+}
+
+impl fmt::Display for MigrateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} cache entries and {} objects of {} bytes ({} entries and {} objects already there, {} entries \
+             with missing objects skipped)",
+            self.entries, self.objects, self.bytes, self.skipped_entries, self.skipped_objects, self.missing_entries
+        )
+    }
+}
+
+/// Copy the cache entries of the capsule ID, with the objects they reference, to another backend.
+/// The objects are copied before the entry, so that an entry already in the destination has its
+/// objects there too, and is skipped when an interrupted migration is resumed. The objects already
+/// in the destination are skipped as well, and the entries with objects missing in the source are
+/// not copied. With `dry_run`, nothing is copied, only counted.
+pub async fn migrate(from: &dyn CachingBackend, to: &dyn CachingBackend, dry_run: bool) -> Result<MigrateSummary> {
+    let mut summary = MigrateSummary::default();
+    let mut copied_objects = BTreeSet::new();
+    for hash in from.list_entries().await? {
+        let inputs = InputHashBundle {
+            hash,
+            ..Default::default()
+        };
+        if to.lookup(&inputs).await?.is_some() {
+            summary.skipped_entries += 1;
+            continue;
+        }
+        let bundle = match from.lookup(&inputs).await? {
+            Some(bundle) => bundle,
+            None => {
+                warn!("Skipping cache entry '{}', which can't be read", inputs.hash);
+                continue;
+            }
+        };
+        // All the objects are checked before any is copied, as an entry with a missing object (e.g.
+        // written with --no_upload, or garbage collected) is not copied.
+        let mut objects = Vec::new();
+        let mut missing = None;
+        for (name, item_hash) in bundle.outputs.stored_objects() {
+            // Chunks and outputs may be shared by the entries, so each object is copied once.
+            if copied_objects.contains(&item_hash) || objects.iter().any(|(_, hash, _)| *hash == item_hash) {
+                continue;
+            }
+            if to.object_size(&item_hash).await?.is_some() {
+                summary.skipped_objects += 1;
+                copied_objects.insert(item_hash);
+                continue;
+            }
+            match from.object_size(&item_hash).await? {
+                Some(size) => objects.push((name, item_hash, size)),
+                None => {
+                    missing = Some(item_hash);
+                    break;
+                }
+            }
+        }
+        if let Some(item_hash) = missing {
+            warn!(
+                "Skipping cache entry '{}' with missing object '{}'",
+                inputs.hash, item_hash
+            );
+            summary.missing_entries += 1;
+            continue;
+        }
+        for (name, item_hash, size) in objects {
+            summary.objects += 1;
+            summary.bytes += if dry_run {
+                size
+            } else {
+                copy_object(from, to, name, &item_hash)
+                    .await
+                    .with_context(|| format!("Copying object '{}' of cache entry '{}'", item_hash, inputs.hash))?
+            };
+            copied_objects.insert(item_hash);
+        }
+        summary.entries += 1;
+        if !dry_run {
+            to.write(&bundle.inputs, &bundle.outputs, bundle.source, bundle.metadata)
+                .await
+                .with_context(|| format!("Writing cache entry '{}'", inputs.hash))?;
+        }
+    }
+    Ok(summary)
+}
+
+/// Copy the object to another backend through a temporary file, as the upload needs its length.
+async fn copy_object(from: &dyn CachingBackend, to: &dyn CachingBackend, name: String, item_hash: &str) -> Result<u64> {
+    let mut reader = from.download_object_file(item_hash).await?;
+    let mut file = tokio::fs::File::from_std(tempfile()?);
+    let length = tokio::io::copy(&mut reader, &mut file).await?;
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+    to.upload_object_file(name, item_hash, Box::pin(file), length).await?;
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::test::{TestBackend, TestBackendConfig};
+    use crate::iohashing::{FileOutput, Output, OutputHashBundle};
+    use crate::workspace_path::WorkspacePath;
+
+    // Store an entry with a single output object in the backend.
+    async fn store_entry(backend: &TestBackend, hash: &str, content: &str) {
+        let object_hash = format!("object_{}", hash);
+        let inputs = InputHashBundle {
+            hash: hash.to_owned(),
+            ..Default::default()
+        };
+        let mut outputs = OutputHashBundle::default();
+        let output = Output::File(FileOutput {
+            filename: WorkspacePath::from(format!("/out/{}", hash)),
+            present: true,
+            mode: 0o644,
+            negative: false,
+            chunks: vec![],
+        });
+        outputs.hash_details.push((output, object_hash.clone()));
+        let file = Box::pin(std::io::Cursor::new(content.as_bytes().to_vec()));
+        backend
+            .upload_object_file(hash.to_owned(), &object_hash, file, content.len() as u64)
+            .await
+            .unwrap();
+        backend
+            .write(&inputs, &outputs, String::new(), Default::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let from = TestBackend::new("wtf", TestBackendConfig::default());
+        store_entry(&from, "a", "first").await;
+        store_entry(&from, "b", "second").await;
+        store_entry(&from, "c", "third").await;
+        // The entry of another capsule ID is not copied.
+        store_entry(&TestBackend::new("other", TestBackendConfig::default()), "d", "fourth").await;
+        // Nor is the one with a missing object.
+        from.remove_object_file("object_c").await.unwrap();
+
+        // A dry run only counts what would be copied.
+        let to = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                failed_writes: 1,
+                ..Default::default()
+            },
+        );
+        let summary = migrate(&from, &to, true).await.unwrap();
+        assert_eq!(
+            (summary.entries, summary.objects, summary.bytes, summary.missing_entries),
+            (2, 2, 11, 1)
+        );
+        assert!(to.list_entries().await.unwrap().is_empty());
+        assert_eq!(to.object_size("object_a").await.unwrap(), None);
+
+        // The migration is interrupted by the failed write of the first entry, after its object is copied.
+        assert!(migrate(&from, &to, false).await.is_err());
+        assert!(to.list_entries().await.unwrap().is_empty());
+        assert_eq!(to.object_size("object_a").await.unwrap(), Some(5));
+
+        // Resuming it skips the object already copied.
+        let summary = migrate(&from, &to, false).await.unwrap();
+        assert_eq!(
+            (summary.entries, summary.objects, summary.skipped_objects, summary.bytes),
+            (2, 1, 1, 6)
+        );
+        assert_eq!(to.list_entries().await.unwrap(), vec!["a", "b"]);
+        let inputs = InputHashBundle {
+            hash: "b".to_owned(),
+            ..Default::default()
+        };
+        let entry = to.lookup(&inputs).await.unwrap().unwrap();
+        assert_eq!(
+            entry.outputs.stored_objects(),
+            vec![("/out/b".to_owned(), "object_b".to_owned())]
+        );
+
+        // Once done, all the entries are skipped.
+        let summary = migrate(&from, &to, false).await.unwrap();
+        assert_eq!(
+            (summary.entries, summary.skipped_entries, summary.missing_entries),
+            (0, 2, 1)
+        );
+    }
+
+    #[test]
+    fn test_migrate_summary() {
+        let summary = MigrateSummary {
+            entries: 2,
+            skipped_entries: 1,
+            missing_entries: 3,
+            objects: 4,
+            skipped_objects: 5,
+            bytes: 1000,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "2 cache entries and 4 objects of 1000 bytes (1 entries and 5 objects already there, 3 entries with \
+             missing objects skipped)"
+        );
+    }
+}
@@ -1,4 +1,7 @@
 pub mod backend;
 pub mod dummy;
+pub mod ipfs;
+pub mod measure;
+pub mod migrate;
 pub mod s3;
 pub mod test;
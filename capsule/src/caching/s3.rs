@@ -1,21 +1,50 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use anyhow::{Context, Result};
 use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
 use async_trait::async_trait;
-use futures::TryStreamExt;
+use flate2::{Compress, Compression, Crc, FlushCompress};
+use futures::{StreamExt, TryStreamExt};
+use hyper::client::HttpConnector;
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_tls::HttpsConnector;
 use hyperx::header::CacheDirective;
-use log::{error, info};
+use log::{error, info, warn};
+use rusoto_core::credential::{
+    AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider, ProfileProvider,
+    ProvideAwsCredentials,
+};
 use rusoto_core::region::Region;
-use rusoto_s3::{GetObjectRequest, HeadObjectRequest, PutObjectRequest, S3Client, S3 as _};
+use rusoto_core::HttpClient;
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectRequest, HeadBucketError, HeadBucketRequest, HeadObjectOutput, HeadObjectRequest,
+    ListObjectsV2Request, PutObjectRequest, S3Client, S3 as _,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
 use serde_json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
 use std::pin::Pin;
+use std::sync::Arc;
 use tempfile::tempfile;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio_util::codec;
+use tokio_util::io::StreamReader;
 
 use crate::caching::backend::CachingBackend;
-use crate::config::Config;
-use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
+use crate::config::{no_proxy_matches, Config};
+use crate::iohashing::{InputHashBundle, InputOutputBundle, Metadata, OutputHashBundle};
+
+/// AWS credentials shared by several clients, so that they are resolved and refreshed once.
+#[derive(Clone)]
+struct SharedCredentials(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+#[async_trait]
+impl ProvideAwsCredentials for SharedCredentials {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
+    }
+}
 
 pub struct S3Backend {
     /// S3 bucket for keys
@@ -35,51 +64,337 @@ pub struct S3Backend {
 
     /// Capsule ID
     pub capsule_id: String,
+
+    /// Whether to gzip cache entries (bundles) written to the keys bucket.
+    pub compress_bundles: bool,
+
+    /// Whether to upload objects with the content type detected from the file name.
+    pub detect_content_type: bool,
+
+    /// The canned ACL of the uploaded objects, the cache entries are uploaded without one.
+    pub objects_acl: Option<String>,
+
+    /// The number of threads gzipping each uploaded object, 1 to gzip it as a stream.
+    pub compression_threads: usize,
+
+    /// Whether to check the size of the objects that already exist, instead of skipping their uploads.
+    pub strict_objects: bool,
+
+    /// Whether to check the size of the objects that already exist too, but only report a mismatch.
+    pub verify_existing_objects: bool,
+
+    /// How many cache entries are fetched concurrently by lookup_many.
+    pub lookup_concurrency: usize,
+
+    /// Levels of hash prefixes in the object keys.
+    pub object_shard_depth: usize,
+
+    /// The zstd dictionary to compress the cache entries (bundles) with, instead of gzip.
+    pub bundle_zstd_dict: Option<Vec<u8>>,
+
+    /// The URL of a CDN in front of the objects bucket, to download the objects from.
+    pub objects_cdn_url: Option<String>,
+
+    /// An HTTP client for the CDN downloads.
+    pub cdn_client: reqwest::Client,
+}
+
+/// The object metadata with the uncompressed size of the object.
+const SIZE_METADATA: &str = "capsule-size";
+
+/// The metadata of a cache entry with the SHA256 of its (uncompressed) JSON.
+const DIGEST_METADATA: &str = "capsule-sha256";
+
+/// Check the JSON of a cache entry against the digest stored with it. The entries written before the
+/// digests were stored have none, and are not checked.
+fn verify_bundle_digest(key: &str, body: &[u8], digest: Option<&String>) -> Result<()> {
+    if let Some(digest) = digest {
+        let actual = format!("{:x}", Sha256::digest(body));
+        if actual != *digest {
+            bail!(
+                "Cache entry '{}' is corrupted: its SHA256 is {}, instead of {}",
+                key,
+                actual,
+                digest
+            );
+        }
+    }
+    Ok(())
+}
+
+/// An existing object with the key of the one being uploaded, but of another size.
+#[derive(Debug, PartialEq)]
+pub struct ObjectMismatch {
+    pub key: String,
+    pub size: u64,
+    pub expected_size: u64,
+}
+
+impl fmt::Display for ObjectMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Object '{}' already exists with size {}, instead of {}, it may be corrupted, or it's a hash collision",
+            self.key, self.size, self.expected_size
+        )
+    }
+}
+
+impl std::error::Error for ObjectMismatch {}
+
+/// Check that an existing object is the same as the one being uploaded, as far as its size tells.
+/// The objects are immutable, so a mismatch means a corrupted object, or a hash collision. Objects
+/// uploaded without the size metadata are not checked, with a warning, as their compressed size
+/// depends on the encoder. A mismatch is returned as an `ObjectMismatch` error.
+fn verify_existing_object(key: &str, existing: &HeadObjectOutput, length: u64) -> Result<()> {
+    let stored_length = match existing
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get(SIZE_METADATA))
+    {
+        Some(stored_length) => stored_length
+            .parse::<u64>()
+            .with_context(|| format!("Invalid size metadata of object '{}'", key))?,
+        None => {
+            warn!("Cannot verify object '{}' uploaded without the size metadata", key);
+            return Ok(());
+        }
+    };
+    if stored_length != length {
+        bail!(ObjectMismatch {
+            key: key.to_owned(),
+            size: stored_length,
+            expected_size: length,
+        });
+    }
+    Ok(())
+}
+
+/// The size of the dictionaries trained by 'capsule train-dict', the zstd default.
+const ZSTD_DICT_SIZE: usize = 110 << 10;
+
+const ZSTD_LEVEL: i32 = 3;
+
+fn zstd_compress(data: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::Compressor::with_dictionary(ZSTD_LEVEL, dict)?.compress(data)?)
+}
+
+fn zstd_decompress(data: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    std::io::Read::read_to_end(&mut zstd::stream::Decoder::with_dictionary(data, dict)?, &mut decoded)?;
+    Ok(decoded)
+}
+
+/// Decompress the cache entry (bundle) read with the given content encoding. Bundles may be written
+/// either compressed or not, depending on --compress_bundles and --bundle_zstd_dict.
+async fn decode_bundle(content_encoding: &str, body: Vec<u8>, zstd_dict: Option<&[u8]>) -> Result<Vec<u8>> {
+    match content_encoding {
+        "gzip" => {
+            let mut decoded = Vec::new();
+            GzipDecoder::new(&body[..])
+                .read_to_end(&mut decoded)
+                .await
+                .context("failed to decompress cache entry")?;
+            Ok(decoded)
+        }
+        "zstd" => {
+            let dict = zstd_dict.context("Cache entry is compressed with zstd, but --bundle_zstd_dict is not given")?;
+            zstd_decompress(&body, dict).context("failed to decompress cache entry")
+        }
+        _ => Ok(body),
+    }
+}
+
+/// The size of the blocks of the objects that are compressed in parallel with --compression_threads.
+const COMPRESSION_BLOCK_SIZE: usize = 1 << 20;
+
+/// Deflate a block on its own, ending it with a sync flush, i.e. on a byte boundary and without
+/// the final block, so that the deflated blocks can be concatenated. Returns the CRC of the block too.
+fn deflate_block(block: Vec<u8>) -> Result<(Crc, Vec<u8>)> {
+    let mut crc = Crc::new();
+    crc.update(&block);
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut deflated = Vec::with_capacity(block.len() / 2 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress.compress_vec(&block[consumed..], &mut deflated, FlushCompress::Sync)?;
+        // The flush is complete once all the input is consumed, and there's output space left.
+        if compress.total_in() as usize == block.len() && deflated.len() < deflated.capacity() {
+            break;
+        }
+        deflated.reserve(deflated.capacity());
+    }
+    Ok((crc, deflated))
+}
+
+/// Gzip the reader into the writer with up to `threads` blocks of `block_size` deflated at once,
+/// the way pigz does it. The deflated blocks are concatenated into a single deflate stream, so
+/// the result is an ordinary single member gzip file, which any gzip decoder reads. The blocks
+/// don't share the history though, so it's slightly larger than the gzip of the whole stream.
+async fn parallel_gzip<R, W>(mut reader: R, writer: &mut W, threads: usize, block_size: usize) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Gzip header: magic, deflate, no flags, no modification time, no extra flags, Unix.
+    writer.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3]).await?;
+    let mut crc = Crc::new();
+    loop {
+        let mut tasks = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let mut block = Vec::with_capacity(block_size);
+            (&mut reader).take(block_size as u64).read_to_end(&mut block).await?;
+            if block.is_empty() {
+                break;
+            }
+            tasks.push(tokio::task::spawn_blocking(move || deflate_block(block)));
+        }
+        if tasks.is_empty() {
+            break;
+        }
+        // Write the blocks in order, as they are deflated.
+        for task in tasks {
+            let (block_crc, deflated) = task.await??;
+            crc.combine(&block_crc);
+            writer.write_all(&deflated).await?;
+        }
+    }
+    // An empty final block with fixed Huffman codes ends the deflate stream, then the gzip trailer.
+    writer.write_all(&[3, 0]).await?;
+    writer.write_all(&crc.sum().to_le_bytes()).await?;
+    writer.write_all(&crc.amount().to_le_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The maximum --object_shard_depth. Each level has 256 prefixes, so this is plenty.
+const MAX_OBJECT_SHARD_DEPTH: usize = 4;
+
+/// The canned ACLs that the objects can be uploaded with.
+const OBJECT_ACLS: [&str; 7] = [
+    "private",
+    "public-read",
+    "public-read-write",
+    "authenticated-read",
+    "aws-exec-read",
+    "bucket-owner-read",
+    "bucket-owner-full-control",
+];
+
+/// Returns the object key with `depth` levels of two hex digit prefixes, e.g. `ab/cd/abcd...`.
+fn shard_key(key: &str, depth: usize) -> String {
+    let mut sharded = String::with_capacity(key.len() + 3 * depth);
+    for level in 0..depth {
+        sharded += &key[2 * level..2 * level + 2];
+        sharded.push('/');
+    }
+    sharded + key
+}
+
+/// Returns the AWS region of a standard S3 endpoint, e.g. `us-west-2` for `https://s3.us-west-2.amazonaws.com`,
+/// also in its legacy `s3-us-west-2`, dualstack, FIPS and virtual host forms, or None for other endpoints.
+fn region_from_endpoint(endpoint: &str) -> Option<String> {
+    let host = endpoint.rsplit("://").next()?.split(['/', ':']).next()?;
+    let host = host
+        .strip_suffix(".amazonaws.com")
+        .or_else(|| host.strip_suffix(".amazonaws.com.cn"))?;
+    let labels: Vec<&str> = host.split('.').collect();
+    let is_region = |label: &&str| label.contains('-') && label.ends_with(|c: char| c.is_ascii_digit());
+    let s3 = labels
+        .iter()
+        .rposition(|label| *label == "s3" || label.starts_with("s3-"))?;
+    if let Some(region) = labels[s3 + 1..].iter().find(|label| is_region(label)) {
+        return Some(region.to_string());
+    }
+    match labels[s3].strip_prefix("s3-") {
+        Some("external-1") => Some("us-east-1".to_owned()),
+        Some(region) if is_region(&region) => Some(region.to_owned()),
+        None if s3 + 1 == labels.len() => Some("us-east-1".to_owned()),
+        _ => None,
+    }
+}
+
+/// Returns the given region, or the one of the endpoint if it's a standard AWS one.
+fn region_name(region: Option<&str>, endpoint: Option<&str>) -> Option<String> {
+    region
+        .map(str::to_owned)
+        .or_else(|| endpoint.and_then(region_from_endpoint))
+}
+
+/// Download the object with the given key from the CDN, which serves it as stored in the bucket.
+async fn download_from_cdn(client: &reqwest::Client, cdn_url: &str, key: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
+    let response = client
+        .get(format!("{}/{}", cdn_url, key))
+        .send()
+        .await?
+        .error_for_status()?;
+    // The same as the S3 downloads, the objects may be stored gzipped either way.
+    let header_is =
+        |name, value: &str| response.headers().get(name).map(|value| value.as_bytes()) == Some(value.as_bytes());
+    let gzipped = header_is(reqwest::header::CONTENT_ENCODING, "gzip")
+        || header_is(reqwest::header::CONTENT_TYPE, "application/gzip");
+    // Not io::Error::other, which needs Rust 1.74.
+    #[allow(clippy::io_other_error)]
+    let body = StreamReader::new(
+        response
+            .bytes_stream()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err)),
+    );
+    if gzipped {
+        Ok(Box::pin(GzipDecoder::new(BufReader::new(body))))
+    } else {
+        Ok(Box::pin(body))
+    }
 }
 
 impl S3Backend {
     pub fn from_config(config: &Config) -> Result<Self> {
-        let client = S3Client::new(Region::Custom {
-            name: config
-                .s3_region
-                .as_ref()
-                .cloned()
-                .ok_or_else(|| anyhow!("S3 region not specified"))?,
-            endpoint: config
-                .s3_endpoint
-                .as_ref()
-                .cloned()
-                .ok_or_else(|| anyhow!("S3 endpoint not specified"))?,
-        });
+        if config.object_shard_depth > MAX_OBJECT_SHARD_DEPTH {
+            bail!(
+                "Object shard depth {} is larger than {}",
+                config.object_shard_depth,
+                MAX_OBJECT_SHARD_DEPTH
+            );
+        }
+        if let Some(acl) = &config.s3_objects_acl {
+            if !OBJECT_ACLS.contains(&acl.as_str()) {
+                bail!(
+                    "Invalid objects ACL '{}', expected one of: {}",
+                    acl,
+                    OBJECT_ACLS.join(", ")
+                );
+            }
+        }
+        // The credentials are resolved once, and shared by all the clients.
+        let credentials = Self::credentials_provider(config)?;
+        let client = Self::client(
+            config,
+            &credentials,
+            Self::region("S3", config.s3_region.as_deref(), config.s3_endpoint.as_deref())?,
+        )?;
         let client_uploads = if config.s3_uploads_endpoint.is_some() || config.s3_uploads_region.is_some() {
-            S3Client::new(Region::Custom {
-                name: config
-                    .s3_uploads_region
-                    .as_ref()
-                    .cloned()
-                    .ok_or_else(|| anyhow!("S3 uploads region not specified"))?,
-                endpoint: config
-                    .s3_uploads_endpoint
-                    .as_ref()
-                    .cloned()
-                    .ok_or_else(|| anyhow!("S3 uploads endpoint not specified"))?,
-            })
+            Self::client(
+                config,
+                &credentials,
+                Self::region(
+                    "S3 uploads",
+                    config.s3_uploads_region.as_deref(),
+                    config.s3_uploads_endpoint.as_deref(),
+                )?,
+            )?
         } else {
             client.clone()
         };
         let client_downloads = if config.s3_downloads_endpoint.is_some() || config.s3_downloads_region.is_some() {
-            S3Client::new(Region::Custom {
-                name: config
-                    .s3_downloads_region
-                    .as_ref()
-                    .cloned()
-                    .ok_or_else(|| anyhow!("S3 downloads region not specified"))?,
-                endpoint: config
-                    .s3_downloads_endpoint
-                    .as_ref()
-                    .cloned()
-                    .ok_or_else(|| anyhow!("S3 downloads endpoint not specified"))?,
-            })
+            Self::client(
+                config,
+                &credentials,
+                Self::region(
+                    "S3 downloads",
+                    config.s3_downloads_region.as_deref(),
+                    config.s3_downloads_endpoint.as_deref(),
+                )?,
+            )?
         } else {
             client.clone()
         };
@@ -96,47 +411,221 @@ impl S3Backend {
             client_uploads,
             client_downloads,
             capsule_id: config.capsule_id.as_deref().unwrap().to_string(),
+            compress_bundles: config.compress_bundles,
+            detect_content_type: config.s3_detect_content_type,
+            objects_acl: config.s3_objects_acl.clone(),
+            compression_threads: config.compression_threads,
+            strict_objects: config.strict_objects,
+            verify_existing_objects: config.verify_existing_objects,
+            lookup_concurrency: config.concurrent_download_max,
+            object_shard_depth: config.object_shard_depth,
+            bundle_zstd_dict: config
+                .bundle_zstd_dict
+                .as_ref()
+                .map(|dict| {
+                    let path = dict.to_path(config.roots())?;
+                    std::fs::read(&path).with_context(|| format!("Reading zstd dictionary '{}'", path.display()))
+                })
+                .transpose()?,
+            objects_cdn_url: config
+                .objects_cdn_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_owned()),
+            cdn_client: config.reqwest_client()?,
         })
     }
 
-    fn normalize_key(&self, key: &str) -> String {
-        format!("{}/{}/{}", &self.capsule_id, &key[0..2], key)
+    // The region of a client: the given one, or the one inferred from a standard AWS endpoint. A given
+    // region not matching the endpoint is likely a mistake, which S3 only reports with confusing redirects.
+    fn region(kind: &str, region: Option<&str>, endpoint: Option<&str>) -> Result<Region> {
+        let inferred = endpoint.and_then(region_from_endpoint);
+        let name = match (region, inferred) {
+            (Some(region), Some(inferred)) if region != inferred => {
+                warn!(
+                    "{} region '{}' doesn't match the region '{}' of endpoint '{}'",
+                    kind,
+                    region,
+                    inferred,
+                    endpoint.unwrap_or_default()
+                );
+                region.to_owned()
+            }
+            (Some(region), _) => region.to_owned(),
+            (None, Some(inferred)) => inferred,
+            (None, None) => bail!("{} region not specified", kind),
+        };
+        let endpoint = endpoint.ok_or_else(|| anyhow!("{} endpoint not specified", kind))?;
+        Ok(Region::Custom {
+            name,
+            endpoint: endpoint.to_owned(),
+        })
     }
 
-    fn normalize_object_key(&self, key: &str) -> String {
-        format!("{}/{}", &key[0..2], key)
+    // Create an S3 client with the given credentials.
+    fn client(config: &Config, credentials: &SharedCredentials, region: Region) -> Result<S3Client> {
+        Ok(S3Client::new_with(
+            Self::http_client(config)?,
+            credentials.clone(),
+            region,
+        ))
     }
 
-    async fn object_exists(&self, request: HeadObjectRequest) -> Result<bool> {
-        // We use the uploads client, since we have to check object existence before the upload.
-        let result = self.client_uploads.head_object(request).await;
-        match result {
-            Ok(_) => Ok(true),
-            Err(rusoto_core::RusotoError::Service(rusoto_s3::HeadObjectError::NoSuchKey(_))) => Ok(false),
+    // Create the credentials provider: the named profile, or the default discovery chain, and
+    // optionally assuming the given role with these credentials.
+    fn credentials_provider(config: &Config) -> Result<SharedCredentials> {
+        let credentials: Arc<dyn ProvideAwsCredentials + Send + Sync> = match &config.s3_profile {
+            Some(profile) => {
+                let mut provider = ProfileProvider::new()?;
+                provider.set_profile(profile);
+                Arc::new(AutoRefreshingProvider::new(provider)?)
+            }
+            None => Arc::new(DefaultCredentialsProvider::new()?),
+        };
+        let credentials = match &config.s3_assume_role_arn {
+            Some(role_arn) => {
+                // STS is an AWS service, so unlike S3 it can't use the custom S3 endpoint.
+                let region = region_name(config.s3_region.as_deref(), config.s3_endpoint.as_deref())
+                    .and_then(|region| region.parse().ok())
+                    .unwrap_or_default();
+                let sts_client =
+                    StsClient::new_with(Self::http_client(config)?, SharedCredentials(credentials), region);
+                let provider = StsAssumeRoleSessionCredentialsProvider::new(
+                    sts_client,
+                    role_arn.clone(),
+                    config.s3_role_session_name.clone(),
+                    None,
+                    None,
+                    None,
+                    None,
+                );
+                Arc::new(AutoRefreshingProvider::new(provider)?)
+            }
+            None => credentials,
+        };
+        Ok(SharedCredentials(credentials))
+    }
+
+    // Create the HTTP dispatcher going through the configured HTTP proxy, and trusting the CA bundle.
+    fn http_client(config: &Config) -> Result<HttpClient<ProxyConnector<HttpsConnector<HttpConnector>>>> {
+        let mut tls = native_tls::TlsConnector::builder();
+        for pem in config.ca_certificates()? {
+            tls.add_root_certificate(native_tls::Certificate::from_pem(&pem).context("Parsing CA certificate")?);
+        }
+        let tls = tls.build().context("Creating TLS connector")?;
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let https = HttpsConnector::from((http, tls.clone().into()));
+        // Without proxies, the proxy connector passes connections through to the HTTPS connector.
+        let mut connector = ProxyConnector::unsecured(https);
+        connector.set_tls(Some(tls));
+        if let Some(proxy) = &config.http_proxy {
+            let uri = proxy
+                .parse()
+                .with_context(|| format!("Parsing HTTP proxy URL '{}'", proxy))?;
+            // The hosts in NO_PROXY, e.g. a local MinIO, are reached directly.
+            let intercept = match config.no_proxy.clone() {
+                Some(no_proxy) => Intercept::Custom(
+                    (move |_: Option<&str>, host: Option<&str>, _: Option<u16>| {
+                        !no_proxy_matches(&no_proxy, host.unwrap_or_default())
+                    })
+                    .into(),
+                ),
+                None => Intercept::All,
+            };
+            connector.add_proxy(Proxy::new(intercept, uri));
+        }
+        Ok(HttpClient::from_connector(connector))
+    }
+
+    /// Check that the buckets can be reached, so that a misconfiguration fails before any work is
+    /// done, with a message saying what to check, rather than with an error of the first operation.
+    pub async fn probe(&self, config: &Config) -> Result<()> {
+        let endpoint = config.s3_endpoint.as_deref().unwrap_or_default();
+        let region = region_name(config.s3_region.as_deref(), Some(endpoint)).unwrap_or_default();
+        Self::probe_bucket(&self.client, &self.bucket, endpoint, &region).await?;
+        let downloads_endpoint = config.s3_downloads_endpoint.as_deref().unwrap_or(endpoint);
+        let downloads_region = region_name(
+            config.s3_downloads_region.as_deref(),
+            config.s3_downloads_endpoint.as_deref(),
+        );
+        Self::probe_bucket(
+            &self.client_downloads,
+            &self.bucket_objects,
+            downloads_endpoint,
+            downloads_region.as_deref().unwrap_or(&region),
+        )
+        .await?;
+        // The uploads have a client of their own when either their endpoint or their region is set.
+        if config.s3_uploads_endpoint.is_some() || config.s3_uploads_region.is_some() {
+            let uploads_endpoint = config.s3_uploads_endpoint.as_deref().unwrap_or(endpoint);
+            let uploads_region = region_name(
+                config.s3_uploads_region.as_deref(),
+                config.s3_uploads_endpoint.as_deref(),
+            );
+            Self::probe_bucket(
+                &self.client_uploads,
+                &self.bucket_objects,
+                uploads_endpoint,
+                uploads_region.as_deref().unwrap_or(&region),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn probe_bucket(client: &S3Client, bucket: &str, endpoint: &str, region: &str) -> Result<()> {
+        let request = HeadBucketRequest {
+            bucket: bucket.to_owned(),
+            ..Default::default()
+        };
+        // HEAD responses have no body, so most of the failures come with just the status code.
+        match client.head_bucket(request).await {
+            Ok(_) => Ok(()),
+            Err(rusoto_core::RusotoError::Service(HeadBucketError::NoSuchBucket(_))) => {
+                bail!("S3 bucket '{}' doesn't exist at '{}'", bucket, endpoint)
+            }
             Err(rusoto_core::RusotoError::Unknown(resp)) if resp.status == 404 => {
-                // No such bucket
-                Ok(false)
+                bail!("S3 bucket '{}' doesn't exist at '{}'", bucket, endpoint)
             }
-            Err(e) => {
-                error!("object_exists error: {}", e);
-                Err(e.into())
+            Err(rusoto_core::RusotoError::Unknown(resp)) if resp.status == 403 => {
+                bail!(
+                    "Access to S3 bucket '{}' at '{}' denied, check the credentials",
+                    bucket,
+                    endpoint
+                )
+            }
+            Err(rusoto_core::RusotoError::Unknown(resp)) if resp.status == 301 || resp.status == 400 => {
+                bail!(
+                    "S3 bucket '{}' at '{}' is not in region '{}', check the region",
+                    bucket,
+                    endpoint,
+                    region
+                )
+            }
+            Err(rusoto_core::RusotoError::HttpDispatch(err)) => {
+                bail!(
+                    "Cannot connect to S3 endpoint '{}', check the endpoint: {}",
+                    endpoint,
+                    err
+                )
+            }
+            Err(rusoto_core::RusotoError::Credentials(err)) => {
+                bail!("Cannot get the credentials for S3 bucket '{}': {}", bucket, err)
             }
+            Err(err) => Err(err).with_context(|| format!("Probing S3 bucket '{}' at '{}'", bucket, endpoint)),
         }
     }
-}
 
-#[async_trait]
-impl CachingBackend for S3Backend {
-    fn name(&self) -> &'static str {
-        "s3"
+    fn normalize_key(&self, key: &str) -> String {
+        format!("{}/{}/{}", &self.capsule_id, &key[0..2], key)
     }
 
-    /// Lookup inputs in S3.
-    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
-        let key = self.normalize_key(&inputs.hash);
+    /// Read the cache entry (bundle) with the given key, and return its decompressed JSON. An entry
+    /// not matching its digest is logged, and treated as a miss, as the outputs it lists can't be trusted.
+    async fn get_bundle(&self, key: String) -> Result<Option<Vec<u8>>> {
         let request = GetObjectRequest {
             bucket: self.bucket.clone(),
-            key,
+            key: key.clone(),
             ..Default::default()
         };
         let response = self.client.get_object(request).await;
@@ -150,6 +639,10 @@ impl CachingBackend for S3Backend {
             }
             Err(e) => Err(e.into()),
             Ok(response) => {
+                let digest = response
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get(DIGEST_METADATA).cloned());
                 let body = response.body.context("No reponse body")?;
                 let mut body_reader = body.into_async_read();
                 let mut body = Vec::new();
@@ -157,15 +650,134 @@ impl CachingBackend for S3Backend {
                     .read_to_end(&mut body)
                     .await
                     .context("failed to read HTTP body")?;
-                let bundle = serde_json::from_slice(&body).context("Cannot deserialize output")?;
-                Ok(Some(bundle))
+                let content_encoding = response.content_encoding.unwrap_or_default();
+                body = match decode_bundle(&content_encoding, body, self.bundle_zstd_dict.as_deref()).await {
+                    Ok(body) => body,
+                    // Without the dictionary the entry was compressed with, e.g. after it's retrained, the
+                    // entry can't be read, and is a miss, to be overwritten with the current dictionary.
+                    Err(err) if content_encoding == "zstd" => {
+                        warn!("Cannot read cache entry '{}', treating it as a miss: {:#}", key, err);
+                        return Ok(None);
+                    }
+                    // The corrupted entries mostly fail to decompress, before the digest is checked.
+                    Err(err) if digest.is_some() => {
+                        error!("Cache entry '{}' is corrupted: {:#}", key, err);
+                        return Ok(None);
+                    }
+                    Err(err) => return Err(err),
+                };
+                if let Err(err) = verify_bundle_digest(&key, &body, digest.as_ref()) {
+                    error!("{:#}", err);
+                    return Ok(None);
+                }
+                Ok(Some(body))
+            }
+        }
+    }
+
+    /// List up to `max_keys` keys of the cache entries in the keys bucket, optionally under a prefix.
+    async fn list_keys(&self, prefix: Option<String>, max_keys: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        while keys.len() < max_keys {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: prefix.clone(),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+            let response = self.client.list_objects_v2(request).await?;
+            keys.extend(
+                response
+                    .contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| object.key)
+                    .take(max_keys - keys.len()),
+            );
+            continuation_token = response.next_continuation_token;
+            if continuation_token.is_none() {
+                break;
             }
         }
+        Ok(keys)
+    }
+
+    /// Train a zstd dictionary on up to `max_samples` cache entries in the keys bucket, in the order
+    /// they are listed.
+    pub async fn train_dict(&self, max_samples: usize) -> Result<Vec<u8>> {
+        let keys = self.list_keys(None, max_samples).await?;
+        let samples: Vec<_> = futures::stream::iter(keys.into_iter().map(|key| self.get_bundle(key)))
+            .buffered(self.lookup_concurrency)
+            .try_collect()
+            .await?;
+        let samples: Vec<_> = samples.into_iter().flatten().collect();
+        if samples.is_empty() {
+            bail!("No cache entries found in bucket '{}'", self.bucket);
+        }
+        info!("Training the dictionary on {} cache entries", samples.len());
+        zstd::dict::from_samples(&samples, ZSTD_DICT_SIZE).context("Training the zstd dictionary")
+    }
+
+    fn normalize_object_key(&self, key: &str) -> String {
+        shard_key(key, self.object_shard_depth)
+    }
+
+    async fn head_object(&self, request: HeadObjectRequest) -> Result<Option<HeadObjectOutput>> {
+        // We use the uploads client, since we have to check object existence before the upload.
+        let result = self.client_uploads.head_object(request).await;
+        match result {
+            Ok(output) => Ok(Some(output)),
+            Err(rusoto_core::RusotoError::Service(rusoto_s3::HeadObjectError::NoSuchKey(_))) => Ok(None),
+            Err(rusoto_core::RusotoError::Unknown(resp)) if resp.status == 404 => {
+                // No such bucket
+                Ok(None)
+            }
+            Err(e) => {
+                error!("object_exists error: {}", e);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CachingBackend for S3Backend {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    /// Lookup inputs in S3.
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let body = self.get_bundle(self.normalize_key(&inputs.hash)).await?;
+        body.map(|body| serde_json::from_slice(&body).context("Cannot deserialize output"))
+            .transpose()
+    }
+
+    /// Issue the GETs concurrently, as the cache entries are small, and the lookups are dominated by
+    /// the round-trip time.
+    async fn lookup_many(&self, inputs: &[InputHashBundle]) -> Result<Vec<Option<InputOutputBundle>>> {
+        let lookups: Vec<_> = inputs.iter().map(|inputs| self.lookup(inputs)).collect();
+        futures::stream::iter(lookups)
+            .buffered(self.lookup_concurrency)
+            .try_collect()
+            .await
     }
 
     /// Read a file object from the storage, and return AsyncRead object for consuming by capsule.
     async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
         let key = self.normalize_object_key(item_hash);
+        if let Some(cdn_url) = &self.objects_cdn_url {
+            match download_from_cdn(&self.cdn_client, cdn_url, &key).await {
+                Ok(body) => return Ok(body),
+                Err(err) => {
+                    warn!(
+                        "Failed to download '{}' from the CDN, falling back to S3: {:#}",
+                        key, err
+                    );
+                }
+            }
+        }
         let request = GetObjectRequest {
             bucket: self.bucket_objects.clone(),
             key,
@@ -182,12 +794,16 @@ impl CachingBackend for S3Backend {
         }
     }
 
+    fn object_key(&self, item_hash: &str) -> String {
+        self.normalize_object_key(item_hash)
+    }
+
     async fn upload_object_file(
         &self,
         name: String,
         item_hash: &str,
         file: Pin<Box<dyn AsyncRead + Send>>,
-        _content_length: u64,
+        length: u64,
     ) -> Result<()> {
         // Find the key under which we'll store the object in the bucket.
         let key = self.normalize_object_key(item_hash);
@@ -199,22 +815,45 @@ impl CachingBackend for S3Backend {
         };
 
         // Objects in the content addresable storage are "immutable", so duplicate uploads can be skipped.
-        if self.object_exists(request).await? {
-            info!("Skipping upload for {} with hash '{}'", name, item_hash);
+        // In the strict mode, or with --verify_existing_objects, they are checked first.
+        // The existing objects keep the ACL they were uploaded with, which --s3_objects_acl doesn't change.
+        if let Some(existing) = self.head_object(request).await? {
+            if !self.strict_objects && !self.verify_existing_objects {
+                info!("Skipping upload for {} with hash '{}'", name, item_hash);
+                return Ok(());
+            }
+            // Without the strict mode, the mismatching object is still not overwritten, as it may
+            // be referenced by other cache entries, but it's reported loudly.
+            match verify_existing_object(&key, &existing, length) {
+                Ok(()) => info!("Skipping upload for {} with hash '{}', verified", name, item_hash),
+                Err(err) if self.strict_objects => return Err(err),
+                Err(err) => error!("OBJECT MISMATCH for {}: {:#}", name, err),
+            }
             return Ok(());
-        } else {
-            info!("Uploading object {} to '{}'", name, item_hash);
         }
+        info!("Uploading object {} to '{}'", name, item_hash);
 
         // We cannot compress the file on the fly due to the need for specify Content-length.
         // So we'll create a temporary file with gzip'ed contents and upload it.
-        let mut file = GzipEncoder::new(BufReader::new(file));
         let gzout = tempfile()?;
         let mut gzout = tokio::fs::File::from_std(gzout);
-        tokio::io::copy(&mut file, &mut gzout).await?;
+        if self.compression_threads > 1 {
+            parallel_gzip(file, &mut gzout, self.compression_threads, COMPRESSION_BLOCK_SIZE).await?;
+        } else {
+            tokio::io::copy(&mut GzipEncoder::new(BufReader::new(file)), &mut gzout).await?;
+        }
         let content_length = gzout.metadata().await?.len();
         gzout.seek(std::io::SeekFrom::Start(0)).await?;
 
+        // Gzip as the content encoding, rather than the content type, lets CDNs serve the objects
+        // directly to browsers with the right type, while capsule still decodes them on download.
+        let (content_type, content_encoding) = if self.detect_content_type {
+            let content_type = mime_guess::from_path(&name).first_or_octet_stream();
+            (content_type.to_string(), Some("gzip".to_owned()))
+        } else {
+            ("application/gzip".to_owned(), None)
+        };
+
         // Temporary file is ready, time to upload it.
         let byte_stream = codec::FramedRead::new(gzout, codec::BytesCodec::new()).map_ok(|r| r.freeze());
         let request = PutObjectRequest {
@@ -224,7 +863,10 @@ impl CachingBackend for S3Backend {
             content_length: Some(content_length as i64),
             // Two weeks - content addresable storage doesn't change, so CDNs can cache for long.
             cache_control: Some(CacheDirective::MaxAge(2_592_000).to_string()),
-            content_type: Some("application/gzip".to_owned()),
+            content_type: Some(content_type),
+            content_encoding,
+            metadata: Some(HashMap::from([(SIZE_METADATA.to_owned(), length.to_string())])),
+            acl: self.objects_acl.clone(),
             ..Default::default()
         };
         self.client_uploads.put_object(request).await?;
@@ -232,15 +874,33 @@ impl CachingBackend for S3Backend {
     }
 
     /// Write hashes of inputs and outputs into S3, keyed by hashes of inputs.
-    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+    async fn write(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        metadata: Metadata,
+    ) -> Result<()> {
         let io_bundle = InputOutputBundle {
             inputs: inputs.clone(),
             outputs: outputs.clone(),
             source,
+            metadata,
         };
         let key = self.normalize_key(&io_bundle.inputs.hash);
         // Prepare data for S3 writing.
-        let data = serde_json::to_vec(&io_bundle)?;
+        let mut data = serde_json::to_vec(&io_bundle)?;
+        let digest = format!("{:x}", Sha256::digest(&data));
+        let mut content_encoding = None;
+        if let Some(dict) = &self.bundle_zstd_dict {
+            data = zstd_compress(&data, dict)?;
+            content_encoding = Some("zstd".to_owned());
+        } else if self.compress_bundles {
+            let mut encoded = Vec::new();
+            GzipEncoder::new(&data[..]).read_to_end(&mut encoded).await?;
+            data = encoded;
+            content_encoding = Some("gzip".to_owned());
+        }
         let data_len = data.len();
         let request = PutObjectRequest {
             bucket: self.bucket.clone(),
@@ -248,7 +908,9 @@ impl CachingBackend for S3Backend {
             cache_control: Some(CacheDirective::NoCache.to_string()),
             content_length: Some(data_len as i64),
             content_type: Some("application/json".to_owned()),
+            content_encoding,
             key,
+            metadata: Some(HashMap::from([(DIGEST_METADATA.to_owned(), digest)])),
             ..Default::default()
         };
 
@@ -256,4 +918,272 @@ impl CachingBackend for S3Backend {
         self.client.put_object(request).await?;
         Ok(())
     }
+
+    async fn remove(&self, inputs: &InputHashBundle) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.normalize_key(&inputs.hash),
+            ..Default::default()
+        };
+        self.client.delete_object(request).await?;
+        Ok(())
+    }
+
+    async fn remove_object_file(&self, item_hash: &str) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket_objects.clone(),
+            key: self.normalize_object_key(item_hash),
+            ..Default::default()
+        };
+        self.client_uploads.delete_object(request).await?;
+        Ok(())
+    }
+
+    async fn list_entries(&self) -> Result<Vec<String>> {
+        let prefix = format!("{}/", self.capsule_id);
+        let keys = self.list_keys(Some(prefix), usize::MAX).await?;
+        // The keys are '<capsule_id>/<first two characters of the hash>/<inputs hash>'.
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| key.rsplit('/').next().map(str::to_owned))
+            .collect())
+    }
+
+    /// Return the uncompressed size of the object, if it exists. The objects uploaded before the size
+    /// was stored with them only have the compressed one.
+    async fn object_size(&self, item_hash: &str) -> Result<Option<u64>> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket_objects.clone(),
+            key: self.normalize_object_key(item_hash),
+            ..Default::default()
+        };
+        Ok(self.head_object(request).await?.map(|existing| {
+            existing
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(SIZE_METADATA))
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(existing.content_length.unwrap_or_default() as u64)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_from_endpoint() {
+        for (endpoint, region) in [
+            ("https://s3.us-west-2.amazonaws.com", Some("us-west-2")),
+            ("s3.eu-central-1.amazonaws.com:443", Some("eu-central-1")),
+            ("https://s3-us-west-2.amazonaws.com/", Some("us-west-2")),
+            ("https://s3.dualstack.ap-south-1.amazonaws.com", Some("ap-south-1")),
+            ("https://s3-fips.us-gov-west-1.amazonaws.com", Some("us-gov-west-1")),
+            ("https://my-s3-bucket.s3.us-east-2.amazonaws.com", Some("us-east-2")),
+            ("https://s3.cn-north-1.amazonaws.com.cn", Some("cn-north-1")),
+            ("https://s3.amazonaws.com", Some("us-east-1")),
+            ("https://s3-external-1.amazonaws.com", Some("us-east-1")),
+            ("https://s3-fips.amazonaws.com", None),
+            ("http://localhost:9000", None),
+            ("https://storage.googleapis.com", None),
+            ("https://nyc3.digitaloceanspaces.com", None),
+        ] {
+            assert_eq!(region_from_endpoint(endpoint).as_deref(), region, "{}", endpoint);
+        }
+        assert_eq!(
+            region_name(Some("us-east-1"), Some("https://s3.us-west-2.amazonaws.com")).as_deref(),
+            Some("us-east-1")
+        );
+        assert!(S3Backend::region("S3", None, Some("http://localhost:9000")).is_err());
+        assert!(S3Backend::region("S3", Some("us-east-1"), None).is_err());
+    }
+
+    #[test]
+    fn test_verify_bundle_digest() {
+        let body = br#"{"inputs":{}}"#;
+        let digest = format!("{:x}", Sha256::digest(body));
+        assert!(verify_bundle_digest("wtf/ab/abcd", body, Some(&digest)).is_ok());
+        // The entries without a digest are not checked.
+        assert!(verify_bundle_digest("wtf/ab/abcd", body, None).is_ok());
+        let corrupted = br#"{"inputs":[]}"#;
+        let err = verify_bundle_digest("wtf/ab/abcd", corrupted, Some(&digest)).unwrap_err();
+        assert!(err.to_string().contains("'wtf/ab/abcd' is corrupted"));
+    }
+
+    #[tokio::test]
+    async fn test_decode_bundle() -> Result<()> {
+        let json = br#"{"inputs": {}}"#.to_vec();
+        assert_eq!(decode_bundle("", json.clone(), None).await?, json);
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(&json[..]).read_to_end(&mut gzipped).await?;
+        assert_eq!(decode_bundle("gzip", gzipped.clone(), None).await?, json);
+        // A corrupted entry fails to decompress.
+        let middle = gzipped.len() / 2;
+        gzipped[middle] ^= 0xff;
+        assert!(decode_bundle("gzip", gzipped, None).await.is_err());
+        // Neither can an entry compressed with zstd be read without its dictionary.
+        let dict = zstd::dict::from_samples(&vec![json.clone(); 100], 1024)?;
+        let compressed = zstd_compress(&json, &dict)?;
+        assert_eq!(decode_bundle("zstd", compressed.clone(), Some(&dict)).await?, json);
+        assert!(decode_bundle("zstd", compressed, None).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shard_key() {
+        let key = "abcdef0123";
+        assert_eq!(shard_key(key, 0), "abcdef0123");
+        assert_eq!(shard_key(key, 1), "ab/abcdef0123");
+        assert_eq!(shard_key(key, 3), "ab/cd/ef/abcdef0123");
+    }
+
+    #[test]
+    fn test_verify_existing_object() {
+        let existing = HeadObjectOutput {
+            content_length: Some(30),
+            metadata: Some(HashMap::from([(SIZE_METADATA.to_owned(), "100".to_owned())])),
+            ..Default::default()
+        };
+        assert!(verify_existing_object("ab/abcd", &existing, 100).is_ok());
+        let err = verify_existing_object("ab/abcd", &existing, 101).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ObjectMismatch>(),
+            Some(&ObjectMismatch {
+                key: "ab/abcd".to_owned(),
+                size: 100,
+                expected_size: 101,
+            })
+        );
+        // Without the metadata, the object is not checked, as the compressed sizes depend on the encoder.
+        let existing = HeadObjectOutput {
+            content_length: Some(30),
+            ..Default::default()
+        };
+        assert!(verify_existing_object("ab/abcd", &existing, 100).is_ok());
+        assert!(verify_existing_object("ab/abcd", &existing, 101).is_ok());
+    }
+
+    #[test]
+    fn test_zstd_dict() -> Result<()> {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"inputs":{{"hash":"{:064x}","hash_details":[]}},"source":"{}"}}"#,
+                    i * 7919,
+                    i
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dict = zstd::dict::from_samples(&samples, 4096)?;
+        let compressed = zstd_compress(&samples[5], &dict)?;
+        assert!(compressed.len() < samples[5].len() / 2);
+        assert_eq!(zstd_decompress(&compressed, &dict)?, samples[5]);
+        // A different dictionary can't decompress it.
+        let other_dict = zstd::dict::from_samples(&samples[100..], 4096)?;
+        assert!(zstd_decompress(&compressed, &other_dict).map_or(true, |data| data != samples[5]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parallel_gzip() -> Result<()> {
+        let data: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| format!("line {} {}\n", i % 997, i * 7919 % 13).into_bytes())
+            .collect();
+        let mut serial = Vec::new();
+        GzipEncoder::new(&data[..]).read_to_end(&mut serial).await?;
+        for threads in [1, 3] {
+            let mut gzipped = Vec::new();
+            parallel_gzip(&data[..], &mut gzipped, threads, 4096).await?;
+            // A single member, so a decoder that stops after the first member decodes it all.
+            let mut decoded = Vec::new();
+            GzipDecoder::new(&gzipped[..]).read_to_end(&mut decoded).await?;
+            assert!(decoded == data);
+            let mut decoded = Vec::new();
+            std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gzipped[..]), &mut decoded)?;
+            assert!(decoded == data);
+            assert!(gzipped.len() < serial.len() * 2);
+        }
+        // An empty stream is a valid gzip file too.
+        let mut gzipped = Vec::new();
+        parallel_gzip(&b""[..], &mut gzipped, 2, 4096).await?;
+        let mut decoded = Vec::new();
+        GzipDecoder::new(&gzipped[..]).read_to_end(&mut decoded).await?;
+        assert!(decoded.is_empty());
+        Ok(())
+    }
+
+    // Serve the responses on a local port, one per connection, and return the URL of the server.
+    fn serve(responses: Vec<Vec<u8>>) -> Result<String> {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let rd = stream.read(&mut buf).unwrap();
+                    if rd == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..rd]);
+                }
+                stream.write_all(&response).unwrap();
+            }
+        });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_download_from_cdn() -> Result<()> {
+        let content = b"object content".to_vec();
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(&content[..]).read_to_end(&mut gzipped).await?;
+        let response = |status: &str, headers: &str, body: &[u8]| {
+            let head = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n",
+                status,
+                body.len(),
+                headers
+            );
+            [head.as_bytes(), body].concat()
+        };
+        let url = serve(vec![
+            response("200 OK", "", &content),
+            response("200 OK", "Content-Encoding: gzip\r\n", &gzipped),
+            response("200 OK", "Content-Type: application/gzip\r\n", &gzipped),
+            response("404 Not Found", "", b""),
+        ])?;
+        let client = Config::default().reqwest_client()?;
+        // The objects are decompressed as the S3 downloads are.
+        for _ in 0..3 {
+            let mut downloaded = Vec::new();
+            download_from_cdn(&client, &url, "ab/cdef")
+                .await?
+                .read_to_end(&mut downloaded)
+                .await?;
+            assert_eq!(downloaded, content);
+        }
+        // A missing object fails the download, to fall back to S3.
+        assert!(download_from_cdn(&client, &url, "ab/cdef").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_objects_acl() {
+        let error_with = |acl: &str| {
+            let mut config = Config::default();
+            config.s3_objects_acl = Some(acl.to_owned());
+            S3Backend::from_config(&config).err().map(|err| err.to_string())
+        };
+        let invalid = error_with("public").unwrap();
+        assert!(invalid.starts_with("Invalid objects ACL 'public', expected one of: private,"));
+        // The valid ones may fail later on, e.g. without a region, but not on the ACL.
+        for acl in OBJECT_ACLS {
+            assert!(!error_with(acl).unwrap_or_default().contains("ACL"), "{}", acl);
+        }
+    }
 }
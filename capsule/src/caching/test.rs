@@ -4,12 +4,13 @@ use anyhow::Result;
 use async_trait::async_trait;
 use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::time;
 
-use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
+use crate::iohashing::{InputHashBundle, InputOutputBundle, Metadata, OutputHashBundle};
 
 // This config enables various kinds of failures in the test caching backend.
 #[derive(Default)]
@@ -22,6 +23,10 @@ pub struct TestBackendConfig {
     pub write_timeout: bool,
     pub upload_timeout: bool,
     pub download_timeout: bool,
+    // Number of object downloads to truncate, before serving them intact.
+    pub truncated_downloads: usize,
+    // Number of writes to fail, before writing the entries.
+    pub failed_writes: usize,
 }
 
 // We have to use Arc<RwLock<_>> for internal mutability here because
@@ -30,6 +35,8 @@ pub struct TestBackendConfig {
 pub struct TestBackend {
     keys: Arc<RwLock<HashMap<String, InputOutputBundle>>>,
     objects: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    truncated: AtomicUsize,
+    write_failures: AtomicUsize,
     test_config: TestBackendConfig,
     capsule_id: String,
 }
@@ -71,11 +78,18 @@ impl CachingBackend for TestBackend {
         }
     }
 
-    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+    async fn write(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        metadata: Metadata,
+    ) -> Result<()> {
         if self.test_config.write_timeout {
             time::sleep(Duration::from_millis(500)).await;
         }
-        if self.test_config.failing_write {
+        let failed_write = self.write_failures.fetch_add(1, Ordering::SeqCst) < self.test_config.failed_writes;
+        if self.test_config.failing_write || failed_write {
             Err(anyhow!("Failed to write key"))
         } else {
             let key = self.normalize_key(&inputs.hash);
@@ -86,6 +100,7 @@ impl CachingBackend for TestBackend {
                     inputs: inputs.clone(),
                     outputs: outputs.clone(),
                     source,
+                    metadata,
                 },
             );
             Ok(())
@@ -101,6 +116,9 @@ impl CachingBackend for TestBackend {
         } else {
             let hashmap = self.objects.read().unwrap();
             let object = hashmap.get(item_hash).ok_or_else(|| anyhow!("file not found"))?;
+            if self.truncated.fetch_add(1, Ordering::SeqCst) < self.test_config.truncated_downloads {
+                return Ok(Box::pin(std::io::Cursor::new(object[..object.len() / 2].to_vec())));
+            }
             Ok(Box::pin(std::io::Cursor::new(object.clone())))
         }
     }
@@ -125,4 +143,33 @@ impl CachingBackend for TestBackend {
             Ok(())
         }
     }
+
+    async fn remove(&self, inputs: &InputHashBundle) -> Result<()> {
+        let key = self.normalize_key(&inputs.hash);
+        self.keys.write().unwrap().remove(&key);
+        Ok(())
+    }
+
+    async fn remove_object_file(&self, item_hash: &str) -> Result<()> {
+        self.objects.write().unwrap().remove(item_hash);
+        Ok(())
+    }
+
+    async fn list_entries(&self) -> Result<Vec<String>> {
+        let prefix = self.normalize_key("");
+        let mut entries: Vec<_> = self
+            .keys
+            .read()
+            .unwrap()
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_owned))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    async fn object_size(&self, item_hash: &str) -> Result<Option<u64>> {
+        let objects = self.objects.read().unwrap();
+        Ok(objects.get(item_hash).map(|object| object.len() as u64))
+    }
 }
@@ -1,28 +1,42 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use anyhow::{Context, Result};
 
 use futures::join;
 use futures::stream::{StreamExt, TryStreamExt};
+use futures::Future;
 use glob::glob;
 use indoc::indoc;
-use log::{error, info};
-use std::os::unix::fs::PermissionsExt;
-use std::process::ExitStatus;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use log::{debug, error, info, warn};
+use nix::sys::statvfs::statvfs;
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::NamedTempFile;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::process::Command;
-use tokio::{task, time};
+use tokio::time;
 
 use crate::caching::backend::CachingBackend;
+use crate::chunking::ChunkSizes;
+use crate::clock::{Clock, SystemClock};
 use crate::config::{Config, Milestone};
 use crate::iohashing::*;
-use crate::observability::logger::Logger;
+use crate::observability::logger::{CacheDecision, Logger};
 use crate::workspace_path::WorkspacePath;
 
 static USAGE: &str = "Usage: capsule <capsule arguments ...> -- command [<arguments>]";
 
+// How often to check for a free slot of --global_job_limit.
+const JOB_SLOT_POLL_MILLIS: u64 = 100;
+
 #[cfg(not(test))]
 mod timeouts {
     pub(super) const TIMEOUT_LOOKUP_MILLIS: u64 = 10_000;
@@ -42,21 +56,145 @@ mod timeouts {
     pub(super) const TIMEOUT_DOWNLOAD_MILLIS: u64 = 200;
 }
 
-pub struct Capsule<'a> {
+/// Human readable differences between two cache entries, as printed by 'capsule compare', or None
+/// if they have the same inputs and outputs.
+pub fn describe_bundles_diff(old: &InputOutputBundle, new: &InputOutputBundle) -> Option<String> {
+    // Summarized inputs have no details to compare, and would all look added or removed.
+    let summarized = old.inputs.summarized_details.is_some() || new.inputs.summarized_details.is_some();
+    let input_diffs = if summarized {
+        vec![]
+    } else {
+        old.inputs.diff(&new.inputs)
+    };
+    let output_diffs = old.outputs.diff(&new.outputs);
+    if old.inputs.hash == new.inputs.hash && input_diffs.is_empty() && output_diffs.is_empty() {
+        return None;
+    }
+    let mut description = format!("Inputs hash: {} vs {}\n", old.inputs.hash, new.inputs.hash);
+    if summarized && old.inputs.hash != new.inputs.hash {
+        description += "  the details of the inputs were dropped from a large entry\n";
+    }
+    for diff in input_diffs {
+        description += &format!("  {}\n", diff);
+    }
+    description += &format!("Outputs hash: {} vs {}\n", old.outputs.hash, new.outputs.hash);
+    for diff in output_diffs {
+        description += &format!("  {}\n", diff);
+    }
+    // The commands aren't part of the hashes, but they may explain the differences.
+    let command = |bundle: &InputOutputBundle| match bundle.metadata.get("command") {
+        Some(serde_json::Value::Array(argv)) => {
+            shell_words::join(argv.iter().map(|arg| arg.as_str().unwrap_or_default()))
+        }
+        Some(value) => value.to_string(),
+        None => "not recorded".to_owned(),
+    };
+    if command(old) != command(new) {
+        description += &format!("Command: {} vs {}\n", command(old), command(new));
+    }
+    Some(description)
+}
+
+// Human readable description of a cache entry, as printed by 'capsule inspect'.
+fn describe_bundle(bundle: &InputOutputBundle) -> String {
+    let mut description = format!(
+        "Inputs hash: {}\nSource: {}\nOutputs hash: {}\nOutputs:\n",
+        bundle.inputs.hash, bundle.source, bundle.outputs.hash
+    );
+    let hashed = bundle.outputs.hash_details.iter().map(|details| (details, ""));
+    let unhashed = bundle
+        .outputs
+        .unhashed_details
+        .iter()
+        .map(|details| (details, ", not hashed"));
+    for ((output, hash), unhashed) in hashed.chain(unhashed) {
+        let output = match output {
+            Output::File(fileoutput) => format!(
+                "file {} ({}{}{}, mode {:o})",
+                fileoutput.filename,
+                if fileoutput.present { "present" } else { "absent" },
+                if fileoutput.negative { ", negative" } else { "" },
+                if fileoutput.chunks.is_empty() {
+                    String::new()
+                } else {
+                    format!(", {} chunks", fileoutput.chunks.len())
+                },
+                fileoutput.mode
+            ),
+            Output::ExitCode(code) => format!("exit code {}", code),
+            Output::Signal(signal) => format!("killed by signal {}", signal),
+            Output::Stdout(stdout) => format!("stdout ({} bytes)", stdout.len()),
+            Output::Stderr(stderr) => format!("stderr ({} bytes)", stderr.len()),
+            Output::StdoutObject(len) => format!("stdout ({} bytes, stored as object)", len),
+            Output::StderrObject(len) => format!("stderr ({} bytes, stored as object)", len),
+            Output::Dir(dirname) => format!("empty dir {}", dirname),
+            Output::Directory { dirname, mode } => format!("dir {} (mode {:o})", dirname, mode),
+        };
+        description += &format!("  {}: {}{}\n", output, hash, unhashed);
+    }
+    if !bundle.metadata.is_empty() {
+        description += "Metadata:\n";
+        for (key, value) in &bundle.metadata {
+            description += &format!("  {}: {}\n", key, value);
+        }
+    }
+    description
+}
+
+/// The identity and the state of an output file, which tell whether the command created, replaced,
+/// or modified it.
+#[derive(PartialEq)]
+struct OutputFileStat {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+}
+
+/// The time spent in each phase of the runs, and the bytes transferred, for --verbose_timing.
+#[derive(Default)]
+struct Timings {
+    phases: std::sync::Mutex<Vec<(&'static str, Duration)>>,
+    downloaded_bytes: AtomicU64,
+    uploaded_bytes: AtomicU64,
+}
+
+/// The capsule is generic over the caching backend and the logger, so that they can be statically
+/// dispatched (and inlined) when their types are known, e.g. in benchmarks. By default, they are trait
+/// objects, as when the backend is selected at runtime.
+pub struct Capsule<'a, B: CachingBackend + ?Sized = dyn CachingBackend, L: Logger + ?Sized = dyn Logger> {
     config: &'a Config,
-    caching_backend: &'a dyn CachingBackend,
-    logger: &'a dyn Logger,
+    caching_backend: &'a B,
+    logger: &'a L,
+    clock: &'a dyn Clock,
+    chunk_sizes: ChunkSizes,
+    hash_pool: HashPool,
+    timings: Timings,
 }
 
-impl<'a> Capsule<'a> {
-    pub fn new(config: &'a Config, caching_backend: &'a dyn CachingBackend, logger: &'a dyn Logger) -> Self {
+impl<'a, B: CachingBackend + ?Sized, L: Logger + ?Sized> Capsule<'a, B, L> {
+    pub fn new(config: &'a Config, caching_backend: &'a B, logger: &'a L) -> Self {
         Self {
             config,
             caching_backend,
             logger,
+            clock: &SystemClock,
+            chunk_sizes: ChunkSizes::default(),
+            hash_pool: HashPool::new(config.hash_threads),
+            timings: Timings::default(),
         }
     }
 
+    /// Use the given time source instead of the system clock, e.g. to control the time in tests.
+    pub fn with_clock(self, clock: &'a dyn Clock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Chunk the files with --chunked_objects by the given sizes, e.g. smaller ones in tests.
+    pub fn with_chunk_sizes(self, chunk_sizes: ChunkSizes) -> Self {
+        Self { chunk_sizes, ..self }
+    }
+
     pub fn capsule_id(&self) -> String {
         self.config.capsule_id.as_ref().cloned().unwrap()
     }
@@ -65,11 +203,74 @@ impl<'a> Capsule<'a> {
         self.config.capsule_job.as_ref().cloned().unwrap_or_default()
     }
 
+    /// The inputs to write into the cache entry. An entry over --max_bundle_bytes is an error,
+    /// unless --summarize_large_bundles, in which case it's written with the inputs summarized.
+    fn stored_inputs<'b>(
+        &self,
+        inputs: &'b InputHashBundle,
+        outputs: &OutputHashBundle,
+        metadata: &Metadata,
+    ) -> Result<Cow<'b, InputHashBundle>> {
+        let max_bundle_bytes = match self.config.max_bundle_bytes {
+            Some(max_bundle_bytes) => max_bundle_bytes,
+            None => return Ok(Cow::Borrowed(inputs)),
+        };
+        let bundle_bytes = |inputs: &InputHashBundle| -> Result<usize> {
+            let bundle = InputOutputBundle {
+                inputs: inputs.clone(),
+                outputs: outputs.clone(),
+                source: self.capsule_job(),
+                metadata: metadata.clone(),
+            };
+            Ok(serde_json::to_vec(&bundle)?.len())
+        };
+        let size = bundle_bytes(inputs)?;
+        if size <= max_bundle_bytes {
+            return Ok(Cow::Borrowed(inputs));
+        }
+        if !self.config.summarize_large_bundles {
+            bail!(
+                "The cache entry is {} bytes, over --max_bundle_bytes {}, see --summarize_large_bundles",
+                size,
+                max_bundle_bytes
+            );
+        }
+        let summarized = inputs.summarized();
+        let summarized_size = bundle_bytes(&summarized)?;
+        if summarized_size > max_bundle_bytes {
+            bail!(
+                "The cache entry is {} bytes even with the inputs summarized, over --max_bundle_bytes {}",
+                summarized_size,
+                max_bundle_bytes
+            );
+        }
+        warn!(
+            "The cache entry is {} bytes, over --max_bundle_bytes {}, writing it without the details of {} inputs",
+            size,
+            max_bundle_bytes,
+            inputs.hash_details.len()
+        );
+        Ok(Cow::Owned(summarized))
+    }
+
     pub fn read_inputs(&self) -> Result<InputHashBundle> {
-        let mut inputs = InputSet::default();
+        // The hash given by the caller is trusted, and nothing is read.
+        if let Some(hash) = &self.config.inputs_hash_value {
+            return Ok(InputHashBundle {
+                hash: hash.clone(),
+                hash_details: vec![],
+                ..Default::default()
+            });
+        }
+        let mut inputs = InputSet {
+            normalize_line_endings: self.config.normalize_line_endings,
+            hash_buffer_kb: self.config.hash_buffer_kb,
+            hash_pool: self.hash_pool.clone(),
+            ..Default::default()
+        };
         for file_pattern in &self.config.input_files {
             let mut file_count = 0;
-            let fp = file_pattern.to_path(&self.config.workspace_root)?;
+            let fp = file_pattern.to_path(self.config.roots())?;
             let glob_pattern = fp.to_str().ok_or(anyhow!("can't convert path to string"))?;
             for file in glob(glob_pattern)? {
                 let file = file?;
@@ -81,6 +282,14 @@ impl<'a> Capsule<'a> {
                     };
                     inputs.add_input(Input::File(expansion_file_name));
                     file_count += 1;
+                    // Bail out early, rather than hash e.g. the whole filesystem.
+                    if inputs.inputs.len() > self.config.max_inputs {
+                        bail!(
+                            "Pattern '{}' exceeds the limit of {} input files, see --max_inputs",
+                            file_pattern,
+                            self.config.max_inputs
+                        );
+                    }
                 }
             }
             if file_count == 0 {
@@ -88,40 +297,324 @@ impl<'a> Capsule<'a> {
             }
         }
 
+        for input_cmd in &self.config.input_cmds {
+            let (argv, stdout) = Self::run_command("input", input_cmd)?;
+            // The command decides which files are the inputs, so it's part of the key too.
+            inputs.add_input(Input::ToolTag(format!("input command: {}", shell_words::join(&argv))));
+            let mut file_count = 0;
+            for path in Self::split_paths(&stdout) {
+                let path = std::str::from_utf8(path)
+                    .with_context(|| format!("Non UTF-8 path from input command '{}'", input_cmd))?;
+                let file = WorkspacePath::from(path);
+                if !file.to_path(self.config.roots())?.is_file() {
+                    bail!("Input command '{}' listed '{}', which is not a file", input_cmd, file);
+                }
+                inputs.add_input(Input::File(file));
+                file_count += 1;
+                if inputs.inputs.len() > self.config.max_inputs {
+                    bail!(
+                        "Input command '{}' exceeds the limit of {} input files, see --max_inputs",
+                        input_cmd,
+                        self.config.max_inputs
+                    );
+                }
+            }
+            if file_count == 0 {
+                bail!("Input command '{}' didn't list any files", input_cmd);
+            }
+        }
+
+        if self.config.hash_argv0 {
+            inputs.add_input(Input::File(WorkspacePath::from(self.resolve_argv0()?)));
+        }
+
         for tool_tag in &self.config.tool_tags {
             inputs.add_input(Input::ToolTag(tool_tag.clone()));
         }
+        for tool_tag_cmd in &self.config.tool_tag_cmds {
+            inputs.add_input(Input::ToolTag(Self::tool_tag_from_command(tool_tag_cmd)?));
+        }
+        for tool_tag_file in &self.config.tool_tag_files {
+            inputs.add_input(Input::ToolTag(self.tool_tag_from_file(tool_tag_file)?));
+        }
+        if self.config.git_tool_tag {
+            inputs.add_input(Input::ToolTag(self.git_tool_tag()?));
+        }
+        if !self.config.tool_tag_env.is_empty() {
+            inputs.add_input(Input::ToolTag(self.tool_tag_from_env()?));
+        }
+        if self.config.hash_set_env && !self.config.set_env.is_empty() {
+            let vars: Vec<String> = (self.config.get_set_env()?.iter())
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            inputs.add_input(Input::ToolTag(format!("set env: {}", vars.join("\n"))));
+        }
+        // The outputs are produced by the fallback command, unlike by the command to run, which
+        // only runs on cache hit, so the cache key reflects the former.
+        if self.config.fallback_command.is_some() {
+            let command = shell_words::join(self.config.produce_command()?);
+            inputs.add_input(Input::ToolTag(format!("fallback command: {}", command)));
+        }
         let capsule_id = self.capsule_id();
         inputs
-            .hash_bundle(&self.config.workspace_root)
+            .hash_bundle(self.config.roots())
             .with_context(|| format!("Hashing inputs of capsule '{}'", capsule_id))
     }
 
-    pub fn read_outputs(&self, exit_code: Option<i32>) -> Result<OutputHashBundle> {
-        let mut outputs = OutputSet::default();
-        if let Some(exit_code) = exit_code {
-            outputs.add_output(Output::ExitCode(exit_code));
+    /// Run the given shell-quoted command, the `kind` of which is used in the errors, and return
+    /// its argv and its stdout. Any failure is an error, as e.g. a silently empty tool tag, or input
+    /// list, would weaken the cache key.
+    fn run_command(kind: &str, command: &str) -> Result<(Vec<String>, Vec<u8>)> {
+        let argv = shell_words::split(command).with_context(|| format!("Parsing {} command '{}'", kind, command))?;
+        if argv.is_empty() {
+            bail!("Empty {} command", kind);
+        }
+        let output = std::process::Command::new(&argv[0])
+            .args(&argv[1..])
+            .output()
+            .with_context(|| format!("Running {} command '{}'", kind, command))?;
+        if !output.status.success() {
+            bail!(
+                "The {} command '{}' failed with {}: {}",
+                kind,
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        Ok((argv, output.stdout))
+    }
+
+    /// Split the output of an input command into paths, NUL-delimited if there's a NUL, as
+    /// produced by `find -print0`, and newline-delimited otherwise, skipping empty ones.
+    fn split_paths(output: &[u8]) -> impl Iterator<Item = &[u8]> {
+        let delimiter = if output.contains(&0) { 0 } else { b'\n' };
+        output
+            .split(move |&byte| byte == delimiter)
+            .filter(|path| !path.is_empty())
+    }
+
+    /// Run the given command, and return a tool tag made of its argv and its stdout.
+    ///
+    /// The argv is part of the tag, so that different commands printing the same string produce
+    /// different tags.
+    fn tool_tag_from_command(command: &str) -> Result<String> {
+        let (argv, stdout) = Self::run_command("tool tag", command)?;
+        Ok(format!(
+            "{}: {}",
+            shell_words::join(&argv),
+            String::from_utf8_lossy(&stdout).trim_end()
+        ))
+    }
+
+    /// Return a tool tag made of the file name and the hash of its content.
+    ///
+    /// The file is hashed the same way as the input files, but as it's only a tool tag, it doesn't
+    /// make the file an input, which e.g. would count towards '--max_inputs'. The name is relative to
+    /// the workspace root if the file is in the workspace, so that the tag is the same in every checkout.
+    fn tool_tag_from_file(&self, file: &WorkspacePath) -> Result<String> {
+        let path = file.to_path(self.config.roots())?;
+        let hash = if self.config.normalize_line_endings {
+            text_file_hash(&path)
+        } else {
+            file_hash(&path)
+        }
+        .with_context(|| format!("Hashing tool tag file '{}'", file))?;
+        Ok(format!(
+            "file {}: {}",
+            WorkspacePath::from_full_path(&path, self.config.roots()),
+            hash
+        ))
+    }
+
+    /// Return a tool tag made of the names and values of all environment variables matching
+    /// '--tool_tag_env' patterns, sorted by name.
+    fn tool_tag_from_env(&self) -> Result<String> {
+        let patterns = self
+            .config
+            .tool_tag_env
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern).with_context(|| format!("Invalid --tool_tag_env pattern '{}'", pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let vars: BTreeMap<String, String> = std::env::vars_os()
+            .map(|(name, value)| {
+                (
+                    name.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .filter(|(name, _)| patterns.iter().any(|pattern| pattern.matches(name)))
+            .collect();
+        let vars: Vec<String> = vars.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+        Ok(format!(
+            "env {}: {}",
+            self.config.tool_tag_env.join(","),
+            vars.join("\n")
+        ))
+    }
+
+    /// Find the program of the command producing the outputs, e.g. an interpreter, the way execvp
+    /// would, and return its real path, i.e. the binary that `python3 -> python3.10` points to.
+    fn resolve_argv0(&self) -> Result<PathBuf> {
+        let command = self.config.produce_command()?;
+        let argv0 = command.first().ok_or(anyhow!(USAGE))?;
+        let path = if argv0.contains('/') {
+            PathBuf::from(argv0)
+        } else {
+            let paths = std::env::var_os("PATH").unwrap_or_default();
+            let is_executable = |path: &Path| match path.metadata() {
+                Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+                Err(_) => false,
+            };
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(argv0))
+                .find(|path| is_executable(path))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Command '{}' not found in PATH, --hash_argv0 needs an executable, not e.g. a shell builtin",
+                        argv0
+                    )
+                })?
+        };
+        path.canonicalize()
+            .with_context(|| format!("Resolving command '{}'", path.display()))
+    }
+
+    /// Return a tool tag made of the git HEAD commit of the workspace, with a marker if the working
+    /// tree is dirty.
+    ///
+    /// Not being in a git repository is an error, as a silently empty tag would weaken the cache key.
+    fn git_tool_tag(&self) -> Result<String> {
+        let dir = self.config.workspace_root.as_deref().unwrap_or(".");
+        let git = |args: &[&str]| -> Result<String> {
+            let output = std::process::Command::new("git")
+                .args(args)
+                .current_dir(dir)
+                .output()
+                .with_context(|| format!("Running 'git {}' in '{}'", args.join(" "), dir))?;
+            if !output.status.success() {
+                bail!(
+                    "'git {}' in '{}' failed with {}: {}",
+                    args.join(" "),
+                    dir,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                );
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_owned())
+        };
+        let head = git(&["rev-parse", "HEAD"])?;
+        let dirty = !git(&["status", "--porcelain"])?.is_empty();
+        Ok(format!("git HEAD: {}{}", head, if dirty { " (dirty)" } else { "" }))
+    }
+
+    pub fn read_outputs(&self, exit_status: Option<ExitStatus>, captured: Vec<Output>) -> Result<OutputHashBundle> {
+        let mut outputs = OutputSet {
+            chunked_objects: self.config.chunked_objects.then_some(self.chunk_sizes),
+            hash_buffer_kb: self.config.hash_buffer_kb,
+            hash_pool: self.hash_pool.clone(),
+            ..Default::default()
+        };
+        if let Some(exit_status) = exit_status {
+            // A command terminated by a signal has no exit code, and it's recorded as a failure.
+            if let Some(exit_code) = exit_status.code() {
+                outputs.add_output(Output::ExitCode(exit_code));
+            } else if let Some(signal) = exit_status.signal() {
+                outputs.add_output(Output::Signal(signal));
+            }
+        }
+        for output in captured {
+            match output {
+                Output::Stderr(_) if self.config.unhashed_stderr => outputs.add_unhashed_output(output),
+                _ => outputs.add_output(output),
+            }
         }
-        for file_pattern in &self.config.output_files {
-            let fp = file_pattern.to_path(&self.config.workspace_root)?;
+        // Negative outputs are captured as any other output, but are marked so that their absence
+        // is honored on cache hit.
+        let output_patterns = self.config.output_files.iter().map(|pattern| (pattern, false));
+        let negative_output_patterns = self.config.negative_output_files.iter().map(|pattern| (pattern, true));
+        let exclude_patterns = self.config.exclude_output_patterns()?;
+        for (file_pattern, negative) in output_patterns.chain(negative_output_patterns) {
+            let fp = file_pattern.to_path(self.config.roots())?;
             let glob_pattern = fp.to_str().ok_or(anyhow!("can't convert path to string"))?;
-            let mut present = false;
+            // A trailing '/' declares a directory that must exist, without caching its content.
+            if glob_pattern.len() > 1 && glob_pattern.ends_with('/') {
+                let dir = Path::new(glob_pattern.trim_end_matches('/'));
+                let metadata = dir
+                    .metadata()
+                    .with_context(|| format!("Output directory '{}' doesn't exist", file_pattern))?;
+                if !metadata.is_dir() {
+                    bail!("Output '{}' is not a directory", file_pattern);
+                }
+                outputs.add_output(Output::Directory {
+                    dirname: WorkspacePath::from_full_path(dir, self.config.roots()),
+                    mode: self.output_mode(metadata.permissions().mode() & 0o7777),
+                });
+                continue;
+            }
+            // With '**', the directories the glob matches are walked too, so that no file in them is
+            // missed. Otherwise, directories only matter if they are empty, and are to be preserved.
+            let recursive = glob_pattern.contains("**");
+            let mut files = BTreeSet::new();
+            let mut empty_dirs = BTreeSet::new();
+            let mut walked_dirs: Vec<PathBuf> = Vec::new();
             for file in glob(glob_pattern)? {
                 let file = file?;
                 if file.is_dir() {
+                    if recursive {
+                        // Subdirectories of a walked directory are already walked.
+                        if !walked_dirs.iter().any(|dir| file.starts_with(dir)) {
+                            Self::walk_dir(&file, &mut files, &mut empty_dirs)?;
+                            walked_dirs.push(file);
+                        }
+                    } else if self.config.preserve_empty_dirs && file.read_dir()?.next().is_none() {
+                        empty_dirs.insert(file);
+                    }
+                } else {
+                    files.insert(file);
+                }
+            }
+            // Excluded files are neither hashed nor uploaded, as if the pattern didn't match them.
+            let is_excluded = |file: &Path| {
+                let relative_file = file.strip_prefix("./").unwrap_or(file);
+                exclude_patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path(relative_file))
+            };
+            let mut present = false;
+            if self.config.preserve_empty_dirs {
+                for dir in empty_dirs.iter().filter(|dir| !is_excluded(dir)) {
+                    let dirname = WorkspacePath::from_full_path(dir, self.config.roots());
+                    outputs.add_output(Output::Dir(dirname));
+                    present = true;
+                }
+            }
+            for file in files {
+                // Symlinks to directories are not followed.
+                if file.is_dir() || is_excluded(&file) {
                     continue;
                 }
                 if file.is_file() {
                     // Convert workspace relative patterns to workspace relative expansions.
-                    let mode = file.metadata()?.permissions().mode();
-                    let expansion_file_name =
-                        WorkspacePath::from_full_path(file.as_path(), &self.config.workspace_root);
+                    let mode = self.output_mode(file.metadata()?.permissions().mode());
+                    let expansion_file_name = WorkspacePath::from_full_path(file.as_path(), self.config.roots());
                     outputs.add_output(Output::File(FileOutput {
                         filename: expansion_file_name,
                         present: true,
                         mode,
+                        negative,
+                        chunks: vec![],
                     }));
                     present = true;
+                } else if let Ok(metadata) = file.metadata() {
+                    // Hashing a FIFO or a device would block or fail in obscure ways, so complain early.
+                    bail!(
+                        "Unsupported output file type ({}) of '{}'",
+                        Self::describe_file_type(metadata.file_type()),
+                        file.display()
+                    );
                 }
             }
             if !present {
@@ -130,59 +623,343 @@ impl<'a> Capsule<'a> {
                     filename: file_pattern.clone(),
                     present: false,
                     mode: 0o644, // Default permissions just in case.
+                    negative,
+                    chunks: vec![],
                 }));
             }
         }
         let capsule_id = self.capsule_id();
         outputs
-            .hash_bundle(&self.config.workspace_root)
+            .hash_bundle(self.config.roots())
             .with_context(|| format!("Hashing outputs of capsule '{}'", capsule_id))
     }
 
-    fn equal_outputs(left: &OutputHashBundle, right: &OutputHashBundle) -> bool {
-        left.hash == right.hash
+    /// Apply --mask_output_mode to the mode of an output file. It's applied on restoring too, as the
+    /// entry may have been written without it.
+    fn output_mode(&self, mode: u32) -> u32 {
+        self.config.mask_output_mode.map_or(mode, |mask| mode & mask)
     }
 
-    async fn execute_command(&self, inputs: &InputHashBundle, program_run: &mut AtomicBool) -> Result<ExitStatus> {
-        info!("Executing command: {:?}", self.config.command_to_run);
-        if self.config.command_to_run.is_empty() {
+    // Collect all files under the directory, and the empty directories, including itself.
+    fn walk_dir(dir: &Path, files: &mut BTreeSet<PathBuf>, empty_dirs: &mut BTreeSet<PathBuf>) -> Result<()> {
+        let mut empty = true;
+        for entry in std::fs::read_dir(dir).with_context(|| format!("Reading output directory '{}'", dir.display()))? {
+            let entry = entry?;
+            empty = false;
+            if entry.file_type()?.is_dir() {
+                Self::walk_dir(&entry.path(), files, empty_dirs)?;
+            } else {
+                files.insert(entry.path());
+            }
+        }
+        if empty {
+            empty_dirs.insert(dir.to_owned());
+        }
+        Ok(())
+    }
+
+    fn describe_file_type(file_type: std::fs::FileType) -> &'static str {
+        if file_type.is_fifo() {
+            "named pipe"
+        } else if file_type.is_socket() {
+            "socket"
+        } else if file_type.is_char_device() {
+            "character device"
+        } else if file_type.is_block_device() {
+            "block device"
+        } else {
+            "unknown"
+        }
+    }
+
+    // Copy everything from the reader to the writer, also returning it. What is returned is truncated
+    // to max_bytes, if given, and then ends with a marker, so that it hashes differently from an
+    // output that is not truncated.
+    async fn tee<R, W>(reader: Option<R>, mut writer: W, max_bytes: Option<usize>) -> Result<Option<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut reader = match reader {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        let mut captured = Vec::new();
+        let mut truncated = false;
+        let mut buf = vec![0; 65536];
+        loop {
+            let rd = reader.read(&mut buf).await?;
+            if rd == 0 {
+                break;
+            }
+            writer.write_all(&buf[..rd]).await?;
+            writer.flush().await?;
+            let room = max_bytes.map_or(rd, |max_bytes| max_bytes.saturating_sub(captured.len()));
+            captured.extend_from_slice(&buf[..rd.min(room)]);
+            truncated |= rd > room;
+        }
+        if truncated {
+            captured.extend_from_slice(format!("\n[capsule: truncated at {} bytes]\n", captured.len()).as_bytes());
+        }
+        Ok(Some(captured))
+    }
+
+    /// Execute the command, and return its exit status, and its stdout/stderr if they are captured.
+    async fn execute_command(
+        &self,
+        inputs: &InputHashBundle,
+        program_run: &mut AtomicBool,
+        env: &[(String, String)],
+    ) -> Result<(ExitStatus, Vec<Output>)> {
+        let command_to_run = self.config.produce_command()?;
+        info!("Executing command: {:?}", command_to_run);
+        if command_to_run.is_empty() {
             Err(anyhow!(USAGE))
         } else {
-            let mut child = Command::new(&self.config.command_to_run[0])
-                .args(&self.config.command_to_run[1..])
-                .env(&self.config.inputs_hash_var, &inputs.hash)
-                .spawn()
-                .with_context(|| "Spawning command")?;
+            let mut command = Command::new(&command_to_run[0]);
+            let inputs_hash = self.config.inputs_hash_format.render(&inputs.hash);
+            command
+                .args(&command_to_run[1..])
+                .env(&self.config.inputs_hash_var, inputs_hash)
+                .envs(self.config.get_set_env()?)
+                .envs(env.iter().cloned());
+            if self.config.capture_stdout.unwrap_or(false) {
+                command.stdout(Stdio::piped());
+            }
+            if self.config.capture_stderr.unwrap_or(false) || self.config.unhashed_stderr {
+                command.stderr(Stdio::piped());
+            }
+            let files_before = if self.config.clean_failed_outputs {
+                Some(self.output_file_stats()?)
+            } else {
+                None
+            };
+            // Held until the command exits, or until capsule does, as the lock goes with the file.
+            let _job_slot = self.acquire_job_slot().await?;
+            let mut child = command.spawn().with_context(|| "Spawning command")?;
             // Having executed the command, just need to tell our caller whether we succeeded in
             // running the program.  this happens as soon as we have a child program.
             program_run.store(true, Ordering::SeqCst);
-            let exit_status = child.wait().await?;
-            Ok(exit_status)
+            // The captured streams are still passed through to our stdout/stderr as they come.
+            let (child_stdout, child_stderr) = (child.stdout.take(), child.stderr.take());
+            let (exit_status, stdout, stderr) = futures::try_join!(
+                async { child.wait().await.map_err(anyhow::Error::from) },
+                Self::tee(child_stdout, tokio::io::stdout(), self.config.capture_max_bytes),
+                Self::tee(child_stderr, tokio::io::stderr(), self.config.capture_max_bytes),
+            )?;
+            if let (Some(files_before), false) = (files_before, exit_status.success()) {
+                self.clean_failed_outputs(&files_before).unwrap_or_else(|err| {
+                    error!("Failed to clean the outputs of the failed command: {:#}", err);
+                });
+            }
+            let captured = itertools::chain(stdout.map(Output::Stdout), stderr.map(Output::Stderr)).collect();
+            Ok((exit_status, captured))
+        }
+    }
+
+    /// With --global_job_limit, wait for a free slot shared with the other capsules, each slot being an
+    /// exclusive flock on one of the lock files. The kernel releases the lock when the returned file is
+    /// closed, so the slot is freed even if capsule crashes or is killed.
+    async fn acquire_job_slot(&self) -> Result<Option<std::fs::File>> {
+        let limit = match self.config.global_job_limit {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        let mut waiting = false;
+        loop {
+            for slot in 0..limit {
+                let path = format!("{}.{}", self.config.global_job_lock, slot);
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&path)
+                    .with_context(|| format!("Opening job slot lock '{}'", path))?;
+                match nix::fcntl::flock(file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusiveNonblock) {
+                    Ok(()) => {
+                        debug!("Acquired job slot '{}'", path);
+                        return Ok(Some(file));
+                    }
+                    Err(nix::errno::Errno::EWOULDBLOCK) => continue,
+                    Err(err) => return Err(err).with_context(|| format!("Locking job slot '{}'", path)),
+                }
+            }
+            if !waiting {
+                info!("All {} global job slots are taken, waiting", limit);
+                waiting = true;
+            }
+            time::sleep(Duration::from_millis(JOB_SLOT_POLL_MILLIS)).await;
+        }
+    }
+
+    /// The output files that exist, with their device, inode, size and modification time, which tell
+    /// whether the command replaced or modified a file.
+    fn output_file_stats(&self) -> Result<BTreeMap<PathBuf, OutputFileStat>> {
+        let mut stats = BTreeMap::new();
+        let output_patterns = self
+            .config
+            .output_files
+            .iter()
+            .chain(&self.config.negative_output_files);
+        for file_pattern in output_patterns {
+            let pattern = file_pattern.to_path(self.config.roots())?;
+            let pattern = pattern.to_str().ok_or(anyhow!("can't convert path to string"))?;
+            for file in glob(pattern)? {
+                let file = file?;
+                let metadata = file.symlink_metadata()?;
+                if metadata.is_file() {
+                    let stat = OutputFileStat {
+                        dev: metadata.dev(),
+                        ino: metadata.ino(),
+                        size: metadata.size(),
+                        mtime: (metadata.mtime(), metadata.mtime_nsec()),
+                    };
+                    stats.insert(file, stat);
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// With --clean_failed_outputs, remove the output files that the failed command created, i.e.
+    /// those that didn't exist before it ran, or were replaced by new files. The files that existed
+    /// before are kept, even if the command modified them, as they may not be reproducible.
+    fn clean_failed_outputs(&self, files_before: &BTreeMap<PathBuf, OutputFileStat>) -> Result<()> {
+        for (file, stat) in self.output_file_stats()? {
+            match files_before.get(&file) {
+                Some(before) if (before.dev, before.ino) == (stat.dev, stat.ino) => {
+                    if *before != stat {
+                        warn!("Keeping output file '{}', modified by the command", file.display());
+                    }
+                }
+                _ => {
+                    info!("Removing output file '{}' made by the failed command", file.display());
+                    std::fs::remove_file(&file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute the command to run on cache hit, when the outputs are produced by --fallback_command.
+    async fn execute_on_hit(&self, inputs: &InputHashBundle, program_run: &mut AtomicBool) -> Result<i32> {
+        info!("Executing command on cache hit: {:?}", self.config.command_to_run);
+        if self.config.command_to_run.is_empty() {
+            return Err(anyhow!(USAGE));
+        }
+        let inputs_hash = self.config.inputs_hash_format.render(&inputs.hash);
+        let mut child = Command::new(&self.config.command_to_run[0])
+            .args(&self.config.command_to_run[1..])
+            .env(&self.config.inputs_hash_var, inputs_hash)
+            .envs(self.config.get_set_env()?)
+            .spawn()
+            .with_context(|| "Spawning command")?;
+        program_run.store(true, Ordering::SeqCst);
+        Ok(Self::exit_code(child.wait().await?))
+    }
+
+    // Get the captured stdout/stderr of the cached run, downloading those stored as objects.
+    async fn fetch_captured(&self, outputs: &OutputHashBundle) -> Result<Vec<Output>> {
+        let mut captured = Vec::new();
+        for (output, item_hash) in outputs.stored_details() {
+            let object_output = match output {
+                Output::Stdout(_) | Output::Stderr(_) => {
+                    captured.push(output.clone());
+                    continue;
+                }
+                Output::StdoutObject(_) => Output::Stdout,
+                Output::StderrObject(_) => Output::Stderr,
+                _ => continue,
+            };
+            let mut reader = self.caching_backend.download_object_file(item_hash).await?;
+            let mut buffer = Vec::new();
+            if copy_and_hash(&mut reader, &mut buffer, &self.hash_pool).await? != *item_hash {
+                return Err(anyhow!("Mismatch of the downloaded captured output hash"));
+            }
+            self.timings
+                .downloaded_bytes
+                .fetch_add(buffer.len() as u64, Ordering::Relaxed);
+            captured.push(object_output(buffer));
+        }
+        Ok(captured)
+    }
+
+    // Replay the captured stdout/stderr of the cached run.
+    async fn replay_captured(captured: Vec<Output>) -> Result<()> {
+        for output in captured {
+            match output {
+                Output::Stdout(buffer) => {
+                    let mut stdout = tokio::io::stdout();
+                    stdout.write_all(&buffer).await?;
+                    stdout.flush().await?;
+                }
+                Output::Stderr(buffer) => {
+                    let mut stderr = tokio::io::stderr();
+                    stderr.write_all(&buffer).await?;
+                    stderr.flush().await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the manifest of present output files and their hashes (object keys), if requested.
+    fn write_output_manifest(&self, outputs: &OutputHashBundle) -> Result<()> {
+        if let Some(manifest) = &self.config.output_manifest {
+            let files: BTreeMap<String, &String> = outputs
+                .hash_details
+                .iter()
+                .filter_map(|(output, hash)| match output {
+                    Output::File(fileoutput) if fileoutput.present => Some((fileoutput.filename.to_string(), hash)),
+                    _ => None,
+                })
+                .collect();
+            let manifest = manifest.to_path(self.config.roots())?;
+            std::fs::write(&manifest, serde_json::to_string_pretty(&files)? + "\n")
+                .with_context(|| format!("Writing output manifest '{}'", manifest.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Write the cache entry, exactly as it's stored in the backend, into the --dump_bundle file.
+    fn dump_bundle(&self, bundle: &InputOutputBundle) -> Result<()> {
+        if let Some(dump) = &self.config.dump_bundle {
+            let dump = dump.to_path(self.config.roots())?;
+            std::fs::write(&dump, serde_json::to_string_pretty(bundle)? + "\n")
+                .with_context(|| format!("Writing bundle dump '{}'", dump.display()))?;
         }
+        Ok(())
     }
 
     async fn execute_and_cache(
         &self,
         inputs: &InputHashBundle,
         lookup_result: &Option<InputOutputBundle>,
+        cache_decision: CacheDecision,
+        cache_decision_detail: Option<&str>,
         program_run: &mut AtomicBool,
     ) -> Result<ExitStatus> {
-        let exit_status = self
-            .execute_command(inputs, program_run)
+        let (exit_status, captured) = self
+            .timed("exec", self.execute_command(inputs, program_run, &[]))
             .await
             .with_context(|| "Waiting for child")?;
         // Now that we got the exit code, we try hard to pass it back to exit.
         // If we fail along the way, we should complain, but still continue.
-        match self.read_outputs(exit_status.code()) {
+        let outputs = self.timed("read_outputs", async { self.read_outputs(Some(exit_status), captured) });
+        match outputs.await {
             Ok(outputs) => {
-                let non_determinism = lookup_result.as_ref().map_or(false, |lookup_result| {
-                    !Self::equal_outputs(&lookup_result.outputs, &outputs)
-                });
+                let output_diffs = lookup_result
+                    .as_ref()
+                    .map(|lookup_result| lookup_result.outputs.diff(&outputs))
+                    .unwrap_or_default();
+                let non_determinism = !output_diffs.is_empty();
 
                 if non_determinism {
-                    error!(
+                    let diffs: String = output_diffs.iter().map(|diff| format!("  {}\n", diff)).collect();
+                    error!("Non-determinism detected (old vs new):\n{}", diffs);
+                    debug!(
                         indoc! {"
-                        Non-determinism detected:
                         Old: {:?}
                         vs
                         New: {:?}\n"},
@@ -190,20 +967,72 @@ impl<'a> Capsule<'a> {
                         &outputs
                     );
                 }
+                // The existing entry may be the good one, so don't race to overwrite it.
+                let keep_entry = non_determinism && self.config.abort_on_output_mismatch_write;
+                if keep_entry {
+                    error!(
+                        "Not writing the cache entry for inputs hash '{}', the outputs differ from the existing one",
+                        inputs.hash
+                    );
+                }
+
+                self.write_output_manifest(&outputs).unwrap_or_else(|err| {
+                    error!("Failed to write output manifest: {:#}", err);
+                });
+
+                let metadata = self.config.get_metadata().unwrap_or_else(|err| {
+                    error!("Failed to read metadata to attach: {:#}", err);
+                    Metadata::default()
+                });
 
                 // Concurrently write the log, cache entry and cache objects (files).
                 // The larger of each of the timeouts is applied to the combined branch.
                 let logger_fut = time::timeout(
                     Duration::from_millis(timeouts::TIMEOUT_LOGGING_MILLIS),
-                    self.logger.log(inputs, &outputs, false, non_determinism),
+                    self.logger
+                        .log(inputs, &outputs, cache_decision, cache_decision_detail, non_determinism),
                 );
+                // Large captured stdout/stderr are uploaded as objects, and are not kept in the entry.
+                let stored_outputs = outputs.with_captured_objects(self.config.capture_inline_threshold_bytes);
+                if self.config.dump_bundle.is_some() {
+                    let bundle = InputOutputBundle {
+                        inputs: inputs.clone(),
+                        outputs: stored_outputs.clone(),
+                        source: self.capsule_job(),
+                        metadata: metadata.clone(),
+                    };
+                    self.dump_bundle(&bundle).unwrap_or_else(|err| {
+                        error!("Failed to dump the cache entry: {:#}", err);
+                    });
+                }
                 let cache_write_fut = time::timeout(
                     Duration::from_millis(timeouts::TIMEOUT_CACHE_WRITE_MILLIS),
-                    self.caching_backend.write(inputs, &outputs, self.capsule_job()),
+                    self.timed("write", async {
+                        if self.config.dry_write {
+                            info!("Dry write: skipping the cache entry for inputs hash '{}'", inputs.hash);
+                            return Ok(());
+                        }
+                        if keep_entry {
+                            return Ok(());
+                        }
+                        let stored_inputs = self.stored_inputs(inputs, &stored_outputs, &metadata)?;
+                        self.caching_backend
+                            .write(&stored_inputs, &stored_outputs, self.capsule_job(), metadata)
+                            .await
+                    }),
                 );
                 let upload_fut = time::timeout(
                     Duration::from_millis(timeouts::TIMEOUT_UPLOAD_MILLIS),
-                    self.upload_files(&outputs),
+                    self.timed("upload", async {
+                        if keep_entry {
+                            return Ok(());
+                        }
+                        if self.config.no_upload {
+                            info!("No upload: skipping the objects for inputs hash '{}'", inputs.hash);
+                            return Ok(());
+                        }
+                        self.upload_files(&outputs).await
+                    }),
                 );
                 let (logger_result, cache_result, upload_result) = join!(logger_fut, cache_write_fut, upload_fut);
 
@@ -233,16 +1062,152 @@ impl<'a> Capsule<'a> {
                 } else {
                     error!("Time out uploading files to cache");
                 }
+                self.run_hook(&self.config.on_miss_exec, inputs, Some(&outputs)).await;
             }
             Err(err) => {
                 error!("Failed to get command outputs: {}", err);
+                self.run_hook(&self.config.on_miss_exec, inputs, None).await;
             }
         }
         Ok(exit_status)
     }
 
+    /// Run the --on_hit_exec or --on_miss_exec hook with the inputs and outputs hashes in its
+    /// environment. Its failures are only logged, and don't change the exit code of the capsule.
+    async fn run_hook(&self, hook: &Option<String>, inputs: &InputHashBundle, outputs: Option<&OutputHashBundle>) {
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return,
+        };
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(hook).env(
+            &self.config.inputs_hash_var,
+            self.config.inputs_hash_format.render(&inputs.hash),
+        );
+        if let Some(outputs) = outputs {
+            command.env("CAPSULE_OUTPUTS_HASH", &outputs.hash);
+        }
+        match command.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => error!("Hook '{}' failed: {}", hook, status),
+            Err(err) => error!("Failed to run hook '{}': {}", hook, err),
+        }
+    }
+
+    // Set the access and modification times of the file to now.
+    fn touch(&self, path: &Path) -> Result<()> {
+        let now = self.clock.now().duration_since(UNIX_EPOCH)?;
+        let now = TimeVal::microseconds(now.as_micros() as i64);
+        nix::sys::stat::utimes(path, &now, &now).with_context(|| format!("Touching '{}'", path.display()))
+    }
+
+    /// Download the content of an output file into the writer, and return whether it matches the
+    /// hash. Chunked files are put together from their chunks, each checked against its own hash.
+    async fn download_object<W>(&self, fileoutput: &FileOutput, item_hash: &str, writer: &mut W) -> Result<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        // Object keys are the SHA256 hashes of their contents, so this checks that we received
+        // exactly the objects we asked for. The content is hashed as it arrives, rather than
+        // reading the file again after.
+        if fileoutput.chunks.is_empty() {
+            let mut reader = self.caching_backend.download_object_file(item_hash).await?;
+            return Ok(copy_and_hash(&mut reader, writer, &self.hash_pool).await? == item_hash);
+        }
+        for chunk in &fileoutput.chunks {
+            let mut reader = self.caching_backend.download_object_file(&chunk.hash).await?;
+            if copy_and_hash(&mut reader, writer, &self.hash_pool).await? != chunk.hash {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Check that the filesystems the output files are restored to have room for all of them, and
+    /// --min_free_disk_mb more, so that a cache hit doesn't fill the disk with a half-written tree.
+    /// The files may be on different filesystems, so each has to fit all of them.
+    fn check_free_disk(&self, outputs: &OutputHashBundle) -> Result<()> {
+        let min_free_disk_mb = match self.config.min_free_disk_mb {
+            Some(min_free_disk_mb) => min_free_disk_mb,
+            None => return Ok(()),
+        };
+        let needed = outputs
+            .file_bytes
+            .saturating_add(min_free_disk_mb.saturating_mul(1 << 20));
+        let mut filesystems = BTreeSet::new();
+        for (item, _) in &outputs.hash_details {
+            if let Output::File(fileoutput) = item {
+                if !fileoutput.present {
+                    continue;
+                }
+                // The file and its directories may not exist yet.
+                let filename = self.restore_path(&fileoutput.filename)?;
+                let dir = filename
+                    .ancestors()
+                    .find(|dir| dir.exists())
+                    .context("No existing directory")?;
+                let stat = statvfs(dir).with_context(|| format!("Getting free disk space of '{}'", dir.display()))?;
+                if !filesystems.insert(stat.filesystem_id()) {
+                    continue;
+                }
+                let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+                if available < needed {
+                    bail!(
+                        "Not enough free disk space in '{}': {} MB available, {} MB needed",
+                        dir.display(),
+                        available >> 20,
+                        needed >> 20
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Download all output files from the caching backend, and place them into destination paths.
+    /// With --ready_list, the list is created anew, and the path of each output file is written to it as soon
+    /// as it is in place. Once all of them are, an empty line marks the list as complete.
     async fn download_files(&self, outputs: &OutputHashBundle) -> Result<()> {
+        let ready_list = match &self.config.ready_list {
+            Some(ready_list) => ready_list.to_path(self.config.roots())?,
+            None => return self.download_files_streaming(outputs, &|_| Ok(())).await,
+        };
+        // The list of an earlier hit is truncated, as its files may no longer be ready.
+        let file = std::fs::File::create(&ready_list)
+            .with_context(|| format!("Creating ready list '{}'", ready_list.display()))?;
+        let file = std::sync::Mutex::new(file);
+        let on_ready = |path: &Path| {
+            // The file is unbuffered, so every line is visible to the readers once written.
+            writeln!(file.lock().unwrap(), "{}", path.display())
+                .with_context(|| format!("Writing ready list '{}'", ready_list.display()))
+        };
+        self.download_files_streaming(outputs, &on_ready).await?;
+        writeln!(file.into_inner().unwrap()).with_context(|| format!("Writing ready list '{}'", ready_list.display()))
+    }
+
+    // Remove the ready list of an earlier hit at the start of the run, and after a failed download, as the
+    // command may be executed instead, and rewrite the files the list claims are ready.
+    fn remove_ready_list(&self) -> Result<()> {
+        if let Some(ready_list) = &self.config.ready_list {
+            let ready_list = ready_list.to_path(self.config.roots())?;
+            match std::fs::remove_file(&ready_list) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| format!("Removing ready list '{}'", ready_list.display()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Download the outputs, calling on_ready with the path of each output file as soon as it is in place,
+    // i.e. verified and moved to its final name, rather than only once all the files are.
+    async fn download_files_streaming(
+        &self,
+        outputs: &OutputHashBundle,
+        on_ready: &(dyn Fn(&Path) -> Result<()> + Sync),
+    ) -> Result<()> {
+        self.check_free_disk(outputs)?;
         // Now download all files that should be present.
         let mut all_files_futures = Vec::new();
         // This loop generates futures for all downloadable files, and places them
@@ -250,30 +1215,68 @@ impl<'a> Capsule<'a> {
         for (item, item_hash) in &outputs.hash_details {
             if let Output::File(ref fileoutput) = item {
                 if fileoutput.present {
-                    info!("Downloading file '{}' hash '{}'", fileoutput.filename, item_hash);
-                    let filename = fileoutput.filename.to_path(&self.config.workspace_root)?;
-                    let dir = filename.parent().context("No parent directory")?;
-                    std::fs::create_dir_all(dir)?;
-                    let file = NamedTempFile::new_in(dir)?;
-                    let (file, path) = file.into_parts();
-                    let mut file_stream = tokio::fs::File::from_std(file);
+                    let filename = self.restore_path(&fileoutput.filename)?;
                     let download_file_fut = async move {
-                        let mut file_body_reader = self.caching_backend.download_object_file(item_hash).await?;
-                        tokio::io::copy(&mut file_body_reader, &mut file_stream).await?;
-                        file_stream.flush().await?;
-                        info!("File {} downloaded, verifying hash", fileoutput.filename);
-                        // Calculating the SHA256 is a long CPU bound op, better do in a thread.
-                        let tmp_path = path.to_path_buf();
-                        let received_hash = task::spawn_blocking(move || file_hash(&tmp_path)).await??;
-                        if received_hash != *item_hash {
-                            return Err(anyhow!("Mismatch of the downloaded file hash"));
+                        // Files already present with the right content need not be downloaded,
+                        // which also preserves their mtime, unless asked to touch them.
+                        if !self.config.force_download && filename.is_file() {
+                            let existing_file = filename.clone();
+                            let existing_hash = self.hash_pool.run(move || file_hash(&existing_file)).await?;
+                            if existing_hash == *item_hash {
+                                info!("File '{}' is up to date, skipping download", fileoutput.filename);
+                                std::fs::set_permissions(
+                                    &filename,
+                                    std::fs::Permissions::from_mode(self.output_mode(fileoutput.mode)),
+                                )?;
+                                if self.config.touch_outputs {
+                                    self.touch(&filename)?;
+                                }
+                                return on_ready(&filename);
+                            }
                         }
+                        info!("Downloading file '{}' hash '{}'", fileoutput.filename, item_hash);
+                        let dir = filename.parent().context("No parent directory")?;
+                        std::fs::create_dir_all(dir)?;
+                        let mut attempt = 0;
+                        let path = loop {
+                            let file = NamedTempFile::new_in(dir)?;
+                            let (file, path) = file.into_parts();
+                            let mut file_stream = tokio::fs::File::from_std(file);
+                            if self.download_object(fileoutput, item_hash, &mut file_stream).await? {
+                                info!("File {} downloaded and verified", fileoutput.filename);
+                                break path;
+                            }
+                            // A truncated or corrupted transfer is usually transient, so try again.
+                            if attempt >= self.config.download_retries {
+                                return Err(anyhow!("Mismatch of the downloaded file hash"));
+                            }
+                            attempt += 1;
+                            warn!(
+                                "Mismatch of the downloaded file '{}' hash, retrying ({}/{})",
+                                fileoutput.filename, attempt, self.config.download_retries
+                            );
+                        };
                         path.persist(&filename)?;
-                        std::fs::set_permissions(&filename, std::fs::Permissions::from_mode(fileoutput.mode))?;
-                        Ok::<(), anyhow::Error>(())
+                        let size = std::fs::metadata(&filename)?.len();
+                        self.timings.downloaded_bytes.fetch_add(size, Ordering::Relaxed);
+                        std::fs::set_permissions(
+                            &filename,
+                            std::fs::Permissions::from_mode(self.output_mode(fileoutput.mode)),
+                        )?;
+                        on_ready(&filename)
                     };
                     all_files_futures.push(download_file_fut);
+                } else if fileoutput.negative && self.config.restore_to.is_none() {
+                    // The output was legitimately absent when cached, so make sure it's absent now.
+                    self.remove_files(&fileoutput.filename)?;
                 }
+            } else if let Output::Dir(ref dirname) = item {
+                std::fs::create_dir_all(self.restore_path(dirname)?)?;
+            } else if let Output::Directory { ref dirname, mode } = item {
+                // Only the directory itself is restored, whatever is in it is left alone.
+                let path = self.restore_path(dirname)?;
+                std::fs::create_dir_all(&path)?;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(self.output_mode(*mode)))?;
             }
         }
         // Limit concurrency to max configured download threads.
@@ -284,22 +1287,128 @@ impl<'a> Capsule<'a> {
         Ok(())
     }
 
+    // The path to restore the output to, which is where it was cached from, unless --restore_to
+    // gives another directory, under which the outputs keep their workspace relative paths, and the
+    // full paths their paths from the root.
+    fn restore_path(&self, output: &WorkspacePath) -> Result<PathBuf> {
+        let restore_to = match &self.config.restore_to {
+            Some(restore_to) => restore_to.to_path(self.config.roots())?,
+            None => return output.to_path(self.config.roots()),
+        };
+        Ok(match output {
+            WorkspacePath::Workspace(path) => restore_to.join(path),
+            WorkspacePath::NonWorkspace(path) => restore_to.join(path.strip_prefix("/").unwrap_or(path)),
+        })
+    }
+
+    // Remove the files matching the given pattern.
+    fn remove_files(&self, file_pattern: &WorkspacePath) -> Result<()> {
+        let pattern = file_pattern.to_path(self.config.roots())?;
+        let pattern = pattern.to_str().ok_or(anyhow!("can't convert path to string"))?;
+        for file in glob(pattern)? {
+            let file = file?;
+            if file.is_file() {
+                info!("Removing output file '{}'", file.display());
+                std::fs::remove_file(&file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The human-readable name of the object for an output file, with --object_name_strip_prefix
+    /// trimmed. It's only used for logging and display, objects are keyed by their hash.
+    fn object_name(&self, filename: &WorkspacePath) -> String {
+        let name = filename.to_string();
+        let stripped = self
+            .config
+            .object_name_strip_prefix
+            .as_ref()
+            .and_then(|prefix| Path::new(&name).strip_prefix(prefix).ok())
+            .filter(|stripped| !stripped.as_os_str().is_empty());
+        match stripped {
+            Some(stripped) => stripped.to_string_lossy().into_owned(),
+            None => name,
+        }
+    }
+
     /// Upload output files into S3, keyed by their hash (content addressed).
     async fn upload_files(&self, outputs: &OutputHashBundle) -> Result<()> {
         let mut all_files_futures = Vec::new();
-        for (item, item_hash) in &outputs.hash_details {
+        // Chunks are content addressed too, so the ones repeated in the outputs are uploaded once.
+        let mut chunk_hashes = BTreeSet::new();
+        for (item, item_hash) in outputs.stored_details() {
             if let Output::File(ref fileoutput) = item {
-                if fileoutput.present {
-                    let object_name = fileoutput.filename.to_string();
-                    let file_name = fileoutput.filename.to_path(&self.config.workspace_root)?;
+                if fileoutput.present && fileoutput.chunks.is_empty() {
+                    let object_name = self.object_name(&fileoutput.filename);
+                    let file_name = fileoutput.filename.to_path(self.config.roots())?;
                     let tokio_file = tokio::fs::File::open(&file_name).await?;
                     let content_length = tokio_file.metadata().await?.len();
+                    if self.config.dry_upload {
+                        self.log_dry_upload(&object_name, item_hash, content_length);
+                        continue;
+                    }
+                    self.timings.uploaded_bytes.fetch_add(content_length, Ordering::Relaxed);
                     all_files_futures.push(self.caching_backend.upload_object_file(
                         object_name,
                         item_hash,
                         Box::pin(tokio_file),
                         content_length,
                     ));
+                } else if fileoutput.present {
+                    let object_name = self.object_name(&fileoutput.filename);
+                    let file_name = fileoutput.filename.to_path(self.config.roots())?;
+                    let mut offset = 0;
+                    for (index, chunk) in fileoutput.chunks.iter().enumerate() {
+                        if chunk_hashes.insert(&chunk.hash) {
+                            let chunk_name =
+                                format!("{} (chunk {}/{})", object_name, index + 1, fileoutput.chunks.len());
+                            if self.config.dry_upload {
+                                self.log_dry_upload(&chunk_name, &chunk.hash, chunk.length);
+                                offset += chunk.length;
+                                continue;
+                            }
+                            self.timings.uploaded_bytes.fetch_add(chunk.length, Ordering::Relaxed);
+                            let file_name = file_name.clone();
+                            let caching_backend = self.caching_backend;
+                            // The file is opened when the chunk is uploaded, not to have a handle per chunk open.
+                            all_files_futures.push(Box::pin(async move {
+                                let mut tokio_file = tokio::fs::File::open(&file_name).await?;
+                                tokio_file.seek(SeekFrom::Start(offset)).await?;
+                                caching_backend
+                                    .upload_object_file(
+                                        chunk_name,
+                                        &chunk.hash,
+                                        Box::pin(tokio_file.take(chunk.length)),
+                                        chunk.length,
+                                    )
+                                    .await
+                            }));
+                        }
+                        offset += chunk.length;
+                    }
+                }
+            }
+            // Same for the captured stdout/stderr that are not kept inline in the cache entry.
+            if let Output::Stdout(buffer) | Output::Stderr(buffer) = item {
+                if buffer.len() > self.config.capture_inline_threshold_bytes {
+                    let object_name = if let Output::Stdout(_) = item {
+                        "stdout"
+                    } else {
+                        "stderr"
+                    };
+                    if self.config.dry_upload {
+                        self.log_dry_upload(object_name, item_hash, buffer.len() as u64);
+                        continue;
+                    }
+                    self.timings
+                        .uploaded_bytes
+                        .fetch_add(buffer.len() as u64, Ordering::Relaxed);
+                    all_files_futures.push(self.caching_backend.upload_object_file(
+                        object_name.to_owned(),
+                        item_hash,
+                        Box::pin(std::io::Cursor::new(buffer.clone())),
+                        buffer.len() as u64,
+                    ));
                 }
             }
         }
@@ -311,114 +1420,515 @@ impl<'a> Capsule<'a> {
         Ok(())
     }
 
-    const DEFAULT_EXIT_CODE: i32 = 1; // A catchall error code with no special meaning.
+    // With --dry_upload, the objects are only logged with the keys they would be uploaded under.
+    fn log_dry_upload(&self, name: &str, item_hash: &str, content_length: u64) {
+        info!(
+            "Dry upload: {} with hash '{}' to key '{}', {} bytes",
+            name,
+            item_hash,
+            self.caching_backend.object_key(item_hash),
+            content_length
+        );
+    }
 
-    pub async fn run_capsule(&self, program_run: &mut AtomicBool) -> Result<i32> {
-        let inputs = self.read_inputs()?;
+    const DEFAULT_EXIT_CODE: i32 = 1; // A catchall error code with no special meaning.
 
-        // If we only need to output the hash, just do it and quit.
-        if self.config.inputs_hash_output {
-            print!("{}", inputs.hash);
-            return Ok(0);
-        }
+    // The exit code to pass back, where termination by a signal is 128 + the signal number, as shells
+    // do, and as it's replayed on cache hit.
+    fn exit_code(exit_status: ExitStatus) -> i32 {
+        exit_status
+            .code()
+            .or_else(|| exit_status.signal().map(|signal| 128 + signal))
+            .unwrap_or(Self::DEFAULT_EXIT_CODE)
+    }
 
-        info!("Capsule inputs hash: {}", inputs.hash);
+    /// Look up the cache entry by the inputs hash alone, without reading the inputs.
+    pub async fn lookup_inputs_hash(&self, inputs_hash: &str) -> Result<Option<InputOutputBundle>> {
+        let inputs = InputHashBundle {
+            hash: inputs_hash.to_owned(),
+            hash_details: vec![],
+            ..Default::default()
+        };
+        self.lookup(&inputs).await
+    }
 
-        // In passive mode, skip everything, except reading inputs as we still want to fill
-        // CAPSULE_INPUTS_HASH with data about the capsule inputs.
-        if self.config.passive {
-            return self
-                .execute_command(&inputs, program_run)
-                .await
-                .with_context(|| "Waiting for child")
-                .map(|exit_status| exit_status.code().unwrap_or(Self::DEFAULT_EXIT_CODE));
-        }
+    /// Read the inputs and look up their cache entry, without running or downloading anything.
+    /// Build orchestrators can use it to check whether the work is already cached before scheduling it.
+    pub async fn lookup_only(&self) -> Result<Option<InputOutputBundle>> {
+        let inputs = self.read_inputs()?;
+        self.lookup(&inputs).await
+    }
 
-        let lookup_result = time::timeout(
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        time::timeout(
             Duration::from_millis(timeouts::TIMEOUT_LOOKUP_MILLIS),
-            self.caching_backend.lookup(&inputs),
+            self.caching_backend.lookup(inputs),
         )
         .await
         .context("Timeout looking up in cache")? // Outer Result wrapping is from Timeout.
-        .context("Looking in cache")?; // Inner Result wrapping is from the lookup itself.
-        if let Some(ref lookup_result) = lookup_result {
-            let log_cache_hit = |msg: &str| {
-                info!(
-                    "Cache hit on {} from {} ({}): {}",
+        .context("Looking in cache") // Inner Result wrapping is from the lookup itself.
+    }
+
+    /// Print the cache entry for the given inputs hash. Returns 1 if there is no such entry.
+    pub async fn inspect(&self, inputs_hash: &str) -> Result<i32> {
+        match self.lookup_inputs_hash(inputs_hash).await? {
+            Some(bundle) => {
+                print!("{}", describe_bundle(&bundle));
+                Ok(0)
+            }
+            None => {
+                error!(
+                    "No cache entry for {} with inputs hash {}",
                     self.capsule_id(),
-                    lookup_result.source,
-                    lookup_result.inputs.hash,
-                    msg
-                )
-            };
-            // We have a cache hit, but in case we are in placebo mode, or we have cached a failure,
-            // we should still not use the cache. Let's figure this out while printing the solution.
-            let mut use_cache = true;
-            if self.config.milestone == Milestone::Placebo {
-                log_cache_hit("ignoring and proceeding with execution");
-                use_cache = false
-            } else {
-                if !self.config.cache_failure {
-                    // If result code from the command is not 0
-                    if lookup_result.outputs.result_code().unwrap_or(1) != 0 {
-                        log_cache_hit("cached failure, proceeding with execution");
-                        use_cache = false;
-                    }
-                }
-                // Check whether we should avoid caching when output files from the cache hit
-                // don't match with the capsule output files from config.
-                if use_cache {
-                    // a predicate selecting all paths for Output::Files from all cached outputs.
-                    fn predicate<X>((output, _): &(Output, X)) -> Option<&WorkspacePath> {
-                        if let Output::File(fileoutput) = output {
-                            if fileoutput.present {
-                                return Some(&fileoutput.filename);
-                            }
-                        }
-                        None
-                    }
-                    let iter = lookup_result.outputs.hash_details.iter().filter_map(predicate);
-                    // If anything doesn't match, don't use the cache!
-                    if !self.config.outputs_match(iter)? {
-                        log_cache_hit("mismatch in output patterns, proceeding with execution");
-                        use_cache = false;
-                    }
+                    inputs_hash
+                );
+                Ok(1)
+            }
+        }
+    }
+
+    /// Check that the caching backend works, by storing a synthetic cache entry and object, and
+    /// reading them back. Returns the exit code, which is 1 if any step failed.
+    pub async fn selftest(&self) -> Result<i32> {
+        let started = self.clock.instant();
+        match self.selftest_round_trip().await {
+            Ok(()) => {
+                info!(
+                    "Self-test of the {} backend passed in {:?}",
+                    self.caching_backend.name(),
+                    self.clock.instant() - started
+                );
+                Ok(0)
+            }
+            Err(err) => {
+                error!(
+                    "Self-test of the {} backend failed after {:?}: {:#}",
+                    self.caching_backend.name(),
+                    self.clock.instant() - started,
+                    err
+                );
+                Ok(1)
+            }
+        }
+    }
+
+    async fn selftest_round_trip(&self) -> Result<()> {
+        // Unique content, so that neither the entry nor the object could be left from another run.
+        let nonce = self.clock.now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let content = format!("capsule self-test {} {}", std::process::id(), nonce).into_bytes();
+        let mut input_set = InputSet::default();
+        input_set.add_input(Input::ToolTag(String::from_utf8_lossy(&content).into_owned()));
+        let inputs = input_set.hash_bundle(&None)?;
+        let mut output_set = OutputSet::default();
+        output_set.add_output(Output::ExitCode(0));
+        output_set.add_output(Output::Stdout(content.clone()));
+        // Store the stdout as an object, like large captured outputs are.
+        let outputs = output_set.hash_bundle(&None)?.with_captured_objects(0);
+        let object_hash = copy_and_hash(&mut &content[..], &mut tokio::io::sink(), &self.hash_pool).await?;
+
+        let step = self.clock.instant();
+        time::timeout(
+            Duration::from_millis(timeouts::TIMEOUT_UPLOAD_MILLIS),
+            self.caching_backend.upload_object_file(
+                "self-test object".to_owned(),
+                &object_hash,
+                Box::pin(std::io::Cursor::new(content.clone())),
+                content.len() as u64,
+            ),
+        )
+        .await
+        .context("Timeout uploading the object")?
+        .context("Uploading the object")?;
+        info!("Uploaded the object in {:?}", self.clock.instant() - step);
+
+        let step = self.clock.instant();
+        time::timeout(
+            Duration::from_millis(timeouts::TIMEOUT_CACHE_WRITE_MILLIS),
+            self.caching_backend
+                .write(&inputs, &outputs, "capsule self-test".to_owned(), Metadata::new()),
+        )
+        .await
+        .context("Timeout writing the cache entry")?
+        .context("Writing the cache entry")?;
+        info!("Wrote the cache entry in {:?}", self.clock.instant() - step);
+
+        let step = self.clock.instant();
+        let bundle = self
+            .lookup_inputs_hash(&inputs.hash)
+            .await?
+            .context("The cache entry just written is not found")?;
+        if bundle.outputs.hash != outputs.hash || bundle.outputs.hash_details != outputs.hash_details {
+            bail!("The cache entry read back differs from the one written");
+        }
+        info!("Looked up the cache entry in {:?}", self.clock.instant() - step);
+
+        let step = self.clock.instant();
+        let mut downloaded = Vec::new();
+        let download_fut = async {
+            let mut reader = self.caching_backend.download_object_file(&object_hash).await?;
+            copy_and_hash(&mut reader, &mut downloaded, &self.hash_pool).await
+        };
+        let downloaded_hash = time::timeout(Duration::from_millis(timeouts::TIMEOUT_DOWNLOAD_MILLIS), download_fut)
+            .await
+            .context("Timeout downloading the object")?
+            .context("Downloading the object")?;
+        if downloaded_hash != object_hash || downloaded != content {
+            bail!("The object read back differs from the one uploaded");
+        }
+        info!("Downloaded the object in {:?}", self.clock.instant() - step);
+
+        // Failing to clean up doesn't mean the backend is unusable for caching.
+        if let Err(err) = self.caching_backend.remove(&inputs).await {
+            warn!("Cannot remove the self-test cache entry: {:#}", err);
+        }
+        if let Err(err) = self.caching_backend.remove_object_file(&object_hash).await {
+            warn!("Cannot remove the self-test object: {:#}", err);
+        }
+        Ok(())
+    }
+
+    // Just execute the command, without any caching.
+    async fn execute_passive(&self, inputs: &InputHashBundle, program_run: &mut AtomicBool) -> Result<i32> {
+        self.execute_command(inputs, program_run, &[])
+            .await
+            .with_context(|| "Waiting for child")
+            .map(|(exit_status, _)| Self::exit_code(exit_status))
+    }
+
+    /// Decide whether the cache entry found by the lookup can be used. A `Hit` is tentative, as
+    /// downloading the outputs may still fail, which turns it into `IgnoredDownloadError`.
+    pub fn cache_decision(&self, lookup_result: &Option<InputOutputBundle>) -> Result<CacheDecision> {
+        let lookup_result = match lookup_result {
+            Some(lookup_result) => lookup_result,
+            None => return Ok(CacheDecision::MissNoEntry),
+        };
+        if self.config.milestone == Milestone::Placebo {
+            return Ok(CacheDecision::IgnoredPlacebo);
+        }
+        if !self.config.cache_failure && lookup_result.outputs.result_code().unwrap_or(1) != 0 {
+            return Ok(CacheDecision::IgnoredFailure);
+        }
+        // a predicate selecting all paths for Output::Files and Output::Directory from all cached outputs.
+        fn predicate<X>((output, _): &(Output, X)) -> Option<&WorkspacePath> {
+            match output {
+                Output::File(fileoutput) if fileoutput.present => Some(&fileoutput.filename),
+                Output::Directory { dirname, .. } => Some(dirname),
+                _ => None,
+            }
+        }
+        let iter = lookup_result.outputs.hash_details.iter().filter_map(predicate);
+        // If anything doesn't match, don't use the cache!
+        if !self.config.outputs_match(iter)? {
+            return Ok(CacheDecision::IgnoredOutputMismatch);
+        }
+        // Decided last, so that the hits that are not downloaded are still told apart in the metrics.
+        if self.config.no_download {
+            return Ok(CacheDecision::IgnoredNoDownload);
+        }
+        Ok(CacheDecision::Hit)
+    }
+
+    /// Log the cache decision with the same fields for every outcome, so that it can be filtered.
+    fn log_cache_decision(
+        &self,
+        cache_decision: CacheDecision,
+        lookup_result: Option<&InputOutputBundle>,
+        detail: Option<&str>,
+    ) {
+        let (source, hash) = lookup_result.map_or(("-", "-"), |lookup_result| {
+            (lookup_result.source.as_str(), lookup_result.inputs.hash.as_str())
+        });
+        let detail = detail.map(|detail| format!(": {}", detail)).unwrap_or_default();
+        let level = match cache_decision {
+            CacheDecision::MissNoEntry => log::Level::Debug,
+            CacheDecision::IgnoredDownloadError => log::Level::Warn,
+            _ => log::Level::Info,
+        };
+        log::log!(
+            level,
+            "Cache decision on {}: {} (source: {}, inputs hash: {}){}",
+            self.capsule_id(),
+            cache_decision,
+            source,
+            hash,
+            detail
+        );
+    }
+
+    /// Run the capsule the given number of times, removing the output files before each run but the
+    /// first, and report whether each run was a cache hit, and how long it took. Used to evaluate
+    /// whether caching is worth it. Returns the exit code of the last run.
+    pub async fn run_capsule_repeated(&self, repeat: usize, program_run: &mut AtomicBool) -> Result<i32> {
+        let mut exit_code = 0;
+        let mut hits = Vec::new();
+        let mut misses = Vec::new();
+        let output_patterns = self
+            .config
+            .output_files
+            .iter()
+            .chain(&self.config.negative_output_files);
+        for iteration in 1..=repeat {
+            if iteration > 1 {
+                for file_pattern in output_patterns.clone() {
+                    self.remove_files(file_pattern)?;
                 }
             }
+            let mut iteration_program_run = AtomicBool::new(false);
+            let start = self.clock.instant();
+            let result = self.run_capsule(&mut iteration_program_run).await;
+            let elapsed = self.clock.instant() - start;
+            let hit = !iteration_program_run.load(Ordering::SeqCst);
+            if !hit {
+                program_run.store(true, Ordering::SeqCst);
+            }
+            exit_code = result?;
+            info!(
+                "Run {}/{}: cache {} in {:.3}s, exit code {}",
+                iteration,
+                repeat,
+                if hit { "hit" } else { "miss" },
+                elapsed.as_secs_f64(),
+                exit_code
+            );
+            if hit {
+                hits.push(elapsed);
+            } else {
+                misses.push(elapsed);
+            }
+        }
+        let average = |durations: &[Duration]| {
+            durations.iter().map(Duration::as_secs_f64).sum::<f64>() / durations.len().max(1) as f64
+        };
+        info!(
+            "Summary of {} runs: {} misses (average {:.3}s), {} hits (average {:.3}s)",
+            repeat,
+            misses.len(),
+            average(&misses),
+            hits.len(),
+            average(&hits)
+        );
+        Ok(exit_code)
+    }
 
-            if use_cache {
-                if let Ok(result) = time::timeout(
-                    Duration::from_millis(timeouts::TIMEOUT_DOWNLOAD_MILLIS),
-                    self.download_files(&lookup_result.outputs),
-                )
+    /// Testing only: run the command twice, without looking up or writing the cache, each time with
+    /// a new `CAPSULE_RUN_NONCE`, and a variable with a new name, and compare the outputs. A hermetic
+    /// command doesn't depend on them, so the differences are non-determinism, which is otherwise only
+    /// revealed by the cache hits with wrong outputs. Returns 1 if the outputs differ.
+    pub async fn probe_nondeterminism(&self, program_run: &mut AtomicBool) -> Result<i32> {
+        warn!("Testing only: --inject_nondeterminism runs the command twice, without caching");
+        let inputs = self.read_inputs()?;
+        let output_patterns = self
+            .config
+            .output_files
+            .iter()
+            .chain(&self.config.negative_output_files);
+        let mut runs = Vec::new();
+        for run in 1..=2 {
+            if run > 1 {
+                for file_pattern in output_patterns.clone() {
+                    self.remove_files(file_pattern)?;
+                }
+            }
+            let nanos = self.clock.now().duration_since(UNIX_EPOCH)?.as_nanos();
+            let nonce = format!("{:x}{:x}{}", nanos, std::process::id(), run);
+            let env = [
+                ("CAPSULE_RUN_NONCE".to_owned(), nonce.clone()),
+                (format!("CAPSULE_RANDOM_{}", nonce), nonce.clone()),
+            ];
+            let (exit_status, captured) = self
+                .execute_command(&inputs, program_run, &env)
                 .await
-                {
-                    match result {
-                        Ok(_) => {
-                            log_cache_hit("success");
-                            // Log successful cached results.
-                            self.logger
-                                .log(&inputs, &lookup_result.outputs, true, false)
-                                .await
-                                .unwrap_or_else(|err| {
-                                    error!("Failed to log results for observability: {}", err);
-                                });
-                            return Ok(lookup_result.outputs.result_code().unwrap_or(Self::DEFAULT_EXIT_CODE));
-                        }
-                        Err(e) => {
-                            log_cache_hit(&format!("failed to retrieve from the cache: {}", e));
+                .with_context(|| "Waiting for child")?;
+            let outputs = self.read_outputs(Some(exit_status), captured)?;
+            runs.push((exit_status, outputs));
+        }
+        let output_diffs = runs[0].1.diff(&runs[1].1);
+        if !output_diffs.is_empty() {
+            let diffs: String = output_diffs.iter().map(|diff| format!("  {}\n", diff)).collect();
+            error!("Non-determinism detected (first vs second run):\n{}", diffs);
+            return Ok(1);
+        }
+        info!("No non-determinism detected, the outputs of both runs are the same");
+        Ok(Self::exit_code(runs[1].0))
+    }
+
+    pub async fn run_capsule(&self, program_run: &mut AtomicBool) -> Result<i32> {
+        let result = self.run_capsule_once(program_run).await;
+        if let Some(summary) = self.caching_backend.summary() {
+            info!("Caching backend summary: {}", summary);
+        }
+        let timing_table = self.timing_table();
+        if self.config.verbose_timing && !self.config.inputs_hash_output {
+            eprint!("{}", timing_table);
+        }
+        if !self.config.inputs_hash_output {
+            // On error, the command is still run without caching, so it's a miss too.
+            let hit = result.is_ok() && !program_run.load(Ordering::SeqCst);
+            if let Err(err) = self.write_hit_marker(hit) {
+                error!("Cannot write the hit marker file: {:#}", err);
+            }
+        }
+        result
+    }
+
+    // Time a phase of the run, for --verbose_timing.
+    async fn timed<T>(&self, phase: &'static str, fut: impl Future<Output = T>) -> T {
+        let start = self.clock.instant();
+        let result = fut.await;
+        let elapsed = self.clock.instant() - start;
+        self.timings.phases.lock().unwrap().push((phase, elapsed));
+        result
+    }
+
+    // Render the timings of the run as a table, and reset them for the next run.
+    fn timing_table(&self) -> String {
+        let phases = std::mem::take(&mut *self.timings.phases.lock().unwrap());
+        let downloaded = self.timings.downloaded_bytes.swap(0, Ordering::Relaxed);
+        let uploaded = self.timings.uploaded_bytes.swap(0, Ordering::Relaxed);
+        let mut table = format!("Capsule timing for {}:\n", self.capsule_id());
+        for (phase, elapsed) in phases {
+            let bytes = match phase {
+                "download" => format!("  {:>12} bytes", downloaded),
+                "upload" => format!("  {:>12} bytes", uploaded),
+                _ => String::new(),
+            };
+            table += &format!("  {:<12} {:>9.3}s{}\n", phase, elapsed.as_secs_f64(), bytes);
+        }
+        table
+    }
+
+    fn write_hit_marker(&self, hit: bool) -> Result<()> {
+        if let Some(marker) = &self.config.hit_marker_file {
+            let marker = marker.to_path(self.config.roots())?;
+            std::fs::write(&marker, if hit { "hit\n" } else { "miss\n" })
+                .with_context(|| format!("Writing '{}'", marker.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Write the inputs that missed the cache into the --log_inputs_on_miss file, or stderr, in the
+    /// format of the inputs of --dump_bundle, so that they can be compared.
+    fn log_inputs_on_miss(&self, inputs: &InputHashBundle) -> Result<()> {
+        if let Some(log) = &self.config.log_inputs_on_miss {
+            let json = serde_json::to_string_pretty(inputs)? + "\n";
+            if log.to_string() == "-" {
+                eprint!("{}", json);
+            } else {
+                let log = log.to_path(self.config.roots())?;
+                std::fs::write(&log, json).with_context(|| format!("Writing inputs log '{}'", log.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_capsule_once(&self, program_run: &mut AtomicBool) -> Result<i32> {
+        let inputs = self.timed("read_inputs", async { self.read_inputs() }).await?;
+
+        // If we only need to output the hash, just do it and quit.
+        if self.config.inputs_hash_output {
+            print!("{}", self.config.inputs_hash_format.render(&inputs.hash));
+            return Ok(0);
+        }
+
+        info!("Capsule inputs hash: {}", inputs.hash);
+        self.remove_ready_list().unwrap_or_else(|err| {
+            error!("Failed to remove the ready list: {:#}", err);
+        });
+
+        // In passive mode, skip everything, except reading inputs as we still want to fill
+        // CAPSULE_INPUTS_HASH with data about the capsule inputs.
+        if self.config.passive {
+            return self.execute_passive(&inputs, program_run).await;
+        }
+
+        let lookup_result = match self.timed("lookup", self.lookup(&inputs)).await {
+            Ok(lookup_result) => lookup_result,
+            Err(err) if self.config.passive_on_missing_backend => {
+                warn!("Caching backend unavailable, running in passive mode: {:#}", err);
+                return self.execute_passive(&inputs, program_run).await;
+            }
+            Err(err) => return Err(err),
+        };
+        if lookup_result.is_none() {
+            self.log_inputs_on_miss(&inputs).unwrap_or_else(|err| {
+                error!("Failed to log the inputs: {:#}", err);
+            });
+        }
+        let mut cache_decision = self.cache_decision(&lookup_result)?;
+        let mut cache_decision_detail = None;
+        if let (CacheDecision::Hit, Some(lookup_result)) = (cache_decision, &lookup_result) {
+            let download_fut = self.timed("download", async {
+                self.download_files(&lookup_result.outputs).await?;
+                self.fetch_captured(&lookup_result.outputs).await
+            });
+            match time::timeout(Duration::from_millis(timeouts::TIMEOUT_DOWNLOAD_MILLIS), download_fut).await {
+                Ok(Ok(captured)) => {
+                    self.log_cache_decision(cache_decision, Some(lookup_result), None);
+                    Self::replay_captured(captured).await?;
+                    if !lookup_result.metadata.is_empty() {
+                        info!(
+                            "Cache hit metadata: {}",
+                            serde_json::to_string(&lookup_result.metadata)?
+                        );
+                    }
+                    self.write_output_manifest(&lookup_result.outputs)
+                        .unwrap_or_else(|err| {
+                            error!("Failed to write output manifest: {:#}", err);
+                        });
+                    self.dump_bundle(lookup_result).unwrap_or_else(|err| {
+                        error!("Failed to dump the cache entry: {:#}", err);
+                    });
+                    // Log successful cached results.
+                    self.logger
+                        .log(&inputs, &lookup_result.outputs, cache_decision, None, false)
+                        .await
+                        .unwrap_or_else(|err| {
+                            error!("Failed to log results for observability: {}", err);
+                        });
+                    self.run_hook(&self.config.on_hit_exec, &inputs, Some(&lookup_result.outputs))
+                        .await;
+                    // The cached result stands, unless the command run on the hit fails.
+                    if self.config.fallback_command.is_some() && self.config.restore_to.is_none() {
+                        let exit_code = self.execute_on_hit(&inputs, program_run).await?;
+                        if exit_code != 0 {
+                            return Ok(exit_code);
                         }
                     }
-                } else {
-                    error!("Time out downloading files");
+                    return Ok(lookup_result.outputs.result_code().unwrap_or(Self::DEFAULT_EXIT_CODE));
+                }
+                Ok(Err(err)) => {
+                    cache_decision = CacheDecision::IgnoredDownloadError;
+                    cache_decision_detail = Some(format!("{:#}", err));
+                    self.log_cache_decision(cache_decision, Some(lookup_result), cache_decision_detail.as_deref());
+                }
+                Err(_) => {
+                    cache_decision = CacheDecision::IgnoredDownloadError;
+                    cache_decision_detail = Some("time out downloading files".to_owned());
+                    self.log_cache_decision(cache_decision, Some(lookup_result), cache_decision_detail.as_deref());
                 }
             }
+            self.remove_ready_list().unwrap_or_else(|err| {
+                error!("Failed to remove the ready list: {:#}", err);
+            });
+        } else {
+            self.log_cache_decision(cache_decision, lookup_result.as_ref(), None);
+        }
+
+        // Restoring the outputs elsewhere is only for cache hits, as the command would write them in place.
+        if let Some(restore_to) = &self.config.restore_to {
+            bail!("Nothing to restore to '{}': {}", restore_to, cache_decision);
         }
 
         // If we got here, we should execute.
-        self.execute_and_cache(&inputs, &lookup_result, program_run)
-            .await
-            .map(|exit_status| exit_status.code().unwrap_or(Self::DEFAULT_EXIT_CODE))
+        self.execute_and_cache(
+            &inputs,
+            &lookup_result,
+            cache_decision,
+            cache_decision_detail.as_deref(),
+            program_run,
+        )
+        .await
+        .map(Self::exit_code)
     }
 }
 
@@ -433,6 +1943,8 @@ mod tests {
     use super::*;
     use crate::caching::dummy;
     use crate::caching::test::{TestBackend, TestBackendConfig};
+    use crate::clock::TestClock;
+    use crate::config::Backend;
     use crate::observability::dummy::Dummy;
     use serial_test::serial;
     use tempfile::TempDir;
@@ -476,6 +1988,142 @@ mod tests {
         assert_eq!(out_file_contents, EMPTY_SHA256);
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_signal() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--cache_failure",
+                "--",
+                "/bin/bash",
+                "-c",
+                "kill -SEGV $$",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        for cache_hit in [false, true] {
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let mut program_run = AtomicBool::new(false);
+            let code = capsule.run_capsule(&mut program_run).await.unwrap();
+            assert_eq!(code, 128 + 11);
+            assert_eq!(program_run.load(Ordering::SeqCst), !cache_hit);
+        }
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().unwrap();
+        assert!(entry
+            .outputs
+            .hash_details
+            .iter()
+            .any(|(output, _)| *output == Output::Signal(11)));
+
+        // The signal is not confused with the exit code of the same value.
+        let signaled = capsule.read_outputs(Some(ExitStatus::from_raw(11)), vec![]).unwrap();
+        let exited = capsule
+            .read_outputs(Some(ExitStatus::from_raw(139 << 8)), vec![])
+            .unwrap();
+        assert_eq!(signaled.result_code(), exited.result_code());
+        assert_ne!(signaled.hash, exited.hash);
+
+        // Without --cache_failure, the signaled run is not used.
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--",
+                "/bin/bash",
+                "-c",
+                "kill -SEGV $$",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_backend_none() {
+        let tmp_dir = TempDir::new().unwrap();
+        let out_file = tmp_dir.path().join("xx");
+        let backend = TestBackend::new("-", TestBackendConfig::default());
+        // No capsule ID is needed.
+        let config = Config::new(
+            [
+                "capsule",
+                "-b",
+                "none",
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo -n ${{CAPSULE_INPUTS_HASH}} >> {}", out_file.to_string_lossy()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        assert!(matches!(config.backend, Backend::None));
+        assert!(config.passive);
+        // The command is run every time, as nothing is cached.
+        for _ in 0..2 {
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let mut program_run = AtomicBool::new(false);
+            capsule.run_capsule(&mut program_run).await.unwrap();
+            assert!(program_run.load(Ordering::SeqCst));
+        }
+        let inputs = Capsule::new(&config, &backend, &Dummy).read_inputs().unwrap();
+        assert!(backend.lookup(&inputs).await.unwrap().is_none());
+        let out_file_contents = std::fs::read_to_string(out_file).unwrap();
+        assert_eq!(out_file_contents, EMPTY_SHA256.repeat(2));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_capsule_inputs_hash_env_short() {
+        let tmp_dir = TempDir::new().unwrap();
+        let out_file = tmp_dir.path().join("xx");
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--inputs_hash_format",
+                "short",
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo -n ${{CAPSULE_INPUTS_HASH}} > {}", out_file.to_string_lossy()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let _ = capsule.run_capsule(&mut program_run).await.unwrap();
+        let out_file_contents = std::fs::read_to_string(out_file).unwrap();
+        assert_eq!(out_file_contents, &EMPTY_SHA256[..12]);
+        // The cache key is still the full hash.
+        let inputs = capsule.read_inputs().unwrap();
+        assert_eq!(inputs.hash, EMPTY_SHA256);
+        assert!(backend.lookup(&inputs).await.unwrap().is_some());
+    }
+
     #[test]
     #[serial]
     fn test_nonexistent_glob() {
@@ -504,6 +2152,33 @@ mod tests {
         assert!(inputs.unwrap().hash_details[0].0 == Input::File(Path::new("/bin/echo").into()));
     }
 
+    #[test]
+    #[serial]
+    fn test_max_inputs() {
+        let backend = dummy::DummyBackend::default();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-i",
+                "/bin/*",
+                "--max_inputs",
+                "1",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let err = capsule.read_inputs().unwrap_err();
+        assert!(format!("{:#}", err).contains("Pattern '/bin/*' exceeds the limit of 1 input files"));
+    }
+
     #[test]
     #[serial]
     fn test_invalid_glob() {
@@ -517,31 +2192,17 @@ mod tests {
         assert!(capsule.read_inputs().is_err());
     }
 
-    fn create_file_tree(dir: &Path) -> PathBuf {
-        let root = dir.join("root");
-        fs::create_dir_all(root.join("dir1").join("subdir1")).unwrap();
-        fs::create_dir_all(root.join("dir2").join("subdir2")).unwrap();
-        File::create(root.join("123")).unwrap();
-        File::create(root.join("dir1").join("111")).unwrap();
-        File::create(root.join("dir1").join("222")).unwrap();
-        File::create(root.join("dir2").join("subdir2").join("111")).unwrap();
-        File::create(root.join("dir2").join("subdir2").join("222")).unwrap();
-        root
-    }
-
     #[test]
     #[serial]
-    fn test_recursive_glob() {
-        let tmp_dir = TempDir::new().unwrap();
-        let root = create_file_tree(tmp_dir.path());
+    fn test_tool_tag_cmd() {
         let backend = dummy::DummyBackend::default();
         let config = Config::new(
             [
                 "capsule",
                 "-c",
                 "wtf",
-                "-i",
-                &format!("{}/**/111", root.to_str().unwrap()),
+                "--tool_tag_cmd",
+                "/bin/echo 'foo  bar'",
                 "--",
                 "/bin/echo",
             ]
@@ -550,7 +2211,153 @@ mod tests {
         )
         .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
-        let inputs = capsule.read_inputs();
+        let inputs = capsule.read_inputs().unwrap();
+        assert_eq!(
+            inputs.hash_details[0].0,
+            Input::ToolTag("/bin/echo 'foo  bar': foo  bar".to_owned())
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_tool_tag_cmd_failure() {
+        let backend = dummy::DummyBackend::default();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--tool_tag_cmd",
+                "/bin/false",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert!(capsule.read_inputs().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_tool_tag_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let lockfile = tmp_dir.path().join("toolchain.lock");
+        std::fs::write(&lockfile, "").unwrap();
+        let lockfile_name = lockfile.to_str().unwrap();
+        let backend = dummy::DummyBackend::default();
+        let config_with = |extra_args: &[&str]| {
+            let args = ["capsule", "-c", "wtf", "--tool_tag_file", lockfile_name];
+            let args = args.iter().chain(extra_args).chain(&["--", "/bin/echo"]);
+            Config::new(args, None).unwrap()
+        };
+        let config = config_with(&[]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let inputs = capsule.read_inputs().unwrap();
+        assert_eq!(
+            inputs.hash_details[0].0,
+            Input::ToolTag(format!("file {}: {}", lockfile_name, EMPTY_SHA256))
+        );
+        assert_eq!(inputs.file_count, 0);
+
+        // In the workspace, the tag doesn't depend on where the workspace is checked out.
+        let workspace_config = config_with(&["-w", tmp_dir.path().to_str().unwrap()]);
+        let workspace_capsule = Capsule::new(&workspace_config, &backend, &Dummy);
+        assert_eq!(
+            workspace_capsule.read_inputs().unwrap().hash_details[0].0,
+            Input::ToolTag(format!("file //toolchain.lock: {}", EMPTY_SHA256))
+        );
+
+        std::fs::remove_file(&lockfile).unwrap();
+        let err = capsule.read_inputs().unwrap_err();
+        assert!(format!("{:#}", err).contains("Hashing tool tag file"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_git_tool_tag() {
+        let tmp_dir = TempDir::new().unwrap();
+        let workspace_root = tmp_dir.path().to_str().unwrap();
+        let backend = dummy::DummyBackend::default();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-w",
+                workspace_root,
+                "--git_tool_tag",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        // Not a git repository yet.
+        assert!(capsule.read_inputs().is_err());
+
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=capsule", "-c", "user.email=capsule@localhost"])
+                .args(args)
+                .current_dir(workspace_root)
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "initial"]);
+        let tool_tag = |inputs: InputHashBundle| match &inputs.hash_details[0].0 {
+            Input::ToolTag(tag) => tag.clone(),
+            input => panic!("Unexpected input {:?}", input),
+        };
+        let clean_tag = tool_tag(capsule.read_inputs().unwrap());
+        assert!(clean_tag.starts_with("git HEAD: "));
+        assert!(!clean_tag.ends_with(" (dirty)"));
+
+        std::fs::write(tmp_dir.path().join("file"), "").unwrap();
+        assert_eq!(tool_tag(capsule.read_inputs().unwrap()), clean_tag + " (dirty)");
+    }
+
+    fn create_file_tree(dir: &Path) -> PathBuf {
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("dir1").join("subdir1")).unwrap();
+        fs::create_dir_all(root.join("dir2").join("subdir2")).unwrap();
+        File::create(root.join("123")).unwrap();
+        File::create(root.join("dir1").join("111")).unwrap();
+        File::create(root.join("dir1").join("222")).unwrap();
+        File::create(root.join("dir2").join("subdir2").join("111")).unwrap();
+        File::create(root.join("dir2").join("subdir2").join("222")).unwrap();
+        root
+    }
+
+    #[test]
+    #[serial]
+    fn test_recursive_glob() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = create_file_tree(tmp_dir.path());
+        let backend = dummy::DummyBackend::default();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                &format!("{}/**/111", root.to_str().unwrap()),
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let inputs = capsule.read_inputs();
         assert!(inputs.is_ok());
         let inputs = inputs.unwrap();
         assert_eq!(
@@ -713,7 +2520,7 @@ mod tests {
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_miss() {
+    async fn test_inspect() {
         let tmp_dir = TempDir::new().unwrap();
         let backend = TestBackend::new("wtf", TestBackendConfig::default());
         let out_file_1 = tmp_dir.path().join("xx");
@@ -722,6 +2529,8 @@ mod tests {
                 "capsule",
                 "-c",
                 "wtf",
+                "-j",
+                "https://wtfjob.org",
                 "-i",
                 "/bin/echo",
                 "-o",
@@ -739,38 +2548,100 @@ mod tests {
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        assert!(program_run.load(Ordering::SeqCst));
 
-        std::fs::remove_file(&out_file_1).unwrap();
-
-        backend.remove_all();
+        let inputs = capsule.read_inputs().unwrap();
+        let bundle = capsule.lookup_inputs_hash(&inputs.hash).await.unwrap().unwrap();
+        let description = describe_bundle(&bundle);
+        assert!(description.contains(&format!("Inputs hash: {}\n", inputs.hash)));
+        assert!(description.contains("Source: https://wtfjob.org\n"));
+        assert!(description.contains(&format!("  file {} (present, mode ", out_file_1.to_str().unwrap())));
+        assert!(description.contains("  exit code 0: "));
+        assert!(capsule.lookup_inputs_hash("nonexistent").await.unwrap().is_none());
+    }
 
-        // 2nd should NOT be cached, and command run.
+    #[tokio::test]
+    #[serial]
+    async fn test_record_command() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--record_command",
+                "-i",
+                "/bin/echo",
+                "--",
+                "/bin/bash",
+                "-c",
+                "echo 123",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
-        let code = capsule.run_capsule(&mut program_run).await.unwrap();
-        assert_eq!(code, 0);
-        // The 2nd time the program should be run, as there's no cache hit.
-        assert!(program_run.load(Ordering::SeqCst));
+        capsule.run_capsule(&mut program_run).await.unwrap();
 
-        assert!(out_file_1.is_file());
+        let inputs = capsule.read_inputs().unwrap();
+        let bundle = backend.lookup(&inputs).await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_value(&bundle.metadata).unwrap(),
+            serde_json::json!({"command": ["/bin/bash", "-c", "echo 123"]})
+        );
+        assert!(describe_bundle(&bundle).contains("  command: [\"/bin/bash\",\"-c\",\"echo 123\"]\n"));
+
+        // Compared with an entry written without it, e.g. by an older capsule.
+        let mut unrecorded = bundle.clone();
+        unrecorded.inputs.hash = "other".to_owned();
+        unrecorded.metadata.clear();
+        let description = describe_bundles_diff(&bundle, &unrecorded).unwrap();
+        assert!(description.ends_with("Command: /bin/bash -c 'echo 123' vs not recorded\n"));
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_miss_capsule_id() {
+    async fn test_selftest() {
+        let config = Config::new(["capsule", "--selftest"].iter(), None).unwrap();
+        assert!(config.selftest);
+        let backend = TestBackend::new("-", TestBackendConfig::default());
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(capsule.selftest().await.unwrap(), 0);
+
+        let backend = TestBackend::new(
+            "-",
+            TestBackendConfig {
+                truncated_downloads: 1,
+                ..Default::default()
+            },
+        );
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(capsule.selftest().await.unwrap(), 1);
+
+        let backend = dummy::DummyBackend::default();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(capsule.selftest().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_output_manifest() {
         let tmp_dir = TempDir::new().unwrap();
-        let backend = TestBackend::new("wtf1", TestBackendConfig::default());
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
         let out_file_1 = tmp_dir.path().join("xx");
+        let manifest = tmp_dir.path().join("manifest.json");
         let config = Config::new(
             [
                 "capsule",
                 "-c",
-                "wtf1",
+                "wtf",
                 "-i",
                 "/bin/echo",
                 "-o",
                 out_file_1.to_str().unwrap(),
+                "--output_manifest",
+                manifest.to_str().unwrap(),
                 "--",
                 "/bin/bash",
                 "-c",
@@ -782,26 +2653,44 @@ mod tests {
         .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
-        let code = capsule.run_capsule(&mut program_run).await.unwrap();
-        assert_eq!(code, 0);
+        capsule.run_capsule(&mut program_run).await.unwrap();
         assert!(program_run.load(Ordering::SeqCst));
+        let fresh_manifest = std::fs::read_to_string(&manifest).unwrap();
+        let files: BTreeMap<String, String> = serde_json::from_str(&fresh_manifest).unwrap();
+        // Only the output file is listed, with its object hash.
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[out_file_1.to_str().unwrap()], file_hash(&out_file_1).unwrap());
 
-        std::fs::remove_file(&out_file_1).unwrap();
+        // The manifest from the cache hit should be identical.
+        std::fs::remove_file(&manifest).unwrap();
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert_eq!(std::fs::read_to_string(&manifest).unwrap(), fresh_manifest);
+    }
 
-        let backend = TestBackend::new("wtf2", TestBackendConfig::default());
+    #[tokio::test]
+    #[serial]
+    async fn test_attach_metadata() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let attach_file = tmp_dir.path().join("provenance.json");
+        std::fs::write(&attach_file, r#"{"git_sha": "abcdef", "builder": {"host": "ci-1"}}"#).unwrap();
         let config = Config::new(
             [
                 "capsule",
                 "-c",
-                "wtf2", // The only difference is capsule_id, but it should not cache.
+                "wtf",
                 "-i",
                 "/bin/echo",
-                "-o",
-                out_file_1.to_str().unwrap(),
+                "--attach_file",
+                attach_file.to_str().unwrap(),
+                "--attach",
+                "git_sha=123456",
+                "--attach",
+                "job=https://wtfjob.org",
                 "--",
-                "/bin/bash",
-                "-c",
-                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+                "/bin/echo",
             ]
             .iter(),
             None,
@@ -809,47 +2698,125 @@ mod tests {
         .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
-        let code = capsule.run_capsule(&mut program_run).await.unwrap();
-        assert_eq!(code, 0);
-        // The 2nd time the program should be run, as there's no cache hit.
-        assert!(program_run.load(Ordering::SeqCst));
+        capsule.run_capsule(&mut program_run).await.unwrap();
 
-        assert!(out_file_1.is_file());
+        let inputs = capsule.read_inputs().unwrap();
+        let metadata = backend.lookup(&inputs).await.unwrap().unwrap().metadata;
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            serde_json::json!({
+                "builder": {"host": "ci-1"},
+                "git_sha": "123456",
+                "job": "https://wtfjob.org",
+            })
+        );
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_failed_lookup() {
-        let backend = TestBackend::new(
-            "wtf",
-            TestBackendConfig {
-                failing_lookup: true,
-                ..Default::default()
-            },
+    async fn test_include_hostname_tag() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let new_config = |flags: &[&str]| {
+            let mut args = vec!["capsule", "-c", "wtf", "-i", "/bin/echo"];
+            args.extend(flags);
+            args.extend(["--", "/bin/echo"]);
+            Config::new(args.into_iter(), None).unwrap()
+        };
+        let config = new_config(&["--include_hostname_tag"]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        capsule.run_capsule(&mut AtomicBool::new(false)).await.unwrap();
+
+        let mut buffer = [0u8; 256];
+        let hostname = nix::unistd::gethostname(&mut buffer).unwrap().to_str().unwrap();
+        let inputs = capsule.read_inputs().unwrap();
+        let metadata = backend.lookup(&inputs).await.unwrap().unwrap().metadata;
+        assert_eq!(metadata["hostname"], hostname);
+        assert_eq!(
+            metadata["platform"],
+            format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
         );
+
+        // The cache key is the same without the tag, so the entry is a hit.
+        let config = new_config(&[]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(capsule.read_inputs().unwrap().hash, inputs.hash);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[serial]
+    fn test_fifo_output() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let fifo = tmp_dir.path().join("fifo");
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).unwrap();
         let config = Config::new(
-            ["capsule", "-c", "wtf", "-i", "/bin/echo", "--", "/bin/echo"].iter(),
+            ["capsule", "-c", "wtf", "-o", fifo.to_str().unwrap(), "--", "/bin/echo"].iter(),
             None,
         )
         .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
-        let mut program_run = AtomicBool::new(false);
-        let code = capsule.run_capsule(&mut program_run).await;
-        assert!(code.is_err());
+        let err = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unsupported output file type (named pipe)"));
+    }
+
+    #[test]
+    fn test_exclude_output() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let pattern = tmp_dir.path().join("*");
+        std::fs::write(tmp_dir.path().join("out.txt"), "output").unwrap();
+        std::fs::write(tmp_dir.path().join("out.d"), "deps").unwrap();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-o",
+                pattern.to_str().unwrap(),
+                "--exclude_output",
+                "*.d",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let file_outputs = |outputs: OutputHashBundle| -> Vec<(String, bool)> {
+            outputs
+                .hash_details
+                .into_iter()
+                .filter_map(|(output, _)| match output {
+                    Output::File(fileoutput) => Some((fileoutput.filename.to_string(), fileoutput.present)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let outputs = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap();
+        assert_eq!(
+            file_outputs(outputs),
+            vec![(tmp_dir.path().join("out.txt").to_str().unwrap().to_owned(), true)]
+        );
+
+        // With only excluded files left, the output is absent.
+        std::fs::remove_file(tmp_dir.path().join("out.txt")).unwrap();
+        let outputs = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap();
+        assert_eq!(
+            file_outputs(outputs),
+            vec![(pattern.to_str().unwrap().to_owned(), false)]
+        );
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_hit_failure_object() {
+    async fn test_recursive_output_dirs() {
         let tmp_dir = TempDir::new().unwrap();
-        let backend = TestBackend::new(
-            "wtf",
-            TestBackendConfig {
-                failing_download_files: true,
-                ..Default::default()
-            },
-        );
-        let out_file_1 = tmp_dir.path().join("xx");
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let dist = tmp_dir.path().join("dist");
         let config = Config::new(
             [
                 "capsule",
@@ -858,11 +2825,15 @@ mod tests {
                 "-i",
                 "/bin/echo",
                 "-o",
-                out_file_1.to_str().unwrap(),
+                dist.join("**").to_str().unwrap(),
+                "--preserve_empty_dirs",
                 "--",
                 "/bin/bash",
                 "-c",
-                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+                &format!(
+                    "cd {} && mkdir -p dist/a/b dist/empty/sub && echo 123 > dist/a/b/file",
+                    tmp_dir.path().to_str().unwrap()
+                ),
             ]
             .iter(),
             None,
@@ -872,25 +2843,33 @@ mod tests {
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        assert!(program_run.load(Ordering::SeqCst));
-
-        std::fs::remove_file(&out_file_1).unwrap();
+        let outputs = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap();
+        let dirs: Vec<_> = outputs
+            .hash_details
+            .iter()
+            .filter_map(|(output, _)| match output {
+                Output::Dir(dirname) => Some(dirname.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(dirs, vec![dist.join("empty/sub").to_str().unwrap().to_owned()]);
 
+        std::fs::remove_dir_all(&dist).unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        assert!(program_run.load(Ordering::SeqCst));
-        assert!(out_file_1.is_file());
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert_eq!(std::fs::read_to_string(dist.join("a/b/file")).unwrap(), "123\n");
+        assert!(dist.join("empty/sub").is_dir());
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_hit_permissions() {
+    async fn test_directory_output() {
         let tmp_dir = TempDir::new().unwrap();
         let backend = TestBackend::new("wtf", TestBackendConfig::default());
-        let out_file = tmp_dir.path().join("xx");
-        let out_file_name = out_file.to_string_lossy();
+        let scratch = tmp_dir.path().join("scratch");
         let config = Config::new(
             [
                 "capsule",
@@ -899,11 +2878,11 @@ mod tests {
                 "-i",
                 "/bin/echo",
                 "-o",
-                &out_file_name,
+                &format!("{}/", scratch.to_str().unwrap()),
                 "--",
                 "/bin/bash",
                 "-c",
-                &format!("echo '123' > {}; chmod 755 {}", out_file_name, out_file_name),
+                &format!("mkdir -m 700 {0} && echo 123 > {0}/file", scratch.to_str().unwrap()),
             ]
             .iter(),
             None,
@@ -913,34 +2892,34 @@ mod tests {
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        assert!(program_run.load(Ordering::SeqCst));
+        let outputs = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap();
+        assert!(outputs.hash_details.iter().any(|(output, _)| *output
+            == Output::Directory {
+                dirname: WorkspacePath::from(scratch.to_str().unwrap()),
+                mode: 0o700
+            }));
+        assert_eq!(outputs.file_count, 0);
 
-        std::fs::remove_file(&out_file).unwrap();
-
-        let capsule = Capsule::new(&config, &backend, &Dummy);
+        // The directory is recreated empty, with its mode.
+        std::fs::remove_dir_all(&scratch).unwrap();
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        // The 2nd time the program should NOT run.
         assert!(!program_run.load(Ordering::SeqCst));
-        assert!(out_file.is_file());
-        assert_eq!(out_file.metadata().unwrap().permissions().mode() & 0o777, 0o755);
+        assert!(scratch.is_dir());
+        assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+        assert_eq!(scratch.metadata().unwrap().permissions().mode() & 0o777, 0o700);
+
+        // A missing directory is an error.
+        std::fs::remove_dir_all(&scratch).unwrap();
+        assert!(capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).is_err());
     }
 
     #[tokio::test]
     #[serial]
-    // Here the logic changed. OutputBundles contain the information about whether an output file
-    // was present when the cache entry was created.  Before 0.2.9, once we had cache hit with the
-    // output file absent, capsule would try to make sure that the file is removed. In reality an
-    // absent file is usually a miconfiguration. Fixing that misconfiguration should then fix the
-    // job and caching, but with the old logic it would just get cache hits with no output.
-    // So this test actuall checks that the file is *not* removed on cache hit with a file 'not present',
-    // and that the cache hit is ignored.
-    async fn test_cache_file_removal() {
-        let tmp_dir = TempDir::new().unwrap();
+    async fn test_capture_stdout() {
         let backend = TestBackend::new("wtf", TestBackendConfig::default());
-        let out_file = tmp_dir.path().join("xx");
-        let out_file_name = out_file.to_string_lossy();
+        // Both stdout and stderr are captured, stdout is large enough to be stored as an object.
         let config = Config::new(
             [
                 "capsule",
@@ -948,10 +2927,14 @@ mod tests {
                 "wtf",
                 "-i",
                 "/bin/echo",
-                "-o",
-                &out_file_name,
+                "--capture_stdout",
+                "--capture_stderr",
+                "--capture_inline_threshold_bytes",
+                "100",
                 "--",
-                "/bin/echo",
+                "/bin/bash",
+                "-c",
+                "printf 'x%.0s' {1..200}; echo 'err' >&2",
             ]
             .iter(),
             None,
@@ -963,34 +2946,135 @@ mod tests {
         assert_eq!(code, 0);
         assert!(program_run.load(Ordering::SeqCst));
 
-        // Create the file
-        std::fs::File::create(&out_file).unwrap();
-        assert!(out_file.exists());
+        let inputs = capsule.read_inputs().unwrap();
+        let outputs = backend.lookup(&inputs).await.unwrap().unwrap().outputs;
+        assert!(outputs
+            .hash_details
+            .iter()
+            .any(|(output, _)| *output == Output::StdoutObject(200)));
+        assert!(outputs
+            .hash_details
+            .iter()
+            .any(|(output, _)| *output == Output::Stderr(b"err\n".to_vec())));
 
-        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let captured = capsule.fetch_captured(&outputs).await.unwrap();
+        assert!(captured.contains(&Output::Stdout(vec![b'x'; 200])));
+        assert!(captured.contains(&Output::Stderr(b"err\n".to_vec())));
+
+        // The 2nd time the program should NOT run.
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        // The 2nd time the program should NOT run.
+        assert!(!program_run.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_repeat() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file_1 = tmp_dir.path().join("xx");
+        let runs_file = tmp_dir.path().join("runs");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_1.to_str().unwrap(),
+                "--repeat",
+                "3",
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!(
+                    "echo '123' > {}; echo run >> {}",
+                    out_file_1.to_str().unwrap(),
+                    runs_file.to_str().unwrap()
+                ),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.repeat, Some(3));
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule_repeated(3, &mut program_run).await.unwrap();
+        assert_eq!(code, 0);
         assert!(program_run.load(Ordering::SeqCst));
+        // The program only ran the first time, and the output was restored from the cache after.
+        assert_eq!(std::fs::read_to_string(&runs_file).unwrap(), "run\n");
+        assert_eq!(std::fs::read_to_string(&out_file_1).unwrap(), "123\n");
 
-        // Because the out file was not present when the run was cached, we should expect it
-        // to be removed.
-        assert!(out_file.exists());
+        let config = Config::new(
+            ["capsule", "-c", "wtf", "--repeat", "0", "--", "/bin/echo"].iter(),
+            None,
+        );
+        assert!(config.is_err());
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_lookup_timeout() {
+    async fn test_cache_hit_up_to_date_file() {
         let tmp_dir = TempDir::new().unwrap();
+        // Downloads fail, so that only the files that are up to date can be restored.
         let backend = TestBackend::new(
             "wtf",
             TestBackendConfig {
-                lookup_timeout: true,
+                failing_download_files: true,
                 ..Default::default()
             },
         );
         let out_file_1 = tmp_dir.path().join("xx");
+        let args = |force_download: bool| {
+            let mut args = vec![
+                "capsule".to_owned(),
+                "-c".to_owned(),
+                "wtf".to_owned(),
+                "-i".to_owned(),
+                "/bin/echo".to_owned(),
+                "-o".to_owned(),
+                out_file_1.to_str().unwrap().to_owned(),
+            ];
+            if force_download {
+                args.push("--force_download".to_owned());
+            }
+            args.extend([
+                "--".to_owned(),
+                "/bin/bash".to_owned(),
+                "-c".to_owned(),
+                format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+            ]);
+            args
+        };
+        let config = Config::new(args(false), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // The output file is up to date, so it's a cache hit without a download.
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+
+        // With --force_download, the download is attempted, and fails.
+        let config = Config::new(args(true), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_touch_outputs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file_1 = tmp_dir.path().join("xx");
         let config = Config::new(
             [
                 "capsule",
@@ -1000,6 +3084,7 @@ mod tests {
                 "/bin/echo",
                 "-o",
                 out_file_1.to_str().unwrap(),
+                "--touch_outputs",
                 "--",
                 "/bin/bash",
                 "-c",
@@ -1009,22 +3094,86 @@ mod tests {
             None,
         )
         .unwrap();
-        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+        let capsule = Capsule::new(&config, &backend, &Dummy).with_clock(&clock);
         let mut program_run = AtomicBool::new(false);
-        capsule.run_capsule(&mut program_run).await.unwrap_err();
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // Make the output file look old, as if its prerequisites were newer.
+        let old = TimeVal::seconds(1_000_000_000);
+        nix::sys::stat::utimes(&out_file_1, &old, &old).unwrap();
+
+        // The up to date output file is not downloaded, but its mtime is updated.
+        clock.advance(Duration::from_secs(60));
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        let mtime = fs::metadata(&out_file_1).unwrap().modified().unwrap();
+        assert_eq!(mtime, UNIX_EPOCH + Duration::from_secs(1_500_000_060));
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_download_timeout() {
+    async fn test_cache_decision() {
         let tmp_dir = TempDir::new().unwrap();
-        let backend = TestBackend::new(
-            "wtf",
-            TestBackendConfig {
-                download_timeout: true,
-                ..Default::default()
-            },
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file_1 = tmp_dir.path().join("xx");
+        let out_file_2 = tmp_dir.path().join("yy");
+        let config_with = |output: &Path, exit_code: i32, extra: &[&str]| {
+            let command = format!("echo '123' > {}; exit {}", output.to_str().unwrap(), exit_code);
+            let mut args = vec![
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                output.to_str().unwrap(),
+            ];
+            args.extend_from_slice(extra);
+            args.extend_from_slice(&["--", "/bin/bash", "-c", &command]);
+            Config::new(args.iter(), None).unwrap()
+        };
+
+        let config = config_with(&out_file_1, 0, &[]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let inputs = capsule.read_inputs().unwrap();
+        assert_eq!(capsule.cache_decision(&None).unwrap(), CacheDecision::MissNoEntry);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let lookup_result = backend.lookup(&inputs).await.unwrap();
+        assert_eq!(capsule.cache_decision(&lookup_result).unwrap(), CacheDecision::Hit);
+
+        // The same entry doesn't match the output patterns of a different config.
+        let config = config_with(&out_file_2, 0, &[]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(
+            capsule.cache_decision(&lookup_result).unwrap(),
+            CacheDecision::IgnoredOutputMismatch
+        );
+
+        // Cached failures are only used with --cache_failure.
+        let config = config_with(&out_file_2, 1, &[]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let inputs = capsule.read_inputs().unwrap();
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let lookup_result = backend.lookup(&inputs).await.unwrap();
+        assert_eq!(
+            capsule.cache_decision(&lookup_result).unwrap(),
+            CacheDecision::IgnoredFailure
         );
+        let config = config_with(&out_file_2, 1, &["--cache_failure"]);
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(capsule.cache_decision(&lookup_result).unwrap(), CacheDecision::Hit);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_miss() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
         let out_file_1 = tmp_dir.path().join("xx");
         let config = Config::new(
             [
@@ -1052,34 +3201,30 @@ mod tests {
 
         std::fs::remove_file(&out_file_1).unwrap();
 
-        // Running 2nd time, expect a cache hit, but a download problem.
+        backend.remove_all();
+
+        // 2nd should NOT be cached, and command run.
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
-        // Returns ok, despite the download problem, as it would just execute the program
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-
-        // The 2nd time the program should be run, because of timeout downloading.
+        // The 2nd time the program should be run, as there's no cache hit.
         assert!(program_run.load(Ordering::SeqCst));
+
+        assert!(out_file_1.is_file());
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_write_timeout() {
+    async fn test_cache_miss_capsule_id() {
         let tmp_dir = TempDir::new().unwrap();
-        let backend = TestBackend::new(
-            "wtf",
-            TestBackendConfig {
-                write_timeout: true,
-                ..Default::default()
-            },
-        );
+        let backend = TestBackend::new("wtf1", TestBackendConfig::default());
         let out_file_1 = tmp_dir.path().join("xx");
         let config = Config::new(
             [
                 "capsule",
                 "-c",
-                "wtf",
+                "wtf1",
                 "-i",
                 "/bin/echo",
                 "-o",
@@ -1096,30 +3241,101 @@ mod tests {
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
-        // Despite the write errors, the capsule successfully executed the program,
-        // so the return code is zero.
         assert_eq!(code, 0);
         assert!(program_run.load(Ordering::SeqCst));
 
-        // 2nd capsule, should NOT be cached, as the capsule call above failed to write to the
-        // cache, despite successful completion of the underlying program.
+        std::fs::remove_file(&out_file_1).unwrap();
+
+        let backend = TestBackend::new("wtf2", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf2", // The only difference is capsule_id, but it should not cache.
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_1.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-        // The program must have been run.
+        // The 2nd time the program should be run, as there's no cache hit.
         assert!(program_run.load(Ordering::SeqCst));
+
         assert!(out_file_1.is_file());
     }
 
     #[tokio::test]
     #[serial]
-    async fn test_cache_upload_timeout() {
+    async fn test_cache_failed_lookup() {
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                failing_lookup: true,
+                ..Default::default()
+            },
+        );
+        let config = Config::new(
+            ["capsule", "-c", "wtf", "-i", "/bin/echo", "--", "/bin/echo"].iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await;
+        assert!(code.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_failed_lookup_passive() {
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                failing_lookup: true,
+                ..Default::default()
+            },
+        );
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--passive_on_missing_backend",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_hit_failure_object() {
         let tmp_dir = TempDir::new().unwrap();
         let backend = TestBackend::new(
             "wtf",
             TestBackendConfig {
-                upload_timeout: true,
+                failing_download_files: true,
                 ..Default::default()
             },
         );
@@ -1145,20 +3361,1639 @@ mod tests {
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
-        // Despite the upload errors, the capsule successfully executed the program,
-        // so the return code is zero.
         assert_eq!(code, 0);
         assert!(program_run.load(Ordering::SeqCst));
 
-        // 2nd capsule, should NOT be cached, as the capsule call above failed to upload to the
-        // cache, despite successful completion of the underlying program.
+        std::fs::remove_file(&out_file_1).unwrap();
+
         let capsule = Capsule::new(&config, &backend, &Dummy);
         let mut program_run = AtomicBool::new(false);
         let code = capsule.run_capsule(&mut program_run).await.unwrap();
         assert_eq!(code, 0);
-
-        // The program must have been run.
         assert!(program_run.load(Ordering::SeqCst));
         assert!(out_file_1.is_file());
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_hit_marker_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let marker = tmp_dir.path().join("marker");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--hit_marker_file",
+                marker.to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        for expected in ["miss\n", "hit\n"] {
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let mut program_run = AtomicBool::new(false);
+            capsule.run_capsule(&mut program_run).await.unwrap();
+            assert_eq!(std::fs::read_to_string(&marker).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_ready_list() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let ready_list = tmp_dir.path().join("ready");
+        let outputs = [tmp_dir.path().join("a"), tmp_dir.path().join("b")];
+        let command = format!("echo a > {}; echo b > {}", outputs[0].display(), outputs[1].display());
+        let pattern = format!("{}/[ab]", tmp_dir.path().display());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                &pattern,
+                "--ready_list",
+                ready_list.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        // The list is only written when the outputs are restored from the cache, and the one left by an
+        // earlier run is removed on miss.
+        std::fs::write(&ready_list, "stale\n").unwrap();
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!ready_list.exists());
+
+        std::fs::remove_file(&outputs[0]).unwrap();
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let contents = std::fs::read_to_string(&ready_list).unwrap();
+        // The empty line marks the list as complete.
+        let (files, rest) = contents.split_once("\n\n").unwrap();
+        assert_eq!(rest, "");
+        let mut ready: Vec<_> = files.lines().map(PathBuf::from).collect();
+        ready.sort();
+        // The output that is already up to date is ready too.
+        assert_eq!(ready, outputs);
+
+        // When the download fails, the command is run instead, and the list is removed.
+        let missing_hash = file_hash(&outputs[0]).unwrap();
+        backend.remove_object_file(&missing_hash).await.unwrap();
+        std::fs::remove_file(&outputs[0]).unwrap();
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+        assert!(!ready_list.exists());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_log_inputs_on_miss() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let log = tmp_dir.path().join("inputs.json");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-t",
+                "tag",
+                "--log_inputs_on_miss",
+                log.to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let inputs: InputHashBundle = serde_json::from_str(&std::fs::read_to_string(&log).unwrap()).unwrap();
+        assert_eq!(inputs.hash, capsule.read_inputs().unwrap().hash);
+        assert_eq!(inputs.hash_details.len(), 2);
+
+        // Nothing is written on cache hit.
+        std::fs::remove_file(&log).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(!log.exists());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_dump_bundle() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let dump = tmp_dir.path().join("bundle.json");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-j",
+                "job_1",
+                "-i",
+                "/bin/echo",
+                "--attach",
+                "key=value",
+                "--dump_bundle",
+                dump.to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+        let written = std::fs::read_to_string(&dump).unwrap();
+        let bundle: InputOutputBundle = serde_json::from_str(&written).unwrap();
+        assert_eq!(bundle.inputs.hash, capsule.read_inputs().unwrap().hash);
+        assert_eq!(bundle.source, "job_1");
+        assert_eq!(bundle.metadata["key"], "value");
+        assert_eq!(bundle.outputs.result_code(), Some(0));
+
+        // On cache hit, the same entry is dumped.
+        std::fs::remove_file(&dump).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert_eq!(std::fs::read_to_string(&dump).unwrap(), written);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_download_retries() {
+        let tmp_dir = TempDir::new().unwrap();
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_str().unwrap();
+        for (retries, truncated_downloads, cache_hit) in [("1", 1, true), ("0", 1, false), ("2", 3, false)] {
+            let backend = TestBackend::new(
+                "wtf",
+                TestBackendConfig {
+                    truncated_downloads,
+                    ..Default::default()
+                },
+            );
+            let config = Config::new(
+                [
+                    "capsule",
+                    "-c",
+                    "wtf",
+                    "-i",
+                    "/bin/echo",
+                    "-o",
+                    out_file_name,
+                    "--download_retries",
+                    retries,
+                    "--",
+                    "/bin/bash",
+                    "-c",
+                    &format!("echo '123' > {}", out_file_name),
+                ]
+                .iter(),
+                None,
+            )
+            .unwrap();
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let mut program_run = AtomicBool::new(false);
+            capsule.run_capsule(&mut program_run).await.unwrap();
+            std::fs::remove_file(&out_file).unwrap();
+
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let mut program_run = AtomicBool::new(false);
+            let code = capsule.run_capsule(&mut program_run).await.unwrap();
+            assert_eq!(code, 0);
+            assert_eq!(program_run.load(Ordering::SeqCst), !cache_hit);
+            assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "123\n");
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_hit_permissions() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_string_lossy();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                &out_file_name,
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}; chmod 755 {}", out_file_name, out_file_name),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        std::fs::remove_file(&out_file).unwrap();
+
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        // The 2nd time the program should NOT run.
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(out_file.is_file());
+        assert_eq!(out_file.metadata().unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_mask_output_mode() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_str().unwrap();
+        let command = format!("echo '123' > {}; chmod 6755 {}", out_file_name, out_file_name);
+        let args = [
+            "capsule",
+            "-c",
+            "wtf",
+            "-i",
+            "/bin/echo",
+            "-o",
+            out_file_name,
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ];
+        // An entry written without the mask.
+        let config = Config::new(args.iter(), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+        std::fs::remove_file(&out_file).unwrap();
+
+        // The setuid and setgid bits are stripped when restoring.
+        let mut masked_args = args.to_vec();
+        masked_args.insert(1, "--mask_output_mode");
+        masked_args.insert(2, "0777");
+        let config = Config::new(masked_args.iter(), None).unwrap();
+        assert_eq!(config.mask_output_mode, Some(0o777));
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert_eq!(out_file.metadata().unwrap().permissions().mode() & 0o7777, 0o755);
+
+        // And when storing.
+        let outputs = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap();
+        assert!(outputs.hash_details.iter().any(|(output, _)| match output {
+            Output::File(fileoutput) => fileoutput.mode == 0o755,
+            _ => false,
+        }));
+
+        masked_args[2] = "0o7777";
+        assert_eq!(
+            Config::new(masked_args.iter(), None).unwrap().mask_output_mode,
+            Some(0o7777)
+        );
+        masked_args[2] = "rwx";
+        assert!(Config::new(masked_args.iter(), None).is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    // Here the logic changed. OutputBundles contain the information about whether an output file
+    // was present when the cache entry was created.  Before 0.2.9, once we had cache hit with the
+    // output file absent, capsule would try to make sure that the file is removed. In reality an
+    // absent file is usually a miconfiguration. Fixing that misconfiguration should then fix the
+    // job and caching, but with the old logic it would just get cache hits with no output.
+    // So this test actuall checks that the file is *not* removed on cache hit with a file 'not present',
+    // and that the cache hit is ignored.
+    async fn test_cache_file_removal() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_string_lossy();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                &out_file_name,
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // Create the file
+        std::fs::File::create(&out_file).unwrap();
+        assert!(out_file.exists());
+
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        // The 2nd time the program should NOT run.
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // Because the out file was not present when the run was cached, we should expect it
+        // to be removed.
+        assert!(out_file.exists());
+    }
+
+    #[tokio::test]
+    #[serial]
+    // Unlike with regular outputs, an absent negative output is a valid cached state, so the cache
+    // hit should be used, and the file should be removed.
+    async fn test_negative_output_removal() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_string_lossy();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--negative_output",
+                &out_file_name,
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // Create the file
+        std::fs::File::create(&out_file).unwrap();
+        assert!(out_file.exists());
+
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        // The 2nd time the program should NOT run.
+        assert!(!program_run.load(Ordering::SeqCst));
+
+        // Because the out file was not present when the run was cached, it should be removed.
+        assert!(!out_file.exists());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_lookup_timeout() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                lookup_timeout: true,
+                ..Default::default()
+            },
+        );
+        let out_file_1 = tmp_dir.path().join("xx");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_1.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_download_timeout() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                download_timeout: true,
+                ..Default::default()
+            },
+        );
+        let out_file_1 = tmp_dir.path().join("xx");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_1.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        std::fs::remove_file(&out_file_1).unwrap();
+
+        // Running 2nd time, expect a cache hit, but a download problem.
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        // Returns ok, despite the download problem, as it would just execute the program
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+
+        // The 2nd time the program should be run, because of timeout downloading.
+        assert!(program_run.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_write_timeout() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                write_timeout: true,
+                ..Default::default()
+            },
+        );
+        let out_file_1 = tmp_dir.path().join("xx");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_1.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        // Despite the write errors, the capsule successfully executed the program,
+        // so the return code is zero.
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // 2nd capsule, should NOT be cached, as the capsule call above failed to write to the
+        // cache, despite successful completion of the underlying program.
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        // The program must have been run.
+        assert!(program_run.load(Ordering::SeqCst));
+        assert!(out_file_1.is_file());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_upload_timeout() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                upload_timeout: true,
+                ..Default::default()
+            },
+        );
+        let out_file_1 = tmp_dir.path().join("xx");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_1.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}", out_file_1.to_str().unwrap()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        // Despite the upload errors, the capsule successfully executed the program,
+        // so the return code is zero.
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // 2nd capsule, should NOT be cached, as the capsule call above failed to upload to the
+        // cache, despite successful completion of the underlying program. The output file is
+        // removed, as otherwise it's up to date, and needs no download.
+        std::fs::remove_file(&out_file_1).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+
+        // The program must have been run.
+        assert!(program_run.load(Ordering::SeqCst));
+        assert!(out_file_1.is_file());
+    }
+
+    #[test]
+    fn test_object_name_strip_prefix() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--object_name_strip_prefix",
+                "/home/ci/build",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let name = |path: &str| capsule.object_name(&WorkspacePath::from(path));
+        assert_eq!(name("/home/ci/build/target/out.bin"), "target/out.bin");
+        // Only whole path components are trimmed.
+        assert_eq!(name("/home/ci/build2/out.bin"), "/home/ci/build2/out.bin");
+        assert_eq!(name("/home/ci/build"), "/home/ci/build");
+        assert_eq!(name("//target/out.bin"), "//target/out.bin");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_chunked_objects() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_str().unwrap();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_name,
+                "--chunked_objects",
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("head -c 300000 /dev/urandom > {}", out_file_name),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        // Small chunks, so that the test doesn't need a large file.
+        let chunk_sizes = ChunkSizes {
+            min_file: 128 << 10,
+            min: 4 << 10,
+            avg: 16 << 10,
+            max: 64 << 10,
+        };
+        let capsule = Capsule::new(&config, &backend, &Dummy).with_chunk_sizes(chunk_sizes);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+        let content = std::fs::read(&out_file).unwrap();
+
+        // The entry references the chunks, and only they are stored.
+        let entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().unwrap();
+        let (fileoutput, item_hash) = match &entry.outputs.hash_details[..] {
+            [(Output::ExitCode(0), _), (Output::File(fileoutput), item_hash)]
+            | [(Output::File(fileoutput), item_hash), (Output::ExitCode(0), _)] => (fileoutput, item_hash),
+            details => panic!("Unexpected outputs {:?}", details),
+        };
+        assert!(fileoutput.chunks.len() > 1);
+        assert_eq!(fileoutput.chunks.iter().map(|chunk| chunk.length).sum::<u64>(), 300000);
+        assert!(backend.download_object_file(item_hash).await.is_err());
+
+        std::fs::remove_file(&out_file).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(std::fs::read(&out_file).unwrap() == content);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_dry_upload() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_str().unwrap();
+        let command = format!("echo hello > {}", out_file_name);
+        let args = [
+            "capsule",
+            "-c",
+            "wtf",
+            "-i",
+            "/bin/echo",
+            "-o",
+            out_file_name,
+            "--dry_upload",
+        ];
+        let config = Config::new(args.iter().chain(&["--", "/bin/bash", "-c", &command]), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // The entry is written, but the object is not uploaded.
+        let entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().unwrap();
+        for (item, item_hash) in &entry.outputs.hash_details {
+            if let Output::File(_) = item {
+                assert!(backend.download_object_file(item_hash).await.is_err());
+            }
+        }
+
+        // With --dry_write, the entry isn't written either.
+        backend.remove_all();
+        let config = Config::new(
+            args.iter().chain(&["--dry_write", "--", "/bin/bash", "-c", &command]),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+        assert!(backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_dry_write() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let command = format!("echo hello > {}", out_file.display());
+        let out = out_file.to_str().unwrap();
+        let run = |flags: &[&'static str]| {
+            let args = [&["-c", "wtf", "-i", "/bin/echo", "-o", out][..], flags].concat();
+            run_bash(&backend, &Dummy, args, &command)
+        };
+        assert_eq!(run(&["--dry_write"]).await, (CacheDecision::MissNoEntry, 0, true));
+
+        // The object is uploaded, but the entry isn't written, so the next run misses too.
+        let hash = file_hash(&out_file).unwrap();
+        assert!(backend.download_object_file(&hash).await.is_ok());
+        assert_eq!(run(&[]).await, (CacheDecision::MissNoEntry, 0, true));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_abort_on_output_mismatch_write() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_str().unwrap();
+        let command = format!("head -c 16 /dev/urandom > {}", out_file_name);
+        let args = ["capsule", "-c", "wtf", "-i", "/bin/echo", "-o", out_file_name];
+        let config = Config::new(args.iter().chain(&["--", "/bin/bash", "-c", &command]), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().unwrap();
+
+        // In placebo mode the command is run again, and its outputs differ, but the entry is kept.
+        let config = Config::new(
+            args.iter().chain(&[
+                "--placebo",
+                "--abort_on_output_mismatch_write",
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ]),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+        let new_entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().unwrap();
+        assert_eq!(new_entry.outputs.hash, entry.outputs.hash);
+
+        // Without the option, the entry is overwritten.
+        let config = Config::new(
+            args.iter().chain(&["--placebo", "--", "/bin/bash", "-c", &command]),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let new_entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap().unwrap();
+        assert_ne!(new_entry.outputs.hash, entry.outputs.hash);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_lookup_only() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            ["capsule", "-c", "wtf", "-i", "/bin/echo", "--", "/bin/echo"].iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert!(capsule.lookup_only().await.unwrap().is_none());
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let entry = capsule.lookup_only().await.unwrap().unwrap();
+        assert_eq!(entry.inputs.hash, capsule.read_inputs().unwrap().hash);
+
+        let backend = TestBackend::new(
+            "wtf",
+            TestBackendConfig {
+                lookup_timeout: true,
+                ..Default::default()
+            },
+        );
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert!(capsule.lookup_only().await.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_on_hit_exec() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let hook_log = tmp_dir.path().join("hook_log");
+        let on_hit = format!(
+            "echo hit $CAPSULE_INPUTS_HASH $CAPSULE_OUTPUTS_HASH >> {}",
+            hook_log.display()
+        );
+        let on_miss = format!(
+            "echo miss $CAPSULE_INPUTS_HASH $CAPSULE_OUTPUTS_HASH >> {}; exit 1",
+            hook_log.display()
+        );
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--on_hit_exec",
+                &on_hit,
+                "--on_miss_exec",
+                &on_miss,
+                "--",
+                "/bin/true",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        // The failing hook doesn't change the exit code.
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert!(program_run.load(Ordering::SeqCst));
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert!(!program_run.load(Ordering::SeqCst));
+
+        let inputs_hash = capsule.read_inputs().unwrap().hash;
+        let outputs_hash = capsule.lookup_only().await.unwrap().unwrap().outputs.hash;
+        assert_eq!(
+            std::fs::read_to_string(&hook_log).unwrap(),
+            format!("miss {0} {1}\nhit {0} {1}\n", inputs_hash, outputs_hash)
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_unhashed_stderr() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--unhashed_stderr",
+                "--",
+                "/bin/bash",
+                "-c",
+                "date +%N >&2",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let entry = capsule.lookup_only().await.unwrap().unwrap();
+        assert!(entry
+            .outputs
+            .hash_details
+            .iter()
+            .all(|(output, _)| !matches!(output, Output::Stderr(_))));
+        assert!(matches!(entry.outputs.unhashed_details[..], [(Output::Stderr(_), _)]));
+        // The outputs hash is the same as without stderr.
+        let outputs = capsule.read_outputs(Some(ExitStatus::from_raw(0)), vec![]).unwrap();
+        assert_eq!(entry.outputs.hash, outputs.hash);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_min_free_disk_mb() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out_file_name = out_file.to_str().unwrap();
+        let command = format!("echo hello > {}", out_file_name);
+        let config_with = |min_free_disk_mb: &str| {
+            let args = [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file_name,
+                "--min_free_disk_mb",
+                min_free_disk_mb,
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ];
+            Config::new(args.iter(), None).unwrap()
+        };
+        let config = config_with("0");
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // No disk has an exabyte free, so the hit is abandoned, and the command is run.
+        std::fs::remove_file(&out_file).unwrap();
+        let config = config_with("1000000000000");
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+
+        std::fs::remove_file(&out_file).unwrap();
+        let config = config_with("1");
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "hello\n");
+    }
+
+    #[test]
+    #[serial]
+    fn test_tool_tag_env() {
+        std::env::set_var("CAPSULE_TEST_TAG_B", "2");
+        std::env::set_var("CAPSULE_TEST_TAG_A", "1");
+        std::env::set_var("CAPSULE_TEST_OTHER", "3");
+        let backend = dummy::DummyBackend::default();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--tool_tag_env",
+                "CAPSULE_TEST_TAG_*,CAPSULE_TEST_NONE",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let inputs = capsule.read_inputs().unwrap();
+        assert_eq!(
+            inputs.hash_details[0].0,
+            Input::ToolTag(
+                "env CAPSULE_TEST_TAG_*,CAPSULE_TEST_NONE: CAPSULE_TEST_TAG_A=1\nCAPSULE_TEST_TAG_B=2".to_owned()
+            )
+        );
+
+        // Removing a matching variable changes the key.
+        std::env::remove_var("CAPSULE_TEST_TAG_B");
+        assert_ne!(capsule.read_inputs().unwrap().hash, inputs.hash);
+        std::env::remove_var("CAPSULE_TEST_TAG_A");
+        std::env::remove_var("CAPSULE_TEST_OTHER");
+    }
+
+    #[tokio::test]
+    async fn test_tee_capture_max_bytes() {
+        // The stream is passed through in full, but the captured part is truncated, and marked so.
+        let mut passed: Vec<u8> = Vec::new();
+        let captured = <Capsule>::tee(Some(&b"0123456789"[..]), &mut passed, Some(4)).await;
+        assert_eq!(passed, b"0123456789");
+        assert_eq!(captured.unwrap().unwrap(), b"0123\n[capsule: truncated at 4 bytes]\n");
+
+        let captured = <Capsule>::tee(Some(&b"0123"[..]), tokio::io::sink(), Some(4)).await;
+        assert_eq!(captured.unwrap().unwrap(), b"0123");
+        let captured = <Capsule>::tee(Some(&b"0123"[..]), tokio::io::sink(), None).await;
+        assert_eq!(captured.unwrap().unwrap(), b"0123");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_restore_to() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("out");
+        let staging = tmp_dir.path().join("staging");
+        let out = out_file.to_str().unwrap().to_owned();
+        let args = |restore_to: Option<&Path>| {
+            let mut args: Vec<String> = ["capsule", "-c", "wtf", "-i", "/bin/echo", "-o", &out]
+                .map(String::from)
+                .into();
+            if let Some(restore_to) = restore_to {
+                args.extend(["--restore_to".to_owned(), restore_to.to_str().unwrap().to_owned()]);
+            }
+            args.extend(["--", "/bin/bash", "-c"].map(String::from));
+            args.push(format!("echo 123 > {}", out));
+            args
+        };
+        // Nothing is run on cache miss.
+        let config = Config::new(args(Some(&staging)).into_iter(), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        assert!(capsule.run_capsule(&mut program_run).await.is_err());
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(!out_file.exists());
+
+        let config = Config::new(args(None).into_iter(), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        std::fs::remove_file(&out_file).unwrap();
+
+        // On cache hit, the output is restored under the directory, and not in place.
+        let config = Config::new(args(Some(&staging)).into_iter(), None).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(!out_file.exists());
+        let restored = staging.join(out_file.strip_prefix("/").unwrap());
+        assert_eq!(std::fs::read_to_string(restored).unwrap(), "123\n");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_fallback_command() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("out");
+        let hit_file = tmp_dir.path().join("hit");
+        let fallback_command = format!("/bin/bash -c 'echo built > {}'", out_file.to_str().unwrap());
+        let hit_command = format!("touch {}", hit_file.to_str().unwrap());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file.to_str().unwrap(),
+                "--fallback_command",
+                &fallback_command,
+                "--",
+                "/bin/bash",
+                "-c",
+                &hit_command,
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        // The cache miss runs the fallback command, and caches its outputs.
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "built\n");
+        assert!(!hit_file.exists());
+        std::fs::remove_file(&out_file).unwrap();
+        // The cache hit restores the outputs, and runs the command to run.
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "built\n");
+        assert!(hit_file.exists());
+
+        // The cache key depends on the fallback command, and not on the command to run.
+        let inputs_hash = |fallback_command: Option<&str>, command: &str| {
+            let mut args = vec!["capsule", "-c", "wtf"];
+            if let Some(fallback_command) = fallback_command {
+                args.extend(["--fallback_command", fallback_command]);
+            }
+            args.extend(["--", command]);
+            let config = Config::new(args.into_iter(), None).unwrap();
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            capsule.read_inputs().unwrap().hash
+        };
+        assert_eq!(inputs_hash(Some("make"), "true"), inputs_hash(Some("make"), "false"));
+        assert_ne!(inputs_hash(Some("make"), "true"), inputs_hash(Some("make all"), "true"));
+        assert_ne!(inputs_hash(Some("make"), "true"), inputs_hash(None, "true"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_argv0() {
+        let tmp_dir = TempDir::new().unwrap();
+        let interpreter = tmp_dir.path().join("interpreter-1.0");
+        std::fs::write(&interpreter, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&interpreter, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let link = tmp_dir.path().join("interpreter");
+        std::os::unix::fs::symlink(&interpreter, &link).unwrap();
+        let backend = dummy::DummyBackend::default();
+        let read_inputs = |command: &str| {
+            let config = Config::new(["capsule", "-c", "wtf", "--hash_argv0", "--", command].iter(), None).unwrap();
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            capsule.read_inputs()
+        };
+        // The symlink is resolved to the binary it points to.
+        let inputs = read_inputs(link.to_str().unwrap()).unwrap();
+        let input_file = Input::File(WorkspacePath::from(interpreter.canonicalize().unwrap()));
+        assert!(inputs.hash_details.iter().any(|(input, _)| *input == input_file));
+        // Upgrading the interpreter changes the hash.
+        std::fs::write(&interpreter, "#!/bin/bash\n").unwrap();
+        assert_ne!(read_inputs(link.to_str().unwrap()).unwrap().hash, inputs.hash);
+
+        // Commands without a slash are looked up in PATH.
+        assert_eq!(read_inputs("sh").unwrap().file_count, 1);
+        let err = read_inputs("no-such-command-xyz").unwrap_err();
+        assert!(format!("{:#}", err).contains("not found in PATH"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_inject_nondeterminism() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("out");
+        let probe = |command: String| {
+            let out = out_file.to_str().unwrap().to_owned();
+            let backend = &backend;
+            async move {
+                let args = ["capsule", "-c", "wtf", "--inject_nondeterminism", "-o", &out, "--"];
+                let config = Config::new(args.into_iter().chain(["/bin/bash", "-c", &command]), None).unwrap();
+                let capsule = Capsule::new(&config, backend, &Dummy);
+                let mut program_run = AtomicBool::new(false);
+                capsule.probe_nondeterminism(&mut program_run).await.unwrap()
+            }
+        };
+        let out = out_file.to_str().unwrap();
+        assert_eq!(probe(format!("echo hermetic > {}", out)).await, 0);
+        assert_eq!(probe(format!("echo $CAPSULE_RUN_NONCE > {}", out)).await, 1);
+        assert_eq!(probe(format!("env | grep CAPSULE_RANDOM_ > {}", out)).await, 1);
+        // Nothing is written to the cache.
+        let config = Config::new(["capsule", "-c", "wtf", "--", "/bin/true"].iter(), None).unwrap();
+        let inputs = Capsule::new(&config, &backend, &Dummy).read_inputs().unwrap();
+        assert!(backend.lookup(&inputs).await.unwrap().is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_input_cmd() {
+        let tmp_dir = TempDir::new().unwrap();
+        let first = tmp_dir.path().join("first file");
+        let second = tmp_dir.path().join("second");
+        std::fs::write(&first, "1").unwrap();
+        std::fs::write(&second, "2").unwrap();
+        let input_cmd = |command: String| {
+            let config = Config::new(
+                vec![
+                    "capsule".to_owned(),
+                    "-c".to_owned(),
+                    "wtf".to_owned(),
+                    "--input_cmd".to_owned(),
+                    command,
+                    "--".to_owned(),
+                    "/bin/echo".to_owned(),
+                ]
+                .into_iter(),
+                None,
+            )
+            .unwrap();
+            let backend = dummy::DummyBackend::default();
+            Capsule::new(&config, &backend, &Dummy).read_inputs()
+        };
+        let newlines = format!(
+            "/usr/bin/printf '%s\\n\\n%s\\n' '{}' '{}'",
+            first.display(),
+            second.display()
+        );
+        let inputs = input_cmd(newlines.clone()).unwrap();
+        let details: Vec<_> = inputs.hash_details.iter().map(|(input, _)| input.clone()).collect();
+        assert!(details.contains(&Input::File(WorkspacePath::from(first.clone()))));
+        assert!(details.contains(&Input::File(WorkspacePath::from(second.clone()))));
+        assert!(details.contains(&Input::ToolTag(format!(
+            "input command: {}",
+            shell_words::join(shell_words::split(&newlines).unwrap())
+        ))));
+        // NUL-delimited paths, and the same files, listed by another command, give another key.
+        let nuls = format!(
+            "/usr/bin/printf '%s\\0%s\\0' '{}' '{}'",
+            first.display(),
+            second.display()
+        );
+        let nul_inputs = input_cmd(nuls).unwrap();
+        assert_eq!(nul_inputs.hash_details.len(), inputs.hash_details.len());
+        assert_ne!(nul_inputs.hash, inputs.hash);
+        // Changing a listed file changes the key.
+        std::fs::write(&second, "3").unwrap();
+        assert_ne!(input_cmd(newlines).unwrap().hash, inputs.hash);
+        // Listing no files, a missing file, or failing is an error.
+        assert!(input_cmd("/bin/true".to_owned()).is_err());
+        let missing = tmp_dir.path().join("missing");
+        assert!(input_cmd(format!("/bin/echo '{}'", missing.display())).is_err());
+        assert!(input_cmd("/bin/false".to_owned()).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_bundle_bytes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut args = vec!["capsule".to_owned(), "-c".to_owned(), "wtf".to_owned()];
+        for i in 0..20 {
+            let input = tmp_dir.path().join(format!("input{}", i));
+            std::fs::write(&input, i.to_string()).unwrap();
+            args.extend(["-i".to_owned(), input.to_str().unwrap().to_owned()]);
+        }
+        let stored_inputs = |flags: &[&str]| -> Result<(InputHashBundle, InputHashBundle)> {
+            let mut args = args.clone();
+            args.extend(flags.iter().map(|flag| flag.to_string()));
+            args.extend(["--".to_owned(), "/bin/echo".to_owned()]);
+            let config = Config::new(args.into_iter(), None)?;
+            let backend = dummy::DummyBackend::default();
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let inputs = capsule.read_inputs()?;
+            let stored = capsule
+                .stored_inputs(&inputs, &OutputHashBundle::default(), &Metadata::new())?
+                .into_owned();
+            Ok((inputs, stored))
+        };
+        let (inputs, stored) = stored_inputs(&["--max_bundle_bytes", "100000"]).unwrap();
+        assert_eq!(stored.hash_details, inputs.hash_details);
+        assert!(stored.summarized_details.is_none());
+        assert!(stored_inputs(&["--max_bundle_bytes", "1000"]).is_err());
+        // Summarized, the entry keeps the hash, so the key is the same.
+        let (inputs, stored) = stored_inputs(&["--max_bundle_bytes", "1000", "--summarize_large_bundles"]).unwrap();
+        assert_eq!(stored.hash, inputs.hash);
+        assert!(stored.hash_details.is_empty());
+        assert_eq!(stored.summarized_details, Some(20));
+        assert_eq!(stored.file_count, 20);
+        // Even the summarized entry doesn't fit.
+        assert!(stored_inputs(&["--max_bundle_bytes", "10", "--summarize_large_bundles"]).is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_set_env() {
+        let tmp_dir = TempDir::new().unwrap();
+        let out_file = tmp_dir.path().join("out");
+        let out = out_file.to_str().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let command = format!("echo $SOURCE_DATE_EPOCH > {}", out);
+        let config = |flags: &[&str]| {
+            let args = ["capsule", "-c", "wtf", "-o", out]
+                .into_iter()
+                .chain(flags.iter().copied());
+            Config::new(args.chain(["--", "/bin/bash", "-c", &command]), None).unwrap()
+        };
+        let set_env = ["--set_env", "SOURCE_DATE_EPOCH=1", "--set_env", "SOURCE_DATE_EPOCH=42"];
+        let config_set = config(&set_env);
+        let capsule = Capsule::new(&config_set, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "42\n");
+        assert_ne!(std::env::var("SOURCE_DATE_EPOCH").ok(), Some("42".to_owned()));
+        // The variables are part of the inputs hash only with --hash_set_env.
+        let hash = |config: &Config| Capsule::new(config, &backend, &Dummy).read_inputs().unwrap().hash;
+        let config_none = config(&[]);
+        assert_eq!(hash(&config_set), hash(&config_none));
+        let config_hashed = config(&[&set_env[..], &["--hash_set_env"]].concat());
+        assert_ne!(hash(&config_hashed), hash(&config_none));
+        let config_other = config(&["--set_env", "SOURCE_DATE_EPOCH=43", "--hash_set_env"]);
+        assert_ne!(hash(&config_hashed), hash(&config_other));
+    }
+
+    // Run a capsule with the given arguments, and the command given to bash, and return the cache decision
+    // looked up before the run, the exit code, and whether the command was run.
+    async fn run_bash(
+        backend: &TestBackend,
+        logger: &dyn Logger,
+        args: Vec<&str>,
+        command: &str,
+    ) -> (CacheDecision, i32, bool) {
+        let args = ["capsule"]
+            .into_iter()
+            .chain(args)
+            .chain(["--", "/bin/bash", "-c", command]);
+        let config = Config::new(args, None).unwrap();
+        let capsule = Capsule::new(&config, backend, logger);
+        let entry = backend.lookup(&capsule.read_inputs().unwrap()).await.unwrap();
+        let decision = capsule.cache_decision(&entry).unwrap();
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        (decision, code, program_run.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_no_upload_no_download() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let runs_file = tmp_dir.path().join("runs");
+        let command = format!(
+            "echo hello > {}; echo run >> {}",
+            out_file.to_str().unwrap(),
+            runs_file.to_str().unwrap()
+        );
+        let out = out_file.to_str().unwrap();
+        let run = |flags: &[&'static str]| {
+            let args = [&["-c", "wtf", "-i", "/bin/echo", "-o", out][..], flags].concat();
+            run_bash(&backend, &Dummy, args, &command)
+        };
+        let runs = || std::fs::read_to_string(&runs_file).unwrap().lines().count();
+
+        // The entry is written, but the object is not uploaded.
+        run(&["--no_upload"]).await;
+        assert!(backend
+            .download_object_file(&file_hash(&out_file).unwrap())
+            .await
+            .is_err());
+        // The output is up to date, so the hit doesn't need the object.
+        assert_eq!(run(&[]).await, (CacheDecision::Hit, 0, false));
+        assert_eq!(runs(), 1);
+
+        // With the objects uploaded, it's a hit, but the command is still run with --no_download.
+        backend.remove_all();
+        run(&[]).await;
+        assert_eq!(run(&[]).await, (CacheDecision::Hit, 0, false));
+        assert_eq!(runs(), 2);
+        assert_eq!(
+            run(&["--no_download"]).await,
+            (CacheDecision::IgnoredNoDownload, 0, true)
+        );
+        assert_eq!(runs(), 3);
+    }
+
+    // Records the cache decisions logged for observability.
+    #[derive(Default)]
+    struct DecisionLogger(std::sync::Mutex<Vec<(CacheDecision, Option<String>)>>);
+
+    #[async_trait::async_trait]
+    impl Logger for DecisionLogger {
+        async fn log(
+            &self,
+            _inputs_bundle: &InputHashBundle,
+            _output_bundle: &OutputHashBundle,
+            cache_decision: CacheDecision,
+            cache_decision_detail: Option<&str>,
+            _non_determinism: bool,
+        ) -> Result<()> {
+            let decision = (cache_decision, cache_decision_detail.map(str::to_owned));
+            self.0.lock().unwrap().push(decision);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_log_cache_decision_detail() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let out = out_file.to_str().unwrap();
+        let command = format!("echo hello > {}", out);
+        let logger = DecisionLogger::default();
+        let run = |flags: &[&'static str]| {
+            let args = [&["-c", "wtf", "-i", "/bin/echo", "-o", out][..], flags].concat();
+            run_bash(&backend, &logger, args, &command)
+        };
+        run(&["--no_upload"]).await;
+        // Without the object, the entry is found, but the download fails, which is the detail.
+        std::fs::remove_file(&out_file).unwrap();
+        run(&[]).await;
+        // The command is run then, and the object is uploaded.
+        std::fs::remove_file(&out_file).unwrap();
+        run(&[]).await;
+        let decisions = logger.0.lock().unwrap();
+        assert_eq!(decisions[0], (CacheDecision::MissNoEntry, None));
+        assert_eq!(decisions[1].0, CacheDecision::IgnoredDownloadError);
+        assert!(decisions[1].1.is_some());
+        assert_eq!(decisions[2], (CacheDecision::Hit, None));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_clean_failed_outputs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let dir = tmp_dir.path().to_str().unwrap();
+        let kept = tmp_dir.path().join("kept");
+        let modified = tmp_dir.path().join("modified");
+        let created = tmp_dir.path().join("created");
+        let pattern = format!("{}/*", dir);
+        let run = |command: String, flags: &'static [&'static str]| {
+            let (backend, pattern) = (&backend, &pattern);
+            async move {
+                // The command is a tool tag, so that the runs don't hit each other's entries.
+                let args = [&["-c", "wtf", "-t", &command, "-o", pattern][..], flags].concat();
+                run_bash(backend, &Dummy, args, &command).await.1
+            }
+        };
+        std::fs::write(&kept, "kept").unwrap();
+        std::fs::write(&modified, "modified\n").unwrap();
+        let command = |code| format!("echo new > {0}/created; echo more >> {0}/modified; exit {1}", dir, code);
+        // By default, the outputs of the failed command are left as they are.
+        assert_eq!(run(command(3), &[]).await, 3);
+        assert!(created.exists());
+        std::fs::remove_file(&created).unwrap();
+        // The outputs of a successful command are never removed.
+        assert_eq!(run(command(0), &["--clean_failed_outputs"]).await, 0);
+        assert!(created.exists());
+        std::fs::remove_file(&created).unwrap();
+        // Only the file created by the failed command is removed, the ones that existed before are kept.
+        assert_eq!(run(command(4), &["--clean_failed_outputs"]).await, 4);
+        assert!(!created.exists());
+        assert_eq!(std::fs::read_to_string(&kept).unwrap(), "kept");
+        assert_eq!(
+            std::fs::read_to_string(&modified).unwrap(),
+            "modified\nmore\nmore\nmore\n"
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_global_job_limit() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let lock = tmp_dir.path().join("jobs").to_str().unwrap().to_owned();
+        let runs = tmp_dir.path().join("runs");
+        let command = format!("echo run >> {}", runs.display());
+        let run = |limit: &'static str| {
+            let args = vec![
+                "-c",
+                "wtf",
+                "--backend",
+                "none",
+                "--global_job_limit",
+                limit,
+                "--global_job_lock",
+                &lock,
+            ];
+            async { run_bash(&backend, &Dummy, args, &command).await.1 }
+        };
+        // Another capsule holds the only slot, so the command waits for it.
+        let slot = std::fs::File::create(format!("{}.0", lock)).unwrap();
+        nix::fcntl::flock(slot.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive).unwrap();
+        assert!(time::timeout(Duration::from_millis(500), run("1")).await.is_err());
+        assert!(!runs.exists());
+        // With two slots, the second one is free.
+        assert_eq!(run("2").await, 0);
+        assert!(runs.exists());
+        // Closing the file of the slot frees it.
+        drop(slot);
+        assert_eq!(run("1").await, 0);
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\nrun\n");
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_no_outputs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let runs = tmp_dir.path().join("runs");
+        let run = |exit_code: i32, flags: &'static [&'static str]| {
+            let backend = &backend;
+            let command = format!("echo run >> {}; exit {}", runs.display(), exit_code);
+            async move {
+                // The exit code is a tool tag, so that the commands have entries of their own.
+                let tag = exit_code.to_string();
+                let args = [&["-c", "wtf", "-i", "/bin/echo", "-t", &tag][..], flags].concat();
+                run_bash(backend, &Dummy, args, &command).await
+            }
+        };
+        let runs = || std::fs::read_to_string(&runs).map_or(0, |runs| runs.lines().count());
+
+        // The entry has only the exit code, and a hit replays it without running the command.
+        assert_eq!(run(0, &[]).await, (CacheDecision::MissNoEntry, 0, true));
+        assert_eq!(run(0, &[]).await, (CacheDecision::Hit, 0, false));
+        assert_eq!(runs(), 1);
+
+        // Failures are cached, but only replayed with --cache_failure.
+        assert_eq!(run(3, &[]).await, (CacheDecision::MissNoEntry, 3, true));
+        assert_eq!(run(3, &[]).await, (CacheDecision::IgnoredFailure, 3, true));
+        assert_eq!(run(3, &["--cache_failure"]).await, (CacheDecision::Hit, 3, false));
+        assert_eq!(runs(), 3);
+
+        // An entry with output files doesn't match the capsule without outputs.
+        let out = tmp_dir.path().join("out");
+        let pattern = out.to_str().unwrap().to_owned();
+        std::fs::write(&out, "out").unwrap();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-t",
+                "0",
+                "-o",
+                &pattern,
+                "--",
+                "/bin/true",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        backend.remove_all();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        capsule.run_capsule(&mut AtomicBool::new(false)).await.unwrap();
+        assert_eq!(run(0, &[]).await, (CacheDecision::IgnoredOutputMismatch, 0, true));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_inputs_hash_value() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let runs = tmp_dir.path().join("runs");
+        let command = format!("echo run >> {}", runs.display());
+        let run = |hash: &'static str| async {
+            let args = vec!["-c", "wtf", "--inputs_hash_value", hash];
+            run_bash(&backend, &Dummy, args, &command).await.2
+        };
+        let hash_a = "1111111111111111111111111111111111111111111111111111111111111111";
+        let hash_b = "2222222222222222222222222222222222222222222222222222222222222222";
+        // The entries are keyed by the given hash alone.
+        assert!(run(hash_a).await);
+        assert!(!run(hash_a).await);
+        assert!(run(hash_b).await);
+        assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 2);
+        for hash in [hash_a, hash_b] {
+            let inputs = InputHashBundle {
+                hash: hash.to_owned(),
+                ..Default::default()
+            };
+            assert!(backend.lookup(&inputs).await.unwrap().is_some());
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_timing_table() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_file = tmp_dir.path().join("xx");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                out_file.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!("echo '123' > {}", out_file.to_str().unwrap()),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        // The clock stands still, so all the phases take no time.
+        let clock = TestClock::new(UNIX_EPOCH);
+        let capsule = Capsule::new(&config, &backend, &Dummy).with_clock(&clock);
+        capsule.run_capsule_once(&mut AtomicBool::new(false)).await.unwrap();
+        // Writing the entry and uploading the objects run concurrently, so they finish in any order.
+        let mut rows: Vec<String> = capsule.timing_table().lines().map(str::to_owned).collect();
+        rows[5..].sort();
+        assert_eq!(
+            rows,
+            vec![
+                "Capsule timing for wtf:",
+                "  read_inputs      0.000s",
+                "  lookup           0.000s",
+                "  exec             0.000s",
+                "  read_outputs     0.000s",
+                "  upload           0.000s             4 bytes",
+                "  write            0.000s",
+            ]
+        );
+
+        std::fs::remove_file(&out_file).unwrap();
+        capsule.run_capsule_once(&mut AtomicBool::new(false)).await.unwrap();
+        assert_eq!(
+            capsule.timing_table(),
+            indoc! {"
+                Capsule timing for wtf:
+                  read_inputs      0.000s
+                  lookup           0.000s
+                  download         0.000s             4 bytes
+            "}
+        );
+    }
 }
@@ -0,0 +1,221 @@
+//! Content-defined chunking of large output files (FastCDC).
+//!
+//! The chunk boundaries are determined by the content itself, using a rolling gear hash, so that
+//! an insertion or deletion in a file only changes the chunks around it. The chunks are stored
+//! as objects keyed by their own hashes, so the unchanged regions of a file are deduplicated
+//! across its versions.
+//!
+//! The parameters and the gear table determine where the files are cut, so changing them makes
+//! all the existing chunks useless (but not invalid).
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The sizes the files are chunked by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkSizes {
+    /// Files smaller than this are not chunked, but stored as single objects.
+    pub min_file: u64,
+    pub min: usize,
+    /// A power of two.
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl ChunkSizes {
+    /// Normalized chunking: before the average size, cuts are less likely (more bits must be zero),
+    /// and after it they are more likely, which concentrates the chunk sizes around the average.
+    /// The masks use the high bits of the hash, as they depend on more of the preceding bytes.
+    fn masks(&self) -> (u64, u64) {
+        let avg_bits = self.avg.trailing_zeros();
+        (!(u64::MAX >> (avg_bits + 1)), !(u64::MAX >> (avg_bits - 1)))
+    }
+}
+
+impl Default for ChunkSizes {
+    fn default() -> Self {
+        Self {
+            min_file: 8 << 20,
+            min: 256 << 10,
+            avg: 1 << 20,
+            max: 4 << 20,
+        }
+    }
+}
+
+/// The gear table maps the bytes to pseudorandom values. It's generated with SplitMix64 from a
+/// fixed seed, so that it's the same everywhere.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6361_7073_756c_6573; // "capsules"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A chunk of a file, stored as an object keyed by its hash. The file is the concatenation of
+/// its chunks.
+#[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub hash: String,
+    pub length: u64,
+}
+
+/// Returns the length of the first chunk of the data.
+fn cut_point(data: &[u8], sizes: &ChunkSizes) -> usize {
+    if data.len() <= sizes.min {
+        return data.len();
+    }
+    let (mask_small, mask_large) = sizes.masks();
+    let end = data.len().min(sizes.max);
+    let normal = end.min(sizes.avg);
+    let mut hash: u64 = 0;
+    let mut i = sizes.min;
+    while i < normal {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_small == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    while i < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        if hash & mask_large == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    end
+}
+
+/// Returns the hash of the given file, the same as `file_hash`, and its chunks.
+pub fn file_chunks(filename: &Path, sizes: &ChunkSizes) -> Result<(String, Vec<Chunk>)> {
+    let mut f = File::open(filename).with_context(|| format!("Reading file '{}'", filename.to_string_lossy()))?;
+    let mut acc = Sha256::new();
+    let mut chunks = Vec::new();
+    let mut buf = Vec::with_capacity(2 * sizes.max);
+    let mut eof = false;
+    loop {
+        // Keep at least a chunk of maximum size in the buffer, so that the cut points don't depend
+        // on how the file happens to be read.
+        while !eof && buf.len() < sizes.max {
+            let start = buf.len();
+            buf.resize(2 * sizes.max, 0);
+            let rd = f.read(&mut buf[start..])?;
+            buf.truncate(start + rd);
+            eof = rd == 0;
+        }
+        if buf.is_empty() {
+            break;
+        }
+        let length = cut_point(&buf, sizes);
+        let chunk = &buf[..length];
+        acc.update(chunk);
+        chunks.push(Chunk {
+            hash: format!("{:x}", Sha256::digest(chunk)),
+            length: length as u64,
+        });
+        buf.drain(..length);
+    }
+    Ok((format!("{:x}", acc.finalize()), chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::file_hash;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    // Smaller sizes, to check the chunking on small files too.
+    const SMALL_SIZES: ChunkSizes = ChunkSizes {
+        min_file: 128 << 10,
+        min: 4 << 10,
+        avg: 16 << 10,
+        max: 64 << 10,
+    };
+
+    // Splits the data in memory into chunks, returning their lengths.
+    fn chunk_lengths(mut data: &[u8], sizes: &ChunkSizes) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        while !data.is_empty() {
+            let length = cut_point(data, sizes);
+            lengths.push(length);
+            data = &data[length..];
+        }
+        lengths
+    }
+
+    // Deterministic pseudorandom data, so that the cut points are found by the content.
+    fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        for sizes in [ChunkSizes::default(), SMALL_SIZES] {
+            let data = random_data(4 * sizes.max, 1);
+            let lengths = chunk_lengths(&data, &sizes);
+            assert_eq!(lengths.iter().sum::<usize>(), data.len());
+            assert!(lengths.len() > 4);
+            let (last, rest) = lengths.split_last().unwrap();
+            assert!(*last <= sizes.max);
+            for length in rest {
+                assert!((sizes.min..=sizes.max).contains(length));
+            }
+            // Data without any variation is cut at the maximum size.
+            assert_eq!(
+                chunk_lengths(&vec![0; 2 * sizes.max + sizes.avg], &sizes),
+                vec![sizes.max, sizes.max, sizes.avg]
+            );
+            assert!(chunk_lengths(&[], &sizes).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_file_chunks() -> Result<()> {
+        for sizes in [ChunkSizes::default(), SMALL_SIZES] {
+            let data = random_data(3 * sizes.max, 2);
+            let mut file = NamedTempFile::new()?;
+            file.write_all(&data)?;
+            let (hash, chunks) = file_chunks(file.path(), &sizes)?;
+            assert_eq!(hash, file_hash(file.path())?);
+            // The chunks are the same as when the data is chunked in memory.
+            let lengths: Vec<_> = chunks.iter().map(|chunk| chunk.length as usize).collect();
+            assert_eq!(lengths, chunk_lengths(&data, &sizes));
+
+            // An insertion in the middle changes only the chunks around it.
+            let middle = 3 * sizes.max / 2;
+            let mut modified = data[..middle].to_vec();
+            modified.extend_from_slice(b"inserted");
+            modified.extend_from_slice(&data[middle..]);
+            let mut modified_file = NamedTempFile::new()?;
+            modified_file.write_all(&modified)?;
+            let (_, modified_chunks) = file_chunks(modified_file.path(), &sizes)?;
+            let changed = modified_chunks.iter().filter(|chunk| !chunks.contains(chunk)).count();
+            assert!(changed <= 2, "{} chunks changed", changed);
+        }
+        Ok(())
+    }
+}
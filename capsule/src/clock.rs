@@ -0,0 +1,81 @@
+//! The time source of a capsule.
+//!
+//! Capsule reads the wall clock (e.g. to touch the outputs) and the monotonic clock (to time the
+//! runs) through the `Clock` trait, rather than directly, so that tests can control the time and
+//! check the behavior depending on it precisely.
+
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::{Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    /// The wall clock time.
+    fn now(&self) -> SystemTime;
+
+    /// The monotonic time, for measuring durations.
+    fn instant(&self) -> Instant;
+}
+
+/// The real clock of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still, until it's advanced explicitly.
+#[cfg(test)]
+pub struct TestClock {
+    times: Mutex<(SystemTime, Instant)>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            times: Mutex::new((now, Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut times = self.times.lock().unwrap();
+        times.0 += duration;
+        times.1 += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        self.times.lock().unwrap().0
+    }
+
+    fn instant(&self) -> Instant {
+        self.times.lock().unwrap().1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_test_clock() {
+        let start_time = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let clock = TestClock::new(start_time);
+        let start = clock.instant();
+        assert_eq!(clock.instant(), start);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.instant() - start, Duration::from_millis(1500));
+        assert_eq!(clock.now(), start_time + Duration::from_millis(1500));
+    }
+}
@@ -7,12 +7,14 @@ use log::error;
 use regex::Regex;
 use serde::Deserialize;
 use std::collections::BTreeMap;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{env, ffi::OsString};
 use toml;
 
-use crate::workspace_path::WorkspacePath;
+use crate::iohashing::{Metadata, DEFAULT_HASH_BUFFER_KB};
+use crate::workspace_path::{WorkspacePath, WorkspaceRoots};
 
 #[derive(Debug, Derivative, PartialEq)]
 #[derivative(Default)]
@@ -30,6 +32,35 @@ pub enum Backend {
     #[derivative(Default)]
     Dummy, // No backend means dummy.
     S3,
+    None, // Caching is disabled, as in the passive mode.
+}
+
+// How the inputs hash is shown to the outside world, in `--inputs_hash` and the inputs hash variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Derivative)]
+#[derivative(Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InputsHashFormat {
+    #[derivative(Default)]
+    Hex,
+    Base64, // URL-safe base64 of the hash, without padding.
+    Short,  // First 12 hex characters.
+}
+
+impl InputsHashFormat {
+    // Render the hex sha256 inputs hash. The cache key is always the full hex hash.
+    pub fn render(&self, hash: &str) -> String {
+        match self {
+            InputsHashFormat::Hex => hash.to_owned(),
+            InputsHashFormat::Base64 => {
+                let bytes: Vec<u8> = (0..hash.len())
+                    .step_by(2)
+                    .filter_map(|i| hash.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                    .collect();
+                base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+            }
+            InputsHashFormat::Short => hash.chars().take(12).collect(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Derivative)]
@@ -41,12 +72,41 @@ pub struct Config {
     #[serde(default)]
     pub workspace_root: Option<String>,
 
+    // Named workspace roots, for paths of the form //@name/...
+    #[serde(default)]
+    pub workspace_roots: BTreeMap<String, String>,
+
+    // Name of the file marking the workspace root, if it's not given explicitly.
+    #[serde(default = "default_workspace_marker")]
+    #[derivative(Default(value = "default_workspace_marker()"))]
+    pub workspace_marker: String,
+
+    // Don't auto-detect the workspace root, e.g. to keep the cache keys of full paths unchanged.
+    #[serde(default)]
+    pub no_workspace_detection: bool,
+
     #[serde(default)]
     pub verbose: bool,
 
+    // Only log capsule's own warnings and errors, regardless of CAPSULE_LOG.
+    #[serde(default)]
+    pub quiet: bool,
+
+    // Print the time spent in each phase of the run to stderr at the end.
+    #[serde(default)]
+    pub verbose_timing: bool,
+
     #[serde(default)]
     pub passive: bool, // In the passive mode, capsule simply runs the binary, without even cache lookups etc.
 
+    // Switch to passive mode, instead of failing, if the backend cannot be created or looked up.
+    #[serde(default)]
+    pub passive_on_missing_backend: bool,
+
+    // Instead of caching, measure how much would be stored, without any network access.
+    #[serde(default)]
+    pub measure: bool,
+
     #[serde(default)]
     pub cache_failure: bool,
 
@@ -59,27 +119,197 @@ pub struct Config {
     #[serde(default)]
     pub capsule_job: Option<String>,
 
+    // Record the command producing the outputs in the cache entry.
+    #[serde(default)]
+    pub record_command: bool,
+
+    // Resolve the relative input and output patterns of a Capsule.toml section against the directory of the file.
+    #[serde(default)]
+    pub relative_to_file: bool,
+
+    // Sections of other config files to combine into a Capsule.toml section, as 'file.toml#section'.
+    #[serde(default)]
+    pub include: Vec<String>,
+
     #[serde(default)]
     #[serde(rename = "input")]
     pub input_files: Vec<WorkspacePath>,
 
+    #[serde(default)]
+    pub normalize_line_endings: bool,
+
+    // Safety valve against runaway input globs.
+    #[serde(default = "default_max_inputs")]
+    #[derivative(Default(value = "default_max_inputs()"))]
+    pub max_inputs: usize,
+
+    // Maximum size of the buffer the input and output files are read into to hash them.
+    #[serde(default = "default_hash_buffer_kb")]
+    #[derivative(Default(value = "default_hash_buffer_kb()"))]
+    pub hash_buffer_kb: usize,
+
+    // Number of threads hashing the input and output files, and the downloaded objects, on their own,
+    // rather than one by one.
+    #[serde(default = "default_hash_threads")]
+    #[derivative(Default(value = "default_hash_threads()"))]
+    pub hash_threads: usize,
+
     #[serde(default)]
     #[serde(rename = "tool_tag")]
     pub tool_tags: Vec<String>,
 
+    #[serde(default)]
+    #[serde(rename = "tool_tag_cmd")]
+    pub tool_tag_cmds: Vec<String>,
+
+    // Commands printing lists of input files, e.g. discovered dependencies.
+    #[serde(default)]
+    #[serde(rename = "input_cmd")]
+    pub input_cmds: Vec<String>,
+
+    #[serde(default)]
+    #[serde(rename = "tool_tag_file")]
+    pub tool_tag_files: Vec<WorkspacePath>,
+
+    // Use the git HEAD of the workspace as a tool tag.
+    #[serde(default)]
+    pub git_tool_tag: bool,
+
+    // Make the program of the command, found in PATH, e.g. an interpreter, an input.
+    #[serde(default)]
+    pub hash_argv0: bool,
+
+    // Name patterns of the environment variables that are snapshotted together as one tool tag.
+    #[serde(default)]
+    pub tool_tag_env: Vec<String>,
+
     #[serde(default)]
     #[serde(rename = "output")]
     pub output_files: Vec<WorkspacePath>,
 
+    #[serde(default)]
+    #[serde(rename = "negative_output")]
+    pub negative_output_files: Vec<WorkspacePath>,
+
+    #[serde(default)]
+    #[serde(rename = "exclude_output")]
+    pub exclude_output_files: Vec<WorkspacePath>,
+
+    #[serde(default)]
+    pub preserve_empty_dirs: bool,
+
+    // Mask applied to the modes of output files, when they are stored and restored.
+    #[serde(default)]
+    pub mask_output_mode: Option<u32>,
+
+    // Remove the output files created by the command, if it fails.
+    #[serde(default)]
+    pub clean_failed_outputs: bool,
+
+    // Limit on the commands run at once by all the capsules sharing the lock, e.g. by the capsules of a build.
+    #[serde(default)]
+    pub global_job_limit: Option<usize>,
+
+    // Path prefix of the lock files of the slots of the global job limit. The default one is per user, as the
+    // lock files of another user can't be opened for writing.
+    #[serde(default = "default_global_job_lock")]
+    #[derivative(Default(value = "default_global_job_lock()"))]
+    pub global_job_lock: String,
+
+    #[serde(default)]
+    pub output_manifest: Option<WorkspacePath>,
+
+    // Directory to restore the cached outputs into, under their workspace paths, never running the command.
+    #[serde(default)]
+    pub restore_to: Option<WorkspacePath>,
+
+    // File to write "hit" or "miss" into, depending on whether the command was run.
+    #[serde(default)]
+    pub hit_marker_file: Option<WorkspacePath>,
+
+    // File to write the path of each output to as soon as it's restored, for consumers to start early.
+    #[serde(default)]
+    pub ready_list: Option<WorkspacePath>,
+
+    // Shell commands to run after a cache hit is restored, or after the command is run on a miss.
+    #[serde(default)]
+    pub on_hit_exec: Option<String>,
+    #[serde(default)]
+    pub on_miss_exec: Option<String>,
+
+    // File to write the cache entry into, as JSON, after writing it or on cache hit.
+    #[serde(default)]
+    pub dump_bundle: Option<WorkspacePath>,
+
+    // File to write the inputs into, as JSON, on cache miss. "-" is stderr.
+    #[serde(default)]
+    pub log_inputs_on_miss: Option<WorkspacePath>,
+
+    // Log the objects that would be uploaded with their keys, instead of uploading them.
+    #[serde(default)]
+    pub dry_upload: bool,
+
+    // Don't write the cache entry.
+    #[serde(default)]
+    pub dry_write: bool,
+
+    // Write the cache entry, but don't upload the objects, nor log them like dry_upload.
+    #[serde(default)]
+    pub no_upload: bool,
+
+    // Look up the cache entry, but run the command on a hit, rather than download the objects.
+    #[serde(default)]
+    pub no_download: bool,
+
+    // Keep the existing cache entry if the outputs differ from it, instead of overwriting it.
+    #[serde(default)]
+    pub abort_on_output_mismatch_write: bool,
+
+    // Environment variables to set for the command, as NAME=value pairs, e.g. SOURCE_DATE_EPOCH.
+    #[serde(default)]
+    pub set_env: Vec<String>,
+
+    // Make the --set_env variables part of the inputs hash.
+    #[serde(default)]
+    pub hash_set_env: bool,
+
+    // Metadata to store with the cache entry, as key=value pairs, and JSON files with objects.
+    #[serde(default)]
+    pub attach: Vec<String>,
+
+    #[serde(default)]
+    #[serde(rename = "attach_file")]
+    pub attach_files: Vec<WorkspacePath>,
+
+    // Record the host, OS and architecture that wrote the cache entry in its metadata.
+    #[serde(default)]
+    pub include_hostname_tag: bool,
+
     #[serde(default)]
     pub capture_stdout: Option<bool>,
 
     #[serde(default)]
     pub capture_stderr: Option<bool>,
 
+    // Capture stderr to replay it, but leave it out of the outputs hash.
+    #[serde(default)]
+    pub unhashed_stderr: bool,
+
+    #[serde(default = "default_capture_inline_threshold_bytes")]
+    #[derivative(Default(value = "default_capture_inline_threshold_bytes()"))]
+    pub capture_inline_threshold_bytes: usize,
+
+    // Captured stdout/stderr are truncated to this size, they are still passed through in full.
+    #[serde(default)]
+    pub capture_max_bytes: Option<usize>,
+
     #[serde(default)]
     pub command_to_run: Vec<String>,
 
+    // Command producing the outputs on cache miss, in which case the command to run only runs on cache hit.
+    #[serde(default)]
+    pub fallback_command: Option<String>,
+
     #[serde(default)]
     pub honeycomb_token: Option<String>,
 
@@ -96,6 +326,11 @@ pub struct Config {
     #[serde(default)]
     honeycomb_kv: Vec<String>,
 
+    // Send only 1 in this many events to Honeycomb, chosen by the inputs hash.
+    #[serde(default = "default_honeycomb_sample_rate")]
+    #[derivative(Default(value = "default_honeycomb_sample_rate()"))]
+    pub honeycomb_sample_rate: u64,
+
     #[serde(default)]
     pub s3_bucket: Option<String>,
 
@@ -120,12 +355,150 @@ pub struct Config {
     #[serde(default)]
     pub s3_downloads_region: Option<String>,
 
+    // CDN in front of the objects bucket, to download the objects from.
+    #[serde(default)]
+    pub objects_cdn_url: Option<String>,
+
+    // AWS credentials: a named profile instead of the default discovery, and a role to assume.
+    #[serde(default)]
+    pub s3_profile: Option<String>,
+
+    #[serde(default)]
+    pub s3_assume_role_arn: Option<String>,
+
+    #[serde(default = "default_role_session_name")]
+    #[derivative(Default(value = "default_role_session_name()"))]
+    pub s3_role_session_name: String,
+
+    #[serde(default)]
+    pub compress_bundles: bool,
+
+    #[serde(default)]
+    pub s3_detect_content_type: bool,
+
+    // Canned ACL of the objects uploaded to s3_bucket_objects (but not of the cache entries), e.g. public-read.
+    #[serde(default)]
+    pub s3_objects_acl: Option<String>,
+
+    // Number of threads gzipping each uploaded object in blocks, rather than as a single stream.
+    #[serde(default = "default_compression_threads")]
+    #[derivative(Default(value = "default_compression_threads()"))]
+    pub compression_threads: usize,
+
+    // Check that the objects that already exist have the size of the ones being uploaded.
+    #[serde(default)]
+    pub strict_objects: bool,
+
+    // Check the existing objects like strict_objects, but only report a mismatch, without failing.
+    #[serde(default)]
+    pub verify_existing_objects: bool,
+
+    // Check that the S3 buckets can be reached before doing anything else.
+    #[serde(default)]
+    pub s3_probe: bool,
+
+    // Levels of two hex digit prefixes in the object keys. It must be the same for all users of a bucket.
+    #[serde(default = "default_object_shard_depth")]
+    #[derivative(Default(value = "default_object_shard_depth()"))]
+    pub object_shard_depth: usize,
+
+    // Dictionary to compress the cache entries with zstd, trained with 'capsule train-dict'.
+    #[serde(default)]
+    pub bundle_zstd_dict: Option<WorkspacePath>,
+
+    #[serde(default)]
+    pub force_download: bool,
+
+    #[serde(default)]
+    pub touch_outputs: bool,
+
+    #[serde(default = "default_download_retries")]
+    #[derivative(Default(value = "default_download_retries()"))]
+    pub download_retries: usize,
+
+    // Free disk space to leave on the filesystems of the outputs after restoring them on cache hit.
+    #[serde(default)]
+    pub min_free_disk_mb: Option<u64>,
+
+    // Leading path trimmed from the names of uploaded objects, e.g. the build directory.
+    #[serde(default)]
+    pub object_name_strip_prefix: Option<String>,
+
+    // Store large output files as content-defined chunks, deduplicated across versions.
+    #[serde(default)]
+    pub chunked_objects: bool,
+
+    // Limit on the size of the serialized cache entries, larger ones are not written.
+    #[serde(default)]
+    pub max_bundle_bytes: Option<usize>,
+
+    // Write the cache entries over max_bundle_bytes without the details of the inputs, rather than fail.
+    #[serde(default)]
+    pub summarize_large_bundles: bool,
+
+    #[serde(default)]
+    pub ipfs_api_url: Option<String>,
+
+    // Proxy and CA bundle for the S3 and Honeycomb clients, defaulting to HTTPS_PROXY and SSL_CERT_FILE.
+    #[serde(default)]
+    pub http_proxy: Option<String>,
+
+    // Comma separated hosts reached directly rather than through the proxy, defaulting to NO_PROXY.
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+
     #[serde(default)]
     pub inputs_hash_var: String,
 
     #[serde(default)]
     pub inputs_hash_output: bool,
 
+    // The inputs hash computed by the caller, trusted instead of hashing any inputs.
+    #[serde(default)]
+    pub inputs_hash_value: Option<String>,
+
+    #[serde(default)]
+    pub inputs_hash_format: InputsHashFormat,
+
+    // Number of times to run the capsule, for benchmarking the cache.
+    #[serde(skip)]
+    pub repeat: Option<usize>,
+
+    // Testing only: run twice with changing environment, without caching, and compare the outputs.
+    #[serde(skip)]
+    pub inject_nondeterminism: bool,
+
+    // Inputs hash of the cache entry to print with 'capsule inspect'.
+    #[serde(skip)]
+    pub inspect_hash: Option<String>,
+
+    // Check the caching backend with a round trip of a synthetic entry, instead of running a command.
+    #[serde(skip)]
+    pub selftest: bool,
+
+    // Where 'capsule train-dict' writes the dictionary trained on the existing cache entries.
+    #[serde(skip)]
+    pub train_dict_output: Option<WorkspacePath>,
+
+    // How many cache entries 'capsule train-dict' samples.
+    #[serde(skip)]
+    pub train_dict_samples: Option<usize>,
+
+    // The two dumped cache entries that 'capsule compare' compares.
+    #[serde(skip)]
+    pub compare_bundles: Option<(WorkspacePath, WorkspacePath)>,
+
+    // The backend flags of the source and the destination that 'capsule migrate' copies the entries between.
+    #[serde(skip)]
+    pub migrate: Option<(Vec<String>, Vec<String>)>,
+
+    // Only report what 'capsule migrate' would copy.
+    #[serde(skip)]
+    pub migrate_dry_run: bool,
+
     #[serde(default = "default_concurrent_download_max")]
     #[derivative(Default(value = "default_concurrent_download_max()"))]
     pub concurrent_download_max: usize,
@@ -143,28 +516,264 @@ fn default_concurrent_download_max() -> usize {
 fn default_concurrent_upload_max() -> usize {
     3
 }
+fn default_capture_inline_threshold_bytes() -> usize {
+    4096
+}
+fn default_workspace_marker() -> String {
+    ".capsule-workspace".to_owned()
+}
+fn default_global_job_lock() -> String {
+    let file_name = format!("capsule-jobs.{}.lock", nix::unistd::getuid());
+    env::temp_dir().join(file_name).to_string_lossy().into_owned()
+}
+fn default_role_session_name() -> String {
+    "capsule".to_owned()
+}
+fn default_object_shard_depth() -> usize {
+    1
+}
+fn default_max_inputs() -> usize {
+    100_000
+}
+fn default_honeycomb_sample_rate() -> u64 {
+    1
+}
+fn default_hash_buffer_kb() -> usize {
+    DEFAULT_HASH_BUFFER_KB
+}
+fn default_hash_threads() -> usize {
+    1
+}
+fn default_compression_threads() -> usize {
+    1
+}
+fn default_download_retries() -> usize {
+    1
+}
+
+// Find the workspace root by walking up from 'dir' to the first directory that contains either the
+// marker file, or a Cargo.toml with a [workspace] section.
+fn find_workspace_root(dir: &Path, marker: &str) -> Option<PathBuf> {
+    let is_cargo_workspace = |dir: &Path| {
+        let manifest = std::fs::read_to_string(dir.join("Cargo.toml"))
+            .ok()
+            .and_then(|contents| contents.parse::<toml::Value>().ok());
+        matches!(manifest, Some(manifest) if manifest.get("workspace").is_some())
+    };
+    dir.ancestors()
+        .find(|dir| dir.join(marker).is_file() || is_cargo_workspace(dir))
+        .map(Path::to_owned)
+}
+
+// Whether the host is in the comma separated NO_PROXY list, i.e. is the host, or a subdomain, of one
+// of its entries (with or without the leading dot), or the list is '*'.
+pub fn no_proxy_matches(no_proxy: &str, host: &str) -> bool {
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        let domain = entry.trim_start_matches('.');
+        entry == "*"
+            || (!domain.is_empty()
+                && (host.eq_ignore_ascii_case(domain)
+                    || (host.len() > domain.len()
+                        && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+                        && host.as_bytes()[host.len() - domain.len() - 1] == b'.')))
+    })
+}
 
 impl Config {
+    // The default and named roots that workspace relative paths are resolved against.
+    pub fn roots(&self) -> WorkspaceRoots<'_> {
+        WorkspaceRoots {
+            default: &self.workspace_root,
+            named: &self.workspace_roots,
+        }
+    }
+
     // Merge one config (e.g. Capsule.toml) into another (~/.capsules.toml)
     // It destroys the argument.
     pub fn merge(&mut self, config: &mut Self) {
         if self.capsule_id.is_none() {
             self.capsule_id = config.capsule_id.take();
         }
+        for (name, root) in std::mem::take(&mut config.workspace_roots) {
+            self.workspace_roots.entry(name).or_insert(root);
+        }
         if config.verbose {
             self.verbose = true;
         }
+        if config.quiet {
+            self.quiet = true;
+        }
+        if config.normalize_line_endings {
+            self.normalize_line_endings = true;
+        }
+        if config.preserve_empty_dirs {
+            self.preserve_empty_dirs = true;
+        }
+        if config.git_tool_tag {
+            self.git_tool_tag = true;
+        }
+        if config.hash_argv0 {
+            self.hash_argv0 = true;
+        }
         self.input_files.append(&mut config.input_files);
         self.output_files.append(&mut config.output_files);
+        self.negative_output_files.append(&mut config.negative_output_files);
+        self.exclude_output_files.append(&mut config.exclude_output_files);
         self.tool_tags.append(&mut config.tool_tags);
+        self.tool_tag_cmds.append(&mut config.tool_tag_cmds);
+        self.input_cmds.append(&mut config.input_cmds);
+        self.tool_tag_files.append(&mut config.tool_tag_files);
+        self.tool_tag_env.append(&mut config.tool_tag_env);
+        self.set_env.append(&mut config.set_env);
+        if config.hash_set_env {
+            self.hash_set_env = true;
+        }
+        self.attach.append(&mut config.attach);
+        self.attach_files.append(&mut config.attach_files);
+        if config.include_hostname_tag {
+            self.include_hostname_tag = true;
+        }
         self.capture_stdout = config.capture_stdout;
         self.capture_stderr = config.capture_stderr;
+        if config.unhashed_stderr {
+            self.unhashed_stderr = true;
+        }
         if self.honeycomb_dataset.is_none() {
             self.honeycomb_dataset = config.honeycomb_dataset.take();
         }
         if self.honeycomb_token.is_none() {
             self.honeycomb_token = config.honeycomb_token.take();
         }
+        if self.http_proxy.is_none() {
+            self.http_proxy = config.http_proxy.take();
+        }
+        if self.no_proxy.is_none() {
+            self.no_proxy = config.no_proxy.take();
+        }
+        if self.ca_bundle.is_none() {
+            self.ca_bundle = config.ca_bundle.take();
+        }
+        if self.fallback_command.is_none() {
+            self.fallback_command = config.fallback_command.take();
+        }
+        if self.on_hit_exec.is_none() {
+            self.on_hit_exec = config.on_hit_exec.take();
+        }
+        if self.on_miss_exec.is_none() {
+            self.on_miss_exec = config.on_miss_exec.take();
+        }
+    }
+
+    // Fill in what a Capsule.toml section doesn't specify from a less specific section, i.e. from
+    // a matching pattern section, or [default]. Lists are combined. It destroys the argument.
+    // This is merge, except that the section keeps its own capture options, which merge overrides.
+    fn inherit(&mut self, defaults: &mut Self) {
+        if self.capture_stdout.is_some() {
+            defaults.capture_stdout = self.capture_stdout;
+        }
+        if self.capture_stderr.is_some() {
+            defaults.capture_stderr = self.capture_stderr;
+        }
+        if defaults.relative_to_file {
+            self.relative_to_file = true;
+        }
+        self.include.append(&mut defaults.include);
+        self.merge(defaults);
+    }
+
+    // Make the relative (non workspace) input and output patterns relative to the given directory,
+    // i.e. the one of the Capsule.toml they come from, rather than to the current directory.
+    fn resolve_relative_to(&mut self, dir: &Path, roots: WorkspaceRoots) {
+        // The directory is taken literally, while the patterns are globs.
+        let dir = PathBuf::from(glob::Pattern::escape(&dir.to_string_lossy()));
+        for pattern in self
+            .input_files
+            .iter_mut()
+            .chain(self.output_files.iter_mut())
+            .chain(self.negative_output_files.iter_mut())
+            .chain(self.exclude_output_files.iter_mut())
+        {
+            if let WorkspacePath::NonWorkspace(path) = pattern {
+                if path.is_relative() {
+                    *pattern = WorkspacePath::from_full_path(&dir.join(&path), roots);
+                }
+            }
+        }
+    }
+
+    // Combine into a Capsule.toml section the sections of other config files that it includes, recursively.
+    // The included sections fill in what the including one doesn't specify, as [default] does, and it keeps
+    // its own capture options. The relative file names are resolved against the directory of the including file.
+    // Each of the included sections may set its own `relative_to_file`. The stack holds the sections being
+    // included, to reject cycles.
+    fn resolve_includes(
+        &mut self,
+        dir: &Path,
+        roots: WorkspaceRoots,
+        stack: &mut Vec<(PathBuf, String)>,
+    ) -> Result<()> {
+        for include in std::mem::take(&mut self.include) {
+            let (file, section) = include
+                .rsplit_once('#')
+                .ok_or_else(|| anyhow!("Invalid include '{}', expected 'file.toml#section'", include))?;
+            let path = dir.join(WorkspacePath::from(file).to_path(roots)?);
+            let canonical = path
+                .canonicalize()
+                .with_context(|| format!("Reading included config '{}'", path.display()))?;
+            let key = (canonical, section.to_owned());
+            if stack.contains(&key) {
+                bail!("Include cycle: '{}' includes itself", include);
+            }
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Reading included config '{}'", path.display()))?;
+            let mut included = toml::from_str::<BTreeMap<String, Config>>(&contents)
+                .with_context(|| format!("Parsing included config '{}'", path.display()))?
+                .remove(section)
+                .ok_or_else(|| anyhow!("Cannot find section '{}' in config '{}'", section, path.display()))?;
+            let included_dir = path.parent().unwrap_or_else(|| Path::new(""));
+            if included.relative_to_file {
+                included.resolve_relative_to(included_dir, roots);
+            }
+            stack.push(key);
+            included.resolve_includes(included_dir, roots, stack)?;
+            stack.pop();
+            self.inherit(&mut included);
+        }
+        Ok(())
+    }
+
+    // Remove from the Capsule.toml sections the ones applying to the given section name, and combine
+    // them into one. The exact match is the most specific, then the glob pattern sections (with more
+    // literal characters being more specific, and ties broken by name), and [default] is the least
+    // specific. There has to be either an exact or a pattern match.
+    fn take_section(dir_config: &mut BTreeMap<String, Config>, section: &str) -> Result<Option<Config>> {
+        let is_pattern = |name: &str| name.contains(['*', '?', '[']);
+        let mut patterns = Vec::new();
+        for name in dir_config
+            .keys()
+            .filter(|name| is_pattern(name) && name.as_str() != section)
+        {
+            let pattern = glob::Pattern::new(name).with_context(|| format!("Invalid section pattern '{}'", name))?;
+            if pattern.matches(section) {
+                let specificity = name.chars().filter(|c| !matches!(c, '*' | '?' | '[' | ']')).count();
+                patterns.push((std::cmp::Reverse(specificity), name.clone()));
+            }
+        }
+        patterns.sort();
+
+        let mut sections: Vec<Config> = dir_config
+            .remove(section)
+            .into_iter()
+            .chain(patterns.iter().filter_map(|(_, name)| dir_config.remove(name)))
+            .collect();
+        if sections.is_empty() {
+            return Ok(None);
+        }
+        let mut result = sections.remove(0);
+        for mut less_specific in sections.into_iter().chain(dir_config.remove("default")) {
+            result.inherit(&mut less_specific);
+        }
+        Ok(Some(result))
     }
 
     pub fn new<I, T>(cmdline_args: I, default_toml: Option<&Path>) -> Result<Self>
@@ -193,22 +802,51 @@ impl Config {
                     .takes_value(true)
                     .multiple_occurrences(false),
             )
+            .arg(
+                Arg::new("capsule_id_file")
+                    .help("File containing the ID of the capsule, if it's not given with -c")
+                    .long("capsule_id_file")
+                    .takes_value(true)
+                    .multiple_occurrences(false),
+            )
             .arg(
                 Arg::new("file")
-                    .help("Location of the Capsules.toml file")
+                    .help("Location of the Capsules.toml file ('-' for stdin)")
                     .short('f')
                     .long("file")
                     .takes_value(true)
+                    .allow_hyphen_values(true)
                     .multiple_occurrences(false),
             )
+            .arg(
+                Arg::new("config_inline")
+                    .help("Contents of the Capsules.toml file, instead of --file")
+                    .long("config_inline")
+                    .takes_value(true)
+                    .multiple_occurrences(false)
+                    .conflicts_with("file"),
+            )
             .arg(
                 Arg::new("workspace_root")
-                    .help("Workspace root for paths starting with a double slash")
+                    .help("Workspace root for paths starting with a double slash, or name=/path for //@name/ paths")
                     .short('w')
                     .long("workspace_root")
                     .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("workspace_marker")
+                    .help("Marker file to auto-detect the workspace root by, if it's not specified")
+                    .long("workspace_marker")
+                    .takes_value(true)
                     .multiple_occurrences(false),
             )
+            .arg(
+                Arg::new("no_workspace_detection")
+                    .help("Don't auto-detect the workspace root, if it's not specified")
+                    .long("no_workspace_detection")
+                    .takes_value(false),
+            )
             .arg(
                 Arg::new("capsule_job")
                     .help("The ID of the capsule job")
@@ -217,6 +855,12 @@ impl Config {
                     .takes_value(true)
                     .multiple_occurrences(false),
             )
+            .arg(
+                Arg::new("record_command")
+                    .help("Record the command in the cache entry, to be shown by 'capsule inspect'")
+                    .long("record_command")
+                    .takes_value(false),
+            )
             .arg(
                 Arg::new("input")
                     .help("Input file")
@@ -226,33 +870,277 @@ impl Config {
                     .multiple_occurrences(true),
             )
             .arg(
-                Arg::new("tool_tag")
-                    .help("Tool tag (compiler version, docker image sha, etc.)")
-                    .short('t')
-                    .long("tool_tag")
+                Arg::new("input_list0")
+                    .help("File with a NUL-delimited list of input files")
+                    .long("input_list0")
                     .takes_value(true)
                     .multiple_occurrences(true),
             )
             .arg(
-                Arg::new("output")
-                    .help("Output file")
-                    .short('o')
-                    .long("output")
+                Arg::new("input_cmd")
+                    .help("Command printing a newline or NUL-delimited list of input files")
+                    .long("input_cmd")
                     .takes_value(true)
                     .multiple_occurrences(true),
             )
             .arg(
-                Arg::new("capture_stdout")
-                    .help("Capture stdout with the cached bundle")
-                    .long("capture_stdout")
+                Arg::new("normalize_line_endings")
+                    .help("Hash text input files with line endings normalized to LF")
+                    .long("normalize_line_endings")
                     .takes_value(false),
             )
             .arg(
-                Arg::new("capture_stderr")
-                    .help("Capture stderr with the cached bundle")
+                Arg::new("max_inputs")
+                    .help("Fail if the inputs expand to more than this number of files (default: 100000)")
+                    .long("max_inputs")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("hash_buffer_kb")
+                    .help("Read the files to hash with a buffer of up to this size in KiB (default: 4)")
+                    .long("hash_buffer_kb")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("hash_threads")
+                    .help("Hash the input and output files with this many threads (default: 1)")
+                    .long("hash_threads")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("tool_tag")
+                    .help("Tool tag (compiler version, docker image sha, etc.)")
+                    .short('t')
+                    .long("tool_tag")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("tool_tag_cmd")
+                    .help("Command whose stdout is used as a tool tag (e.g. 'rustc --version')")
+                    .long("tool_tag_cmd")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("git_tool_tag")
+                    .help("Use the git HEAD commit of the workspace, and whether it's dirty, as a tool tag")
+                    .long("git_tool_tag")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("hash_argv0")
+                    .help("Make the program of the command, as found in PATH (e.g. an interpreter), an input")
+                    .long("hash_argv0")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("tool_tag_file")
+                    .help("File whose content hash is used as a tool tag (e.g. a toolchain lockfile)")
+                    .long("tool_tag_file")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("tool_tag_env")
+                    .help("Env var name patterns, whose matches are used as one tool tag (e.g. 'CARGO_*,RUST*')")
+                    .long("tool_tag_env")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("output")
+                    .help("Output file")
+                    .short('o')
+                    .long("output")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("negative_output")
+                    .help("Output file which may legitimately be absent, in which case it is removed on cache hit")
+                    .long("negative_output")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("exclude_output")
+                    .help("Pattern of files matched by output patterns, which are not cached")
+                    .long("exclude_output")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("preserve_empty_dirs")
+                    .help("Cache the empty directories matched by output patterns, and recreate them on cache hit")
+                    .long("preserve_empty_dirs")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("set_env")
+                    .long("set_env")
+                    .help("Environment variable NAME=value to set for the command only")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("hash_set_env")
+                    .long("hash_set_env")
+                    .help("Make the --set_env variables part of the inputs hash")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("attach")
+                    .long("attach")
+                    .help("Metadata key=value to store with the cache entry")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("attach_file")
+                    .long("attach_file")
+                    .help("JSON file with a metadata object to store with the cache entry")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .arg(
+                Arg::new("include_hostname_tag")
+                    .long("include_hostname_tag")
+                    .help("Record the host, OS and architecture writing the cache entry in its metadata")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("mask_output_mode")
+                    .long("mask_output_mode")
+                    .help("Octal mask to apply to the modes of output files, e.g. 0777")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("global_job_limit")
+                    .long("global_job_limit")
+                    .help("Maximum number of commands run at once by all the capsules sharing --global_job_lock")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("global_job_lock")
+                    .long("global_job_lock")
+                    .help("Path prefix of the lock files for --global_job_limit")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("clean_failed_outputs")
+                    .long("clean_failed_outputs")
+                    .help("Remove the output files that a failed command created, keeping the ones that existed before")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("output_manifest")
+                    .long("output_manifest")
+                    .help("Write a JSON manifest of output files and their hashes to the given path")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("restore_to")
+                    .long("restore_to")
+                    .help("Restore the cached outputs under this directory instead, and fail on cache miss")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("ready_list")
+                    .long("ready_list")
+                    .help("On cache hit, write the path of each output file to this file as soon as it's restored")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("hit_marker_file")
+                    .long("hit_marker_file")
+                    .help("Write 'hit' or 'miss' to the given path, depending on whether the command was run")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("on_hit_exec")
+                    .long("on_hit_exec")
+                    .help("Shell command to run after the outputs are restored from the cache")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("on_miss_exec")
+                    .long("on_miss_exec")
+                    .help("Shell command to run after the command is run on cache miss")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("dump_bundle")
+                    .long("dump_bundle")
+                    .help("Write the cache entry as JSON to the given path")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("log_inputs_on_miss")
+                    .long("log_inputs_on_miss")
+                    .help("On cache miss, write the inputs as JSON to the given path, or '-' for stderr")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("dry_upload")
+                    .long("dry_upload")
+                    .help("Log the object keys of the outputs instead of uploading them")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("dry_write")
+                    .long("dry_write")
+                    .help("Don't write the cache entry")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("no_upload")
+                    .long("no_upload")
+                    .help("Write the cache entry, but don't upload the output objects")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("no_download")
+                    .long("no_download")
+                    .help("Run the command on cache hit too, rather than download the output objects")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("abort_on_output_mismatch_write")
+                    .long("abort_on_output_mismatch_write")
+                    .help("Don't overwrite the cache entry when non-determinism is detected")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("capture_stdout")
+                    .help("Capture stdout with the cached bundle")
+                    .long("capture_stdout")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("capture_stderr")
+                    .help("Capture stderr with the cached bundle")
                     .long("capture_stderr")
                     .takes_value(false),
             )
+            .arg(
+                Arg::new("unhashed_stderr")
+                    .help("Capture stderr with the cached bundle, but not as part of the outputs hash")
+                    .long("unhashed_stderr")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("capture_inline_threshold_bytes")
+                    .help("Captured stdout/stderr larger than this are stored as objects")
+                    .long("capture_inline_threshold_bytes")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("capture_max_bytes")
+                    .help("Captured stdout/stderr are truncated to this size")
+                    .long("capture_max_bytes")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new("verbose")
                     .help("Verbose output")
@@ -260,6 +1148,19 @@ impl Config {
                     .long("verbose")
                     .takes_value(false),
             )
+            .arg(
+                Arg::new("verbose_timing")
+                    .help("Print a table of the time spent in each phase of the run to stderr")
+                    .long("verbose_timing")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("quiet")
+                    .help("Only log capsule's own warnings and errors")
+                    .short('q')
+                    .long("quiet")
+                    .takes_value(false),
+            )
             .arg(
                 Arg::new("placebo")
                     .help("Placebo mode")
@@ -273,6 +1174,18 @@ impl Config {
                     .long("passive")
                     .takes_value(false),
             )
+            .arg(
+                Arg::new("measure")
+                    .help("Always execute, and report how much would be stored in the cache, without storing it")
+                    .long("measure")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("passive_on_missing_backend")
+                    .help("Fall back to passive mode if the caching backend is unavailable")
+                    .long("passive_on_missing_backend")
+                    .takes_value(false),
+            )
             .arg(
                 Arg::new("cache_failure")
                     .help("Use cached failures")
@@ -283,7 +1196,7 @@ impl Config {
                     .short('b')
                     .long("backend")
                     .help("which backend to use")
-                    .possible_values(&["dummy", "s3"]),
+                    .possible_values(&["dummy", "s3", "none"]),
             )
             .arg(
                 Arg::new("honeycomb_dataset")
@@ -316,6 +1229,12 @@ impl Config {
                     .takes_value(true)
                     .multiple_occurrences(true),
             )
+            .arg(
+                Arg::new("honeycomb_sample_rate")
+                    .long("honeycomb_sample_rate")
+                    .help("Send 1 in N events to Honeycomb, chosen by the inputs hash (default: 1)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new("s3_bucket")
                     .long("s3_bucket")
@@ -364,6 +1283,156 @@ impl Config {
                     .help("S3 downloads region")
                     .takes_value(true),
             )
+            .arg(
+                Arg::new("objects_cdn_url")
+                    .long("objects_cdn_url")
+                    .help("URL of a CDN serving the objects bucket, to download the objects from")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("s3_profile")
+                    .long("s3_profile")
+                    .help("AWS profile for the S3 credentials")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("s3_assume_role_arn")
+                    .long("s3_assume_role_arn")
+                    .help("ARN of the AWS role to assume for S3 access")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("s3_role_session_name")
+                    .long("s3_role_session_name")
+                    .help("Session name when assuming the AWS role (default: capsule)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("compress_bundles")
+                    .long("compress_bundles")
+                    .help("Gzip the cache entries written to the keys bucket")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("s3_detect_content_type")
+                    .long("s3_detect_content_type")
+                    .help("Upload objects with the content type of the original file, and gzip content encoding")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("s3_objects_acl")
+                    .long("s3_objects_acl")
+                    .help("Canned ACL of the uploaded objects, e.g. public-read, the cache entries stay private")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("compression_threads")
+                    .long("compression_threads")
+                    .help("Gzip each uploaded object in blocks with this many threads (default: 1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("s3_probe")
+                    .long("s3_probe")
+                    .help("Check that the S3 buckets can be reached before running the capsule")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("strict_objects")
+                    .long("strict_objects")
+                    .help("Fail uploads of objects that already exist with a different size")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("verify_existing_objects")
+                    .long("verify_existing_objects")
+                    .help("Report the objects that already exist with a different size than the ones being uploaded")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("object_shard_depth")
+                    .long("object_shard_depth")
+                    .help("Levels of hash prefixes in the object keys (default: 1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("bundle_zstd_dict")
+                    .long("bundle_zstd_dict")
+                    .help("Compress the cache entries with zstd, using the given dictionary")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("force_download")
+                    .long("force_download")
+                    .help("On cache hit, download output files even if they are already up to date")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("touch_outputs")
+                    .long("touch_outputs")
+                    .help("On cache hit, update the mtime of output files that are already up to date")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("chunked_objects")
+                    .long("chunked_objects")
+                    .help("Store large output files as content-defined chunks")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("max_bundle_bytes")
+                    .long("max_bundle_bytes")
+                    .help("Don't write the cache entries larger than this many bytes of JSON")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("summarize_large_bundles")
+                    .long("summarize_large_bundles")
+                    .help("Drop the details of the inputs from the cache entries over --max_bundle_bytes")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("object_name_strip_prefix")
+                    .long("object_name_strip_prefix")
+                    .help("Leading path to trim from the names of uploaded objects")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("download_retries")
+                    .long("download_retries")
+                    .help("Times to retry downloading an output file with a mismatching hash (default: 1)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("min_free_disk_mb")
+                    .long("min_free_disk_mb")
+                    .help("Don't restore the outputs from the cache, unless this many MB remain free")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("ipfs_api_url")
+                    .long("ipfs_api_url")
+                    .help("IPFS node HTTP API URL, to store objects in IPFS")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("http_proxy")
+                    .long("http_proxy")
+                    .help("HTTP(S) proxy URL for S3 and Honeycomb (default: $HTTPS_PROXY)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("no_proxy")
+                    .long("no_proxy")
+                    .help("Comma separated hosts to reach without the proxy (default: $NO_PROXY)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("ca_bundle")
+                    .long("ca_bundle")
+                    .help("PEM file with extra CA certificates for S3 and Honeycomb (default: $SSL_CERT_FILE)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::new("inputs_hash_var")
                     .long("inputs_hash_var")
@@ -377,7 +1446,120 @@ impl Config {
                     .help("Output the hash value to stdout, no cache lookup, storage, or execution")
                     .takes_value(false),
             )
-            .arg(Arg::new("command_to_run").last(true));
+            .arg(
+                Arg::new("inputs_hash_value")
+                    .long("inputs_hash_value")
+                    .help("Use this hex SHA256 as the inputs hash, instead of hashing any inputs")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("inputs_hash_format")
+                    .long("inputs_hash_format")
+                    .help("Format of the inputs hash in the output and the variable")
+                    .possible_values(["hex", "base64", "short"]),
+            )
+            .arg(
+                Arg::new("repeat")
+                    .long("repeat")
+                    .help("Run N times, removing the outputs in between, and report cache hits and timings")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("inject_nondeterminism")
+                    .long("inject_nondeterminism")
+                    .help("Testing only: run twice with a changing environment, without caching, and compare outputs")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("selftest")
+                    .long("selftest")
+                    .help("Check that the caching backend can store and return a synthetic entry, and exit")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::new("fallback_command")
+                    .long("fallback_command")
+                    .help("Command producing the outputs on cache miss, the command to run then runs on cache hit")
+                    .takes_value(true),
+            )
+            .arg(Arg::new("command_to_run").last(true))
+            .subcommand(
+                App::new("inspect")
+                    .about("Print the cache entry for the given inputs hash")
+                    .arg(
+                        Arg::new("hash")
+                            .long("hash")
+                            .help("Inputs hash of the cache entry")
+                            .takes_value(true)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                App::new("train-dict")
+                    .about("Train a zstd dictionary for --bundle_zstd_dict on the cache entries in the keys bucket")
+                    .arg(
+                        Arg::new("output")
+                            .long("output")
+                            .help("Path to write the dictionary to")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::new("samples")
+                            .long("samples")
+                            .help("Number of cache entries to train on (default: 1000)")
+                            .takes_value(true),
+                    ),
+            )
+            .subcommand(
+                App::new("compare")
+                    .about("Print the differences between two cache entries dumped with --dump_bundle")
+                    .arg(
+                        Arg::new("a")
+                            .long("a")
+                            .help("Path of the old cache entry")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::new("b")
+                            .long("b")
+                            .help("Path of the new cache entry")
+                            .takes_value(true)
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                App::new("migrate")
+                    .about("Copy the cache entries of a capsule_id, and the objects they reference, to another backend")
+                    .arg(
+                        Arg::new("from")
+                            .long("from")
+                            .help("Backend flags of the source, e.g. '--s3_bucket old' (default: the configured backend)")
+                            .takes_value(true)
+                            .allow_hyphen_values(true),
+                    )
+                    .arg(
+                        Arg::new("to")
+                            .long("to")
+                            .help("Backend flags of the destination, e.g. '--s3_bucket new --s3_bucket_objects new-objects'")
+                            .takes_value(true)
+                            .allow_hyphen_values(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::new("prefix")
+                            .long("prefix")
+                            .help("The capsule_id of the cache entries to copy")
+                            .takes_value(true)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::new("dry_run")
+                            .long("dry_run")
+                            .help("Only report the number and the size of the entries and objects to copy"),
+                    ),
+            );
 
         // Look at the first element of command line, to find and remember argv[0].
 
@@ -410,11 +1592,28 @@ impl Config {
         // First pass over command line args to find
         // 'file', 'capsule_id', and 'workspace_root' arguments.
         let mut config_file: Option<WorkspacePath> = None;
+        let mut config_inline: Option<String> = None;
         let mut config_section: Option<String> = None;
+        let mut capsule_id_file: Option<WorkspacePath> = None;
         for matches in &match_sources {
             // 'file' could be a workspace relative path, so figure out the root first.
-            if let Some(value) = matches.value_of("workspace_root") {
-                config.workspace_root = Some(value.into());
+            for value in matches.values_of("workspace_root").into_iter().flatten() {
+                lazy_static! {
+                    static ref NAMED_ROOT_RE: Regex = Regex::new(r"^([a-zA-Z0-9_-]+)=(.+)$").unwrap();
+                }
+                // Paths containing '=' that don't start with a name are still the default root.
+                match NAMED_ROOT_RE.captures(value) {
+                    Some(caps) => {
+                        config.workspace_roots.insert(caps[1].into(), caps[2].into());
+                    }
+                    None => config.workspace_root = Some(value.into()),
+                }
+            }
+            if let Some(value) = matches.value_of("workspace_marker") {
+                config.workspace_marker = value.into();
+            }
+            if matches.is_present("no_workspace_detection") {
+                config.no_workspace_detection = true;
             }
             if let Some(file) = matches.value_of("file") {
                 lazy_static! {
@@ -430,20 +1629,54 @@ impl Config {
                 if let Some(section) = caps.get(2) {
                     config_section = Some(section.as_str().into());
                 }
+                config_inline = None;
+            }
+            if let Some(contents) = matches.value_of("config_inline") {
+                config_inline = Some(contents.to_owned());
+                config_file = None;
             }
             if let Some(capsule_id) = matches.value_of("capsule_id") {
                 config.capsule_id = Some(capsule_id.to_owned());
-            } else if matches.is_present("inputs_hash") || matches.is_present("passive") {
-                // For --inputs_hash, or --passive, capsule_id doesn't matter, so let's just silence
-                // the check below.
+            } else if matches.is_present("inputs_hash")
+                || matches.is_present("passive")
+                || matches.is_present("selftest")
+                || matches.subcommand_matches("train-dict").is_some()
+                || matches.subcommand_matches("compare").is_some()
+                || matches.value_of("backend") == Some("none")
+            {
+                // For --inputs_hash, --passive, --selftest, train-dict, compare, or --backend none, capsule_id
+                // doesn't matter, so let's just silence the check below.
                 config.capsule_id = Some("-".to_owned());
             }
+            // For migrate, the capsule_id is the prefix of the entries to copy.
+            if let Some(migrate) = matches.subcommand_matches("migrate") {
+                config.capsule_id = migrate.value_of("prefix").map(Into::into);
+            }
+            if let Some(file) = matches.value_of("capsule_id_file") {
+                capsule_id_file = Some(file.into());
+            }
+        }
+
+        // Without an explicit workspace root, look for it up from the current directory.
+        if config.workspace_root.is_none() && !config.no_workspace_detection {
+            let cwd = env::current_dir().context("Getting current directory")?;
+            config.workspace_root =
+                find_workspace_root(&cwd, &config.workspace_marker).map(|root| root.to_string_lossy().into_owned());
         }
 
         // Read the main TOML (usually from Capsule.toml in the current directory).
+        // It can also be given inline, or read from stdin with '-f -'.
         let mut dir_config: BTreeMap<String, Config> = BTreeMap::new();
-        if let Some(config_file) = config_file.as_ref() {
-            if let Ok(contents) = std::fs::read_to_string(config_file.to_path(&config.workspace_root)?) {
+        if let Some(contents) = config_inline.as_ref() {
+            dir_config = toml::from_str::<BTreeMap<String, Config>>(contents).context("Parsing --config_inline")?;
+        } else if config_file == Some(WorkspacePath::from("-")) {
+            let mut contents = String::new();
+            std::io::stdin()
+                .read_to_string(&mut contents)
+                .context("Reading config from stdin")?;
+            dir_config = toml::from_str::<BTreeMap<String, Config>>(&contents).context("Parsing config from stdin")?;
+        } else if let Some(config_file) = config_file.as_ref() {
+            if let Ok(contents) = std::fs::read_to_string(config_file.to_path(config.roots())?) {
                 dir_config = toml::from_str::<BTreeMap<String, Config>>(&contents)?;
             }
         }
@@ -452,11 +1685,21 @@ impl Config {
         for matches in &match_sources {
             if let Some(capsule_id) = matches.value_of("capsule_id") {
                 config.capsule_id = Some(capsule_id.to_owned());
-            } else if matches.is_present("inputs_hash") || matches.is_present("passive") {
-                // For --inputs_hash, or --passive, capsule_id doesn't matter, so let's just silence
-                // the check below.
+            } else if matches.is_present("inputs_hash")
+                || matches.is_present("passive")
+                || matches.is_present("selftest")
+                || matches.subcommand_matches("train-dict").is_some()
+                || matches.subcommand_matches("compare").is_some()
+                || matches.value_of("backend") == Some("none")
+            {
+                // For --inputs_hash, --passive, --selftest, train-dict, compare, or --backend none, capsule_id
+                // doesn't matter, so let's just silence the check below.
                 config.capsule_id = Some("-".to_owned());
             }
+            // For migrate, the capsule_id is the prefix of the entries to copy.
+            if let Some(migrate) = matches.subcommand_matches("migrate") {
+                config.capsule_id = migrate.value_of("prefix").map(Into::into);
+            }
         }
 
         // If still no capsule_id, maybe we have a config_section defined? Then we'll use this
@@ -467,6 +1710,20 @@ impl Config {
             }
         }
 
+        // The build system may have written the capsule_id into a file.
+        if config.capsule_id.is_none() {
+            if let Some(file) = capsule_id_file {
+                let path = file.to_path(config.roots())?;
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Reading capsule ID file '{}'", path.display()))?;
+                let capsule_id = contents.trim_end();
+                if capsule_id.is_empty() {
+                    bail!("Capsule ID file '{}' is empty", path.display());
+                }
+                config.capsule_id = Some(capsule_id.to_owned());
+            }
+        }
+
         // Finally, if there's only one entry in Capsules.toml, it is implied,
         // and we don't have to specify the -c flag.
         if config.capsule_id.is_none() {
@@ -487,13 +1744,35 @@ impl Config {
 
         // Now finally merge the correct section of the config file.
         if dir_config.len() > 0 {
-            if let Some(mut single_config) = dir_config.remove(config_section) {
+            if let Some(mut single_config) = Self::take_section(&mut dir_config, config_section)? {
+                let path = config_file
+                    .as_ref()
+                    .filter(|file| **file != WorkspacePath::from("-"))
+                    .map(|file| file.to_path(config.roots()))
+                    .transpose()?;
+                let dir = path
+                    .as_ref()
+                    .and_then(|path| path.parent())
+                    .unwrap_or_else(|| Path::new(""));
+                if single_config.relative_to_file {
+                    if path.is_none() {
+                        bail!("relative_to_file is only supported in config files");
+                    }
+                    single_config.resolve_relative_to(dir, config.roots());
+                }
+                // The includes of an inline config, or one from stdin, are relative to the current directory.
+                let mut stack: Vec<_> = path
+                    .iter()
+                    .filter_map(|path| path.canonicalize().ok())
+                    .map(|path| (path, config_section.clone()))
+                    .collect();
+                single_config.resolve_includes(dir, config.roots(), &mut stack)?;
                 config.merge(&mut single_config);
             } else {
                 bail!(
                     "Cannot find section '{}' in config '{}'",
                     config_section,
-                    config_file.unwrap()
+                    config_file.map_or_else(|| "<inline>".to_owned(), |file| file.to_string())
                 );
             }
         }
@@ -506,27 +1785,209 @@ impl Config {
             if let Some(inputs) = matches.values_of("input") {
                 config.input_files.extend(inputs.map(Into::into));
             }
+            if let Some(input_lists) = matches.values_of("input_list0") {
+                for input_list in input_lists {
+                    config.input_files.extend(Self::read_input_list0(input_list)?);
+                }
+            }
+            if matches.is_present("normalize_line_endings") {
+                config.normalize_line_endings = true;
+            }
+            if let Some(value) = matches.value_of("max_inputs") {
+                config.max_inputs = value.parse().context("Invalid --max_inputs value")?;
+            }
+            if let Some(value) = matches.value_of("hash_buffer_kb") {
+                config.hash_buffer_kb = value.parse().context("Invalid --hash_buffer_kb value")?;
+            }
+            if let Some(value) = matches.value_of("hash_threads") {
+                config.hash_threads = value.parse().context("Invalid --hash_threads value")?;
+            }
             if let Some(tool_tags) = matches.values_of("tool_tag") {
                 config.tool_tags.extend(tool_tags.map(|x| x.to_owned()));
             }
+            if let Some(input_cmds) = matches.values_of("input_cmd") {
+                config.input_cmds.extend(input_cmds.map(|x| x.to_owned()));
+            }
+            if let Some(tool_tag_cmds) = matches.values_of("tool_tag_cmd") {
+                config.tool_tag_cmds.extend(tool_tag_cmds.map(|x| x.to_owned()));
+            }
+            if matches.is_present("git_tool_tag") {
+                config.git_tool_tag = true;
+            }
+            if matches.is_present("hash_argv0") {
+                config.hash_argv0 = true;
+            }
+            if let Some(tool_tag_files) = matches.values_of("tool_tag_file") {
+                config.tool_tag_files.extend(tool_tag_files.map(Into::into));
+            }
+            if let Some(tool_tag_env) = matches.values_of("tool_tag_env") {
+                config.tool_tag_env.extend(
+                    tool_tag_env
+                        .flat_map(|x| x.split(','))
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.to_owned()),
+                );
+            }
             if let Some(outputs) = matches.values_of("output") {
                 config.output_files.extend(outputs.map(Into::into));
             }
+            if let Some(outputs) = matches.values_of("negative_output") {
+                config.negative_output_files.extend(outputs.map(Into::into));
+            }
+            if let Some(excludes) = matches.values_of("exclude_output") {
+                config.exclude_output_files.extend(excludes.map(Into::into));
+            }
+            if matches.is_present("preserve_empty_dirs") {
+                config.preserve_empty_dirs = true;
+            }
+            if let Some(values) = matches.values_of("set_env") {
+                config.set_env.extend(values.map(|x| x.to_owned()));
+            }
+            if matches.is_present("hash_set_env") {
+                config.hash_set_env = true;
+            }
+            if let Some(values) = matches.values_of("attach") {
+                config.attach.extend(values.map(|x| x.to_owned()));
+            }
+            if let Some(files) = matches.values_of("attach_file") {
+                config.attach_files.extend(files.map(Into::into));
+            }
+            if matches.is_present("include_hostname_tag") {
+                config.include_hostname_tag = true;
+            }
+            if let Some(value) = matches.value_of("mask_output_mode") {
+                let octal = value.strip_prefix("0o").unwrap_or(value);
+                config.mask_output_mode =
+                    Some(u32::from_str_radix(octal, 8).context("Invalid --mask_output_mode value, must be octal")?);
+            }
+            if let Some(value) = matches.value_of("global_job_limit") {
+                let limit = value.parse().context("Invalid --global_job_limit value")?;
+                if limit == 0 {
+                    bail!("--global_job_limit must be positive");
+                }
+                config.global_job_limit = Some(limit);
+            }
+            if let Some(value) = matches.value_of("global_job_lock") {
+                config.global_job_lock = value.into();
+            }
+            if matches.is_present("clean_failed_outputs") {
+                config.clean_failed_outputs = true;
+            }
+            if let Some(value) = matches.value_of("output_manifest") {
+                config.output_manifest = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("restore_to") {
+                config.restore_to = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("ready_list") {
+                config.ready_list = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("hit_marker_file") {
+                config.hit_marker_file = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("on_hit_exec") {
+                config.on_hit_exec = Some(value.to_owned());
+            }
+            if let Some(value) = matches.value_of("on_miss_exec") {
+                config.on_miss_exec = Some(value.to_owned());
+            }
+            if let Some(value) = matches.value_of("dump_bundle") {
+                config.dump_bundle = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("log_inputs_on_miss") {
+                config.log_inputs_on_miss = Some(value.into());
+            }
+            if matches.is_present("dry_upload") {
+                config.dry_upload = true;
+            }
+            if matches.is_present("dry_write") {
+                config.dry_write = true;
+            }
+            if matches.is_present("no_upload") {
+                config.no_upload = true;
+            }
+            if matches.is_present("no_download") {
+                config.no_download = true;
+            }
+            if matches.is_present("abort_on_output_mismatch_write") {
+                config.abort_on_output_mismatch_write = true;
+            }
             if matches.is_present("capture_stdout") {
                 config.capture_stdout = Some(true);
             }
             if matches.is_present("capture_stderr") {
                 config.capture_stderr = Some(true);
             }
+            if matches.is_present("unhashed_stderr") {
+                config.unhashed_stderr = true;
+            }
+            if let Some(value) = matches.value_of("capture_inline_threshold_bytes") {
+                config.capture_inline_threshold_bytes = value
+                    .parse()
+                    .context("Invalid --capture_inline_threshold_bytes value")?;
+            }
+            if let Some(value) = matches.value_of("capture_max_bytes") {
+                config.capture_max_bytes = Some(value.parse().context("Invalid --capture_max_bytes value")?);
+            }
             if matches.is_present("verbose") {
                 config.verbose = true;
             }
+            if matches.is_present("quiet") {
+                config.quiet = true;
+            }
+            if matches.is_present("verbose_timing") {
+                config.verbose_timing = true;
+            }
+            if matches.is_present("measure") {
+                config.measure = true;
+            }
             if matches.is_present("passive") {
                 config.passive = true;
             }
+            if matches.is_present("passive_on_missing_backend") {
+                config.passive_on_missing_backend = true;
+            }
             if matches.is_present("inputs_hash") {
                 config.inputs_hash_output = true;
             }
+            if let Some(value) = matches.value_of("inputs_hash_value") {
+                config.inputs_hash_value = Some(value.to_owned());
+            }
+            if let Some(value) = matches.value_of("repeat") {
+                let repeat = value.parse().context("Invalid --repeat value")?;
+                if repeat == 0 {
+                    bail!("--repeat must be at least 1");
+                }
+                config.repeat = Some(repeat);
+            }
+            if matches.is_present("inject_nondeterminism") {
+                config.inject_nondeterminism = true;
+            }
+            if matches.is_present("selftest") {
+                config.selftest = true;
+            }
+            if let Some(inspect) = matches.subcommand_matches("inspect") {
+                config.inspect_hash = inspect.value_of("hash").map(Into::into);
+            }
+            if let Some(train_dict) = matches.subcommand_matches("train-dict") {
+                config.train_dict_output = train_dict.value_of("output").map(Into::into);
+                if let Some(value) = train_dict.value_of("samples") {
+                    config.train_dict_samples = Some(value.parse().context("Invalid --samples value")?);
+                }
+            }
+            if let Some(compare) = matches.subcommand_matches("compare") {
+                if let (Some(a), Some(b)) = (compare.value_of("a"), compare.value_of("b")) {
+                    config.compare_bundles = Some((a.into(), b.into()));
+                }
+            }
+            if let Some(migrate) = matches.subcommand_matches("migrate") {
+                let flags = |name| -> Result<Vec<String>> {
+                    let flags = migrate.value_of(name).unwrap_or_default();
+                    shell_words::split(flags).with_context(|| format!("Parsing --{} flags '{}'", name, flags))
+                };
+                config.migrate = Some((flags("from")?, flags("to")?));
+                config.migrate_dry_run = migrate.is_present("dry_run");
+            }
             if matches.is_present("placebo") {
                 config.milestone = Milestone::Placebo;
             }
@@ -536,12 +1997,22 @@ impl Config {
             if let Some(capsule_job) = matches.value_of("capsule_job") {
                 config.capsule_job = Some(capsule_job.to_owned());
             }
+            if matches.is_present("record_command") {
+                config.record_command = true;
+            }
             if let Some(command) = matches.values_of("command_to_run") {
                 config.command_to_run = command.map(|x| x.to_owned()).collect();
             }
+            if let Some(fallback_command) = matches.value_of("fallback_command") {
+                config.fallback_command = Some(fallback_command.to_owned());
+            }
             if let Some(backend) = matches.value_of("backend") {
                 if backend == "s3" {
                     config.backend = Backend::S3;
+                } else if backend == "none" {
+                    // Nothing is looked up or written, but the inputs hash is still exported.
+                    config.backend = Backend::None;
+                    config.passive = true;
                 }
             }
             if let Some(value) = matches.value_of("honeycomb_dataset") {
@@ -559,6 +2030,9 @@ impl Config {
             if let Some(values) = matches.values_of("honeycomb_kv") {
                 config.honeycomb_kv.extend(values.map(|x| x.to_owned()));
             }
+            if let Some(value) = matches.value_of("honeycomb_sample_rate") {
+                config.honeycomb_sample_rate = value.parse().context("Invalid --honeycomb_sample_rate value")?;
+            }
             if let Some(value) = matches.value_of("s3_bucket") {
                 config.s3_bucket = Some(value.into());
             }
@@ -583,18 +2057,149 @@ impl Config {
             if let Some(value) = matches.value_of("s3_downloads_endpoint") {
                 config.s3_downloads_endpoint = Some(value.into());
             }
+            if let Some(value) = matches.value_of("objects_cdn_url") {
+                config.objects_cdn_url = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("s3_profile") {
+                config.s3_profile = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("s3_assume_role_arn") {
+                config.s3_assume_role_arn = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("s3_role_session_name") {
+                config.s3_role_session_name = value.into();
+            }
+            if matches.is_present("compress_bundles") {
+                config.compress_bundles = true;
+            }
+            if matches.is_present("s3_detect_content_type") {
+                config.s3_detect_content_type = true;
+            }
+            if let Some(value) = matches.value_of("s3_objects_acl") {
+                config.s3_objects_acl = Some(value.to_owned());
+            }
+            if let Some(value) = matches.value_of("compression_threads") {
+                config.compression_threads = value.parse().context("Invalid --compression_threads value")?;
+            }
+            if matches.is_present("s3_probe") {
+                config.s3_probe = true;
+            }
+            if matches.is_present("strict_objects") {
+                config.strict_objects = true;
+            }
+            if matches.is_present("verify_existing_objects") {
+                config.verify_existing_objects = true;
+            }
+            if let Some(value) = matches.value_of("object_shard_depth") {
+                config.object_shard_depth = value.parse().context("Invalid --object_shard_depth value")?;
+            }
+            if let Some(value) = matches.value_of("bundle_zstd_dict") {
+                config.bundle_zstd_dict = Some(value.into());
+            }
+            if matches.is_present("force_download") {
+                config.force_download = true;
+            }
+            if matches.is_present("touch_outputs") {
+                config.touch_outputs = true;
+            }
+            if matches.is_present("chunked_objects") {
+                config.chunked_objects = true;
+            }
+            if let Some(value) = matches.value_of("max_bundle_bytes") {
+                config.max_bundle_bytes = Some(value.parse().context("Invalid --max_bundle_bytes value")?);
+            }
+            if matches.is_present("summarize_large_bundles") {
+                config.summarize_large_bundles = true;
+            }
+            if let Some(value) = matches.value_of("object_name_strip_prefix") {
+                config.object_name_strip_prefix = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("download_retries") {
+                config.download_retries = value.parse().context("Invalid --download_retries value")?;
+            }
+            if let Some(value) = matches.value_of("min_free_disk_mb") {
+                config.min_free_disk_mb = Some(value.parse().context("Invalid --min_free_disk_mb value")?);
+            }
+            if let Some(value) = matches.value_of("ipfs_api_url") {
+                config.ipfs_api_url = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("http_proxy") {
+                config.http_proxy = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("no_proxy") {
+                config.no_proxy = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("ca_bundle") {
+                config.ca_bundle = Some(value.into());
+            }
             if let Some(value) = matches.value_of("inputs_hash_var") {
                 config.inputs_hash_var = value.to_string();
             }
+            if let Some(value) = matches.value_of("inputs_hash_format") {
+                config.inputs_hash_format = match value {
+                    "base64" => InputsHashFormat::Base64,
+                    "short" => InputsHashFormat::Short,
+                    _ => InputsHashFormat::Hex,
+                };
+            }
+        }
+
+        if config.http_proxy.is_none() {
+            config.http_proxy = env::var("HTTPS_PROXY").or_else(|_| env::var("https_proxy")).ok();
+        }
+        if config.no_proxy.is_none() {
+            config.no_proxy = env::var("NO_PROXY").or_else(|_| env::var("no_proxy")).ok();
+        }
+        if config.ca_bundle.is_none() {
+            config.ca_bundle = env::var("SSL_CERT_FILE").ok();
+        }
+
+        if let Some(hash) = &config.inputs_hash_value {
+            if hash.len() != 64 || !hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')) {
+                bail!("Invalid --inputs_hash_value '{}', expected a hex SHA256", hash);
+            }
+            // The declared inputs would be silently ignored, so they're likely not what the hash covers.
+            let declared_inputs = !config.input_files.is_empty()
+                || !config.input_cmds.is_empty()
+                || !config.tool_tags.is_empty()
+                || !config.tool_tag_cmds.is_empty()
+                || !config.tool_tag_files.is_empty()
+                || !config.tool_tag_env.is_empty()
+                || config.git_tool_tag
+                || config.hash_argv0
+                || config.hash_set_env;
+            if declared_inputs {
+                bail!("--inputs_hash_value cannot be combined with inputs or tool tags");
+            }
         }
 
-        if config.command_to_run.is_empty() && !config.inputs_hash_output {
+        if config.command_to_run.is_empty()
+            && !config.inputs_hash_output
+            && config.inspect_hash.is_none()
+            && !config.selftest
+            && config.train_dict_output.is_none()
+            && config.compare_bundles.is_none()
+            && config.migrate.is_none()
+        {
             bail!("The command to run was not specified");
         }
+        if config.fallback_command.is_some() && config.produce_command()?.is_empty() {
+            bail!("The fallback command is empty");
+        }
 
         Ok(config)
     }
 
+    /// The command producing the outputs: the --fallback_command if given, or the command to run.
+    pub fn produce_command(&self) -> Result<Vec<String>> {
+        match &self.fallback_command {
+            Some(command) => {
+                shell_words::split(command).with_context(|| format!("Parsing fallback command '{}'", command))
+            }
+            None => Ok(self.command_to_run.clone()),
+        }
+    }
+
     pub fn get_honeycomb_kv(&self) -> Result<Vec<(String, String)>> {
         self.honeycomb_kv
             .iter()
@@ -603,32 +2208,153 @@ impl Config {
             .ok_or_else(|| anyhow!("Can't parse honeycomb_kv"))
     }
 
-    // Check if all paths match at least one of the specified outputs.
-    pub fn outputs_match<'a, I: Iterator<Item = &'a WorkspacePath>>(&self, paths: I) -> Result<bool> {
-        // Take all patterns from globs in self.output_files
-        let patterns = self
-            .output_files
-            .iter()
+    // Read the PEM certificates from the CA bundle, if any, one PEM block per certificate.
+    pub fn ca_certificates(&self) -> Result<Vec<Vec<u8>>> {
+        let ca_bundle = match &self.ca_bundle {
+            Some(ca_bundle) => ca_bundle,
+            None => return Ok(Vec::new()),
+        };
+        let contents =
+            std::fs::read_to_string(ca_bundle).with_context(|| format!("Reading CA bundle '{}'", ca_bundle))?;
+        const END: &str = "-----END CERTIFICATE-----";
+        let certificates: Vec<_> = contents
+            .match_indices("-----BEGIN CERTIFICATE-----")
+            .filter_map(|(begin, _)| {
+                contents[begin..]
+                    .find(END)
+                    .map(|end| contents.as_bytes()[begin..begin + end + END.len()].to_vec())
+            })
+            .collect();
+        if certificates.is_empty() {
+            bail!("No certificates in CA bundle '{}'", ca_bundle);
+        }
+        Ok(certificates)
+    }
+
+    // Create an HTTP client for the requests other than those of the S3 clients, i.e. to Honeycomb and
+    // the objects CDN, with the same proxy and certificates as the S3 clients.
+    pub fn reqwest_client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.http_proxy {
+            let no_proxy = self.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string);
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(no_proxy));
+        }
+        for pem in self.ca_certificates()? {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(builder.build()?)
+    }
+
+    // Read a NUL-delimited list of input files, as produced by `find -print0`. The paths are taken
+    // literally, so any glob characters in them are escaped.
+    fn read_input_list0(input_list: &str) -> Result<Vec<WorkspacePath>> {
+        let contents = std::fs::read(input_list).with_context(|| format!("Reading input list '{}'", input_list))?;
+        let inputs = contents
+            .split(|&byte| byte == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| {
+                let path = std::str::from_utf8(path)
+                    .with_context(|| format!("Non UTF-8 path in input list '{}'", input_list))?;
+                Ok(WorkspacePath::from(glob::Pattern::escape(path)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if inputs.is_empty() {
+            bail!("Input list '{}' didn't match any files", input_list);
+        }
+        Ok(inputs)
+    }
+
+    // The --set_env variables, sorted by name, later values of a variable overriding earlier ones.
+    pub fn get_set_env(&self) -> Result<Vec<(String, String)>> {
+        let mut vars = BTreeMap::new();
+        for value in &self.set_env {
+            match value.split_once('=') {
+                Some((name, value)) if !name.is_empty() => vars.insert(name.to_owned(), value.to_owned()),
+                _ => bail!("Can't parse set_env '{}', expected NAME=value", value),
+            };
+        }
+        Ok(vars.into_iter().collect())
+    }
+
+    // Metadata to store with the cache entry: the objects from the --attach_file's, and then the
+    // --attach key=value pairs, later values overriding earlier ones.
+    pub fn get_metadata(&self) -> Result<Metadata> {
+        let mut metadata = Metadata::new();
+        // Only the metadata, and never the cache key, so the entries of all the hosts are shared.
+        if self.include_hostname_tag {
+            let mut buffer = [0u8; 256];
+            let hostname = nix::unistd::gethostname(&mut buffer).context("Getting the hostname")?;
+            metadata.insert("hostname".to_owned(), hostname.to_string_lossy().into());
+            let platform = format!("{}-{}", env::consts::OS, env::consts::ARCH);
+            metadata.insert("platform".to_owned(), platform.into());
+        }
+        // With --record_command, the argv producing the outputs, which is not hashed either.
+        if self.record_command {
+            metadata.insert("command".to_owned(), self.produce_command()?.into());
+        }
+        for file in &self.attach_files {
+            let path = file.to_path(self.roots())?;
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("Reading attached file '{}'", path.display()))?;
+            let object: Metadata = serde_json::from_str(&contents)
+                .with_context(|| format!("Parsing attached file '{}' as a JSON object", path.display()))?;
+            metadata.extend(object);
+        }
+        for value in &self.attach {
+            let (key, value) = value
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Can't parse attach '{}', expected key=value", value))?;
+            metadata.insert(key.to_owned(), value.into());
+        }
+        Ok(metadata)
+    }
+
+    // Patterns of the files that are excluded from the outputs, even if they match an output pattern.
+    pub fn exclude_output_patterns(&self) -> Result<Vec<glob::Pattern>> {
+        self.to_glob_patterns(self.exclude_output_files.iter())
+            .with_context(|| "Invalid exclude output pattern")
+    }
+
+    fn to_glob_patterns<'a, I: Iterator<Item = &'a WorkspacePath>>(&self, paths: I) -> Result<Vec<glob::Pattern>> {
+        paths
             .map(|path| {
-                let path = path.to_path(&self.workspace_root)?;
+                let path = path.to_path(self.roots())?;
                 let path = path.to_str().ok_or(anyhow!("Cannot convert path to str"))?;
                 // Fix a common problem with patterns starting with ./
                 let path = if let Some(stripped) = path.strip_prefix("./") {
                     stripped
                 } else {
-                    &path
+                    path
+                };
+                // Directory outputs, declared with a trailing '/', match the directory path.
+                let path = if path.len() > 1 {
+                    path.trim_end_matches('/')
+                } else {
+                    path
                 };
                 glob::Pattern::from_str(path).context("invalid pattern")
             })
-            .collect::<Result<Vec<glob::Pattern>, _>>()
+            .collect()
+    }
+
+    // Check if all paths match at least one of the specified outputs, and that each of the outputs
+    // (except negative outputs, which may legitimately be absent) has at least one matching path.
+    // Without any outputs, only an entry without output files matches.
+    pub fn outputs_match<'a, I: Iterator<Item = &'a WorkspacePath>>(&self, paths: I) -> Result<bool> {
+        // Take all patterns from globs in self.output_files and self.negative_output_files
+        let patterns = self
+            .to_glob_patterns(self.output_files.iter().chain(self.negative_output_files.iter()))
             .with_context(|| "Invalid output file pattern")?;
-        assert_eq!(patterns.len(), self.output_files.len());
+        assert_eq!(
+            patterns.len(),
+            self.output_files.len() + self.negative_output_files.len()
+        );
         let mut pattern_has_matches = vec![false; patterns.len()];
         // For each given path, try to find at least one match in the patterns.
         for path in paths {
             let mut has_match = false;
             for (i, pattern) in patterns.iter().enumerate() {
-                if pattern.matches_path(&path.to_path(&self.workspace_root)?) {
+                if pattern.matches_path(&path.to_path(self.roots())?) {
                     has_match = true;
                     pattern_has_matches[i] = true;
                     break;
@@ -640,7 +2366,7 @@ impl Config {
             }
         }
         let mut result = true;
-        for (i, has_matches) in pattern_has_matches.iter().enumerate() {
+        for (i, has_matches) in pattern_has_matches[..self.output_files.len()].iter().enumerate() {
             if !has_matches {
                 error!("pattern {} does not have matching paths", self.output_files[i]);
                 result = false;
@@ -741,6 +2467,54 @@ mod tests {
         assert_eq!(config.output_files, vec![WorkspacePath::from("compiled_binary")]);
     }
 
+    #[test]
+    #[serial]
+    fn test_toml_section_patterns() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        let config_contents: &'static str = indoc! {r#"
+           [default]
+           capture_stdout = true
+           tool_tag = ["default"]
+
+           ["bin/*"]
+           capture_stdout = false
+           tool_tag = ["bin"]
+
+           ["bin/foo*"]
+           tool_tag = ["bin-foo"]
+
+           ["lib/*"]
+           tool_tag = ["lib"]
+        "#};
+        config_file.write_all(config_contents.as_bytes()).unwrap();
+        config_file.flush().unwrap();
+        let new_config = |capsule_id: &str| {
+            Config::new(
+                vec![
+                    "placebo",
+                    "-c",
+                    capsule_id,
+                    "-f",
+                    config_file.path().to_str().unwrap(),
+                    "--",
+                    "/bin/echo",
+                ],
+                None,
+            )
+        };
+
+        let config = new_config("bin/foobar").unwrap();
+        assert_eq!(config.tool_tags, vec!["bin-foo", "bin", "default"]);
+        assert_eq!(config.capture_stdout, Some(false));
+
+        let config = new_config("lib/baz").unwrap();
+        assert_eq!(config.tool_tags, vec!["lib", "default"]);
+        assert_eq!(config.capture_stdout, Some(true));
+
+        // [default] alone is not a match.
+        assert!(new_config("doc/index").is_err());
+    }
+
     #[test]
     #[serial]
     fn test_toml_defaults() {
@@ -803,6 +2577,35 @@ mod tests {
         assert_eq!(config.tool_tags, vec!["docker-ABCDEF", "docker-1234"]);
     }
 
+    #[test]
+    #[serial]
+    fn test_toml_exec_hooks() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        let config_contents: &'static str = indoc! {r#"
+           on_hit_exec = "touch hit"
+           on_miss_exec = "touch miss"
+        "#};
+        config_file.write(config_contents.as_bytes()).unwrap();
+        config_file.flush().unwrap();
+
+        // The command line takes precedence, and the rest comes from the file.
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "--on_miss_exec",
+                "touch missed",
+                "--",
+                "/bin/echo",
+            ],
+            Some(config_file.path()),
+        )
+        .unwrap();
+        assert_eq!(config.on_hit_exec.as_deref(), Some("touch hit"));
+        assert_eq!(config.on_miss_exec.as_deref(), Some("touch missed"));
+    }
+
     #[test]
     #[serial]
     fn test_toml_capsule_id_mismatch() {
@@ -993,6 +2796,108 @@ mod tests {
         assert!(!config.outputs_match(vec![].into_iter()).unwrap());
     }
 
+    #[test]
+    #[serial]
+    fn test_outputs_match_no_outputs() {
+        let config = Config::new(vec!["placebo", "-c", "my_capsule", "--", "/bin/true"], None).unwrap();
+        assert!(config.outputs_match(vec![].into_iter()).unwrap());
+        assert!(!config
+            .outputs_match(vec![&WorkspacePath::from("build-out/stale")].into_iter())
+            .unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_outputs_match_negative() {
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "-o",
+                "build-out/bin",
+                "--negative_output",
+                "build-out/optional",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(config
+            .outputs_match(vec![&WorkspacePath::from("build-out/bin")].into_iter())
+            .unwrap());
+        assert!(config
+            .outputs_match(
+                vec![
+                    &WorkspacePath::from("build-out/bin"),
+                    &WorkspacePath::from("build-out/optional"),
+                ]
+                .into_iter()
+            )
+            .unwrap());
+        assert!(!config
+            .outputs_match(vec![&WorkspacePath::from("build-out/optional")].into_iter())
+            .unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_inspect() {
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "--backend",
+                "s3",
+                "inspect",
+                "--hash",
+                "0123abcd",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.inspect_hash.as_deref(), Some("0123abcd"));
+        assert!(config.command_to_run.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_train_dict() {
+        let config = Config::new(
+            vec![
+                "capsule",
+                "--backend",
+                "s3",
+                "--bundle_zstd_dict",
+                "//bundles.dict",
+                "train-dict",
+                "--output",
+                "/tmp/bundles.dict",
+                "--samples",
+                "100",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.train_dict_output, Some(WorkspacePath::from("/tmp/bundles.dict")));
+        assert_eq!(config.train_dict_samples, Some(100));
+        assert_eq!(config.bundle_zstd_dict, Some(WorkspacePath::from("//bundles.dict")));
+        assert!(config.command_to_run.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_compare() {
+        let config = Config::new(vec!["capsule", "compare", "--a", "a.json", "--b", "//b.json"], None).unwrap();
+        assert_eq!(
+            config.compare_bundles,
+            Some((WorkspacePath::from("a.json"), WorkspacePath::from("//b.json")))
+        );
+        assert!(config.command_to_run.is_empty());
+    }
+
     #[test]
     #[serial]
     fn test_workspace_root() {
@@ -1037,4 +2942,679 @@ mod tests {
             PathBuf::from("/foo/bar/my/output/file")
         );
     }
+
+    #[test]
+    #[serial]
+    fn test_named_workspace_roots() {
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-w",
+                "/foo/bar",
+                "-w",
+                "sub=/foo/bar/nested/sub",
+                "-c",
+                "my_capsule",
+                "-i",
+                "//@sub/my/input/file",
+                "-i",
+                "//my/input/file2",
+                "-o",
+                "//@sub/my/dir/",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.workspace_root.as_ref().unwrap(), "/foo/bar");
+        assert_eq!(config.workspace_roots["sub"], "/foo/bar/nested/sub");
+        assert_eq!(
+            config.input_files[0].to_path(config.roots()).unwrap(),
+            PathBuf::from("/foo/bar/nested/sub/my/input/file")
+        );
+        assert_eq!(
+            config.input_files[1].to_path(config.roots()).unwrap(),
+            PathBuf::from("/foo/bar/my/input/file2")
+        );
+        // The trailing '/' of the directory outputs is kept.
+        assert_eq!(
+            config.output_files[0].to_path(config.roots()).unwrap(),
+            PathBuf::from("/foo/bar/nested/sub/my/dir/")
+        );
+        assert!(WorkspacePath::from("//@unknown/x").to_path(config.roots()).is_err());
+
+        // Full paths are made relative to the nearest enclosing root.
+        assert_eq!(
+            WorkspacePath::from_full_path(Path::new("/foo/bar/nested/sub/x"), config.roots()),
+            WorkspacePath::from("//@sub/x")
+        );
+        assert_eq!(
+            WorkspacePath::from_full_path(Path::new("/foo/bar/nested/x"), config.roots()),
+            WorkspacePath::from("//nested/x")
+        );
+
+        // The named roots from the config file are added to those of the command line, which take precedence.
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-w",
+                "sub=/foo/bar/nested/sub",
+                "-c",
+                "my_capsule",
+                "--config_inline",
+                indoc! {r#"
+                    [my_capsule]
+                    workspace_roots = { sub = "/toml/sub", other = "/toml/other" }
+                "#},
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.workspace_roots["sub"], "/foo/bar/nested/sub");
+        assert_eq!(config.workspace_roots["other"], "/toml/other");
+    }
+
+    #[test]
+    #[serial]
+    fn test_named_workspace_root_collision() {
+        // The named root 'lib' is not the 'lib' directory of the default root.
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-w",
+                "/ws",
+                "-w",
+                "lib=/ws/vendor/lib",
+                "-c",
+                "my_capsule",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        for (file, path) in [("/ws/lib/x.rs", "//lib/x.rs"), ("/ws/vendor/lib/x.rs", "//@lib/x.rs")] {
+            let workspace_path = WorkspacePath::from_full_path(Path::new(file), config.roots());
+            assert_eq!(workspace_path, WorkspacePath::from(path));
+            assert_eq!(workspace_path.to_path(config.roots()).unwrap(), PathBuf::from(file));
+        }
+
+        // A path containing '=' without a name before it is the default root.
+        let config = Config::new(
+            vec!["placebo", "-w", "/ws/a=b", "-c", "my_capsule", "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.workspace_root.as_deref(), Some("/ws/a=b"));
+        assert!(config.workspace_roots.is_empty());
+    }
+
+    #[test]
+    fn test_find_workspace_root() {
+        let root = tempfile::TempDir::new().unwrap();
+        let subdir = root.path().join("a/b");
+        std::fs::create_dir_all(&subdir).unwrap();
+        assert_eq!(find_workspace_root(&subdir, ".capsule-workspace"), None);
+
+        // A Cargo.toml without [workspace] is not a marker.
+        std::fs::write(root.path().join("a/Cargo.toml"), "[package]\nname = \"a\"\n").unwrap();
+        assert_eq!(find_workspace_root(&subdir, ".capsule-workspace"), None);
+
+        std::fs::write(root.path().join("Cargo.toml"), "[workspace]\nmembers = [\"a\"]\n").unwrap();
+        assert_eq!(
+            find_workspace_root(&subdir, ".capsule-workspace"),
+            Some(root.path().to_owned())
+        );
+
+        // The closest marker wins, and the marker name is configurable.
+        std::fs::write(subdir.join("WORKSPACE"), "").unwrap();
+        assert_eq!(find_workspace_root(&subdir, "WORKSPACE"), Some(subdir.clone()));
+        std::fs::write(root.path().join("a/.capsule-workspace"), "").unwrap();
+        assert_eq!(
+            find_workspace_root(&subdir, ".capsule-workspace"),
+            Some(root.path().join("a"))
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_workspace_detection() {
+        let root = tempfile::TempDir::new().unwrap();
+        let root_path = root.path().canonicalize().unwrap();
+        let subdir = root_path.join("a");
+        std::fs::create_dir(&subdir).unwrap();
+        std::fs::write(root_path.join(".capsule-workspace"), "").unwrap();
+        let cwd = env::current_dir().unwrap();
+        env::set_current_dir(&subdir).unwrap();
+        let new_config = |flags: &[&str]| {
+            let args = [&["capsule", "-c", "wtf"][..], flags, &["--", "/bin/echo"]].concat();
+            Config::new(args, None).unwrap()
+        };
+        let detected = new_config(&[]);
+        let not_detected = new_config(&["--no_workspace_detection"]);
+        env::set_current_dir(cwd).unwrap();
+
+        // Without -w, the root is detected by default.
+        assert_eq!(detected.workspace_root, Some(root_path.to_string_lossy().into_owned()));
+        assert_eq!(not_detected.workspace_root, None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_input_list0() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let awkward = dir.path().join("with space\nand [newline]");
+        std::fs::write(&awkward, "data").unwrap();
+        let list = dir.path().join("inputs.lst");
+        std::fs::write(&list, format!("{}\0/bin/echo\0", awkward.to_str().unwrap())).unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-i",
+                "/bin/bash",
+                "--input_list0",
+                list.to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.input_files.len(), 3);
+        assert_eq!(config.input_files[2], WorkspacePath::from("/bin/echo"));
+        // The path is escaped, so that as a glob it matches exactly the listed file.
+        let pattern = config.input_files[1].to_path(&config.workspace_root).unwrap();
+        let matched: Vec<_> = glob::glob(pattern.to_str().unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(matched, vec![awkward]);
+
+        // An empty list is an error, like a glob that matches no files.
+        std::fs::write(&list, "").unwrap();
+        assert!(Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "--input_list0",
+                list.to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_ca_bundle() {
+        let mut ca_bundle = NamedTempFile::new().unwrap();
+        let config_contents = indoc! {"
+            # Some comment
+            -----BEGIN CERTIFICATE-----
+            Zmlyc3Q=
+            -----END CERTIFICATE-----
+            -----BEGIN CERTIFICATE-----
+            c2Vjb25k
+            -----END CERTIFICATE-----
+        "};
+        ca_bundle.write_all(config_contents.as_bytes()).unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "--ca_bundle",
+                ca_bundle.path().to_str().unwrap(),
+                "--http_proxy",
+                "http://proxy:3128",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.http_proxy.as_deref(), Some("http://proxy:3128"));
+        assert_eq!(
+            config.ca_certificates().unwrap(),
+            vec![
+                b"-----BEGIN CERTIFICATE-----\nZmlyc3Q=\n-----END CERTIFICATE-----".to_vec(),
+                b"-----BEGIN CERTIFICATE-----\nc2Vjb25k\n-----END CERTIFICATE-----".to_vec(),
+            ]
+        );
+
+        // The standard variables are the defaults.
+        env::set_var("SSL_CERT_FILE", ca_bundle.path());
+        env::set_var("HTTPS_PROXY", "http://other-proxy:3128");
+        env::set_var("NO_PROXY", "localhost,.internal");
+        let config = Config::new(vec!["capsule", "-c", "my_capsule", "--", "/bin/echo"], None).unwrap();
+        env::remove_var("SSL_CERT_FILE");
+        env::remove_var("HTTPS_PROXY");
+        env::remove_var("NO_PROXY");
+        assert_eq!(config.http_proxy.as_deref(), Some("http://other-proxy:3128"));
+        assert_eq!(config.no_proxy.as_deref(), Some("localhost,.internal"));
+        assert_eq!(config.ca_certificates().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_no_proxy_matches() {
+        let no_proxy = "localhost, .internal,minio.example.com,10.0.0.1";
+        assert!(no_proxy_matches(no_proxy, "localhost"));
+        assert!(no_proxy_matches(no_proxy, "s3.internal"));
+        assert!(no_proxy_matches(no_proxy, "internal"));
+        assert!(no_proxy_matches(no_proxy, "minio.example.com"));
+        assert!(no_proxy_matches(no_proxy, "eu.minio.example.com"));
+        assert!(no_proxy_matches(no_proxy, "10.0.0.1"));
+        assert!(!no_proxy_matches(no_proxy, "notminio.example.com"));
+        assert!(!no_proxy_matches(no_proxy, "s3.amazonaws.com"));
+        assert!(!no_proxy_matches("", "localhost"));
+        assert!(no_proxy_matches("*", "s3.amazonaws.com"));
+    }
+
+    #[test]
+    fn test_inputs_hash_format() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(InputsHashFormat::Hex.render(hash), hash);
+        assert_eq!(InputsHashFormat::Short.render(hash), "e3b0c44298fc");
+        assert_eq!(
+            InputsHashFormat::Base64.render(hash),
+            "47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_inputs_hash_value() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let new_config = |value: &str, extra: &[&str]| {
+            let mut args = vec!["capsule", "-c", "my_capsule", "--inputs_hash_value", value];
+            args.extend(extra);
+            args.extend(["--", "/bin/echo"]);
+            Config::new(args, None)
+        };
+        assert_eq!(new_config(hash, &[]).unwrap().inputs_hash_value.as_deref(), Some(hash));
+        assert!(new_config(&hash[1..], &[]).is_err());
+        assert!(new_config(&hash.to_uppercase(), &[]).is_err());
+        assert!(new_config(hash, &["-i", "/bin/echo"]).is_err());
+        assert!(new_config(hash, &["-t", "tag"]).is_err());
+        assert!(new_config(hash, &["--hash_argv0"]).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_s3_credentials_options() {
+        let config = Config::new(vec!["capsule", "-c", "my_capsule", "--", "/bin/echo"], None).unwrap();
+        assert_eq!(config.s3_profile, None);
+        assert_eq!(config.s3_assume_role_arn, None);
+        assert_eq!(config.s3_role_session_name, "capsule");
+
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "--s3_profile",
+                "ci",
+                "--s3_assume_role_arn",
+                "arn:aws:iam::123456789012:role/capsule",
+                "--s3_role_session_name",
+                "build-42",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.s3_profile.as_deref(), Some("ci"));
+        assert_eq!(
+            config.s3_assume_role_arn.as_deref(),
+            Some("arn:aws:iam::123456789012:role/capsule")
+        );
+        assert_eq!(config.s3_role_session_name, "build-42");
+    }
+
+    #[test]
+    #[serial]
+    fn test_capsule_id_file() {
+        let mut capsule_id_file = NamedTempFile::new().unwrap();
+        capsule_id_file.write_all(b"//my/target \n").unwrap();
+        let capsule_id_path = capsule_id_file.path().to_str().unwrap();
+        let config = Config::new(
+            vec!["capsule", "--capsule_id_file", capsule_id_path, "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.capsule_id.as_deref(), Some("//my/target"));
+
+        // An explicit -c takes precedence.
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "explicit",
+                "--capsule_id_file",
+                capsule_id_path,
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.capsule_id.as_deref(), Some("explicit"));
+
+        let empty_file = NamedTempFile::new().unwrap();
+        let empty_path = empty_file.path().to_str().unwrap();
+        assert!(Config::new(
+            vec!["capsule", "--capsule_id_file", empty_path, "--", "/bin/echo"],
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_quiet() {
+        let config = Config::new(vec!["capsule", "-c", "my_capsule", "--", "/bin/echo"], None).unwrap();
+        assert!(!config.quiet);
+        let config = Config::new(vec!["capsule", "-c", "my_capsule", "-q", "--", "/bin/echo"], None).unwrap();
+        assert!(config.quiet);
+
+        let mut default_config_file = NamedTempFile::new().unwrap();
+        default_config_file.write_all(b"quiet = true\n").unwrap();
+        let config = Config::new(
+            vec!["capsule", "-c", "my_capsule", "--", "/bin/echo"],
+            Some(default_config_file.path()),
+        )
+        .unwrap();
+        assert!(config.quiet);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_inline() {
+        let config_contents = indoc! {r#"
+           [my_capsule]
+           output=["compiled_binary"]
+           [other_capsule]
+           output=["other_binary"]
+        "#};
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "--config_inline",
+                config_contents,
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.output_files, vec![WorkspacePath::from("compiled_binary")]);
+
+        let err = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "missing",
+                "--config_inline",
+                config_contents,
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Cannot find section 'missing' in config '<inline>'");
+        Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "--config_inline",
+                "[my_capsule",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_fallback_command() {
+        let config_with = |fallback_command: &str| {
+            let mut args = vec!["capsule", "-c", "wtf", "--fallback_command", fallback_command];
+            args.extend(["--", "true"]);
+            Config::new(args.iter(), None)
+        };
+        let config = config_with("make 'all the things'").unwrap();
+        assert_eq!(config.produce_command().unwrap(), vec!["make", "all the things"]);
+        assert_eq!(config.command_to_run, vec!["true"]);
+        let config = Config::new(["capsule", "-c", "wtf", "--", "true"].iter(), None).unwrap();
+        assert_eq!(config.produce_command().unwrap(), vec!["true"]);
+        assert!(config_with(" ").is_err());
+        assert!(config_with("'").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_relative_to_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let subdir = dir.path().join("sub [1]");
+        std::fs::create_dir(&subdir).unwrap();
+        let config_file = subdir.join("Capsule.toml");
+        std::fs::write(
+            &config_file,
+            indoc! {r#"
+               [default]
+               relative_to_file = true
+
+               [my_capsule]
+               input = ["src/*.rs", "/etc/passwd"]
+               output = ["out/"]
+            "#},
+        )
+        .unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.to_str().unwrap(),
+                "-i",
+                "cmdline.rs",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        let escaped_subdir = PathBuf::from(glob::Pattern::escape(subdir.to_str().unwrap()));
+        assert_eq!(
+            config.input_files,
+            vec![
+                WorkspacePath::from(escaped_subdir.join("src/*.rs")),
+                WorkspacePath::from("/etc/passwd"),
+                // The command line patterns are still relative to the current directory.
+                WorkspacePath::from("cmdline.rs"),
+            ]
+        );
+        assert_eq!(
+            config.output_files,
+            vec![WorkspacePath::from(escaped_subdir.join("out/"))]
+        );
+
+        // There's no file to be relative to with an inline config.
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "--config_inline",
+                "[my_capsule]\nrelative_to_file = true\n",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_set_env() {
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--set_env",
+                "TZ=UTC",
+                "--set_env",
+                "SOURCE_DATE_EPOCH=1",
+                "--set_env",
+                "SOURCE_DATE_EPOCH=a=b",
+                "--",
+                "/bin/true",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_set_env().unwrap(),
+            vec![
+                ("SOURCE_DATE_EPOCH".to_owned(), "a=b".to_owned()),
+                ("TZ".to_owned(), "UTC".to_owned())
+            ]
+        );
+        for invalid in ["TZ", "=UTC"] {
+            let config = Config::new(
+                ["capsule", "-c", "wtf", "--set_env", invalid, "--", "/bin/true"].iter(),
+                None,
+            );
+            assert!(config.unwrap().get_set_env().is_err());
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_toml_include() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(dir.path().join("common")).unwrap();
+        std::fs::write(
+            dir.path().join("common/base.toml"),
+            indoc! {r#"
+               [base]
+               input = ["/etc/hosts"]
+               tool_tag = ["base"]
+               include = ["more.toml#more"]
+
+               [loop]
+               include = ["../Capsule.toml#looping"]
+            "#},
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("common/more.toml"),
+            indoc! {r#"
+               [more]
+               relative_to_file = true
+               output = ["out/*"]
+               tool_tag = ["more"]
+            "#},
+        )
+        .unwrap();
+        let config_file = dir.path().join("Capsule.toml");
+        std::fs::write(
+            &config_file,
+            indoc! {r#"
+               [my_capsule]
+               tool_tag = ["mine"]
+               capture_stdout = true
+               include = ["common/base.toml#base"]
+
+               [looping]
+               include = ["common/base.toml#loop"]
+            "#},
+        )
+        .unwrap();
+        let new_config = |capsule_id: &str| {
+            Config::new(
+                vec![
+                    "capsule",
+                    "-c",
+                    capsule_id,
+                    "-f",
+                    config_file.to_str().unwrap(),
+                    "-t",
+                    "cmdline",
+                    "--",
+                    "/bin/echo",
+                ],
+                None,
+            )
+        };
+
+        let config = new_config("my_capsule").unwrap();
+        assert_eq!(config.tool_tags, vec!["mine", "base", "more", "cmdline"]);
+        assert_eq!(config.input_files, vec![WorkspacePath::from("/etc/hosts")]);
+        assert_eq!(
+            config.output_files,
+            vec![WorkspacePath::from(dir.path().join("common/out/*"))]
+        );
+        // The included sections don't reset the capture options of the including one.
+        assert_eq!(config.capture_stdout, Some(true));
+
+        let error = new_config("looping").unwrap_err();
+        assert!(format!("{:#}", error).contains("cycle"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_migrate() {
+        let config = Config::new(
+            vec![
+                "capsule",
+                "--backend",
+                "s3",
+                "--s3_bucket",
+                "old",
+                "migrate",
+                "--to",
+                "--s3_bucket new --s3_endpoint 'https://s3.eu-west-1.amazonaws.com'",
+                "--prefix",
+                "wtf",
+                "--dry_run",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            config.migrate,
+            Some((
+                vec![],
+                vec![
+                    "--s3_bucket".to_owned(),
+                    "new".to_owned(),
+                    "--s3_endpoint".to_owned(),
+                    "https://s3.eu-west-1.amazonaws.com".to_owned()
+                ]
+            ))
+        );
+        assert!(config.migrate_dry_run);
+        // The flags before 'migrate' configure the source.
+        assert_eq!(config.s3_bucket.as_deref(), Some("old"));
+        assert_eq!(config.capsule_id, Some("wtf".to_owned()));
+        assert!(config.command_to_run.is_empty());
+    }
 }
@@ -2,12 +2,19 @@ use anyhow;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task;
 
-use crate::workspace_path::WorkspacePath;
+use crate::chunking::{file_chunks, Chunk, ChunkSizes};
+use crate::workspace_path::{WorkspacePath, WorkspaceRoots};
 
 #[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum Input {
@@ -17,10 +24,27 @@ pub enum Input {
     File(WorkspacePath),
 }
 
+impl Input {
+    // Identifies the input across two computations of the inputs hash, to compare them. The tool
+    // tags have nothing but their value, so a changed one is reported as removed and added.
+    fn diff_name(&self) -> String {
+        match self {
+            Input::ToolTag(tag) => format!("tool tag {}", tag),
+            Input::File(filename) => format!("file {}", filename),
+        }
+    }
+}
+
 /// Input set is the set of all inputs to the build step.
 #[derive(Default, Debug, Clone)]
 pub struct InputSet {
     pub inputs: Vec<Input>,
+    /// Hash text files with CRLF and CR line endings normalized to LF.
+    pub normalize_line_endings: bool,
+    /// Maximum size of the buffer the files are read into, in KiB, see `file_hash_buffered`.
+    pub hash_buffer_kb: usize,
+    /// The threads to hash the files on, see `HashPool`.
+    pub hash_pool: HashPool,
 }
 
 #[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -28,51 +52,339 @@ pub struct FileOutput {
     pub filename: WorkspacePath,
     pub present: bool,
     pub mode: u32,
+    /// Declared with --negative_output, i.e. the file being absent is a valid cached state.
+    #[serde(default)]
+    pub negative: bool,
+    /// With --chunked_objects, large files are stored as these chunks, rather than as a single object.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
 }
 
 #[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum Output {
     File(FileOutput),
     ExitCode(i32),
+    /// The number of the signal that terminated the command, in which case there's no exit code.
+    Signal(i32),
     Stdout(Vec<u8>),
     Stderr(Vec<u8>),
+    /// Captured stdout too large to be kept inline, stored as an object. Holds its length.
+    StdoutObject(u64),
+    /// Captured stderr too large to be kept inline, stored as an object. Holds its length.
+    StderrObject(u64),
+    /// Empty output directory, recorded with --preserve_empty_dirs.
+    Dir(WorkspacePath),
+    /// Output directory declared with a trailing '/', of which only the presence and the mode are
+    /// recorded, but not the content.
+    Directory {
+        dirname: WorkspacePath,
+        mode: u32,
+    },
+}
+
+impl Output {
+    // Identifies the output across the runs of the capsule, to compare them.
+    fn diff_name(&self) -> String {
+        match self {
+            Output::File(fileoutput) => format!("file {}", fileoutput.filename),
+            Output::ExitCode(_) | Output::Signal(_) => "exit status".to_owned(),
+            Output::Stdout(_) | Output::StdoutObject(_) => "stdout".to_owned(),
+            Output::Stderr(_) | Output::StderrObject(_) => "stderr".to_owned(),
+            Output::Dir(dirname) => format!("empty dir {}", dirname),
+            Output::Directory { dirname, .. } => format!("dir {}", dirname),
+        }
+    }
+
+    // The value of the output to compare and report, which is its hash, unless it's more readable.
+    fn diff_value(&self, hash: &str) -> String {
+        match self {
+            Output::ExitCode(code) => format!("exit code {}", code),
+            Output::Signal(signal) => format!("signal {}", signal),
+            _ => hash.to_owned(),
+        }
+    }
+}
+
+/// A difference between the outputs of two runs of a capsule with the same inputs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutputDiff {
+    /// The output file is present in one run, and absent in the other.
+    Presence { name: String, old: bool, new: bool },
+    /// The output has different content in the two runs.
+    Content { name: String, old: String, new: String },
+    /// The output is only recorded in the old run.
+    Removed { name: String, old: String },
+    /// The output is only recorded in the new run.
+    Added { name: String, new: String },
+}
+
+impl fmt::Display for OutputDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let presence = |present: &bool| if *present { "present" } else { "absent" };
+        match self {
+            OutputDiff::Presence { name, old, new } => {
+                write!(f, "{}: {} vs {}", name, presence(old), presence(new))
+            }
+            OutputDiff::Content { name, old, new } => write!(f, "{}: {} vs {}", name, old, new),
+            OutputDiff::Removed { name, old } => write!(f, "{}: {} vs missing", name, old),
+            OutputDiff::Added { name, new } => write!(f, "{}: missing vs {}", name, new),
+        }
+    }
+}
+
+/// A difference between two computations of the inputs hash, e.g. of two builds.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputDiff {
+    /// The input file has different content.
+    Content { name: String, old: String, new: String },
+    /// The input is only in the old inputs.
+    Removed { name: String, old: String },
+    /// The input is only in the new inputs.
+    Added { name: String, new: String },
+}
+
+impl fmt::Display for InputDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputDiff::Content { name, old, new } => write!(f, "{}: {} vs {}", name, old, new),
+            InputDiff::Removed { name, old } => write!(f, "{}: {} vs missing", name, old),
+            InputDiff::Added { name, new } => write!(f, "{}: missing vs {}", name, new),
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct InputHashBundle {
     pub hash: String,
     pub hash_details: Vec<(Input, String)>,
+    /// Number of input files, and their total size in bytes.
+    #[serde(default)]
+    pub file_count: usize,
+    #[serde(default)]
+    pub file_bytes: u64,
+    /// Number of the inputs whose details were dropped from a large cache entry, see `summarized`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summarized_details: Option<usize>,
+}
+
+impl InputHashBundle {
+    /// The inputs without the hashes of the individual inputs, but with their count, for the cache
+    /// entries that would be too large otherwise. The details are only needed for observability,
+    /// e.g. to explain misses, while the hash is all that's needed to look the entries up.
+    pub fn summarized(&self) -> InputHashBundle {
+        InputHashBundle {
+            hash: self.hash.clone(),
+            hash_details: vec![],
+            file_count: self.file_count,
+            file_bytes: self.file_bytes,
+            summarized_details: Some(self.hash_details.len()),
+        }
+    }
+
+    /// Compare the inputs with other ones, one by one, and return the differences, which explain
+    /// why the inputs hashes differ. The inputs are matched by the file names and the tool tags.
+    pub fn diff(&self, new: &InputHashBundle) -> Vec<InputDiff> {
+        if self.hash == new.hash {
+            return vec![];
+        }
+        let by_name = |bundle: &'_ InputHashBundle| -> BTreeMap<String, String> {
+            bundle
+                .hash_details
+                .iter()
+                .map(|(input, hash)| (input.diff_name(), hash.clone()))
+                .collect()
+        };
+        let (old_inputs, mut new_inputs) = (by_name(self), by_name(new));
+        let mut diffs = Vec::new();
+        for (name, old) in old_inputs {
+            match new_inputs.remove(&name) {
+                Some(new) if new != old => diffs.push(InputDiff::Content { name, old, new }),
+                Some(_) => {}
+                None => diffs.push(InputDiff::Removed { name, old }),
+            }
+        }
+        for (name, new) in new_inputs {
+            diffs.push(InputDiff::Added { name, new });
+        }
+        diffs
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct OutputHashBundle {
     pub hash: String,
     pub hash_details: Vec<(Output, String)>,
+    /// Outputs stored and replayed with the entry, but not part of its hash, e.g. stderr with
+    /// --unhashed_stderr, so that they are not compared between the runs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unhashed_details: Vec<(Output, String)>,
+    /// Number of present output files, and their total size in bytes.
+    #[serde(default)]
+    pub file_count: usize,
+    #[serde(default)]
+    pub file_bytes: u64,
 }
 
 impl OutputHashBundle {
-    // Find the result code in all the fields.
+    // Find the result code in all the fields. Termination by a signal is reported as 128 + the
+    // signal number, as shells do.
     pub fn result_code(&self) -> Option<i32> {
         for (output, _) in &self.hash_details {
-            if let Output::ExitCode(code) = output {
-                return Some(*code);
+            match output {
+                Output::ExitCode(code) => return Some(*code),
+                Output::Signal(signal) => return Some(128 + *signal),
+                _ => {}
             }
         }
         None
     }
+
+    /// Compare the outputs with the ones of another run with the same inputs, one by one, and return
+    /// the differences. The outputs are matched by the file names, and the kinds of the other outputs.
+    pub fn diff(&self, new: &OutputHashBundle) -> Vec<OutputDiff> {
+        // The aggregate hash covers all the outputs, so the equal ones need no comparison.
+        if self.hash == new.hash {
+            return vec![];
+        }
+        let by_name = |bundle: &'_ OutputHashBundle| -> BTreeMap<String, (Output, String)> {
+            bundle
+                .hash_details
+                .iter()
+                .map(|(output, hash)| (output.diff_name(), (output.clone(), hash.clone())))
+                .collect()
+        };
+        let (old_outputs, mut new_outputs) = (by_name(self), by_name(new));
+        let mut diffs = Vec::new();
+        for (name, (old_output, old_hash)) in old_outputs {
+            let old = old_output.diff_value(&old_hash);
+            let (new_output, new_hash) = match new_outputs.remove(&name) {
+                Some(new_output) => new_output,
+                None => {
+                    diffs.push(OutputDiff::Removed { name, old });
+                    continue;
+                }
+            };
+            if let (Output::File(old_file), Output::File(new_file)) = (&old_output, &new_output) {
+                if old_file.present != new_file.present {
+                    diffs.push(OutputDiff::Presence {
+                        name,
+                        old: old_file.present,
+                        new: new_file.present,
+                    });
+                    continue;
+                }
+            }
+            let new = new_output.diff_value(&new_hash);
+            if old != new {
+                diffs.push(OutputDiff::Content { name, old, new });
+            }
+        }
+        for (name, (new_output, new_hash)) in new_outputs {
+            diffs.push(OutputDiff::Added {
+                name,
+                new: new_output.diff_value(&new_hash),
+            });
+        }
+        diffs
+    }
+
+    /// All the stored outputs, both the hashed and the unhashed ones.
+    pub fn stored_details(&self) -> impl Iterator<Item = &(Output, String)> {
+        self.hash_details.iter().chain(&self.unhashed_details)
+    }
+
+    /// The objects the stored outputs reference, as (name, hash) pairs: the present files that are not
+    /// chunked, the chunks of the chunked ones, and the captured stdout/stderr stored as objects.
+    pub fn stored_objects(&self) -> Vec<(String, String)> {
+        let mut objects = Vec::new();
+        for (output, hash) in self.stored_details() {
+            match output {
+                Output::File(fileoutput) if fileoutput.present && fileoutput.chunks.is_empty() => {
+                    objects.push((fileoutput.filename.to_string(), hash.clone()));
+                }
+                Output::File(fileoutput) if fileoutput.present => {
+                    let count = fileoutput.chunks.len();
+                    for (index, chunk) in fileoutput.chunks.iter().enumerate() {
+                        let name = format!("{} (chunk {}/{})", fileoutput.filename, index + 1, count);
+                        objects.push((name, chunk.hash.clone()));
+                    }
+                }
+                Output::StdoutObject(_) => objects.push(("stdout".to_owned(), hash.clone())),
+                Output::StderrObject(_) => objects.push(("stderr".to_owned(), hash.clone())),
+                _ => {}
+            }
+        }
+        objects
+    }
+
+    /// Returns the bundle to be stored, where the captured stdout/stderr larger than the threshold
+    /// are replaced with references to objects (keyed by the same hashes). The hash is not changed.
+    pub fn with_captured_objects(&self, inline_threshold: usize) -> Self {
+        let with_objects = |details: &Vec<(Output, String)>| {
+            details
+                .iter()
+                .map(|(output, hash)| {
+                    let output = match output {
+                        Output::Stdout(buffer) if buffer.len() > inline_threshold => {
+                            Output::StdoutObject(buffer.len() as u64)
+                        }
+                        Output::Stderr(buffer) if buffer.len() > inline_threshold => {
+                            Output::StderrObject(buffer.len() as u64)
+                        }
+                        output => output.clone(),
+                    };
+                    (output, hash.clone())
+                })
+                .collect()
+        };
+        Self {
+            hash: self.hash.clone(),
+            hash_details: with_objects(&self.hash_details),
+            unhashed_details: with_objects(&self.unhashed_details),
+            ..*self
+        }
+    }
 }
 
+/// User defined metadata attached to the cache entry with --attach and --attach_file.
+pub type Metadata = BTreeMap<String, serde_json::Value>;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct InputOutputBundle {
     pub inputs: InputHashBundle,
     pub outputs: OutputHashBundle,
     pub source: String,
+    #[serde(default)]
+    pub metadata: Metadata,
 }
 
 /// Output set is the set of all process outputs.
 #[derive(Default)]
 pub struct OutputSet {
     pub outputs: Vec<Output>,
+    /// Outputs stored with the cache entry, but not part of its hash.
+    pub unhashed_outputs: Vec<Output>,
+    /// Split large files into content-defined chunks of these sizes.
+    pub chunked_objects: Option<ChunkSizes>,
+    /// Maximum size of the buffer the files are read into, in KiB, see `file_hash_buffered`.
+    pub hash_buffer_kb: usize,
+    /// The threads to hash the files on, see `HashPool`.
+    pub hash_pool: HashPool,
+}
+
+/// The default, and the minimum, size of the buffer the files are read into for hashing, in KiB.
+pub const DEFAULT_HASH_BUFFER_KB: usize = 4;
+
+// Allocate the buffer to read the file into, of up to buffer_kb, but no larger than the file needs,
+// so that small files don't pay for the allocation of a large buffer. One byte more than the file
+// size lets the file be read with one read(2), and the end of it be seen with the next one.
+fn hash_buffer(f: &File, buffer_kb: usize) -> Vec<u8> {
+    let file_size = f.metadata().map_or(usize::MAX, |metadata| metadata.len() as usize);
+    let size = file_size
+        .saturating_add(1)
+        .min(buffer_kb << 10)
+        .max(DEFAULT_HASH_BUFFER_KB << 10);
+    vec![0; size]
 }
 
 /// Returns the hash of the given file.
@@ -81,10 +393,15 @@ pub struct OutputSet {
 /// output of stat(2), except atime, so that we don't have to read
 /// them twice during a single build process.
 pub fn file_hash(filename: &Path) -> Result<String> {
-    const BUFSIZE: usize = 4096;
+    file_hash_buffered(filename, DEFAULT_HASH_BUFFER_KB)
+}
+
+/// Returns the hash of the given file, reading it with a buffer of up to `buffer_kb` KiB, which
+/// makes fewer syscalls for large files. The hash doesn't depend on the buffer size.
+pub fn file_hash_buffered(filename: &Path, buffer_kb: usize) -> Result<String> {
     let mut acc = Sha256::new();
     let mut f = File::open(filename).with_context(|| format!("Reading input file '{}'", filename.to_string_lossy()))?;
-    let mut buf: [u8; BUFSIZE] = [0; BUFSIZE];
+    let mut buf = hash_buffer(&f, buffer_kb);
     loop {
         let rd = f.read(&mut buf)?;
         if rd == 0 {
@@ -95,6 +412,213 @@ pub fn file_hash(filename: &Path) -> Result<String> {
     Ok(format!("{:x}", acc.finalize()))
 }
 
+/// Returns the hash of the given file, with CRLF and CR line endings normalized to LF, so that it
+/// matches the hash of the same file with LF line endings.
+///
+/// Binary files (containing NUL bytes) are not normalized, and the result is the same as with
+/// `file_hash`.
+pub fn text_file_hash(filename: &Path) -> Result<String> {
+    text_file_hash_buffered(filename, DEFAULT_HASH_BUFFER_KB)
+}
+
+/// Same as `text_file_hash`, reading the file with a buffer of up to `buffer_kb` KiB.
+pub fn text_file_hash_buffered(filename: &Path, buffer_kb: usize) -> Result<String> {
+    let mut raw_acc = Sha256::new();
+    let mut acc = Sha256::new();
+    let mut f = File::open(filename).with_context(|| format!("Reading input file '{}'", filename.to_string_lossy()))?;
+    let mut buf = hash_buffer(&f, buffer_kb);
+    let mut normalized = Vec::with_capacity(buf.len());
+    let mut binary = false;
+    // Whether the last byte was a CR, which may be followed by LF in the next read.
+    let mut pending_cr = false;
+    loop {
+        let rd = f.read(&mut buf)?;
+        if rd == 0 {
+            break;
+        }
+        raw_acc.update(&buf[..rd]);
+        if binary {
+            continue;
+        }
+        if buf[..rd].contains(&0) {
+            binary = true;
+            continue;
+        }
+        normalized.clear();
+        for &byte in &buf[..rd] {
+            if pending_cr {
+                normalized.push(b'\n');
+                pending_cr = false;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+            if byte == b'\r' {
+                pending_cr = true;
+            } else {
+                normalized.push(byte);
+            }
+        }
+        acc.update(&normalized);
+    }
+    if binary {
+        return Ok(format!("{:x}", raw_acc.finalize()));
+    }
+    if pending_cr {
+        acc.update(b"\n");
+    }
+    Ok(format!("{:x}", acc.finalize()))
+}
+
+/// Copies the reader into the writer, and returns the hash of the copied content, i.e. the same
+/// hash that `file_hash` would return for the written file.
+///
+/// Hashing while copying overlaps the hash calculation with the I/O, instead of reading the whole
+/// file once again after it's written. The copy goes through a pair of buffers, each filled by
+/// several reads: one is hashed on the hash pool and written, so the hashing doesn't hold up the
+/// async executor, while the other one is read. The hash is SHA256 as for all object keys, so the
+/// result can be compared with the key of the object.
+pub async fn copy_and_hash<R, W>(reader: &mut R, writer: &mut W, hash_pool: &HashPool) -> Result<String>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    const BUFSIZE: usize = 1 << 20;
+    let mut acc = Sha256::new();
+    let mut buf = vec![0; BUFSIZE];
+    let mut next = vec![0; BUFSIZE];
+    let mut filled = fill_buffer(reader, &mut buf).await?;
+    while filled > 0 {
+        let shared = Arc::new(buf);
+        let hashed = {
+            let shared = shared.clone();
+            hash_pool.run(move || {
+                acc.update(&shared[..filled]);
+                Ok(acc)
+            })
+        };
+        let written = async {
+            writer.write_all(&shared[..filled]).await?;
+            hashed.await
+        };
+        let (hashed, next_filled) = futures::join!(written, fill_buffer(reader, &mut next));
+        acc = hashed?;
+        // The hash job has dropped its reference by now, so the buffer is reused.
+        buf = Arc::try_unwrap(shared).unwrap_or_else(|_| vec![0; BUFSIZE]);
+        std::mem::swap(&mut buf, &mut next);
+        filled = next_filled?;
+    }
+    writer.flush().await?;
+    Ok(format!("{:x}", acc.finalize()))
+}
+
+// Read into the buffer until it's full, or the reader ends, and return the number of bytes read.
+async fn fill_buffer<R: AsyncRead + Unpin + ?Sized>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+type HashJob = Box<dyn FnOnce() + Send>;
+
+/// The threads to hash the files on, with --hash_threads, kept for the whole run of the capsule.
+///
+/// They are capsule's own, and not the blocking pool of the async runtime, which is shared with
+/// the I/O. Without threads, the files are hashed one by one, on the calling thread, or on the
+/// blocking pool from async code. Clones share the threads.
+#[derive(Clone, Debug, Default)]
+pub struct HashPool {
+    sender: Option<mpsc::Sender<HashJob>>,
+}
+
+impl HashPool {
+    pub fn new(threads: usize) -> Self {
+        if threads <= 1 {
+            return Self::default();
+        }
+        let (sender, receiver) = mpsc::channel::<HashJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            // The threads exit once all the clones of the pool are dropped.
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => return,
+                }
+            });
+        }
+        Self { sender: Some(sender) }
+    }
+
+    /// Runs the function on the pool, or on the blocking pool of the async runtime without threads.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return task::spawn_blocking(f).await?,
+        };
+        let (result_sender, result) = futures::channel::oneshot::channel();
+        let job = Box::new(move || {
+            let _ = result_sender.send(f());
+        });
+        sender
+            .send(job)
+            .map_err(|_| anyhow::anyhow!("Hashing threads are gone"))?;
+        result.await.map_err(|_| anyhow::anyhow!("Hashing thread panicked"))?
+    }
+
+    /// Returns the hashes of the given files, in their order, hashing them with the given function.
+    fn hash_files(
+        &self,
+        files: Vec<PathBuf>,
+        buffer_kb: usize,
+        hash: fn(&Path, usize) -> Result<String>,
+    ) -> Result<Vec<String>> {
+        let sender = match &self.sender {
+            Some(sender) if files.len() > 1 => sender,
+            _ => return files.iter().map(|file| hash(file, buffer_kb)).collect(),
+        };
+        let (hash_sender, hash_receiver) = mpsc::channel();
+        let count = files.len();
+        for (index, file) in files.into_iter().enumerate() {
+            let hash_sender = hash_sender.clone();
+            let job = Box::new(move || {
+                let _ = hash_sender.send((index, hash(&file, buffer_kb)));
+            });
+            sender
+                .send(job)
+                .map_err(|_| anyhow::anyhow!("Hashing threads are gone"))?;
+        }
+        drop(hash_sender);
+        let mut hashes = vec![String::new(); count];
+        let mut received = 0;
+        for (index, hash) in hash_receiver {
+            hashes[index] = hash?;
+            received += 1;
+        }
+        if received < count {
+            return Err(anyhow::anyhow!("Hashing thread panicked"));
+        }
+        Ok(hashes)
+    }
+}
+
+fn file_size(filename: &Path) -> Result<u64> {
+    Ok(std::fs::metadata(filename)
+        .with_context(|| format!("Reading metadata of file '{}'", filename.to_string_lossy()))?
+        .len())
+}
+
 fn string_hash(s: &str) -> String {
     let mut acc = Sha256::new();
     acc.update(s.as_bytes());
@@ -121,39 +645,50 @@ impl InputSet {
     /// Returns the HEX string of the hash of the whole input set.
     ///
     /// We calculate the whole hash bundle, and discard the separate hashes.
-    pub fn hash(self, root: &Option<String>) -> Result<String> {
-        self.hash_bundle(root).map(|x| x.hash)
+    pub fn hash<'a>(self, roots: impl Into<WorkspaceRoots<'a>>) -> Result<String> {
+        self.hash_bundle(roots).map(|x| x.hash)
     }
 
     /// Returns the HEX string of the hash of the files in the input set, and the total hash.
     ///
     /// It does this by calculating a SHA256 hash of all SHA256 hashes of inputs (being either file
     /// or tool tag) sorted by the values of the hashes themselves.
-    pub fn hash_bundle(self, root: &Option<String>) -> Result<InputHashBundle> {
+    pub fn hash_bundle<'a>(self, roots: impl Into<WorkspaceRoots<'a>>) -> Result<InputHashBundle> {
+        let roots = roots.into();
         // Calculate the hash of the input set independently of the order.
         let mut hash_bundle = InputHashBundle::default();
+        let mut files = vec![];
+        for input in &self.inputs {
+            if let Input::File(filename) = input {
+                let path = filename.to_path(roots)?;
+                hash_bundle.file_count += 1;
+                hash_bundle.file_bytes += file_size(&path)?;
+                files.push(path);
+            }
+        }
+        let file_hash = if self.normalize_line_endings {
+            text_file_hash_buffered
+        } else {
+            file_hash_buffered
+        };
+        let mut file_hashes = self
+            .hash_pool
+            .hash_files(files, self.hash_buffer_kb, file_hash)?
+            .into_iter();
         for input in self.inputs {
             let hash = match input {
-                Input::File(ref filename) => {
-                    let path = filename.to_path(root)?;
-                    file_hash(&path)?
-                }
+                Input::File(_) => file_hashes.next().expect("All input files are hashed"),
                 Input::ToolTag(ref s) => string_hash(s),
             };
             hash_bundle.hash_details.push((input, hash));
         }
         // Sort inputs hashes by the hash value, but so that tool_tags come first.
         // This is needed so that when we cap our JSON, we could still see tool_tags.
-        hash_bundle.hash_details.sort_by(|a, b| {
-            if let Input::ToolTag(_) = a.0 {
-                if let Input::ToolTag(_) = b.0 {
-                    a.1.cmp(&b.1)
-                } else {
-                    Ordering::Less
-                }
-            } else {
-                a.1.cmp(&b.1)
-            }
+        // Inputs with equal hashes (e.g. files with the same content) are ordered by themselves,
+        // so that the details don't depend on the order in which the inputs were found.
+        hash_bundle.hash_details.sort_by(|(a, a_hash), (b, b_hash)| {
+            let key = |input: &Input| !matches!(input, Input::ToolTag(_));
+            (key(a), a_hash, a).cmp(&(key(b), b_hash, b))
         });
         hash_bundle.hash = bundle_hash(hash_bundle.hash_details.iter().map(|(inp, hash)| {
             (
@@ -176,42 +711,93 @@ impl OutputSet {
     /// Returns the HEX string of the hash of the whole input set.
     ///
     /// We calculate the whole hash bundle, and discard the separate hashes.
-    pub fn hash(self, root: &Option<String>) -> Result<String> {
-        self.hash_bundle(root).map(|x| x.hash)
+    pub fn hash<'a>(self, roots: impl Into<WorkspaceRoots<'a>>) -> Result<String> {
+        self.hash_bundle(roots).map(|x| x.hash)
     }
 
     /// Returns the HEX string of the hash of the files in the input set, and the total hash.
     ///
     /// It does this by calculating a SHA256 hash of all SHA256 hashes of inputs (being either file
     /// or tool tag) sorted by the values of the hashes themselves.
-    pub fn hash_bundle(self, root: &Option<String>) -> Result<OutputHashBundle> {
+    pub fn hash_bundle<'a>(self, roots: impl Into<WorkspaceRoots<'a>>) -> Result<OutputHashBundle> {
+        let roots = roots.into();
         // Calculate the hash of the input set independently of the order.
         let mut hash_bundle = OutputHashBundle::default();
-        for output in self.outputs {
+        let outputs = self.outputs.into_iter().map(|output| (output, true));
+        let unhashed_outputs = self.unhashed_outputs.into_iter().map(|output| (output, false));
+        let outputs: Vec<_> = outputs.chain(unhashed_outputs).collect();
+        // The files that are not chunked are hashed in advance, possibly in parallel.
+        let mut files = vec![];
+        let mut chunked_files = vec![];
+        for (output, _) in &outputs {
+            if let Output::File(file_output) = output {
+                if file_output.present {
+                    let path = file_output.filename.to_path(roots)?;
+                    let size = file_size(&path)?;
+                    hash_bundle.file_count += 1;
+                    hash_bundle.file_bytes += size;
+                    if matches!(self.chunked_objects, Some(sizes) if size >= sizes.min_file) {
+                        chunked_files.push(path);
+                    } else {
+                        files.push(path);
+                    }
+                }
+            }
+        }
+        let mut file_hashes = self
+            .hash_pool
+            .hash_files(files, self.hash_buffer_kb, file_hash_buffered)?
+            .into_iter();
+        let mut chunked_files = chunked_files.into_iter();
+        for (mut output, hashed) in outputs {
             let hash = match output {
-                Output::File(ref file_output) => {
+                Output::File(ref mut file_output) => {
                     if file_output.present {
-                        let path = file_output.filename.to_path(root)?;
-                        file_hash(&path)?
+                        let path = file_output.filename.to_path(roots)?;
+                        // The chunks are not part of the hash, as they are determined by the content.
+                        if chunked_files.as_slice().first() == Some(&path) {
+                            chunked_files.next();
+                            let sizes = self.chunked_objects.as_ref().expect("Only chunked with the sizes");
+                            let (hash, chunks) = file_chunks(&path, sizes)?;
+                            file_output.chunks = chunks;
+                            hash
+                        } else {
+                            file_hashes.next().expect("All output files are hashed")
+                        }
                     } else {
                         "".to_string()
                     }
                 }
                 Output::ExitCode(code) => string_hash(&code.to_string()),
+                Output::Signal(signal) => string_hash(&signal.to_string()),
                 Output::Stdout(ref buffer) => bytes_hash(buffer),
                 Output::Stderr(ref buffer) => bytes_hash(buffer),
+                Output::Dir(ref dirname) => string_hash(&dirname.to_string()),
+                Output::Directory { ref dirname, mode } => string_hash(&format!("{} {:o}", dirname, mode)),
+                Output::StdoutObject(_) | Output::StderrObject(_) => {
+                    return Err(anyhow::anyhow!("Captured output objects cannot be hashed"));
+                }
             };
-            hash_bundle.hash_details.push((output, hash));
+            if hashed {
+                hash_bundle.hash_details.push((output, hash));
+            } else {
+                hash_bundle.unhashed_details.push((output, hash));
+            }
+        }
+        // Sort outputs hashes by the hash value, and the equal ones by the outputs themselves.
+        for details in [&mut hash_bundle.hash_details, &mut hash_bundle.unhashed_details] {
+            details.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
         }
-        // Sort inputs hashes by the hash value.
-        hash_bundle.hash_details.sort_by(|a, b| a.1.cmp(&b.1));
         hash_bundle.hash = bundle_hash(hash_bundle.hash_details.iter().map(|(inp, hash)| {
             (
                 match inp {
                     Output::File(_) => "File",
                     Output::ExitCode(_) => "ExitCode",
-                    Output::Stdout(_) => "StdOut",
-                    Output::Stderr(_) => "StdErr",
+                    Output::Signal(_) => "Signal",
+                    Output::Stdout(_) | Output::StdoutObject(_) => "StdOut",
+                    Output::Stderr(_) | Output::StderrObject(_) => "StdErr",
+                    Output::Dir(_) => "Dir",
+                    Output::Directory { .. } => "Directory",
                 },
                 &hash[..],
             )
@@ -222,6 +808,10 @@ impl OutputSet {
     pub fn add_output(&mut self, output: Output) {
         self.outputs.push(output)
     }
+
+    pub fn add_unhashed_output(&mut self, output: Output) {
+        self.unhashed_outputs.push(output)
+    }
 }
 
 #[cfg(test)]
@@ -241,6 +831,213 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn copy_and_hash_test() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        // More than a buffer, so that the copy goes through both.
+        let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        for hash_pool in [HashPool::default(), HashPool::new(2)] {
+            let mut writer = tokio::fs::File::create(file.path()).await?;
+            let hash = copy_and_hash(&mut &content[..], &mut writer, &hash_pool).await?;
+            assert_eq!(std::fs::read(file.path())?, content);
+            assert_eq!(hash, file_hash(file.path())?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_captured_objects() -> Result<()> {
+        let mut output_set = OutputSet::default();
+        output_set.add_output(Output::ExitCode(0));
+        output_set.add_output(Output::Stdout(b"short".to_vec()));
+        output_set.add_output(Output::Stderr(b"a bit longer".to_vec()));
+        let bundle = output_set.hash_bundle(&None)?;
+        let stored = bundle.with_captured_objects(8);
+        assert_eq!(stored.hash, bundle.hash);
+        let stderr_hash = bytes_hash(b"a bit longer");
+        assert!(stored
+            .hash_details
+            .contains(&(Output::Stdout(b"short".to_vec()), bytes_hash(b"short"))));
+        assert!(stored.hash_details.contains(&(Output::StderrObject(12), stderr_hash)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unhashed_outputs() -> Result<()> {
+        let mut output_set = OutputSet::default();
+        output_set.add_output(Output::ExitCode(0));
+        output_set.add_unhashed_output(Output::Stderr(b"12:00:00 done".to_vec()));
+        let bundle = output_set.hash_bundle(&None)?;
+        let mut output_set = OutputSet::default();
+        output_set.add_output(Output::ExitCode(0));
+        output_set.add_unhashed_output(Output::Stderr(b"12:00:01 done".to_vec()));
+        let other_bundle = output_set.hash_bundle(&None)?;
+        assert_eq!(bundle.hash, other_bundle.hash);
+        assert!(bundle.diff(&other_bundle).is_empty());
+        assert_eq!(bundle.hash_details.len(), 1);
+        assert_eq!(bundle.stored_details().count(), 2);
+
+        // They are stored as objects the same way as the hashed ones.
+        let stored = bundle.with_captured_objects(8);
+        assert_eq!(
+            stored.unhashed_details,
+            vec![(Output::StderrObject(13), bytes_hash(b"12:00:00 done"))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_diff() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"content")?;
+        file.flush()?;
+        let bundle = |tool_tag: &str| -> Result<InputHashBundle> {
+            let mut input_set = InputSet::default();
+            input_set.add_input(Input::File(file.path().into()));
+            input_set.add_input(Input::ToolTag(tool_tag.to_owned()));
+            input_set.hash_bundle(&None)
+        };
+        let old = bundle("rustc 1.56")?;
+        assert!(old.diff(&old).is_empty());
+
+        let new = bundle("rustc 1.57")?;
+        let mut changed = new.clone();
+        changed.hash = "changed".to_owned();
+        for (input, hash) in &mut changed.hash_details {
+            if let Input::File(_) = input {
+                *hash = "0123".to_owned();
+            }
+        }
+        let file_name = format!("file {}", file.path().display());
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                InputDiff::Removed {
+                    name: "tool tag rustc 1.56".to_owned(),
+                    old: string_hash("rustc 1.56")
+                },
+                InputDiff::Added {
+                    name: "tool tag rustc 1.57".to_owned(),
+                    new: string_hash("rustc 1.57")
+                },
+            ]
+        );
+        let file_diff = new.diff(&changed);
+        assert_eq!(file_diff.len(), 1);
+        assert!(file_diff[0].to_string().starts_with(&file_name));
+        assert!(file_diff[0].to_string().ends_with(" vs 0123"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_diff() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"content")?;
+        file.flush()?;
+        let file_output = |present| {
+            Output::File(FileOutput {
+                filename: file.path().into(),
+                present,
+                mode: 0o644,
+                negative: false,
+                chunks: vec![],
+            })
+        };
+        let bundle = |outputs: Vec<Output>| -> Result<OutputHashBundle> {
+            let mut output_set = OutputSet::default();
+            for output in outputs {
+                output_set.add_output(output);
+            }
+            output_set.hash_bundle(&None)
+        };
+        let old = bundle(vec![
+            Output::ExitCode(0),
+            Output::Stdout(b"old".to_vec()),
+            file_output(true),
+        ])?;
+        assert!(old.diff(&old).is_empty());
+
+        let new = bundle(vec![
+            Output::Signal(9),
+            Output::Stdout(b"new".to_vec()),
+            file_output(false),
+        ])?;
+        let file_name = format!("file {}", file.path().display());
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                OutputDiff::Content {
+                    name: "exit status".to_owned(),
+                    old: "exit code 0".to_owned(),
+                    new: "signal 9".to_owned()
+                },
+                OutputDiff::Presence {
+                    name: file_name.clone(),
+                    old: true,
+                    new: false
+                },
+                OutputDiff::Content {
+                    name: "stdout".to_owned(),
+                    old: bytes_hash(b"old"),
+                    new: bytes_hash(b"new")
+                },
+            ]
+        );
+        assert_eq!(
+            old.diff(&new)[1].to_string(),
+            format!("{}: present vs absent", file_name)
+        );
+
+        let new = bundle(vec![
+            Output::ExitCode(0),
+            Output::Stderr(b"old".to_vec()),
+            file_output(true),
+        ])?;
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                OutputDiff::Removed {
+                    name: "stdout".to_owned(),
+                    old: bytes_hash(b"old")
+                },
+                OutputDiff::Added {
+                    name: "stderr".to_owned(),
+                    new: bytes_hash(b"old")
+                },
+            ]
+        );
+
+        // Only the order of the equal outputs differs.
+        let mut reordered = old.clone();
+        reordered.hash_details.reverse();
+        reordered.hash = "reordered".to_owned();
+        assert!(old.diff(&reordered).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn text_file_hash_test() -> Result<()> {
+        let hash_of = |content: &[u8]| -> Result<(String, String)> {
+            let mut file = NamedTempFile::new()?;
+            file.write_all(content)?;
+            file.flush()?;
+            Ok((text_file_hash(file.path())?, file_hash(file.path())?))
+        };
+        let (lf_hash, _) = hash_of(b"line 1\nline 2\n")?;
+        assert_eq!(hash_of(b"line 1\r\nline 2\r\n")?.0, lf_hash);
+        assert_eq!(hash_of(b"line 1\rline 2\r")?.0, lf_hash);
+        // CRLF split between the reads.
+        let mut long_crlf = vec![b'x'; 4095];
+        long_crlf.extend_from_slice(b"\r\n");
+        let mut long_lf = vec![b'x'; 4095];
+        long_lf.extend_from_slice(b"\n");
+        assert_eq!(hash_of(&long_crlf)?.0, hash_of(&long_lf)?.0);
+        // Binary files are hashed as is.
+        let (text_hash, raw_hash) = hash_of(b"\0binary\r\n")?;
+        assert_eq!(text_hash, raw_hash);
+        Ok(())
+    }
+
     #[test]
     fn file_hash_nonexistent() {
         assert!(file_hash(Path::new("/nonexistent-capsule-input")).is_err());
@@ -306,9 +1103,188 @@ mod tests {
             "f409e4c7ae76997e69556daae6139bee1f02e4f618d3da8deea10bb35b6c0ebd"
         );
         input_set.add_input(Input::File(file2.path().into()));
+        let bundle = input_set.hash_bundle(&None).unwrap();
         assert_eq!(
-            input_set.hash(&None).unwrap(),
+            bundle.hash,
             "a282f3da61a4bc322a8d31da6d30a0e924017962acbef2f6996b81709de8cdc3"
         );
+        assert_eq!(bundle.file_count, 2);
+        assert_eq!(bundle.file_bytes, 10);
+    }
+
+    #[test]
+    fn test_hash_buffer_kb() -> Result<()> {
+        // Large enough for several reads, with a CRLF across the default buffer boundary.
+        let mut content = vec![b'x'; 100_000];
+        content[4095] = b'\r';
+        content[4096] = b'\n';
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&content)?;
+        file.flush()?;
+        let (hash, text_hash) = (file_hash(file.path())?, text_file_hash(file.path())?);
+        for buffer_kb in [0, 1, 4, 64, 1024] {
+            assert_eq!(file_hash_buffered(file.path(), buffer_kb)?, hash);
+            assert_eq!(text_file_hash_buffered(file.path(), buffer_kb)?, text_hash);
+        }
+        assert_eq!(hash_buffer(file.as_file(), 1024).len(), 100_001);
+        assert_eq!(hash_buffer(file.as_file(), 64).len(), 64 << 10);
+        assert_eq!(hash_buffer(file.as_file(), 0).len(), DEFAULT_HASH_BUFFER_KB << 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_threads() -> Result<()> {
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let mut file = NamedTempFile::new()?;
+            write!(file, "content {}", i % 7)?;
+            files.push(file);
+        }
+        let bundles = |hash_threads: usize| -> Result<(InputHashBundle, OutputHashBundle)> {
+            let hash_pool = HashPool::new(hash_threads);
+            let mut input_set = InputSet {
+                hash_pool: hash_pool.clone(),
+                ..Default::default()
+            };
+            let mut output_set = OutputSet {
+                hash_pool,
+                ..Default::default()
+            };
+            for file in &files {
+                input_set.add_input(Input::File(file.path().into()));
+                output_set.add_output(Output::File(FileOutput {
+                    filename: file.path().into(),
+                    present: true,
+                    mode: 0o644,
+                    negative: false,
+                    chunks: vec![],
+                }));
+            }
+            Ok((input_set.hash_bundle(&None)?, output_set.hash_bundle(&None)?))
+        };
+        let (inputs, outputs) = bundles(1)?;
+        for hash_threads in [0, 4, 32] {
+            let (other_inputs, other_outputs) = bundles(hash_threads)?;
+            assert_eq!(other_inputs.hash_details, inputs.hash_details);
+            assert_eq!(other_outputs.hash_details, outputs.hash_details);
+        }
+        // A file failing to hash fails the whole set.
+        let hash_pool = HashPool::new(2);
+        let missing = hash_pool.hash_files(
+            vec![files[0].path().into(), "/nonexistent/file".into()],
+            DEFAULT_HASH_BUFFER_KB,
+            file_hash_buffered,
+        );
+        assert!(missing.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_set_equal_hashes() -> Result<()> {
+        let mut files = Vec::new();
+        for _ in 0..3 {
+            let mut file = NamedTempFile::new()?;
+            file.write_all(b"same content")?;
+            files.push(file);
+        }
+        let bundle_of = |order: &[usize]| -> Result<InputHashBundle> {
+            let mut input_set = InputSet::default();
+            for &i in order {
+                input_set.add_input(Input::File(files[i].path().into()));
+            }
+            input_set.hash_bundle(&None)
+        };
+        let bundle = bundle_of(&[0, 1, 2])?;
+        for order in [[2, 1, 0], [1, 0, 2], [2, 0, 1]] {
+            assert_eq!(bundle_of(&order)?.hash_details, bundle.hash_details);
+        }
+        let inputs: Vec<_> = bundle.hash_details.iter().map(|(input, _)| input).collect();
+        assert!(inputs.windows(2).all(|pair| pair[0] < pair[1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stored_objects() -> Result<()> {
+        let file = |name: &str, present, chunks| {
+            Output::File(FileOutput {
+                filename: WorkspacePath::from(name),
+                present,
+                mode: 0o644,
+                negative: false,
+                chunks,
+            })
+        };
+        let chunks = vec![
+            Chunk {
+                hash: "c1".to_owned(),
+                length: 10,
+            },
+            Chunk {
+                hash: "c2".to_owned(),
+                length: 20,
+            },
+        ];
+        let bundle = OutputHashBundle {
+            hash: "h".to_owned(),
+            hash_details: vec![
+                (Output::ExitCode(0), "e".to_owned()),
+                (file("a.txt", true, vec![]), "a".to_owned()),
+                (file("missing.txt", false, vec![]), "m".to_owned()),
+                (file("big.bin", true, chunks), "b".to_owned()),
+                (Output::Stdout(b"short".to_vec()), "o".to_owned()),
+            ],
+            unhashed_details: vec![(Output::StderrObject(100), "s".to_owned())],
+            ..Default::default()
+        };
+        assert_eq!(
+            bundle.stored_objects(),
+            vec![
+                ("a.txt".to_owned(), "a".to_owned()),
+                ("big.bin (chunk 1/2)".to_owned(), "c1".to_owned()),
+                ("big.bin (chunk 2/2)".to_owned(), "c2".to_owned()),
+                ("stderr".to_owned(), "s".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_set_tool_tags_and_files() -> Result<()> {
+        // Files with the hashes on both sides of the tool tag's hash.
+        let tag_hash = string_hash("tool tag");
+        let mut files = Vec::new();
+        let (mut lower, mut higher) = (false, false);
+        for i in 0.. {
+            let content = format!("content {}", i);
+            let below = string_hash(&content) < tag_hash;
+            if (below && !lower) || (!below && !higher) {
+                let mut file = NamedTempFile::new()?;
+                file.write_all(content.as_bytes())?;
+                files.push(file);
+                lower |= below;
+                higher |= !below;
+            }
+            if lower && higher {
+                break;
+            }
+        }
+        let bundle_of = |order: &[usize]| -> Result<InputHashBundle> {
+            let mut input_set = InputSet::default();
+            for &i in order {
+                match i {
+                    0 => input_set.add_input(Input::ToolTag("tool tag".to_owned())),
+                    i => input_set.add_input(Input::File(files[i - 1].path().into())),
+                }
+            }
+            input_set.hash_bundle(&None)
+        };
+        let bundle = bundle_of(&[0, 1, 2])?;
+        for order in [[0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]] {
+            let other = bundle_of(&order)?;
+            assert_eq!(other.hash, bundle.hash);
+            assert_eq!(other.hash_details, bundle.hash_details);
+        }
+        assert_eq!(bundle.hash_details[0].0, Input::ToolTag("tool tag".to_owned()));
+        Ok(())
     }
 }
@@ -1,5 +1,7 @@
 pub mod caching;
 pub mod capsule;
+pub mod chunking;
+pub mod clock;
 pub mod config;
 pub mod iohashing;
 pub mod observability;
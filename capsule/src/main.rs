@@ -1,19 +1,122 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use capsule::caching::backend::CachingBackend;
 use capsule::caching::dummy;
+use capsule::caching::ipfs;
+use capsule::caching::measure;
+use capsule::caching::migrate;
 use capsule::caching::s3;
-use capsule::capsule::Capsule;
+use capsule::capsule::{describe_bundles_diff, Capsule};
 use capsule::config::{Backend, Config};
+use capsule::iohashing::InputOutputBundle;
 use capsule::observability::dummy::Dummy as DummyLogger;
 use capsule::observability::honeycomb;
 use capsule::observability::logger::Logger;
+use capsule::workspace_path::WorkspacePath;
 use capsule::wrapper;
-use log::error;
+use log::{error, info, warn};
 use std::env;
 use std::path::Path;
 use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 
+fn dummy_backend(config: &Config) -> dummy::DummyBackend {
+    dummy::DummyBackend {
+        verbose_output: config.verbose,
+        capsule_id: config.capsule_id.as_ref().cloned().unwrap(),
+    }
+}
+
+async fn create_backend(config: &Config) -> Result<Box<dyn CachingBackend + Send + Sync>> {
+    // Measuring replaces the caching backend, as nothing is stored.
+    if config.measure {
+        return Ok(Box::new(measure::MeasureBackend::from_config(config)));
+    }
+    let backend: Box<dyn CachingBackend + Send + Sync> = match config.backend {
+        // With no backend, capsule runs in passive mode, and the backend is never used.
+        Backend::Dummy | Backend::None => Box::new(dummy_backend(config)),
+        Backend::S3 => {
+            let backend = s3::S3Backend::from_config(config)?;
+            if config.s3_probe {
+                backend.probe(config).await?;
+            }
+            Box::new(backend)
+        }
+    };
+    // Objects may be stored in IPFS, while the keys still go through the backend above.
+    if config.ipfs_api_url.is_some() {
+        Ok(Box::new(ipfs::IpfsBackend::from_config(config, backend)?))
+    } else {
+        Ok(backend)
+    }
+}
+
+// Train a dictionary for --bundle_zstd_dict on the existing cache entries, and write it out.
+async fn train_dict(config: &Config, output: &WorkspacePath) -> Result<i32> {
+    if !matches!(config.backend, Backend::S3) {
+        bail!("Training a dictionary needs --backend s3");
+    }
+    let backend = s3::S3Backend::from_config(config)?;
+    let dict = backend
+        .train_dict(config.train_dict_samples.unwrap_or(DEFAULT_TRAIN_DICT_SAMPLES))
+        .await?;
+    let path = output.to_path(config.roots())?;
+    std::fs::write(&path, &dict).with_context(|| format!("Writing dictionary '{}'", path.display()))?;
+    info!("Wrote a dictionary of {} bytes to '{}'", dict.len(), path.display());
+    Ok(0)
+}
+
+const DEFAULT_TRAIN_DICT_SAMPLES: usize = 1000;
+
+// Print the differences between two dumped cache entries, and exit with 1 if there are any, as diff does.
+fn compare(config: &Config, old: &WorkspacePath, new: &WorkspacePath) -> Result<i32> {
+    let read_bundle = |bundle: &WorkspacePath| -> Result<InputOutputBundle> {
+        let path = bundle.to_path(config.roots())?;
+        let contents = std::fs::read(&path).with_context(|| format!("Reading cache entry '{}'", path.display()))?;
+        serde_json::from_slice(&contents).with_context(|| format!("Parsing cache entry '{}'", path.display()))
+    };
+    match describe_bundles_diff(&read_bundle(old)?, &read_bundle(new)?) {
+        Some(description) => {
+            print!("{}", description);
+            Ok(1)
+        }
+        None => Ok(0),
+    }
+}
+
+// Copy the cache entries of the capsule_id, and the objects they reference, from one S3 backend to another.
+// Each side is configured like capsule itself, with its flags given on top of the configuration files. Without
+// --from, the source is the backend capsule itself is configured with, the flags before 'migrate' included.
+async fn migrate_entries(config: &Config, from: &[String], to: &[String], default_toml: Option<&Path>) -> Result<i32> {
+    let capsule_id = config.capsule_id.as_ref().cloned().unwrap();
+    let backend = |flags: &[String]| -> Result<s3::S3Backend> {
+        let mut args: Vec<String> = ["capsule", "--backend", "s3", "-c", &capsule_id]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        args.extend(flags.iter().cloned());
+        // The command is never run, but the configuration needs one.
+        args.extend(["--".to_owned(), "true".to_owned()]);
+        let config = Config::new(args, default_toml)
+            .with_context(|| format!("Parsing backend flags '{}'", shell_words::join(flags)))?;
+        if !matches!(config.backend, Backend::S3) {
+            bail!("Migrating cache entries needs --backend s3");
+        }
+        s3::S3Backend::from_config(&config)
+    };
+    let from = if from.is_empty() {
+        s3::S3Backend::from_config(config)?
+    } else {
+        backend(from)?
+    };
+    let summary = migrate::migrate(&from, &backend(to)?, config.migrate_dry_run).await?;
+    if config.migrate_dry_run {
+        info!("Would copy {}", summary);
+    } else {
+        info!("Copied {}", summary);
+    }
+    Ok(0)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging. Default is INFO level, can be overridden in CAPSULE_LOG
@@ -26,21 +129,62 @@ async fn main() -> Result<()> {
     // was run, or after. This flag says whether the program was actually run.
     let mut program_run = AtomicBool::new(false);
     let program_run_ref = &mut program_run;
+    // The command to fall back to if it's not the one after --, i.e. the --fallback_command.
+    let mut fallback_command = None;
+    let fallback_command_ref = &mut fallback_command;
+    // The --set_env variables, which the command sees when it's run without capsule too.
+    let mut fallback_env = vec![];
+    let fallback_env_ref = &mut fallback_env;
     // Place all the initialization logic is a separate block, so that the ? bailouts
     // return the result right there.
     let result = async move {
         let default_toml = std::env::var("HOME").ok().map(|home| home + "/.capsules.toml");
-        let config = Config::new(
+        let mut config = Config::new(
             env::args(),
             default_toml.as_ref().map(Path::new),
         )?;
+        // --quiet takes precedence over CAPSULE_LOG. The wrapped program's output is not logged, so it's unaffected.
+        if config.quiet {
+            log::set_max_level(log::max_level().min(log::LevelFilter::Warn));
+        }
+        if config.fallback_command.is_some() {
+            *fallback_command_ref = Some(config.produce_command()?);
+        }
+        *fallback_env_ref = config.get_set_env()?;
+        // Comparing cache entries doesn't run anything, and reports errors with 2, as diff does.
+        if let Some((old, new)) = &config.compare_bundles {
+            return compare(&config, old, new).or_else(|err| {
+                error!("Capsule error: {:#}", err);
+                Ok(2)
+            });
+        }
+        // Training a dictionary doesn't run anything either, so there is nothing to fall back to.
+        if let Some(output) = &config.train_dict_output {
+            return train_dict(&config, output).await.or_else(|err| {
+                error!("Capsule error: {:#}", err);
+                Ok(1)
+            });
+        }
+
+        // Neither does migrating the cache entries.
+        if let Some((from, to)) = &config.migrate {
+            return migrate_entries(&config, from, to, default_toml.as_ref().map(Path::new))
+                .await
+                .or_else(|err| {
+                    error!("Capsule error: {:#}", err);
+                    Ok(1)
+                });
+        }
+
         // First, instantiate our caching backend (S3, Dummy, or possibly other in the future).
-        let backend: Box<dyn CachingBackend> = match config.backend {
-            Backend::Dummy => Box::new(dummy::DummyBackend {
-                verbose_output: config.verbose,
-                capsule_id: config.capsule_id.as_ref().cloned().unwrap(),
-            }),
-            Backend::S3 => Box::new(s3::S3Backend::from_config(&config)?),
+        let backend = match create_backend(&config).await {
+            Ok(backend) => backend,
+            Err(err) if config.passive_on_missing_backend => {
+                warn!("Caching backend unavailable, running in passive mode: {:#}", err);
+                config.passive = true;
+                Box::new(dummy_backend(&config))
+            }
+            Err(err) => return Err(err),
         };
         // Instantiate our logger (for observability)
         let logger: Box<dyn Logger> = if config.honeycomb_dataset.is_some() {
@@ -51,7 +195,35 @@ async fn main() -> Result<()> {
 
         let capsule = Capsule::new(&config, backend.as_ref(), logger.as_ref());
 
-        capsule.run_capsule(program_run_ref).await
+        // Inspecting a cache entry doesn't run anything, so there is nothing to fall back to.
+        if let Some(inputs_hash) = &config.inspect_hash {
+            return capsule.inspect(inputs_hash).await.or_else(|err| {
+                error!("Capsule error: {:#}", err);
+                Ok(1)
+            });
+        }
+
+        if config.selftest {
+            return capsule.selftest().await;
+        }
+
+        // Restoring the outputs elsewhere must not fall back to running the command.
+        if config.restore_to.is_some() {
+            return capsule.run_capsule(program_run_ref).await.or_else(|err| {
+                error!("Capsule error: {:#}", err);
+                Ok(1)
+            });
+        }
+
+        if config.inject_nondeterminism {
+            return capsule.probe_nondeterminism(program_run_ref).await;
+        }
+
+        if let Some(repeat) = config.repeat {
+            capsule.run_capsule_repeated(repeat, program_run_ref).await
+        } else {
+            capsule.run_capsule(program_run_ref).await
+        }
     }
     .await;
 
@@ -65,7 +237,11 @@ async fn main() -> Result<()> {
             // If we failed to run the program, try falling back to
             // just 'exec' behavior without any results caching.
             if !program_run.load(Ordering::SeqCst) {
-                wrapper::exec().expect("Execution of wrapped program failed");
+                match fallback_command {
+                    Some(command) => wrapper::exec_command(command, &fallback_env),
+                    None => wrapper::exec(&fallback_env),
+                }
+                .expect("Execution of wrapped program failed");
                 unreachable!()
             } else {
                 Err(err)
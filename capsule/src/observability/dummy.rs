@@ -1,4 +1,4 @@
-use super::logger::Logger;
+use super::logger::{CacheDecision, Logger};
 use crate::iohashing::{InputHashBundle, OutputHashBundle};
 use anyhow::Result;
 use async_trait::async_trait;
@@ -11,7 +11,8 @@ impl Logger for Dummy {
         &self,
         _inputs_bundle: &InputHashBundle,
         _output_bundle: &OutputHashBundle,
-        _result_from_cache: bool,
+        _cache_decision: CacheDecision,
+        _cache_decision_detail: Option<&str>,
         _non_determinism: bool,
     ) -> Result<()> {
         Ok(())
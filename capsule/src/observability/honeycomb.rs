@@ -2,13 +2,13 @@ use crate::{
     config::Config,
     iohashing::{Input, InputHashBundle, Output, OutputHashBundle},
 };
-use anyhow::anyhow;
 use anyhow::Result;
+use anyhow::{anyhow, bail};
 use async_trait::async_trait;
 use reqwest;
 use serde_json;
 
-use super::logger::Logger;
+use super::logger::{CacheDecision, Logger};
 
 pub struct Honeycomb {
     /// Honeycomb dataset ('capsule', or 'capsule-test' etc.)
@@ -28,10 +28,19 @@ pub struct Honeycomb {
 
     /// Extra Key-values.
     pub extra_kv: Vec<(String, String)>,
+
+    /// Only 1 in this many events is sent.
+    pub sample_rate: u64,
+
+    /// HTTP client, with the configured proxy and CA bundle.
+    pub client: reqwest::Client,
 }
 
 impl Honeycomb {
     pub fn from_config(config: &Config) -> Result<Self> {
+        if config.honeycomb_sample_rate == 0 {
+            bail!("Honeycomb sample rate must be at least 1");
+        }
         Ok(Self {
             dataset: config
                 .honeycomb_dataset
@@ -51,10 +60,20 @@ impl Honeycomb {
                 .ok_or_else(|| anyhow!("Honeycomb Trace ID is not specified"))?,
             parent_id: config.honeycomb_parent_id.clone(),
             extra_kv: config.get_honeycomb_kv()?,
+            sample_rate: config.honeycomb_sample_rate,
+            client: config.reqwest_client()?,
         })
     }
 }
 
+/// Whether the event of the capsule with the given inputs hash is sent, with 1 in sample_rate sent.
+/// The decision only depends on the inputs hash, so that the same build is either always sampled, or
+/// never, and its hits and misses can be followed.
+fn sampled(inputs_hash: &str, sample_rate: u64) -> bool {
+    let prefix = inputs_hash.get(..16).unwrap_or(inputs_hash);
+    u64::from_str_radix(prefix, 16).unwrap_or(0) % sample_rate == 0
+}
+
 /// Max number of JSON entries in the dict. We need to cap it so that
 /// the JSON Size doesn't exceed 100kB.
 const MAX_JSON_ENTRIES: usize = 200;
@@ -85,6 +104,8 @@ fn hash_details_to_json(bundle: &InputHashBundle) -> serde_json::Value {
     if !tool_tag_map.is_empty() {
         json_map.insert("tool_tag".into(), serde_json::Value::Object(tool_tag_map));
     }
+    json_map.insert("file_count".into(), bundle.file_count.into());
+    json_map.insert("file_bytes".into(), bundle.file_bytes.into());
     serde_json::Value::Object(json_map)
 }
 
@@ -92,6 +113,7 @@ fn hash_details_to_json(bundle: &InputHashBundle) -> serde_json::Value {
 fn output_hash_details_to_json(bundle: &OutputHashBundle) -> serde_json::Value {
     let mut file_map = serde_json::Map::<String, serde_json::Value>::new();
     let mut exit_code: Option<i32> = None;
+    let mut signal: Option<i32> = None;
     for (output, hash) in bundle.hash_details.iter() {
         // Cap the size of the resulting JSON.
         if file_map.len() > MAX_JSON_ENTRIES {
@@ -105,6 +127,9 @@ fn output_hash_details_to_json(bundle: &OutputHashBundle) -> serde_json::Value {
             Output::ExitCode(code) => {
                 exit_code = Some(*code);
             }
+            Output::Signal(number) => {
+                signal = Some(*number);
+            }
             _ => {}
         }
     }
@@ -115,6 +140,11 @@ fn output_hash_details_to_json(bundle: &OutputHashBundle) -> serde_json::Value {
     if let Some(code) = exit_code {
         json_map.insert("exit_code".into(), serde_json::Value::Number(code.into()));
     }
+    if let Some(signal) = signal {
+        json_map.insert("signal".into(), serde_json::Value::Number(signal.into()));
+    }
+    json_map.insert("file_count".into(), bundle.file_count.into());
+    json_map.insert("file_bytes".into(), bundle.file_bytes.into());
     serde_json::Value::Object(json_map)
 }
 
@@ -124,13 +154,24 @@ impl Logger for Honeycomb {
         &self,
         inputs_bundle: &InputHashBundle,
         output_bundle: &OutputHashBundle,
-        result_from_cache: bool,
+        cache_decision: CacheDecision,
+        cache_decision_detail: Option<&str>,
         non_determinism: bool,
     ) -> Result<()> {
+        if !sampled(&inputs_bundle.hash, self.sample_rate) {
+            return Ok(());
+        }
         let mut map = serde_json::Map::new();
         map.insert("trace.trace_id".into(), self.trace_id.clone().into());
         map.insert("trace.span_id".into(), self.capsule_id.clone().into());
-        map.insert("result_from_cache".into(), result_from_cache.into());
+        map.insert(
+            "result_from_cache".into(),
+            (cache_decision == CacheDecision::Hit).into(),
+        );
+        map.insert("cache_decision".into(), cache_decision.as_str().into());
+        if let Some(detail) = cache_decision_detail {
+            map.insert("cache_decision_detail".into(), detail.into());
+        }
         map.insert("non_determinism".into(), non_determinism.into());
         map.insert("inputs_hash".into(), inputs_bundle.hash.clone().into());
         map.insert("inputs_hash_details".into(), hash_details_to_json(inputs_bundle));
@@ -145,13 +186,34 @@ impl Logger for Honeycomb {
         for (key, value) in &self.extra_kv {
             map.insert(key.to_owned(), value.to_owned().into());
         }
-        let client = reqwest::Client::new();
-        client
+        self.client
             .post(format!("https://api.honeycomb.io/1/events/{}", self.dataset))
             .header("X-Honeycomb-Team", &self.honeycomb_token)
+            // Honeycomb multiplies the counts of the sampled events by their sample rate.
+            .header("X-Honeycomb-Samplerate", self.sample_rate)
             .json(&map)
             .send()
             .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampled() {
+        let hash = "00000000000000c8ffff";
+        assert!(sampled(hash, 1));
+        assert!(sampled(hash, 100));
+        assert!(sampled(hash, 200));
+        assert!(!sampled(hash, 7));
+        // Hashes not in hex, e.g. from tests, are always sampled.
+        assert!(sampled("-", 7));
+        let count = (0..1000u64)
+            .filter(|n| sampled(&format!("{:016x}", n * 0x9e37_79b9), 10))
+            .count();
+        assert_eq!(count, 100);
+    }
+}
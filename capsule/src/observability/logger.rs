@@ -1,14 +1,57 @@
 use crate::iohashing::{InputHashBundle, OutputHashBundle};
 use anyhow::Result;
 use async_trait::async_trait;
+use std::fmt;
+
+/// Why capsule did or didn't use the cache for a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheDecision {
+    /// The cached results were used, and the command was not run.
+    Hit,
+    /// There was no cache entry for the inputs.
+    MissNoEntry,
+    /// There was a cache entry, but the placebo milestone ignores it.
+    IgnoredPlacebo,
+    /// The cache entry is of a failed command, and `--cache_failure` isn't set.
+    IgnoredFailure,
+    /// The output files of the cache entry don't match the configured output patterns.
+    IgnoredOutputMismatch,
+    /// The output files of the cache entry couldn't be downloaded.
+    IgnoredDownloadError,
+    /// The cache entry would be a hit, but `--no_download` runs the command instead.
+    IgnoredNoDownload,
+}
+
+impl CacheDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheDecision::Hit => "hit",
+            CacheDecision::MissNoEntry => "miss_no_entry",
+            CacheDecision::IgnoredPlacebo => "ignored_placebo",
+            CacheDecision::IgnoredFailure => "ignored_failure",
+            CacheDecision::IgnoredOutputMismatch => "ignored_output_mismatch",
+            CacheDecision::IgnoredDownloadError => "ignored_download_error",
+            CacheDecision::IgnoredNoDownload => "ignored_no_download",
+        }
+    }
+}
+
+impl fmt::Display for CacheDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 #[async_trait]
 pub trait Logger {
+    /// Log the result of a capsule run. The cache decision tells why the command was, or wasn't,
+    /// run, and the detail, if any, is the reason behind it, e.g. the error downloading the outputs.
     async fn log(
         &self,
         inputs_bundle: &InputHashBundle,
         output_bundle: &OutputHashBundle,
-        result_from_cache: bool,
+        cache_decision: CacheDecision,
+        cache_decision_detail: Option<&str>,
         non_determinism: bool,
     ) -> Result<()>;
 }
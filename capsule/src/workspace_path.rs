@@ -3,6 +3,7 @@
 /// prevents us from confusing it with either Strings, or PathBuf's
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::path::{Path, PathBuf};
 
@@ -12,14 +13,38 @@ pub enum WorkspacePath {
     NonWorkspace(PathBuf),
 }
 
-fn normalize_file(file: &Path, workspace_root: &Option<String>) -> PathBuf {
-    if let Some(root) = workspace_root {
-        match file.strip_prefix(root) {
-            Ok(path) => PathBuf::from(format!("//{}", path.display())),
-            Err(_) => file.to_owned(),
+static NO_NAMED_ROOTS: BTreeMap<String, String> = BTreeMap::new();
+
+/// The roots that workspace paths are relative to: the default root for '//path', and the named roots
+/// of nested workspaces, for '//@name/path'. The '@' keeps the named roots apart from the directories
+/// of the default root.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkspaceRoots<'a> {
+    pub default: &'a Option<String>,
+    pub named: &'a BTreeMap<String, String>,
+}
+
+impl<'a> From<&'a Option<String>> for WorkspaceRoots<'a> {
+    fn from(default: &'a Option<String>) -> Self {
+        Self {
+            default,
+            named: &NO_NAMED_ROOTS,
         }
-    } else {
-        file.to_owned()
+    }
+}
+
+fn normalize_file(file: &Path, roots: WorkspaceRoots) -> PathBuf {
+    // The nearest enclosing root, i.e. the longest one, wins.
+    let default = roots.default.iter().map(|root| (None, root));
+    let named = roots.named.iter().map(|(name, root)| (Some(name), root));
+    let nearest = default
+        .chain(named)
+        .filter_map(|(name, root)| file.strip_prefix(root).ok().map(|path| (name, root, path)))
+        .max_by_key(|(_, root, _)| Path::new(root).components().count());
+    match nearest {
+        Some((Some(name), _, path)) => PathBuf::from(format!("//@{}/{}", name, path.display())),
+        Some((None, _, path)) => PathBuf::from(format!("//{}", path.display())),
+        None => file.to_owned(),
     }
 }
 
@@ -32,15 +57,26 @@ impl WorkspacePath {
         }
     }
 
-    pub fn from_full_path(path: &Path, root: &Option<String>) -> Self {
-        Self::new(normalize_file(path, root))
+    pub fn from_full_path<'a>(path: &Path, roots: impl Into<WorkspaceRoots<'a>>) -> Self {
+        Self::new(normalize_file(path, roots.into()))
     }
 
-    pub fn to_path(&self, root: &Option<String>) -> Result<PathBuf> {
+    pub fn to_path<'a>(&self, roots: impl Into<WorkspaceRoots<'a>>) -> Result<PathBuf> {
+        let roots = roots.into();
         match self {
             Self::NonWorkspace(path) => Ok(path.clone()),
             Self::Workspace(path) => {
-                let root = root
+                // The rest of the path is joined as a string, to keep the trailing '/' of the directory outputs.
+                if let Some(named) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+                    let (name, rest) = named.split_once('/').unwrap_or((named, ""));
+                    let root = roots
+                        .named
+                        .get(name)
+                        .ok_or_else(|| anyhow!("Unknown workspace root '{}' in '//{}'", name, path.display()))?;
+                    return Ok(PathBuf::from(root).join(rest));
+                }
+                let root = roots
+                    .default
                     .as_ref()
                     .ok_or(anyhow!("Workspace relative paths used and no workspace_root specified"))?;
                 Ok(PathBuf::from(root).join(path))
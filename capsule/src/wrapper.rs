@@ -7,13 +7,17 @@ use std::ffi::CString;
 
 static USAGE: &str = "Usage: capsule <capsule arguments ...> -- command [<arguments>]";
 
-fn exec_program<I>(program_name: String, args: I) -> Result<()>
+fn exec_program<I>(program_name: String, args: I, vars: &[(String, String)]) -> Result<()>
 where
     I: Iterator<Item = String>,
 {
     let program_cstring = CString::new(program_name)?;
     let args: Vec<String> = args.collect();
     warn!("Fallback exec'ing {:?}", args);
+    // The process is replaced by the program, so these only end up in the program's environment.
+    for (name, value) in vars {
+        env::set_var(name, value);
+    }
     let arg_cstrings = args.into_iter().map(CString::new).collect::<Result<Vec<_>, _>>()?;
 
     match execvp(&program_cstring, &arg_cstrings) {
@@ -22,8 +26,9 @@ where
     }
 }
 
-// Execute a given command transparently passing the original arguments.
-pub fn exec() -> Result<()> {
+// Execute a given command transparently passing the original arguments, with the given
+// environment variables set, e.g. the --set_env ones.
+pub fn exec(vars: &[(String, String)]) -> Result<()> {
     let mut args = env::args();
     let argv0 = &mut args.next();
     if argv0.is_none() {
@@ -37,7 +42,17 @@ pub fn exec() -> Result<()> {
     }
 
     if let Some(program_name) = args.next() {
-        exec_program(program_name.clone(), itertools::chain!([program_name], args))
+        exec_program(program_name.clone(), itertools::chain!([program_name], args), vars)
+    } else {
+        Err(anyhow!(USAGE.to_string()))
+    }
+}
+
+// Execute the given command, e.g. the --fallback_command instead of the one after --.
+pub fn exec_command(command: Vec<String>, vars: &[(String, String)]) -> Result<()> {
+    let mut args = command.into_iter();
+    if let Some(program_name) = args.next() {
+        exec_program(program_name.clone(), itertools::chain!([program_name], args), vars)
     } else {
         Err(anyhow!(USAGE.to_string()))
     }
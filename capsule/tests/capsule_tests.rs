@@ -84,6 +84,93 @@ fn test_s3_cache_hit() {
     assert!(!side_effect.exists());
 }
 
+#[test]
+fn test_s3_cache_hit_compressed_bundles() {
+    let setup_data = common::setup(); // RAII - clean up on destruction.
+    let input = setup_data.path("input.txt");
+    std::fs::write(&input, "input data").unwrap();
+
+    // Write a legacy (uncompressed) entry, and make sure it can be read with --compress_bundles.
+    let side_effect = setup_data.path("side_effect.txt");
+    let command = format!("echo 'hello!' > {}", side_effect.to_str().unwrap());
+    common::capsule(
+        setup_data.port,
+        &[
+            "-c",
+            "wtf",
+            "-b",
+            "s3",
+            "-i",
+            input.to_str().unwrap(),
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ],
+    );
+    assert!(side_effect.exists());
+
+    let side_effect = setup_data.path("side_effect_2.txt");
+    let command = format!("echo 'wtf' > {}", side_effect.to_str().unwrap());
+    common::capsule(
+        setup_data.port,
+        &[
+            "-c",
+            "wtf",
+            "-b",
+            "s3",
+            "--compress_bundles",
+            "-i",
+            input.to_str().unwrap(),
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ],
+    );
+    assert!(!side_effect.exists());
+
+    // Now write a compressed entry, and read it back.
+    let side_effect = setup_data.path("side_effect_3.txt");
+    let command = format!("echo 'hello!' > {}", side_effect.to_str().unwrap());
+    common::capsule(
+        setup_data.port,
+        &[
+            "-c",
+            "wtf_compressed",
+            "-b",
+            "s3",
+            "--compress_bundles",
+            "-i",
+            input.to_str().unwrap(),
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ],
+    );
+    assert!(side_effect.exists());
+
+    let side_effect = setup_data.path("side_effect_4.txt");
+    let command = format!("echo 'wtf' > {}", side_effect.to_str().unwrap());
+    common::capsule(
+        setup_data.port,
+        &[
+            "-c",
+            "wtf_compressed",
+            "-b",
+            "s3",
+            "-i",
+            input.to_str().unwrap(),
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ],
+    );
+    assert!(!side_effect.exists());
+}
+
 #[test]
 fn test_s3_cache_hit_ws_root() {
     let setup_data = common::setup(); // RAII - clean up on destruction.
@@ -0,0 +1,6 @@
+use cargo_capsule::commands::CargoCapsuleBench;
+use cargo_capsule::main_exec;
+
+fn main() {
+    main_exec(CargoCapsuleBench);
+}
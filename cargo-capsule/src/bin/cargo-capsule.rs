@@ -0,0 +1,67 @@
+use std::ffi::OsString;
+
+use cargo::util::command_prelude::*;
+
+use cargo_capsule::commands::{CargoCapsuleBench, CargoCapsuleBuild, CargoCapsuleCheck, CargoCapsuleTest};
+use cargo_capsule::main_exec_with_args;
+
+// Dispatch 'cargo capsule <command> ...' to the implementation of the command. The flags shared by
+// all commands may be given before the command, and are passed on to it.
+fn create_clap_app() -> App {
+    App::new("capsule")
+        .bin_name("cargo capsule")
+        .version(env!("CARGO_PKG_VERSION"))
+        .settings(&[
+            AppSettings::AllowExternalSubcommands,
+            AppSettings::SubcommandRequiredElseHelp,
+        ])
+        .arg(
+            opt("capsule_id", "Set the capsule ID for the call")
+                .value_name("CAPSULE_ID")
+                .short("c"),
+        )
+        .arg(
+            opt(
+                "workspace_root",
+                "If given, all paths will be normalized relative to this root",
+            )
+            .value_name("WORKSPACE_ROOT")
+            .short("w"),
+        )
+        .arg(
+            opt(
+                "global_job_limit",
+                "Maximum number of commands run at once by all the capsules of the build",
+            )
+            .value_name("N"),
+        )
+        .after_help("Commands: build, test, bench, check\n")
+}
+
+fn main() {
+    // Cargo runs 'cargo capsule ...' as 'cargo-capsule capsule ...'.
+    let matches = create_clap_app().get_matches_from(std::env::args_os().skip(1));
+    let (command, command_matches) = matches.subcommand();
+
+    let mut args: Vec<OsString> = vec![format!("capsule-{}", command).into()];
+    for shared_arg in ["capsule_id", "workspace_root", "global_job_limit"] {
+        if let Some(value) = matches.value_of_os(shared_arg) {
+            args.push(format!("--{}", shared_arg).into());
+            args.push(value.into());
+        }
+    }
+    if let Some(command_args) = command_matches.and_then(|m| m.values_of_os("")) {
+        args.extend(command_args.map(Into::into));
+    }
+
+    match command {
+        "build" => main_exec_with_args(CargoCapsuleBuild, args),
+        "test" => main_exec_with_args(CargoCapsuleTest, args),
+        "bench" => main_exec_with_args(CargoCapsuleBench, args),
+        "check" => main_exec_with_args(CargoCapsuleCheck, args),
+        _ => {
+            eprintln!("error: no such command: `{}`, see `cargo capsule --help`", command);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::ffi::OsString;
+
+use cargo::util::command_prelude::*;
+
+use crate::{add_standard_args, CargoCapsuleCommand, PackageSpec};
+
+// Implementaiton of the CargoCapsuleCommand trait
+pub struct CargoCapsuleBench;
+
+impl CargoCapsuleCommand for CargoCapsuleBench {
+    fn command(&self) -> &'static str {
+        "bench"
+    }
+
+    fn mode(&self) -> CompileMode {
+        CompileMode::Bench
+    }
+
+    // Accept a subset of cargo bench options.
+    // Copied with minor modifications from cargo/src/bin/cargo/commands/bench.rs
+    // Additionally, includes the argument --capsule_id to pass to the capsule call.
+    fn create_clap_app(&self) -> App {
+        App::new("capsule-bench")
+            .settings(&[
+                AppSettings::TrailingVarArg,
+                AppSettings::UnifiedHelpMessage,
+                AppSettings::DeriveDisplayOrder,
+                AppSettings::VersionlessSubcommands,
+            ])
+            .setting(AppSettings::TrailingVarArg)
+            .version(env!("CARGO_PKG_VERSION"))
+            .arg(
+                Arg::with_name("BENCHNAME")
+                    .help("If specified, only run benches containing this string in their names"),
+            )
+            .arg(
+                Arg::with_name("args")
+                    .help("Arguments for the bench binary")
+                    .multiple(true)
+                    .last(true),
+            )
+            .arg(
+                opt("capsule_id", "Set the capsule ID for the call")
+                    .value_name("CAPSULE_ID")
+                    .short("c")
+                    .required(true),
+            )
+            .arg(
+                opt(
+                    "workspace_root",
+                    "If given, all paths will be normalized relative to this root",
+                )
+                .value_name("WORKSPACE_ROOT")
+                .short("w")
+                .required(false),
+            )
+            .arg(
+                opt(
+                    "global_job_limit",
+                    "Maximum number of commands run at once by all the capsules of the build",
+                )
+                .value_name("N"),
+            )
+            .arg(opt(
+                "dump-graph",
+                "Print the capsule inputs, outputs and targets for each package, and exit",
+            ))
+            .arg(opt(
+                "keep-going",
+                "Run all the packages even if some of them fail, and report the failures at the end",
+            ))
+            .arg(opt("quiet", "No output printed to stdout").short("q"))
+            .arg(opt("no-run", "Compile, but don't run benchmarks"))
+            .arg(opt("no-fail-fast", "Run all benchmarks regardless of failure"))
+            .arg_targets_all(
+                "Benchmark only this package's library",
+                "Benchmark only the specified binary",
+                "Benchmark all binaries",
+                "Benchmark only the specified example",
+                "Benchmark all examples",
+                "Benchmark only the specified test target",
+                "Benchmark all tests",
+                "Benchmark only the specified bench target",
+                "Benchmark all benches",
+                "Benchmark all targets",
+            )
+            .arg_package_spec(
+                "Package to run benchmarks for",
+                "Benchmark all packages in the workspace",
+                "Exclude packages from the benchmark",
+            )
+            .arg_jobs()
+            .arg_profile("Build artifacts with the specified profile")
+            .arg_features()
+            .arg_target_triple("Build for the target triple")
+            .arg_target_dir()
+            .arg_manifest_path()
+            .arg_ignore_rust_version()
+            .arg_message_format()
+            .after_help("Run `cargo help bench` for more detailed information.\n")
+    }
+
+    // Args should match the ones specified in create_clap_app.
+    fn find_args_to_pass(&self, orig_args: &ArgMatches, spec: &PackageSpec) -> Vec<OsString> {
+        let mut args = Vec::new();
+        // All flag arguments.
+        for opt_arg in [
+            "quiet",
+            "no-run",
+            "no-fail-fast",
+            "ignore-rust-version",
+            "lib",
+            "bins",
+            "examples",
+            "tests",
+            "benches",
+            "all-targets",
+        ] {
+            if orig_args.is_present(opt_arg) {
+                args.push(format!("--{}", opt_arg).into());
+            }
+        }
+        add_standard_args(&mut args, &orig_args, &spec);
+        // Add BENCHNAME
+        if let Some(benchname) = orig_args.value_of("BENCHNAME") {
+            args.push(benchname.into());
+        }
+        // Add all bench harness args, so that they are part of the capsule's args hash too.
+        if let Some(bench_args) = orig_args.values_of("args") {
+            args.push("--".into());
+            args.extend(bench_args.map(Into::into));
+        }
+        args
+    }
+}
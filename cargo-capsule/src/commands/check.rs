@@ -0,0 +1,116 @@
+use std::ffi::OsString;
+
+use cargo::util::command_prelude::*;
+
+use crate::{add_standard_args, CargoCapsuleCommand, PackageSpec};
+
+// Implementation of the CargoCapsuleCommand trait
+pub struct CargoCapsuleCheck;
+
+impl CargoCapsuleCommand for CargoCapsuleCheck {
+    fn command(&self) -> &'static str {
+        "check"
+    }
+
+    fn mode(&self) -> CompileMode {
+        CompileMode::Check { test: false }
+    }
+
+    // Accept a subset of cargo check options.
+    // Copied with minor modifications from cargo/src/bin/cargo/commands/check.rs
+    // Additionally, includes the argument --capsule_id to pass to the capsule call.
+    fn create_clap_app(&self) -> App {
+        App::new("capsule-check")
+            .about("Check a local package and all of its dependencies for errors")
+            .arg(
+                opt("capsule_id", "Set the capsule ID for the call")
+                    .value_name("CAPSULE_ID")
+                    .short("c")
+                    .required(true),
+            )
+            .arg(
+                opt(
+                    "workspace_root",
+                    "If given, all paths will be normalized relative to this root",
+                )
+                .value_name("WORKSPACE_ROOT")
+                .short("w")
+                .required(false),
+            )
+            .arg(
+                opt(
+                    "global_job_limit",
+                    "Maximum number of commands run at once by all the capsules of the build",
+                )
+                .value_name("N"),
+            )
+            .arg(opt(
+                "dump-graph",
+                "Print the capsule inputs, outputs and targets for each package, and exit",
+            ))
+            .arg(opt(
+                "keep-going",
+                "Run all the packages even if some of them fail, and report the failures at the end",
+            ))
+            .arg(opt("quiet", "No output printed to stdout").short("q"))
+            .arg_package_spec(
+                "Package(s) to check",
+                "Check all packages in the workspace",
+                "Exclude packages from the check",
+            )
+            .arg_jobs()
+            .arg_targets_all(
+                "Check only this package's library",
+                "Check only the specified binary",
+                "Check all binaries",
+                "Check only the specified example",
+                "Check all examples",
+                "Check only the specified test target",
+                "Check all tests",
+                "Check only the specified bench target",
+                "Check all benches",
+                "Check all targets",
+            )
+            .arg_release("Check artifacts in release mode, with optimizations")
+            .arg_profile("Check artifacts with the specified profile")
+            .arg_features()
+            .arg_target_triple("Check for the target triple")
+            .arg_target_dir()
+            .arg_manifest_path()
+            .arg_ignore_rust_version()
+            .arg_message_format()
+            .arg_unit_graph()
+            .arg_future_incompat_report()
+            .after_help("Run `cargo help check` for more detailed information.\n")
+    }
+
+    // Args should match the ones specified in create_clap_app.
+    fn find_args_to_pass(&self, orig_args: &ArgMatches, spec: &PackageSpec) -> Vec<OsString> {
+        let mut args = Vec::new();
+        // All flag arguments, except target selection arguments.
+        for opt_arg in [
+            "quiet",
+            "release",
+            "ignore-rust-version",
+            "lib",
+            "bins",
+            "examples",
+            "tests",
+            "benches",
+            "all-targets",
+            "all-features",
+            "no-default-features",
+            "frozen",
+            "locked",
+            "offline",
+        ] {
+            if orig_args.is_present(opt_arg) {
+                args.push(format!("--{}", opt_arg).into());
+            }
+        }
+
+        add_standard_args(&mut args, &orig_args, &spec);
+
+        args
+    }
+}
@@ -0,0 +1,9 @@
+mod bench;
+mod build;
+mod check;
+mod test;
+
+pub use bench::CargoCapsuleBench;
+pub use build::CargoCapsuleBuild;
+pub use check::CargoCapsuleCheck;
+pub use test::CargoCapsuleTest;
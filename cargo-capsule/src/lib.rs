@@ -2,28 +2,50 @@ use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
-use cargo::core::compiler::{unit_graph, CompileKind, FileFlavor, UnitInterner};
+use cargo::core::compiler::unit_graph::UnitGraph;
+use cargo::core::compiler::{unit_graph, BuildContext, CompileKind, FileFlavor, Unit, UnitInterner};
 use cargo::core::shell::Shell;
-use cargo::core::{Source, TargetKind};
+use cargo::core::{Source, TargetKind, Workspace};
 use cargo::ops;
 use cargo::util::command_prelude::*;
 use cargo::util::config;
-use cargo::CliResult;
+use cargo::{CargoResult, CliError, CliResult};
+use cargo_util::paths;
 
 use log::Level::Debug;
 use log::{debug, info, log_enabled};
 
 use sha2::{Digest, Sha256};
 
+pub mod commands;
+mod lto;
+
+// Hash the args passed to cargo, to be used as a tool tag. The options before '--' are grouped with
+// their values, and the groups are sorted, as the order in which they are collected isn't stable.
+// The args after '--' are passed to the test binaries, and their order matters.
 fn args_hash(args: &[OsString]) -> String {
+    let (options, rest) = match args.iter().position(|arg| arg == "--") {
+        Some(pos) => args.split_at(pos),
+        None => (args, &[][..]),
+    };
+    let mut groups: Vec<Vec<&OsString>> = Vec::new();
+    for arg in options {
+        match groups.last_mut() {
+            Some(group) if !arg.as_bytes().starts_with(b"-") => group.push(arg),
+            _ => groups.push(vec![arg]),
+        }
+    }
+    groups.sort();
     let mut acc = Sha256::new();
-    for arg in args {
+    for arg in groups.into_iter().flatten().chain(rest) {
         acc.update(arg.as_bytes());
+        // Separate the args, so that e.g. ["--bin", "ab"] and ["--bina", "b"] hash differently.
+        acc.update([0]);
     }
     format!("{:x}", acc.finalize())
 }
@@ -39,6 +61,138 @@ fn normalize_file(file: &Path, workspace_root: &Option<&str>) -> String {
     }
 }
 
+// The path of a file that cargo copies to the --out-dir, as a capsule output. A relative out-dir is
+// relative to the cwd, as cargo resolves it, and the path is normalized lexically, e.g. of '..', so
+// that it's relative to the workspace root the same way the sources are.
+fn export_file(export_dir: &Path, cwd: &Path, file_name: &Path, workspace_root: &Option<&str>) -> String {
+    let path = paths::normalize_path(&cwd.join(export_dir).join(file_name));
+    normalize_file(&path, workspace_root)
+}
+
+// Find the runs of the build scripts of the local packages that the unit depends on, transitively.
+fn local_build_script_runs<'a>(unit_graph: &'a UnitGraph, root: &'a Unit) -> Vec<&'a Unit> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![root];
+    let mut runs = Vec::new();
+    while let Some(unit) = stack.pop() {
+        if !visited.insert(unit) {
+            continue;
+        }
+        if unit.mode.is_run_custom_build() && unit.is_local() {
+            runs.push(unit);
+        }
+        if let Some(deps) = unit_graph.get(unit) {
+            stack.extend(deps.iter().map(|unit_dep| &unit_dep.unit));
+        }
+    }
+    runs
+}
+
+// Parse the output of a build script run, and return the inputs it declared with
+// `cargo:rerun-if-changed` as -i flags, and the env variables declared with
+// `cargo:rerun-if-env-changed`, with their current values, as -t flags. The paths are relative to the
+// package root. The ones that don't exist anymore are skipped, as a -i matching no files fails the
+// capsule, and directories are taken with everything below them, as cargo does.
+fn parse_build_script_output(output: &str, package_root: &Path, workspace_root: &Option<&str>) -> IoSpec {
+    let mut io_spec = IoSpec::new();
+    for line in output.lines() {
+        if let Some(path) = line.strip_prefix("cargo:rerun-if-changed=") {
+            let path = package_root.join(path);
+            if path.is_dir() {
+                io_spec.insert(("-i".to_string(), normalize_file(&path.join("**/*"), workspace_root)));
+            } else if path.exists() {
+                io_spec.insert(("-i".to_string(), normalize_file(&path, workspace_root)));
+            }
+        } else if let Some(var) = line.strip_prefix("cargo:rerun-if-env-changed=") {
+            let tag = match std::env::var_os(var) {
+                Some(value) => format!("env {}={}", var, value.to_string_lossy()),
+                None => format!("env {} unset", var),
+            };
+            io_spec.insert(("-t".to_string(), tag));
+        }
+    }
+    io_spec
+}
+
+// The run directories of the build scripts of the local packages, i.e. `<package>-<hash>` under the
+// build dir, with the hash of the unit's metadata, as cargo computes it for this configuration.
+fn build_script_run_dirs(bcx: &BuildContext<'_, '_>) -> CargoResult<HashMap<Unit, PathBuf>> {
+    let mut cx = cargo::core::compiler::Context::new(bcx)?;
+    cx.lto = lto::generate(bcx)?;
+    cx.prepare_units()?;
+    let mut run_dirs = HashMap::new();
+    for root in &bcx.roots {
+        for unit in local_build_script_runs(&bcx.unit_graph, root) {
+            run_dirs.insert(unit.clone(), cx.files().build_script_run_dir(unit));
+        }
+    }
+    Ok(run_dirs)
+}
+
+// Build scripts may read files outside of their package, e.g. to generate code into OUT_DIR, and
+// cargo only learns about them from what the script printed when it was last run. That's kept in
+// the `output` file of the run directory of the unit. Nothing is added if the script was never run,
+// or its run directory is gone.
+fn build_script_io_spec(run_dir: &Path, unit: &Unit, workspace_root: &Option<&str>) -> IoSpec {
+    match std::fs::read_to_string(run_dir.join("output")) {
+        Ok(output) => parse_build_script_output(&output, unit.pkg.root(), workspace_root),
+        Err(_) => IoSpec::new(),
+    }
+}
+
+// The directory the units of the kind are uplifted to: the profile's dir in the target dir for the
+// host, and the one under the triple's dir for a --target triple, so the same binary built for
+// several triples has an output per triple. This is modeled after cargo/compiler/context/mod.rs,
+// see prepare_units().
+fn output_dir(ws: &Workspace<'_>, bcx: &BuildContext<'_, '_>, kind: CompileKind) -> PathBuf {
+    let mut output_dir = ws.target_dir();
+    if let CompileKind::Target(target) = kind {
+        output_dir.push(target.short_name());
+    }
+    output_dir.push(bcx.profiles.get_dir_name());
+    output_dir.into_path_unlocked()
+}
+
+// The outputs of a binary root unit: the binary uplifted to the output dir, with its debug info and
+// dep-info files, and their copies in the out-dir, if any.
+fn binary_io_spec(
+    bcx: &BuildContext<'_, '_>,
+    root: &Unit,
+    output_dir: &Path,
+    export_dir: &Option<PathBuf>,
+    cwd: &Path,
+    workspace_root: &Option<&str>,
+) -> CargoResult<IoSpec> {
+    let mut io_spec = IoSpec::new();
+    let info = bcx.target_data.info(root.kind);
+    let triple = bcx.target_data.short_name(&root.kind);
+    let (file_types, _) = info.rustc_outputs(root.mode, root.target.kind(), triple)?;
+    for file_type in file_types {
+        let file_name = output_dir.join(file_type.uplift_filename(&root.target));
+        match file_type.flavor {
+            FileFlavor::Normal => {
+                // Cargo writes the dep-info file next to the uplifted binary, named after the binary
+                // without its suffix. Not with_extension, as the name may contain dots.
+                let dep_info = file_name.with_file_name(format!("{}.d", root.target.name()));
+                io_spec.insert(("-o".to_string(), normalize_file(&dep_info, workspace_root)));
+            }
+            // dSYM bundles are directories, which can't be capsule outputs.
+            FileFlavor::DebugInfo if !file_name.to_string_lossy().ends_with(".dSYM") => {}
+            _ => continue,
+        }
+        io_spec.insert(("-o".to_string(), normalize_file(&file_name, workspace_root)));
+        // The child cargo copies the uplifted files to the out-dir too, which are restored on hit.
+        if let Some(export_dir) = export_dir {
+            let uplift_filename = file_type.uplift_filename(&root.target);
+            io_spec.insert((
+                "-o".to_string(),
+                export_file(export_dir, cwd, Path::new(&uplift_filename), workspace_root),
+            ));
+        }
+    }
+    Ok(io_spec)
+}
+
 pub fn add_standard_args(args: &mut Vec<OsString>, orig_args: &ArgMatches, spec: &PackageSpec) {
     // All single or multiple args, except "bin", "test", "bench".
     for opt_arg in [
@@ -97,12 +251,14 @@ pub trait CargoCapsuleCommand {
     // Find arguments to pass to child cargo calls from curren args.
     fn find_args_to_pass(&self, orig_args: &ArgMatches, spec: &PackageSpec) -> Vec<OsString>;
 
-    // Parse the dependency graph, and make child calls to cargo under capsule.
-    fn exec(&self, config: &mut Config) -> CliResult {
+    // Parse the dependency graph, and make child calls to cargo under capsule. The first of the
+    // args is the name of the command, as cargo passes it to the subcommand binaries.
+    fn exec(&self, config: &mut Config, args: Vec<OsString>) -> CliResult {
         let app = self.create_clap_app();
-        let args = app.get_matches_from_safe(std::env::args_os().skip(1))?;
+        let args = app.get_matches_from_safe(args)?;
         let ws = args.workspace(config)?;
         let workspace_root = args.value_of("workspace_root");
+        let global_job_limit = args.value_of("global_job_limit");
 
         let capsule_id = args.value_of("capsule_id").expect("Capsule ID unknown");
 
@@ -114,6 +270,7 @@ pub trait CargoCapsuleCommand {
             let out_dir = out_dir.resolve_path(config);
             compile_opts.build_config.export_dir = Some(out_dir);
         }
+        let export_dir = compile_opts.build_config.export_dir.clone();
 
         debug!("Workspace: \n{:?}\n", ws);
 
@@ -126,19 +283,7 @@ pub trait CargoCapsuleCommand {
             let _ = unit_graph::emit_serialized_unit_graph(&bcx.roots, &bcx.unit_graph, ws.config())?;
         }
 
-        // We determine the paths for host and target compilations.
-        // This is modeled after cargo/compiler/context/mod.rs, see prepare_units()
-        let dest = bcx.profiles.get_dir_name();
-        let output_host = ws.target_dir().join(&dest);
-        let mut targets = HashMap::new();
-        for kind in bcx.all_kinds.iter() {
-            if let CompileKind::Target(target) = *kind {
-                let mut output_target = ws.target_dir();
-                output_target.push(target.short_name());
-                output_target.push(dest);
-                targets.insert(target, output_target);
-            }
-        }
+        let run_dirs = build_script_run_dirs(&bcx)?;
 
         // For each package
         let mut package_specs = HashMap::<String, PackageSpec>::new();
@@ -178,30 +323,29 @@ pub trait CargoCapsuleCommand {
                 .flatten()
                 .collect();
 
+            // The build scripts' own inputs, as they aren't necessarily among the package sources.
+            for unit in local_build_script_runs(&bcx.unit_graph, root) {
+                io_spec.extend(build_script_io_spec(&run_dirs[unit], unit, &workspace_root));
+            }
+
             let target_kind = root.target.kind().description(); // "bin", "test", "bench", etc...
-            let mut file_name: Option<String> = None;
+            let mut target_name: Option<String> = None;
             if self.binary_outputs() && matches!(*root.target.kind(), TargetKind::Bin) {
-                let info = bcx.target_data.info(root.kind);
-                let triple = bcx.target_data.short_name(&root.kind);
-                let (file_types, _) = info.rustc_outputs(root.mode, root.target.kind(), triple)?;
-                for file_type in file_types {
-                    if file_type.flavor == FileFlavor::Normal {
-                        // This will be run at most once, because there's only one "normal" file in the set.
-                        let suffix = file_type.uplift_filename(&root.target);
-                        file_name = Some(suffix.clone());
-                        let file_name = match root.kind {
-                            CompileKind::Host => output_host.join(suffix),
-                            CompileKind::Target(target) => targets.get(&target).expect("given target").join(suffix),
-                        };
-                        io_spec.insert((
-                            "-o".to_string(),
-                            normalize_file(file_name.as_path_unlocked(), &workspace_root),
-                        ));
-                    }
-                }
+                let output_dir = output_dir(&ws, &bcx, root.kind);
+                io_spec.extend(binary_io_spec(
+                    &bcx,
+                    root,
+                    &output_dir,
+                    &export_dir,
+                    config.cwd(),
+                    &workspace_root,
+                )?);
+                // Binary names are used for --bin, as the file name may have a suffix (e.g. '.exe').
+                target_name = Some(root.target.name().to_string());
             }
 
-            let target_spec_present = file_name.is_some() && ["bin", "test", "bench", "example"].contains(&target_kind);
+            let target_spec_present =
+                target_name.is_some() && ["bin", "test", "bench", "example"].contains(&target_kind);
             // Add the current unit to the package spec for the package of this unit.
             match package_specs.entry(root.pkg.name().to_string()) {
                 Entry::Occupied(mut e) => {
@@ -211,20 +355,40 @@ pub trait CargoCapsuleCommand {
                         package_spec
                             .targets
                             .entry(target_kind)
-                            .and_modify(|e| e.push(file_name.clone().unwrap()))
-                            .or_insert(vec![file_name.unwrap()]);
+                            .and_modify(|e| {
+                                // With several --target triples, the same binary is a root for each of them.
+                                if !e.contains(target_name.as_ref().unwrap()) {
+                                    e.push(target_name.clone().unwrap())
+                                }
+                            })
+                            .or_insert(vec![target_name.unwrap()]);
                     }
                 }
                 Entry::Vacant(e) => {
                     let mut targets = HashMap::new();
                     if target_spec_present {
-                        targets.insert(target_kind, vec![file_name.unwrap()]);
+                        targets.insert(target_kind, vec![target_name.unwrap()]);
                     }
                     e.insert(PackageSpec { io_spec, targets });
                 }
             }
         }
 
+        if args.is_present("dump-graph") {
+            let mut packages: Vec<_> = package_specs.iter().collect();
+            packages.sort_by_key(|(package, _)| *package);
+            for (package, spec) in packages {
+                let pass_args = self.find_args_to_pass(&args, spec);
+                print!("{}", describe_package_spec(package, spec, &pass_args));
+            }
+            return Ok(());
+        }
+
+        // The packages are run in a stable order, so that the first failure is the same every time.
+        let mut package_specs: Vec<_> = package_specs.into_iter().collect();
+        package_specs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let keep_going = args.is_present("keep-going");
+        let mut failures = Vec::new();
         for (package, spec) in package_specs {
             // Modify capsule-id to include a specific root + hash of the args.
             let capsule_id = format!("{}-{}", capsule_id, package);
@@ -249,6 +413,10 @@ pub trait CargoCapsuleCommand {
             if let Some(root) = workspace_root {
                 command.arg("-w").arg(root);
             }
+            // Bounds the commands of this build together with the capsules they run in turn.
+            if let Some(limit) = global_job_limit {
+                command.arg("--global_job_limit").arg(limit);
+            }
             command
                 .args(capsule_args)
                 .args(["-t", &pass_args_hash])
@@ -262,18 +430,71 @@ pub trait CargoCapsuleCommand {
                 "capsule {}",
                 shell_words::join(command.get_args().map(OsStr::to_string_lossy))
             );
-            command
+            let status = command
                 .spawn()
                 .with_context(|| format!("Spawning cargo {}", self.command()))?
                 .wait()
                 .with_context(|| format!("Waiting for cargo {}", self.command()))?;
+            if !status.success() {
+                // Killed by a signal, the child has no exit code.
+                let exit_code = status.code().unwrap_or(1);
+                if !keep_going {
+                    return Err(CliError::new(
+                        anyhow!("cargo {} failed for package '{}'", self.command(), package),
+                        exit_code,
+                    ));
+                }
+                failures.push((package, exit_code));
+            }
         }
 
+        // With --keep-going, the exit code is the one of the first failure.
+        if let Some((_, exit_code)) = failures.first() {
+            return Err(CliError::new(
+                anyhow!(describe_failures(self.command(), &failures)),
+                *exit_code,
+            ));
+        }
         Ok(())
     }
 }
 
+// Describe the capsule call for a package in a readable and stable form, for debugging.
+fn describe_package_spec(package: &str, spec: &PackageSpec, pass_args: &[OsString]) -> String {
+    let mut description = format!("{}:\n", package);
+    let mut io_spec: Vec<_> = spec.io_spec.iter().collect();
+    io_spec.sort();
+    for (flag, value) in io_spec {
+        description += &format!("  {} {}\n", flag, value);
+    }
+    description += &format!("  -t {}\n", args_hash(pass_args));
+    let mut targets: Vec<_> = spec.targets.iter().collect();
+    targets.sort();
+    for (target_kind, names) in targets {
+        description += &format!("  target {}: {}\n", target_kind, names.join(" "));
+    }
+    description += &format!(
+        "  cargo args: {}\n",
+        shell_words::join(pass_args.iter().map(|arg| arg.to_string_lossy()))
+    );
+    description
+}
+
+// Summarize the packages failed with --keep-going, and their exit codes.
+fn describe_failures(command: &str, failures: &[(String, i32)]) -> String {
+    let mut description = format!("cargo {} failed for {} package(s):\n", command, failures.len());
+    for (package, exit_code) in failures {
+        description += &format!("  {} (exit code {})\n", package, exit_code);
+    }
+    description
+}
+
 pub fn main_exec(build: impl CargoCapsuleCommand) {
+    // Cargo runs 'cargo capsule-build ...' as 'cargo-capsule-build capsule-build ...'.
+    main_exec_with_args(build, std::env::args_os().skip(1).collect())
+}
+
+pub fn main_exec_with_args(build: impl CargoCapsuleCommand, args: Vec<OsString>) {
     // Initialize logging. Default is INFO level, can be overridden in CAPSULE_LOG
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Error)
@@ -292,8 +513,181 @@ pub fn main_exec(build: impl CargoCapsuleCommand) {
         }
     };
 
-    let result = build.exec(&mut config);
+    let result = build.exec(&mut config, args);
     if let Err(e) = result {
         cargo::exit_with_error(e, &mut *config.shell())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo::core::compiler::CompileTarget;
+    use serial_test::serial;
+
+    fn to_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(Into::into).collect()
+    }
+
+    #[test]
+    fn test_args_hash_stable() {
+        let args = to_args(&["--release", "--bin", "b", "--bin", "a", "--features", "x"]);
+        let reordered = to_args(&["--bin", "a", "--features", "x", "--bin", "b", "--release"]);
+        assert_eq!(args_hash(&args), args_hash(&args.clone()));
+        assert_eq!(args_hash(&args), args_hash(&reordered));
+
+        // Values stay with their options.
+        let swapped = to_args(&["--release", "--bin", "x", "--bin", "a", "--features", "b"]);
+        assert_ne!(args_hash(&args), args_hash(&swapped));
+        assert_ne!(
+            args_hash(&to_args(&["--bin", "ab"])),
+            args_hash(&to_args(&["--bina", "b"]))
+        );
+
+        // The order of the args after '--' matters.
+        assert_ne!(
+            args_hash(&to_args(&["--release", "--", "a", "b"])),
+            args_hash(&to_args(&["--release", "--", "b", "a"]))
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_parse_build_script_output() {
+        let package_root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let workspace_root = package_root.parent().unwrap().to_str();
+        std::env::set_var("CARGO_CAPSULE_TEST_VAR", "value");
+        let output = "cargo:rerun-if-changed=src/lib.rs\n\
+                      cargo:rerun-if-changed=src/bin\n\
+                      cargo:rerun-if-changed=missing.proto\n\
+                      cargo:rerun-if-env-changed=CARGO_CAPSULE_TEST_VAR\n\
+                      cargo:rerun-if-env-changed=CARGO_CAPSULE_UNSET_VAR\n\
+                      cargo:rustc-cfg=feature_x\n";
+        let mut io_spec: Vec<_> = parse_build_script_output(output, package_root, &workspace_root)
+            .into_iter()
+            .collect();
+        io_spec.sort();
+        let expected: Vec<_> = [
+            ("-i", "//cargo-capsule/src/bin/**/*"),
+            ("-i", "//cargo-capsule/src/lib.rs"),
+            ("-t", "env CARGO_CAPSULE_TEST_VAR=value"),
+            ("-t", "env CARGO_CAPSULE_UNSET_VAR unset"),
+        ]
+        .iter()
+        .map(|(flag, value)| (flag.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(io_spec, expected);
+    }
+
+    #[test]
+    fn test_export_file() {
+        let workspace_root = Some("/ws");
+        let cwd = Path::new("/ws/app");
+        let binary = Path::new("capsule");
+        // The files uplifted to an absolute out-dir, or one relative to the cwd, end up where cargo copies them.
+        assert_eq!(
+            export_file(Path::new("/ws/dist"), cwd, binary, &workspace_root),
+            "//dist/capsule"
+        );
+        assert_eq!(
+            export_file(Path::new("dist"), cwd, binary, &workspace_root),
+            "//app/dist/capsule"
+        );
+        assert_eq!(
+            export_file(Path::new("../dist/./bin"), cwd, binary, &workspace_root),
+            "//dist/bin/capsule"
+        );
+        // Outside of the workspace, or without it, the paths stay absolute.
+        assert_eq!(
+            export_file(Path::new("../../tmp/dist"), cwd, binary, &workspace_root),
+            "/tmp/dist/capsule"
+        );
+        assert_eq!(
+            export_file(Path::new("dist"), cwd, binary, &None),
+            "/ws/app/dist/capsule"
+        );
+    }
+
+    #[test]
+    fn test_binary_io_spec_target_triples() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = "[package]\nname = \"app\"\nversion = \"0.1.0\"\n";
+        std::fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        let config = config::Config::default().unwrap();
+        let ws = Workspace::new(&dir.path().join("Cargo.toml"), &config).unwrap();
+        let mut compile_opts = ops::CompileOptions::new(&config, CompileMode::Build).unwrap();
+        compile_opts.build_config.requested_kinds = ["x86_64-unknown-linux-gnu", "aarch64-unknown-linux-gnu"]
+            .iter()
+            .map(|triple| CompileKind::Target(CompileTarget::new(triple).unwrap()))
+            .collect();
+        let interner = UnitInterner::new();
+        let bcx = ops::create_bcx(&ws, &compile_opts, &interner).unwrap();
+
+        // The binary and its dep-info file, in the output dir of each triple.
+        let workspace_root = dir.path().to_str();
+        let mut io_spec: Vec<_> = bcx
+            .roots
+            .iter()
+            .flat_map(|root| {
+                let output_dir = output_dir(&ws, &bcx, root.kind);
+                binary_io_spec(&bcx, root, &output_dir, &None, config.cwd(), &workspace_root).unwrap()
+            })
+            .collect();
+        io_spec.sort();
+        let expected: Vec<_> = [
+            "//target/aarch64-unknown-linux-gnu/debug/app",
+            "//target/aarch64-unknown-linux-gnu/debug/app.d",
+            "//target/x86_64-unknown-linux-gnu/debug/app",
+            "//target/x86_64-unknown-linux-gnu/debug/app.d",
+        ]
+        .iter()
+        .map(|file| ("-o".to_string(), file.to_string()))
+        .collect();
+        assert_eq!(io_spec, expected);
+    }
+
+    #[test]
+    fn test_describe_package_spec() {
+        let io_spec = [
+            ("-o", "//target/debug/b"),
+            ("-i", "//app/src/main.rs"),
+            ("-t", "serde 1.0.130"),
+        ]
+        .iter()
+        .map(|(flag, value)| (flag.to_string(), value.to_string()))
+        .collect();
+        let targets = HashMap::from([
+            ("test", vec!["integration".to_string()]),
+            ("bin", vec!["b".to_string(), "a".to_string()]),
+        ]);
+        let spec = PackageSpec { io_spec, targets };
+        let pass_args = to_args(&["--bin", "b", "--bin", "a", "--test", "integration"]);
+        // The flags and the target kinds are sorted, and the names keep the order of the args.
+        assert_eq!(
+            describe_package_spec("app", &spec, &pass_args),
+            format!(
+                "app:\n  \
+                 -i //app/src/main.rs\n  \
+                 -o //target/debug/b\n  \
+                 -t serde 1.0.130\n  \
+                 -t {}\n  \
+                 target bin: b a\n  \
+                 target test: integration\n  \
+                 cargo args: --bin b --bin a --test integration\n",
+                args_hash(&pass_args)
+            )
+        );
+    }
+
+    #[test]
+    fn test_describe_failures() {
+        let failures = vec![("a".to_owned(), 101), ("b".to_owned(), 1)];
+        assert_eq!(
+            describe_failures("test", &failures),
+            "cargo test failed for 2 package(s):\n  a (exit code 101)\n  b (exit code 1)\n"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! The LTO settings of the units, as cargo computes them before compiling.
+//!
+//! Cargo hashes them into the metadata of each unit, and so into the names of its directories in
+//! the target dir, e.g. the run directories of the build scripts. The computation is in a private
+//! module of cargo, so it's copied here from `src/cargo/core/compiler/lto.rs` of cargo 0.58.0. The
+//! cargo dependency is pinned to that exact version, and this has to be updated along with it.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use cargo::core::compiler::{BuildContext, CompileMode, CrateType, Lto, Unit};
+use cargo::core::profiles;
+use cargo::CargoResult;
+
+pub fn generate(bcx: &BuildContext<'_, '_>) -> CargoResult<HashMap<Unit, Lto>> {
+    let mut map = HashMap::new();
+    for unit in bcx.roots.iter() {
+        let root_lto = match unit.profile.lto {
+            // LTO not requested, no need for bitcode.
+            profiles::Lto::Bool(false) => Lto::OnlyObject,
+            profiles::Lto::Off => Lto::Off,
+            _ => {
+                let crate_types = unit.target.rustc_crate_types();
+                if unit.target.for_host() {
+                    Lto::OnlyObject
+                } else if needs_object(&crate_types) {
+                    lto_when_needs_object(&crate_types)
+                } else {
+                    // This may or may not participate in LTO, so start with the minimum
+                    // requirements, which may be expanded in `calculate`.
+                    Lto::OnlyBitcode
+                }
+            }
+        };
+        calculate(bcx, &mut map, unit, root_lto)?;
+    }
+    Ok(map)
+}
+
+// Whether any of the crate types needs the object code.
+fn needs_object(crate_types: &[CrateType]) -> bool {
+    crate_types.iter().any(|k| k.can_lto() || k.is_dynamic())
+}
+
+// Dylibs don't support LTO, so they only need the object code, while the mixes with other crate
+// types need both.
+fn lto_when_needs_object(crate_types: &[CrateType]) -> Lto {
+    if crate_types.iter().all(|ct| *ct == CrateType::Dylib) {
+        Lto::OnlyObject
+    } else {
+        Lto::ObjectAndBitcode
+    }
+}
+
+fn calculate(
+    bcx: &BuildContext<'_, '_>,
+    map: &mut HashMap<Unit, Lto>,
+    unit: &Unit,
+    parent_lto: Lto,
+) -> CargoResult<()> {
+    let crate_types = match unit.mode {
+        CompileMode::Test | CompileMode::Bench | CompileMode::Doctest => vec![CrateType::Bin],
+        _ => unit.target.rustc_crate_types(),
+    };
+    // LTO can only be performed if all of the crate types support it.
+    let all_lto_types = crate_types.iter().all(CrateType::can_lto);
+    let lto = if unit.target.for_host() {
+        // No LTO for build scripts and proc macros.
+        Lto::OnlyObject
+    } else if all_lto_types {
+        match unit.profile.lto {
+            profiles::Lto::Named(s) => Lto::Run(Some(s)),
+            profiles::Lto::Off => Lto::Off,
+            profiles::Lto::Bool(true) => Lto::Run(None),
+            profiles::Lto::Bool(false) => Lto::OnlyObject,
+        }
+    } else {
+        match (parent_lto, needs_object(&crate_types)) {
+            (Lto::Run(_), false) => Lto::OnlyBitcode,
+            (Lto::Run(_), true) | (Lto::OnlyBitcode, true) => lto_when_needs_object(&crate_types),
+            (Lto::Off, _) => Lto::Off,
+            (_, false) | (Lto::OnlyObject, true) | (Lto::ObjectAndBitcode, true) => parent_lto,
+        }
+    };
+
+    // A unit appearing several times in the graph gets the union of the requirements.
+    let merged_lto = match map.entry(unit.clone()) {
+        Entry::Vacant(v) => *v.insert(lto),
+        Entry::Occupied(mut v) => {
+            let result = match (lto, v.get()) {
+                (Lto::OnlyBitcode, Lto::OnlyBitcode) => Lto::OnlyBitcode,
+                (Lto::OnlyObject, Lto::OnlyObject) => Lto::OnlyObject,
+                (Lto::Run(s), _) | (_, &Lto::Run(s)) => Lto::Run(s),
+                (Lto::Off, _) | (_, Lto::Off) => Lto::Off,
+                (Lto::ObjectAndBitcode, _) | (_, Lto::ObjectAndBitcode) => Lto::ObjectAndBitcode,
+                (Lto::OnlyObject, Lto::OnlyBitcode) | (Lto::OnlyBitcode, Lto::OnlyObject) => Lto::ObjectAndBitcode,
+            };
+            // No need to recurse if nothing changed.
+            if result == *v.get() {
+                return Ok(());
+            }
+            v.insert(result);
+            result
+        }
+    };
+
+    for dep in &bcx.unit_graph[unit] {
+        calculate(bcx, map, &dep.unit, merged_lto)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo::core::compiler::UnitInterner;
+    use cargo::core::Workspace;
+    use cargo::ops::{self, CompileOptions};
+    use cargo::util::config::Config;
+    use cargo::util::interning::InternedString;
+
+    #[test]
+    fn test_generate_release_lto() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = "[package]\nname = \"lto-test\"\nversion = \"0.1.0\"\n\n[profile.release]\nlto = true\n";
+        std::fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+
+        let config = Config::default().unwrap();
+        let ws = Workspace::new(&dir.path().join("Cargo.toml"), &config).unwrap();
+        let mut compile_opts = CompileOptions::new(&config, CompileMode::Build).unwrap();
+        compile_opts.build_config.requested_profile = InternedString::new("release");
+        let interner = UnitInterner::new();
+        let bcx = ops::create_bcx(&ws, &compile_opts, &interner).unwrap();
+
+        let lto: HashMap<_, _> = generate(&bcx)
+            .unwrap()
+            .into_iter()
+            .map(|(unit, lto)| (unit.target.kind().description(), lto))
+            .collect();
+        // The binary is linked with LTO, and the library it depends on only needs the bitcode for it.
+        assert_eq!(lto.len(), 2);
+        assert_eq!(lto["bin"], Lto::Run(None));
+        assert_eq!(lto["lib"], Lto::OnlyBitcode);
+    }
+}